@@ -17,5 +17,7 @@
 */
 
 fn main() {
-    tauri_build::build()
+    // filey-server is built without the app, see the gui feature
+    #[cfg(feature = "gui")]
+    tauri_build::build();
 }
@@ -0,0 +1,8 @@
+-- Add up migration script here
+create table
+  snippets (
+    id text primary key,
+    title text not null,
+    body text not null,
+    visibility text not null default "private" check (visibility in ("public", "private"))
+  );
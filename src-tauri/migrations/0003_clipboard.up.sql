@@ -0,0 +1,12 @@
+-- Add up migration script here
+create table
+  clipboard_history (
+    id text primary key,
+    kind text not null check (kind in ("text", "image")),
+    text text,
+    image blob,
+    width integer,
+    height integer,
+    origin text not null,
+    created_at integer not null
+  );
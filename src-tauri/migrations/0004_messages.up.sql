@@ -0,0 +1,17 @@
+-- Add up migration script here
+create table
+  conversations (
+    peer text primary key,
+    name text not null,
+    updated_at integer not null
+  );
+
+create table
+  messages (
+    id text primary key,
+    peer text not null references conversations (peer) on delete cascade,
+    direction text not null check (direction in ("incoming", "outgoing")),
+    sender text not null,
+    body text not null,
+    created_at integer not null
+  );
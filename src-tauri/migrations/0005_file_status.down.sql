@@ -0,0 +1,6 @@
+-- Add down migration script here
+alter table files drop column modified_at;
+
+alter table files drop column size;
+
+alter table files drop column missing;
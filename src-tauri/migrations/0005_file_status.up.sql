@@ -0,0 +1,6 @@
+-- Add up migration script here
+alter table files add column missing boolean not null default false;
+
+alter table files add column size integer;
+
+alter table files add column modified_at integer;
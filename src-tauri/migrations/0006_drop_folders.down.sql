@@ -0,0 +1,23 @@
+-- Add down migration script here
+-- SQLite cannot drop a column that references another table, so files is rebuilt without it
+create table
+  files_new (
+    id text primary key,
+    name text not null,
+    mime text not null,
+    visibility text not null default "private" check (visibility in ("public", "private")),
+    path text not null unique,
+    missing boolean not null default false,
+    size integer,
+    modified_at integer
+  );
+
+insert into files_new (id, name, mime, visibility, path, missing, size, modified_at)
+select id, name, mime, visibility, path, missing, size, modified_at
+from files;
+
+drop table files;
+
+alter table files_new rename to files;
+
+drop table drop_folders;
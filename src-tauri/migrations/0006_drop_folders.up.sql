@@ -0,0 +1,11 @@
+-- Add up migration script here
+create table
+  drop_folders (
+    id text primary key,
+    path text not null unique,
+    visibility text not null default "private" check (visibility in ("public", "private")),
+    include_patterns text not null default "",
+    exclude_patterns text not null default ""
+  );
+
+alter table files add column drop_folder_id text references drop_folders (id) on delete cascade;
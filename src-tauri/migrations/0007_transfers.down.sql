@@ -0,0 +1,4 @@
+-- Add down migration script here
+drop table transfer_rules;
+
+drop table transfer_requests;
@@ -0,0 +1,17 @@
+-- Add up migration script here
+create table
+  transfer_requests (
+    id text primary key,
+    peer text not null,
+    sender text not null,
+    files text not null,
+    total_size integer not null,
+    status text not null default "pending" check (status in ("pending", "accepted", "declined", "expired")),
+    created_at integer not null
+  );
+
+create table
+  transfer_rules (
+    peer text primary key,
+    created_at integer not null
+  );
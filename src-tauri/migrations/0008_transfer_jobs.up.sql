@@ -0,0 +1,21 @@
+-- Add up migration script here
+create table
+  transfer_jobs (
+    id text primary key,
+    direction text not null check (direction in ("incoming", "outgoing")),
+    peer text not null,
+    name text not null,
+    source text not null,
+    request_id text,
+    file_index integer,
+    destination text,
+    size integer,
+    transferred integer not null default 0,
+    priority integer not null default 0,
+    state text not null default "queued" check (
+      state in ("queued", "running", "paused", "completed", "failed", "cancelled")
+    ),
+    error text,
+    created_at integer not null,
+    updated_at integer not null
+  );
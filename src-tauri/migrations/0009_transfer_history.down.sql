@@ -0,0 +1,6 @@
+-- Add down migration script here
+alter table transfer_jobs drop column active_ms;
+
+drop index transfer_history_created_at;
+
+drop table transfer_history;
@@ -0,0 +1,21 @@
+-- Add up migration script here
+create table
+  transfer_history (
+    id text primary key,
+    direction text not null check (direction in ("incoming", "outgoing")),
+    peer text not null,
+    peer_name text,
+    name text not null,
+    size integer,
+    hash text,
+    duration_ms integer not null,
+    speed integer,
+    outcome text not null check (outcome in ("completed", "failed", "cancelled")),
+    destination text,
+    error text,
+    created_at integer not null
+  );
+
+create index transfer_history_created_at on transfer_history (created_at);
+
+alter table transfer_jobs add column active_ms integer not null default 0;
@@ -0,0 +1,10 @@
+-- Add down migration script here
+drop table file_hashes;
+
+drop table sync_conflicts;
+
+drop table sync_state;
+
+drop table sync_pairs;
+
+drop table shared_folders;
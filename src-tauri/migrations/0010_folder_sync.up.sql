@@ -0,0 +1,43 @@
+-- Add up migration script here
+create table
+  shared_folders (
+    id text primary key,
+    path text not null unique,
+    name text not null,
+    writable boolean not null default false
+  );
+
+create table
+  sync_pairs (
+    id text primary key,
+    local_path text not null,
+    peer text not null,
+    folder_id text not null,
+    last_synced_at integer,
+    unique (peer, folder_id)
+  );
+
+create table
+  sync_state (
+    pair_id text not null references sync_pairs (id) on delete cascade,
+    path text not null,
+    hash text not null,
+    primary key (pair_id, path)
+  );
+
+create table
+  sync_conflicts (
+    id text primary key,
+    pair_id text not null references sync_pairs (id) on delete cascade,
+    path text not null,
+    conflict_path text not null,
+    created_at integer not null
+  );
+
+create table
+  file_hashes (
+    path text primary key,
+    size integer not null,
+    modified_at integer not null,
+    hash text not null
+  );
@@ -0,0 +1,2 @@
+-- Add down migration script here
+alter table transfer_jobs drop column base_path;
@@ -0,0 +1,2 @@
+-- Add up migration script here
+alter table transfer_jobs add column base_path text;
@@ -0,0 +1,10 @@
+-- Add up migration script here
+create table
+  device_identity (
+    -- There is only ever one row, the identity of this device
+    singleton integer primary key check (singleton = 1),
+    id text not null,
+    name text,
+    secret_key text not null,
+    created_at integer not null
+  );
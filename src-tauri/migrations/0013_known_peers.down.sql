@@ -0,0 +1,4 @@
+-- Add down migration script here
+drop table known_peer_addresses;
+
+drop table known_peers;
@@ -0,0 +1,21 @@
+-- Add up migration script here
+create table
+  known_peers (
+    device_id text primary key,
+    device_name text,
+    nickname text,
+    os_type text not null,
+    public_key text,
+    favourite boolean not null default false,
+    last_address text not null,
+    last_seen_at integer not null,
+    created_at integer not null
+  );
+
+create table
+  known_peer_addresses (
+    device_id text not null references known_peers (device_id) on delete cascade,
+    address text not null,
+    last_seen_at integer not null,
+    primary key (device_id, address)
+  );
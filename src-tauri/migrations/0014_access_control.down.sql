@@ -0,0 +1,6 @@
+-- Add down migration script here
+drop index access_log_created_at;
+
+drop table access_log;
+
+drop table access_rules;
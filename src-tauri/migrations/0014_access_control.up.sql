@@ -0,0 +1,21 @@
+-- Add up migration script here
+create table
+  access_rules (
+    id text primary key,
+    cidr text not null unique,
+    action text not null check (action in ("allow", "deny")),
+    created_at integer not null
+  );
+
+create table
+  access_log (
+    id text primary key,
+    address text not null,
+    method text not null,
+    path text not null,
+    status integer not null,
+    allowed boolean not null,
+    created_at integer not null
+  );
+
+create index access_log_created_at on access_log (created_at);
@@ -0,0 +1,6 @@
+-- Add down migration script here
+drop table file_acl;
+
+drop table peer_group_members;
+
+drop table peer_groups;
@@ -0,0 +1,24 @@
+-- Add up migration script here
+create table
+  peer_groups (
+    id text primary key,
+    name text not null unique,
+    created_at integer not null
+  );
+
+create table
+  peer_group_members (
+    group_id text not null references peer_groups (id) on delete cascade,
+    device_id text not null references known_peers (device_id) on delete cascade,
+    primary key (group_id, device_id)
+  );
+
+create table
+  file_acl (
+    file_id text not null references files (id) on delete cascade,
+    device_id text references known_peers (device_id) on delete cascade,
+    group_id text references peer_groups (id) on delete cascade,
+    check ((device_id is null) != (group_id is null)),
+    unique (file_id, device_id),
+    unique (file_id, group_id)
+  );
@@ -0,0 +1,73 @@
+-- Add up migration script here
+-- SQLite cannot change a check constraint, so the tables are rebuilt with the new one
+
+-- Dropping drop_folders and files deletes the rows that reference them (on delete cascade),
+-- they are copied aside first and put back afterwards
+create temporary table files_backup as
+select * from files;
+
+create temporary table file_acl_backup as
+select * from file_acl;
+
+create table
+  drop_folders_new (
+    id text primary key,
+    path text not null unique,
+    visibility text not null default "private" check (visibility in ("public", "private", "unlisted")),
+    include_patterns text not null default "",
+    exclude_patterns text not null default ""
+  );
+
+insert into drop_folders_new (id, path, visibility, include_patterns, exclude_patterns)
+select id, path, visibility, include_patterns, exclude_patterns
+from drop_folders;
+
+drop table drop_folders;
+
+alter table drop_folders_new rename to drop_folders;
+
+create table
+  files_new (
+    id text primary key,
+    name text not null,
+    mime text not null,
+    visibility text not null default "private" check (visibility in ("public", "private", "unlisted")),
+    path text not null unique,
+    missing boolean not null default false,
+    size integer,
+    modified_at integer,
+    drop_folder_id text references drop_folders (id) on delete cascade
+  );
+
+drop table files;
+
+alter table files_new rename to files;
+
+insert into files
+  (id, name, mime, visibility, path, missing, size, modified_at, drop_folder_id)
+select
+  id, name, mime, visibility, path, missing, size, modified_at, drop_folder_id
+from files_backup;
+
+insert into file_acl
+select * from file_acl_backup;
+
+drop table file_acl_backup;
+
+drop table files_backup;
+
+create table
+  snippets_new (
+    id text primary key,
+    title text not null,
+    body text not null,
+    visibility text not null default "private" check (visibility in ("public", "private", "unlisted"))
+  );
+
+insert into snippets_new (id, title, body, visibility)
+select id, title, body, visibility
+from snippets;
+
+drop table snippets;
+
+alter table snippets_new rename to snippets;
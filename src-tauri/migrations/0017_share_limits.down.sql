@@ -0,0 +1,8 @@
+-- Add down migration script here
+alter table files drop column on_expiry;
+
+alter table files drop column download_count;
+
+alter table files drop column max_downloads;
+
+alter table files drop column share_until;
@@ -0,0 +1,8 @@
+-- Add up migration script here
+alter table files add column share_until integer;
+
+alter table files add column max_downloads integer;
+
+alter table files add column download_count integer not null default 0;
+
+alter table files add column on_expiry text not null default "private" check (on_expiry in ("private", "remove"));
@@ -0,0 +1,2 @@
+-- Add up migration script here
+alter table known_peers add column encrypted boolean not null default false;
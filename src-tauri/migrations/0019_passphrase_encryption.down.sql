@@ -0,0 +1,4 @@
+-- Add down migration script here
+alter table transfer_jobs drop column passphrase;
+
+alter table files drop column passphrase;
@@ -0,0 +1,4 @@
+-- Add up migration script here
+alter table files add column passphrase text;
+
+alter table transfer_jobs add column passphrase text;
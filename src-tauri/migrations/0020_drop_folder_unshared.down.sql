@@ -0,0 +1,2 @@
+-- Add down migration script here
+drop table drop_folder_unshared;
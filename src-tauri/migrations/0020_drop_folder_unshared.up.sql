@@ -0,0 +1,8 @@
+-- Add up migration script here
+create table
+  drop_folder_unshared (
+    drop_folder_id text not null references drop_folders (id) on delete cascade,
+    path text not null,
+    modified_at integer,
+    primary key (drop_folder_id, path)
+  );
@@ -0,0 +1,9 @@
+-- Add up migration script here
+create table
+  transfer_received_files (
+    request_id text not null references transfer_requests (id) on delete cascade,
+    file_index integer not null,
+    destination text not null,
+    received_at integer not null,
+    primary key (request_id, file_index)
+  );
@@ -0,0 +1,2 @@
+-- Add down migration script here
+drop table shared_folder_peers;
@@ -0,0 +1,7 @@
+-- Add up migration script here
+create table
+  shared_folder_peers (
+    folder_id text not null references shared_folders (id) on delete cascade,
+    device_id text not null references known_peers (device_id) on delete cascade,
+    primary key (folder_id, device_id)
+  );
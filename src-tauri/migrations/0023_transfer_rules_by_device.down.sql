@@ -0,0 +1,10 @@
+-- Add down migration script here
+alter table transfer_requests drop column device_id;
+
+drop table transfer_rules;
+
+create table
+  transfer_rules (
+    peer text primary key,
+    created_at integer not null
+  );
@@ -0,0 +1,11 @@
+-- Add up migration script here
+-- The rules were by IP address, which anyone on the network can take, they are not carried over
+drop table transfer_rules;
+
+create table
+  transfer_rules (
+    device_id text primary key references known_peers (device_id) on delete cascade,
+    created_at integer not null
+  );
+
+alter table transfer_requests add column device_id text;
@@ -0,0 +1,4 @@
+-- Add down migration script here
+drop index known_peers_webdav_token;
+
+alter table known_peers drop column webdav_token;
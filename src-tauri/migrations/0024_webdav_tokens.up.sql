@@ -0,0 +1,4 @@
+-- Add up migration script here
+alter table known_peers add column webdav_token text;
+
+create unique index known_peers_webdav_token on known_peers (webdav_token);
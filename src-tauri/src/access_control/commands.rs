@@ -0,0 +1,215 @@
+/*
+  Filey - simple peer-to-peer file sending across devices on different platforms
+  Copyright (C) 2024 Wander Watterson
+
+  This program is free software: you can redistribute it and/or modify
+  it under the terms of the GNU General Public License as published by
+  the Free Software Foundation, either version 3 of the License, or
+  (at your option) any later version.
+
+  This program is distributed in the hope that it will be useful,
+  but WITHOUT ANY WARRANTY; without even the implied warranty of
+  MERCHANTABILITY or FITNESS FOR A PARTICULAR PURPOSE. See the
+  GNU General Public License for more details.
+
+  You should have received a copy of the GNU General Public License
+  along with this program. If not, see <https://www.gnu.org/licenses/>.
+*/
+
+use log::error;
+use sqlx::SqlitePool;
+use std::time::Duration;
+use uuid::{fmt::Hyphenated, Uuid};
+
+use super::models::{
+    parse_cidr, AccessAction, AccessRuleModel, ACCESS_LOG_PRUNE_INTERVAL, MAX_ACCESS_LOG_ENTRIES,
+};
+use crate::{db::now, error::Error};
+
+#[cfg(feature = "gui")]
+use tauri::State;
+
+#[cfg(feature = "gui")]
+use super::models::AccessLogModel;
+#[cfg(feature = "gui")]
+use crate::AppState;
+
+// Returns every allow and deny rule
+#[cfg(feature = "gui")]
+#[tauri::command]
+pub async fn get_access_rules(state: State<'_, AppState>) -> Result<Vec<AccessRuleModel>, Error> {
+    load_access_rules(&state.db).await
+}
+
+/*
+ * Allows or denies a CIDR range, or a single address
+ * A rule that already exists for the same range gets its action replaced
+ */
+#[cfg(feature = "gui")]
+#[tauri::command]
+pub async fn add_access_rule(
+    state: State<'_, AppState>,
+    cidr: String,
+    action: AccessAction,
+) -> Result<AccessRuleModel, Error> {
+    save_access_rule(&state.db, &cidr, action).await
+}
+
+#[cfg(feature = "gui")]
+#[tauri::command]
+pub async fn delete_access_rule(state: State<'_, AppState>, id: Uuid) -> Result<(), Error> {
+    let rule_id = id.to_string();
+    sqlx::query!(
+        "delete from access_rules where id = $1 returning id",
+        rule_id
+    )
+    .fetch_one(&state.db)
+    .await?;
+    Ok(())
+}
+
+// Returns the latest requests that reached the server, the most recent first
+#[cfg(feature = "gui")]
+#[tauri::command]
+pub async fn get_access_log(
+    state: State<'_, AppState>,
+    limit: Option<i64>,
+) -> Result<Vec<AccessLogModel>, Error> {
+    let limit = limit.unwrap_or(-1);
+    let entries = sqlx::query_as!(
+        AccessLogModel,
+        r#"
+            select
+                id as "id!: Hyphenated",
+                address,
+                method,
+                path,
+                status,
+                allowed,
+                created_at
+            from access_log
+            order by created_at desc
+            limit $1
+        "#,
+        limit
+    )
+    .fetch_all(&state.db)
+    .await?;
+
+    Ok(entries)
+}
+
+// Denies the address a request in the access log came from
+#[cfg(feature = "gui")]
+#[tauri::command]
+pub async fn block_from_access_log(
+    state: State<'_, AppState>,
+    id: Uuid,
+) -> Result<AccessRuleModel, Error> {
+    let entry_id = id.to_string();
+    let entry = sqlx::query!("select address from access_log where id = $1", entry_id)
+        .fetch_one(&state.db)
+        .await?;
+
+    save_access_rule(&state.db, &entry.address, AccessAction::Deny).await
+}
+
+#[cfg(feature = "gui")]
+#[tauri::command]
+pub async fn clear_access_log(state: State<'_, AppState>) -> Result<(), Error> {
+    sqlx::query!("delete from access_log")
+        .execute(&state.db)
+        .await?;
+    Ok(())
+}
+
+/*
+ * Keeps the access log to the latest MAX_ACCESS_LOG_ENTRIES entries
+ * This runs on its own every now and then, so the requests only pay for adding their entry
+ */
+pub fn spawn_access_log_pruning(db: SqlitePool) {
+    crate::runtime::spawn(async move {
+        let mut interval = tokio::time::interval(Duration::from_secs(ACCESS_LOG_PRUNE_INTERVAL));
+        loop {
+            interval.tick().await;
+            if let Err(err) = prune_access_log(&db).await {
+                error!("Cannot prune the access log: {err}");
+            }
+        }
+    });
+}
+
+pub async fn prune_access_log(db: &SqlitePool) -> Result<(), Error> {
+    sqlx::query!(
+        "
+            delete from access_log
+            where created_at < (
+                select created_at
+                from access_log
+                order by created_at desc
+                limit 1
+                offset $1
+            )
+        ",
+        MAX_ACCESS_LOG_ENTRIES
+    )
+    .execute(db)
+    .await?;
+    Ok(())
+}
+
+pub async fn load_access_rules(db: &SqlitePool) -> Result<Vec<AccessRuleModel>, Error> {
+    let rules = sqlx::query_as!(
+        AccessRuleModel,
+        r#"
+            select
+                id as "id!: Hyphenated",
+                cidr,
+                action as "action!: AccessAction",
+                created_at
+            from access_rules
+            order by created_at
+        "#
+    )
+    .fetch_all(db)
+    .await?;
+
+    Ok(rules)
+}
+
+async fn save_access_rule(
+    db: &SqlitePool,
+    cidr: &str,
+    action: AccessAction,
+) -> Result<AccessRuleModel, Error> {
+    // Stored the way ipnet writes it, so the same range is always the same text
+    let cidr = parse_cidr(cidr)?.to_string();
+    let id = Uuid::new_v4().to_string();
+    let action_text = action.to_string();
+    let created_at = now();
+
+    let rule = sqlx::query_as!(
+        AccessRuleModel,
+        r#"
+            insert into access_rules
+                (id, cidr, action, created_at)
+            values
+                ($1, $2, $3, $4)
+            on conflict (cidr)
+            do update set action = excluded.action
+            returning
+                id as "id!: Hyphenated",
+                cidr as "cidr!",
+                action as "action!: AccessAction",
+                created_at as "created_at!"
+        "#,
+        id,
+        cidr,
+        action_text,
+        created_at
+    )
+    .fetch_one(db)
+    .await?;
+
+    Ok(rule)
+}
@@ -0,0 +1,180 @@
+/*
+  Filey - simple peer-to-peer file sending across devices on different platforms
+  Copyright (C) 2024 Wander Watterson
+
+  This program is free software: you can redistribute it and/or modify
+  it under the terms of the GNU General Public License as published by
+  the Free Software Foundation, either version 3 of the License, or
+  (at your option) any later version.
+
+  This program is distributed in the hope that it will be useful,
+  but WITHOUT ANY WARRANTY; without even the implied warranty of
+  MERCHANTABILITY or FITNESS FOR A PARTICULAR PURPOSE. See the
+  GNU General Public License for more details.
+
+  You should have received a copy of the GNU General Public License
+  along with this program. If not, see <https://www.gnu.org/licenses/>.
+*/
+
+use axum::{
+    extract::{ConnectInfo, Request, State},
+    middleware::Next,
+    response::{IntoResponse, Response},
+};
+use log::error;
+use sqlx::SqlitePool;
+use std::net::{IpAddr, SocketAddr};
+use uuid::Uuid;
+
+use super::{commands::load_access_rules, models::AccessAction};
+use crate::{db::now, error::Error, http_server::models::ServerState};
+
+/*
+ * Decides whether a client can talk to the server at all, before any route runs
+ *
+ * Deny rules win over allow rules. As long as there is no allow rule, everyone who is not denied
+ * gets in, as soon as there is one, only the allowed ranges do.
+ * The device itself (loopback) always gets in, so a rule cannot lock the app out of its own server
+ *
+ * Every request ends up in the access log, along with whether it was let in
+ */
+pub async fn check_access(
+    State(ServerState { db, .. }): State<ServerState>,
+    ConnectInfo(address): ConnectInfo<SocketAddr>,
+    request: Request,
+    next: Next,
+) -> Response {
+    let ip = address.ip().to_canonical();
+    let method = request.method().to_string();
+    let path = request.uri().path().to_string();
+
+    let allowed = match is_allowed(&db, &ip).await {
+        Ok(allowed) => allowed,
+        // Nobody gets in while the rules cannot be read
+        Err(err) => return err.into_response(),
+    };
+
+    let response = match allowed {
+        true => next.run(request).await,
+        false => Error::AccessDenied(ip.to_string()).into_response(),
+    };
+
+    let status = response.status().as_u16();
+    if let Err(err) = log_access(&db, &ip, &method, &path, status, allowed).await {
+        error!("Cannot write the access log: {err}");
+    }
+
+    response
+}
+
+async fn is_allowed(db: &SqlitePool, ip: &IpAddr) -> Result<bool, Error> {
+    if ip.is_loopback() {
+        return Ok(true);
+    }
+
+    let rules = load_access_rules(db).await?;
+    let denied = rules
+        .iter()
+        .any(|rule| rule.action == AccessAction::Deny && rule.contains(ip));
+    let allow_rules: Vec<_> = rules
+        .iter()
+        .filter(|rule| rule.action == AccessAction::Allow)
+        .collect();
+
+    Ok(!denied && (allow_rules.is_empty() || allow_rules.iter().any(|rule| rule.contains(ip))))
+}
+
+// Adds an entry to the access log, the old ones are dropped later on, see spawn_access_log_pruning
+async fn log_access(
+    db: &SqlitePool,
+    ip: &IpAddr,
+    method: &str,
+    path: &str,
+    status: u16,
+    allowed: bool,
+) -> Result<(), Error> {
+    let id = Uuid::new_v4().to_string();
+    let address = ip.to_string();
+    let created_at = now();
+
+    sqlx::query!(
+        "
+            insert into access_log
+                (id, address, method, path, status, allowed, created_at)
+            values
+                ($1, $2, $3, $4, $5, $6, $7)
+        ",
+        id,
+        address,
+        method,
+        path,
+        status,
+        allowed,
+        created_at
+    )
+    .execute(db)
+    .await?;
+
+    Ok(())
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::db::test_db;
+
+    async fn add_rule(db: &SqlitePool, cidr: &str, action: &str) {
+        sqlx::query(
+            "
+                insert into access_rules
+                    (id, cidr, action, created_at)
+                values
+                    ($1, $2, $3, 0)
+            ",
+        )
+        .bind(Uuid::new_v4().to_string())
+        .bind(cidr)
+        .bind(action)
+        .execute(db)
+        .await
+        .unwrap();
+    }
+
+    async fn allowed(db: &SqlitePool, address: &str) -> bool {
+        is_allowed(db, &address.parse().unwrap()).await.unwrap()
+    }
+
+    #[tokio::test]
+    async fn everyone_gets_in_without_rules() {
+        let db = test_db().await;
+        assert!(allowed(&db, "192.168.1.2").await);
+        assert!(allowed(&db, "fe80::2").await);
+    }
+
+    #[tokio::test]
+    async fn only_allowed_ranges_get_in_once_there_is_an_allow_rule() {
+        let db = test_db().await;
+        add_rule(&db, "192.168.1.0/24", "allow").await;
+        assert!(allowed(&db, "192.168.1.2").await);
+        assert!(!allowed(&db, "10.0.0.2").await);
+    }
+
+    #[tokio::test]
+    async fn deny_wins_over_allow() {
+        let db = test_db().await;
+        add_rule(&db, "192.168.1.0/24", "allow").await;
+        add_rule(&db, "192.168.1.66/32", "deny").await;
+        assert!(allowed(&db, "192.168.1.2").await);
+        assert!(!allowed(&db, "192.168.1.66").await);
+    }
+
+    #[tokio::test]
+    async fn loopback_always_gets_in() {
+        let db = test_db().await;
+        add_rule(&db, "0.0.0.0/0", "deny").await;
+        add_rule(&db, "::/0", "deny").await;
+        assert!(allowed(&db, "127.0.0.1").await);
+        assert!(allowed(&db, "::1").await);
+        assert!(!allowed(&db, "192.168.1.2").await);
+    }
+}
@@ -0,0 +1,146 @@
+/*
+  Filey - simple peer-to-peer file sending across devices on different platforms
+  Copyright (C) 2024 Wander Watterson
+
+  This program is free software: you can redistribute it and/or modify
+  it under the terms of the GNU General Public License as published by
+  the Free Software Foundation, either version 3 of the License, or
+  (at your option) any later version.
+
+  This program is distributed in the hope that it will be useful,
+  but WITHOUT ANY WARRANTY; without even the implied warranty of
+  MERCHANTABILITY or FITNESS FOR A PARTICULAR PURPOSE. See the
+  GNU General Public License for more details.
+
+  You should have received a copy of the GNU General Public License
+  along with this program. If not, see <https://www.gnu.org/licenses/>.
+*/
+
+use ipnet::IpNet;
+use serde::{Deserialize, Serialize};
+use sqlx::{sqlite::SqliteTypeInfo, Database, Decode, Encode, Sqlite, Type};
+use std::{net::IpAddr, str::FromStr};
+use strum::{Display, EnumString};
+use uuid::Uuid;
+
+use crate::error::Error;
+
+// Only the latest entries of the access log are worth keeping around
+pub const MAX_ACCESS_LOG_ENTRIES: i64 = 5000;
+
+// Seconds between two prunings of the access log, it can grow past the limit in between
+pub const ACCESS_LOG_PRUNE_INTERVAL: u64 = 60;
+
+#[derive(Serialize, Deserialize, Debug, Clone, PartialEq, Encode, Display, EnumString)]
+#[serde(rename_all = "lowercase")]
+#[strum(serialize_all = "lowercase")]
+pub enum AccessAction {
+    Allow,
+    Deny,
+}
+
+impl Type<Sqlite> for AccessAction {
+    fn type_info() -> SqliteTypeInfo {
+        <&str as Type<Sqlite>>::type_info()
+    }
+    fn compatible(ty: &SqliteTypeInfo) -> bool {
+        <&str as Type<Sqlite>>::compatible(ty)
+    }
+}
+
+impl<'r, DB: Database> Decode<'r, DB> for AccessAction
+where
+    &'r str: Decode<'r, DB>,
+{
+    fn decode(
+        value: <DB as sqlx::Database>::ValueRef<'r>,
+    ) -> Result<Self, sqlx::error::BoxDynError> {
+        let value = <&str as Decode<DB>>::decode(value)?;
+        Ok(AccessAction::from_str(value).unwrap())
+    }
+}
+
+/*
+ * Allows or denies the addresses in a CIDR range (192.168.1.0/24), or a single address
+ * See access_control::middleware for how the rules are applied
+ */
+#[derive(Debug, Clone, Serialize, Deserialize)]
+#[serde(rename_all = "camelCase")]
+pub struct AccessRuleModel {
+    pub id: Uuid,
+    pub cidr: String,
+    pub action: AccessAction,
+    pub created_at: i64,
+}
+
+impl AccessRuleModel {
+    pub fn contains(&self, address: &IpAddr) -> bool {
+        parse_cidr(&self.cidr).is_ok_and(|net| net.contains(address))
+    }
+}
+
+// A single address is a range of one, 10.0.0.7 => 10.0.0.7/32
+pub fn parse_cidr(cidr: &str) -> Result<IpNet, Error> {
+    let cidr = cidr.trim();
+    IpNet::from_str(cidr)
+        .or_else(|_| IpAddr::from_str(cidr).map(IpNet::from))
+        .map_err(|_| Error::InvalidCidr(cidr.to_string()))
+}
+
+// A request that reached the server, denied or not
+#[derive(Debug, Clone, Serialize, Deserialize)]
+#[serde(rename_all = "camelCase")]
+pub struct AccessLogModel {
+    pub id: Uuid,
+    pub address: String,
+    pub method: String,
+    pub path: String,
+    pub status: i64,
+    pub allowed: bool,
+    pub created_at: i64,
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn rule(cidr: &str) -> AccessRuleModel {
+        AccessRuleModel {
+            id: Uuid::new_v4(),
+            cidr: cidr.to_string(),
+            action: AccessAction::Deny,
+            created_at: 0,
+        }
+    }
+
+    fn ip(address: &str) -> IpAddr {
+        address.parse().unwrap()
+    }
+
+    #[test]
+    fn single_address_is_a_range_of_one() {
+        assert_eq!(parse_cidr("10.0.0.7").unwrap().to_string(), "10.0.0.7/32");
+        assert_eq!(parse_cidr(" fe80::1 ").unwrap().to_string(), "fe80::1/128");
+    }
+
+    #[test]
+    fn invalid_range_is_rejected() {
+        for cidr in ["", "10.0.0", "10.0.0.0/33", "fe80::/129", "localhost"] {
+            assert!(matches!(parse_cidr(cidr), Err(Error::InvalidCidr(_))));
+        }
+    }
+
+    #[test]
+    fn range_contains_its_addresses_only() {
+        let rule = rule("192.168.1.0/24");
+        assert!(rule.contains(&ip("192.168.1.0")));
+        assert!(rule.contains(&ip("192.168.1.255")));
+        assert!(!rule.contains(&ip("192.168.2.1")));
+        assert!(!rule.contains(&ip("::ffff:192.168.1.1")));
+    }
+
+    #[test]
+    fn broken_rule_contains_nothing() {
+        assert!(!rule("192.168.1.0/99").contains(&ip("192.168.1.1")));
+    }
+}
@@ -0,0 +1,475 @@
+/*
+  Filey - simple peer-to-peer file sending across devices on different platforms
+  Copyright (C) 2024 Wander Watterson
+
+  This program is free software: you can redistribute it and/or modify
+  it under the terms of the GNU General Public License as published by
+  the Free Software Foundation, either version 3 of the License, or
+  (at your option) any later version.
+
+  This program is distributed in the hope that it will be useful,
+  but WITHOUT ANY WARRANTY; without even the implied warranty of
+  MERCHANTABILITY or FITNESS FOR A PARTICULAR PURPOSE. See the
+  GNU General Public License for more details.
+
+  You should have received a copy of the GNU General Public License
+  along with this program. If not, see <https://www.gnu.org/licenses/>.
+*/
+
+use log::error;
+use reqwest::{
+    header::{HeaderValue, CONTENT_TYPE},
+    Method,
+};
+use sqlx::SqlitePool;
+use std::time::Duration;
+use tauri::{image::Image, AppHandle, Manager, State};
+use tauri_plugin_clipboard_manager::ClipboardExt;
+use tokio::sync::oneshot::{self, Receiver};
+use uuid::{fmt::Hyphenated, Uuid};
+
+use super::models::{
+    ClipboardContent, ClipboardEntry, ClipboardSync, MAX_CLIPBOARD_HISTORY_ENTRIES,
+    MAX_CLIPBOARD_HISTORY_SIZE,
+};
+use crate::{
+    db::now,
+    device::{
+        commands::{signed_body_headers, signed_headers},
+        models::content_sha256,
+    },
+    error::Error,
+    http_server::{commands::require_capability, models::Capability},
+    noise::client::{get_from_peer, request_known_peer},
+    peers::commands::load_known_peer,
+    AppState, ServerResponse,
+};
+
+// How often the clipboard is checked for changes
+const CLIPBOARD_POLL_INTERVAL: Duration = Duration::from_millis(800);
+
+/*
+ * Turns on clipboard sync with the selected known peers, by device id
+ * From now on, every time the clipboard changes, the new content is pushed to all of them,
+ * and they are the only peers allowed to push their clipboard to us, or pull our history
+ * Calling this again with a different list of peers replaces the old sync
+ */
+#[tauri::command]
+pub async fn start_clipboard_sync(
+    app_handle: AppHandle,
+    peers: Vec<Uuid>,
+    include_images: bool,
+) -> Result<(), Error> {
+    let state = app_handle.state::<AppState>();
+
+    let (shutdown_trigger, shutdown_listener) = oneshot::channel::<()>();
+
+    // Remember what is currently in the clipboard, only the changes made from now on are pushed
+    let last_hash = read_clipboard(&app_handle, include_images).map(|content| content.hash_value());
+
+    if let Some(old_sync) = state.clipboard_sync.lock().await.replace(ClipboardSync {
+        peers,
+        include_images,
+        last_hash,
+        shutdown_trigger,
+    }) {
+        old_sync.shutdown_trigger.send(()).ok();
+    }
+
+    crate::runtime::spawn(watch_clipboard(app_handle.clone(), shutdown_listener));
+
+    Ok(())
+}
+
+// Turns off clipboard sync, by triggering the shutdown of the clipboard watcher
+#[tauri::command]
+pub async fn stop_clipboard_sync(state: State<'_, AppState>) -> Result<(), Error> {
+    if let Some(sync) = state.clipboard_sync.lock().await.take() {
+        sync.shutdown_trigger.send(()).ok();
+    }
+    Ok(())
+}
+
+// Returns the last N entries of the local clipboard history, newest first
+#[tauri::command]
+pub async fn get_clipboard_history(
+    state: State<'_, AppState>,
+    limit: Option<i64>,
+) -> Result<Vec<ClipboardEntry>, Error> {
+    load_clipboard_history(
+        &state.db,
+        limit.unwrap_or(50),
+        true,
+        MAX_CLIPBOARD_HISTORY_SIZE,
+    )
+    .await
+}
+
+// Removes every entry in the local clipboard history
+#[tauri::command]
+pub async fn clear_clipboard_history(state: State<'_, AppState>) -> Result<(), Error> {
+    sqlx::query!("delete from clipboard_history")
+        .execute(&state.db)
+        .await?;
+    Ok(())
+}
+
+/*
+ * Pulls the last N clipboard entries from another Filey peer
+ * The peer has to have clipboard sync turned on with us as one of its selected peers,
+ * on the serving side, it will be handled by a handler in http_server::routes::clipboard
+ */
+#[tauri::command]
+pub async fn get_clipboard_from_peer(
+    state: State<'_, AppState>,
+    ip: &str,
+    limit: Option<i64>,
+) -> Result<Vec<ClipboardEntry>, Error> {
+    require_capability(ip, Capability::Clipboard).await?;
+
+    // Signed, the peer only lets the known peers it syncs with pull its history
+    let path = format!("/clipboard?limit={}", limit.unwrap_or(10));
+    let headers = signed_headers(&state.db, "GET", &path).await?;
+    let response: ServerResponse<Vec<ClipboardEntry>> =
+        get_from_peer(&state.db, ip, &path, headers)
+            .await?
+            .json()
+            .await?;
+
+    Ok(response.data)
+}
+
+/*
+ * Checks the clipboard every once in a while, until the shutdown trigger is fired
+ * (or dropped, which happens when the sync gets replaced)
+ */
+async fn watch_clipboard(app_handle: AppHandle, mut shutdown_listener: Receiver<()>) {
+    let mut interval = tokio::time::interval(CLIPBOARD_POLL_INTERVAL);
+    loop {
+        tokio::select! {
+            _ = &mut shutdown_listener => break,
+            _ = interval.tick() => {}
+        }
+        if let Err(err) = push_clipboard_changes(&app_handle).await {
+            error!("{err}");
+        }
+    }
+}
+
+/*
+ * If the clipboard content is different from the last time we checked,
+ * save it to the history and push it to every selected peer
+ */
+async fn push_clipboard_changes(app_handle: &AppHandle) -> Result<(), Error> {
+    let state = app_handle.state::<AppState>();
+
+    let include_images = match state.clipboard_sync.lock().await.as_ref() {
+        Some(sync) => sync.include_images,
+        None => return Ok(()),
+    };
+
+    let Some(content) = read_clipboard(app_handle, include_images) else {
+        return Ok(());
+    };
+    let hash = content.hash_value();
+
+    /*
+     * Compare and update the last hash while holding the lock,
+     * so a clipboard pushed by a peer in the meantime is not sent back to it
+     */
+    let peers = {
+        let mut sync = state.clipboard_sync.lock().await;
+        let Some(sync) = sync.as_mut() else {
+            return Ok(());
+        };
+        if sync.last_hash == Some(hash) {
+            return Ok(());
+        }
+        sync.last_hash = Some(hash);
+        sync.peers.clone()
+    };
+
+    // Content over the size limits stays on this device
+    content.check_size()?;
+
+    let entry = ClipboardEntry {
+        id: Uuid::new_v4(),
+        origin: "local".into(),
+        created_at: now(),
+        content,
+    };
+    save_clipboard_entry(&state.db, &entry).await?;
+
+    /*
+     * An unreachable peer should not stop the others from receiving the content,
+     * so errors are only logged here
+     */
+    let body = serde_json::to_vec(&entry.content)?;
+    let body_sha256 = content_sha256(&body);
+    for peer in peers {
+        if let Err(err) = push_clipboard(&state.db, peer, &body, &body_sha256).await {
+            error!("Cannot push clipboard to {peer}: {err}");
+        }
+    }
+
+    Ok(())
+}
+
+// Sends the content to a known peer, signed so the peer knows it is us
+async fn push_clipboard(
+    db: &SqlitePool,
+    device_id: Uuid,
+    body: &[u8],
+    body_sha256: &str,
+) -> Result<(), Error> {
+    let peer = load_known_peer(db, device_id).await?;
+    require_capability(&peer.last_address, Capability::Clipboard).await?;
+    let mut headers = signed_body_headers(db, "POST", "/clipboard", body_sha256).await?;
+    headers.insert(CONTENT_TYPE, HeaderValue::from_static("application/json"));
+    request_known_peer(
+        db,
+        device_id,
+        Method::POST,
+        "/clipboard",
+        headers,
+        body.to_vec().into(),
+    )
+    .await?
+    .error_for_status()?;
+    Ok(())
+}
+
+/*
+ * Reads the clipboard, text comes first, images are only read when asked to
+ * Empty or unreadable clipboards are treated as nothing to sync
+ */
+pub fn read_clipboard(app_handle: &AppHandle, include_images: bool) -> Option<ClipboardContent> {
+    let clipboard = app_handle.clipboard();
+
+    if let Ok(text) = clipboard.read_text() {
+        if !text.is_empty() {
+            return Some(ClipboardContent::Text { text });
+        }
+    }
+
+    if include_images {
+        if let Ok(image) = clipboard.read_image() {
+            return Some(ClipboardContent::Image {
+                width: image.width(),
+                height: image.height(),
+                rgba: image.rgba().to_vec(),
+            });
+        }
+    }
+
+    None
+}
+
+// Puts the content into the clipboard of this device
+pub fn write_clipboard(app_handle: &AppHandle, content: &ClipboardContent) -> Result<(), Error> {
+    let clipboard = app_handle.clipboard();
+    match content {
+        ClipboardContent::Text { text } => clipboard.write_text(text)?,
+        ClipboardContent::Image {
+            width,
+            height,
+            rgba,
+        } => clipboard.write_image(&Image::new(rgba, *width, *height))?,
+    }
+    Ok(())
+}
+
+// Inserts a clipboard entry into the history table
+pub async fn save_clipboard_entry(db: &SqlitePool, entry: &ClipboardEntry) -> Result<(), Error> {
+    let id = entry.id.to_string();
+
+    // Split the content into the columns of the history table
+    let (kind, text, image, width, height) = match &entry.content {
+        ClipboardContent::Text { text } => ("text", Some(text.clone()), None, None, None),
+        ClipboardContent::Image {
+            width,
+            height,
+            rgba,
+        } => (
+            "image",
+            None,
+            Some(rgba.clone()),
+            Some(*width as i64),
+            Some(*height as i64),
+        ),
+    };
+
+    sqlx::query!(
+        "
+            insert into clipboard_history
+                (id, kind, text, image, width, height, origin, created_at)
+            values
+                ($1, $2, $3, $4, $5, $6, $7, $8)
+            returning id
+        ",
+        id,
+        kind,
+        text,
+        image,
+        width,
+        height,
+        entry.origin,
+        entry.created_at
+    )
+    .fetch_optional(db)
+    .await?;
+
+    // The oldest entries make room, once there are too many or they take up too much space
+    sqlx::query!(
+        "
+            delete from clipboard_history
+            where id in (
+                select id
+                from (
+                    select
+                        id,
+                        row_number() over newest as position,
+                        sum(coalesce(length(cast(text as blob)), 0) + coalesce(length(image), 0))
+                            over newest as total
+                    from clipboard_history
+                    window newest as (order by created_at desc, id)
+                )
+                where position > $1 or total > $2
+            )
+        ",
+        MAX_CLIPBOARD_HISTORY_ENTRIES,
+        MAX_CLIPBOARD_HISTORY_SIZE
+    )
+    .execute(db)
+    .await?;
+
+    Ok(())
+}
+
+/*
+ * Loads the last N entries of the clipboard history, newest first
+ * Only as many as fit in max_size bytes of content, images are left out unless included
+ */
+pub async fn load_clipboard_history(
+    db: &SqlitePool,
+    limit: i64,
+    include_images: bool,
+    max_size: i64,
+) -> Result<Vec<ClipboardEntry>, Error> {
+    let rows = sqlx::query!(
+        r#"
+            select
+                id as "id!: Hyphenated",
+                kind as "kind!",
+                text,
+                image,
+                width,
+                height,
+                origin as "origin!",
+                created_at as "created_at!"
+            from (
+                select
+                    *,
+                    sum(coalesce(length(cast(text as blob)), 0) + coalesce(length(image), 0))
+                        over (order by created_at desc, id) as total
+                from clipboard_history
+                where $2 or kind = 'text'
+            )
+            where total <= $3
+            order by created_at desc
+            limit $1
+        "#,
+        limit,
+        include_images,
+        max_size
+    )
+    .fetch_all(db)
+    .await?;
+
+    // Put the columns back together into the content enum
+    let entries = rows
+        .into_iter()
+        .map(|row| ClipboardEntry {
+            id: row.id.into_uuid(),
+            origin: row.origin,
+            created_at: row.created_at,
+            content: match row.kind.as_str() {
+                "image" => ClipboardContent::Image {
+                    width: row.width.unwrap_or_default() as u32,
+                    height: row.height.unwrap_or_default() as u32,
+                    rgba: row.image.unwrap_or_default(),
+                },
+                _ => ClipboardContent::Text {
+                    text: row.text.unwrap_or_default(),
+                },
+            },
+        })
+        .collect();
+
+    Ok(entries)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::db::test_db;
+
+    fn entry(created_at: i64, content: ClipboardContent) -> ClipboardEntry {
+        ClipboardEntry {
+            id: Uuid::new_v4(),
+            origin: "local".into(),
+            created_at,
+            content,
+        }
+    }
+
+    fn text(text: &str) -> ClipboardContent {
+        ClipboardContent::Text { text: text.into() }
+    }
+
+    fn image(size: usize) -> ClipboardContent {
+        ClipboardContent::Image {
+            width: 1,
+            height: 1,
+            rgba: vec![0; size],
+        }
+    }
+
+    #[tokio::test]
+    async fn history_keeps_the_newest_entries() {
+        let db = test_db().await;
+        for created_at in 0..MAX_CLIPBOARD_HISTORY_ENTRIES + 5 {
+            save_clipboard_entry(&db, &entry(created_at, text("copied")))
+                .await
+                .unwrap();
+        }
+
+        let history = load_clipboard_history(&db, 1000, true, MAX_CLIPBOARD_HISTORY_SIZE)
+            .await
+            .unwrap();
+        assert_eq!(history.len() as i64, MAX_CLIPBOARD_HISTORY_ENTRIES);
+        assert_eq!(history.last().unwrap().created_at, 5);
+    }
+
+    #[tokio::test]
+    async fn loading_stops_at_the_size_and_can_leave_images_out() {
+        let db = test_db().await;
+        save_clipboard_entry(&db, &entry(0, text("first")))
+            .await
+            .unwrap();
+        save_clipboard_entry(&db, &entry(1, image(100)))
+            .await
+            .unwrap();
+        save_clipboard_entry(&db, &entry(2, image(100)))
+            .await
+            .unwrap();
+
+        let history = load_clipboard_history(&db, 10, true, 150).await.unwrap();
+        assert_eq!(history.len(), 1);
+        assert_eq!(history[0].created_at, 2);
+
+        let history = load_clipboard_history(&db, 10, false, 150).await.unwrap();
+        assert_eq!(history.len(), 1);
+        assert!(matches!(history[0].content, ClipboardContent::Text { .. }));
+    }
+}
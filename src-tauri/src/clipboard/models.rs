@@ -0,0 +1,119 @@
+/*
+  Filey - simple peer-to-peer file sending across devices on different platforms
+  Copyright (C) 2024 Wander Watterson
+
+  This program is free software: you can redistribute it and/or modify
+  it under the terms of the GNU General Public License as published by
+  the Free Software Foundation, either version 3 of the License, or
+  (at your option) any later version.
+
+  This program is distributed in the hope that it will be useful,
+  but WITHOUT ANY WARRANTY; without even the implied warranty of
+  MERCHANTABILITY or FITNESS FOR A PARTICULAR PURPOSE. See the
+  GNU General Public License for more details.
+
+  You should have received a copy of the GNU General Public License
+  along with this program. If not, see <https://www.gnu.org/licenses/>.
+*/
+
+use serde::{Deserialize, Serialize};
+use std::hash::{DefaultHasher, Hash, Hasher};
+use tokio::sync::oneshot::Sender;
+use uuid::Uuid;
+
+use crate::error::Error;
+
+// Text bigger than 1 MiB is most likely not something copied by hand
+pub const MAX_CLIPBOARD_TEXT_SIZE: usize = 1024 * 1024;
+
+// Raw RGBA bytes, a 4K screenshot is roughly 32 MiB, so this leaves some headroom
+pub const MAX_CLIPBOARD_IMAGE_SIZE: usize = 40 * 1024 * 1024;
+
+/*
+ * How much of the clipboard history is kept, the oldest entries go first
+ * Every copied screenshot lands there, without a limit the database would only ever grow
+ */
+pub const MAX_CLIPBOARD_HISTORY_ENTRIES: i64 = 200;
+pub const MAX_CLIPBOARD_HISTORY_SIZE: i64 = 200 * 1024 * 1024;
+
+// How much content a peer can pull at once, a few screenshots and not the whole history
+pub const MAX_CLIPBOARD_PULL_SIZE: i64 = 2 * MAX_CLIPBOARD_IMAGE_SIZE as i64;
+
+/*
+ * The state of the clipboard sync, stored in the tauri state while syncing is turned on
+ * It holds the known peers (by device id) that we push to (and accept from), the hash of the
+ * last content that went through the clipboard so that the same content does not bounce back
+ * and forth, and the trigger to stop the clipboard watcher
+ */
+pub struct ClipboardSync {
+    pub peers: Vec<Uuid>,
+    pub include_images: bool,
+    pub last_hash: Option<u64>,
+    pub shutdown_trigger: Sender<()>,
+}
+
+// What is inside the clipboard, tagged by kind so the JSON reads {"kind": "text", "text": "..."}
+#[derive(Debug, Clone, Hash, Serialize, Deserialize)]
+#[serde(tag = "kind", rename_all = "lowercase")]
+pub enum ClipboardContent {
+    Text {
+        text: String,
+    },
+    Image {
+        width: u32,
+        height: u32,
+        #[serde(with = "base64_bytes")]
+        rgba: Vec<u8>,
+    },
+}
+
+impl ClipboardContent {
+    // Used to detect clipboard changes without keeping the entire content around
+    pub fn hash_value(&self) -> u64 {
+        let mut hasher = DefaultHasher::new();
+        self.hash(&mut hasher);
+        hasher.finish()
+    }
+
+    // Rejects content that goes over the size limits
+    pub fn check_size(&self) -> Result<(), Error> {
+        let (size, limit) = match self {
+            ClipboardContent::Text { text } => (text.len(), MAX_CLIPBOARD_TEXT_SIZE),
+            ClipboardContent::Image { rgba, .. } => (rgba.len(), MAX_CLIPBOARD_IMAGE_SIZE),
+        };
+        match size > limit {
+            true => Err(Error::ClipboardTooLarge(size)),
+            false => Ok(()),
+        }
+    }
+}
+
+/*
+ * An entry in the clipboard history
+ * The origin is either "local" when it was copied on this device,
+ * or the device id of the peer that pushed it
+ */
+#[derive(Debug, Clone, Serialize, Deserialize)]
+#[serde(rename_all = "camelCase")]
+pub struct ClipboardEntry {
+    pub id: Uuid,
+    pub origin: String,
+    pub created_at: i64,
+    #[serde(flatten)]
+    pub content: ClipboardContent,
+}
+
+// Image bytes are sent as a base64 string, a JSON array of numbers would be 4 times bigger
+mod base64_bytes {
+    use base64::{engine::general_purpose::STANDARD, Engine};
+    use serde::{Deserialize, Deserializer, Serializer};
+
+    pub fn serialize<S: Serializer>(bytes: &[u8], serializer: S) -> Result<S::Ok, S::Error> {
+        serializer.serialize_str(&STANDARD.encode(bytes))
+    }
+
+    pub fn deserialize<'de, D: Deserializer<'de>>(deserializer: D) -> Result<Vec<u8>, D::Error> {
+        let encoded = String::deserialize(deserializer)?;
+        STANDARD.decode(encoded).map_err(serde::de::Error::custom)
+    }
+}
@@ -18,8 +18,17 @@
 
 use log::{error, info};
 use sqlx::SqlitePool;
+use std::time::{SystemTime, UNIX_EPOCH};
 use tokio::fs::{create_dir_all, File};
 
+// Current unix timestamp in seconds, this is what the time columns in the database store
+pub fn now() -> i64 {
+    SystemTime::now()
+        .duration_since(UNIX_EPOCH)
+        .map(|duration| duration.as_secs() as i64)
+        .unwrap_or_default()
+}
+
 pub async fn connect_and_migrate_db(path: String) -> SqlitePool {
     /*
      * The path is the full direction from the directory to the file data.db itself
@@ -69,3 +78,37 @@ pub async fn connect_and_migrate_db(path: String) -> SqlitePool {
 
     db
 }
+
+// A migrated database that lives in memory, for the tests
+#[cfg(test)]
+pub async fn test_db() -> SqlitePool {
+    // One connection, every connection to :memory: would get a database of its own
+    let db = sqlx::sqlite::SqlitePoolOptions::new()
+        .max_connections(1)
+        .connect("sqlite::memory:")
+        .await
+        .expect("Cannot open the test database");
+    sqlx::migrate!("./migrations")
+        .run(&db)
+        .await
+        .expect("Migration failed");
+    db
+}
+
+// Remembers a peer as known, with the device key it signs with when there is one, for the tests
+#[cfg(test)]
+pub async fn insert_known_peer(db: &SqlitePool, device_id: uuid::Uuid, public_key: Option<&str>) {
+    sqlx::query(
+        "
+            insert into known_peers
+                (device_id, os_type, public_key, last_address, last_seen_at, created_at)
+            values
+                ($1, 'linux', $2, '127.0.0.1', 0, 0)
+        ",
+    )
+    .bind(device_id.to_string())
+    .bind(public_key)
+    .execute(db)
+    .await
+    .expect("Cannot insert the known peer");
+}
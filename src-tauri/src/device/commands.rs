@@ -16,16 +16,34 @@
   along with this program. If not, see <https://www.gnu.org/licenses/>.
 */
 
-use crate::error::Error;
+use base64::{engine::general_purpose::STANDARD, Engine};
+use ed25519_dalek::{Signer, SigningKey};
+use reqwest::header::{HeaderMap, HeaderValue};
+use sqlx::SqlitePool;
+use uuid::{fmt::Hyphenated, Uuid};
+
+use super::models::{
+    content_sha256, signed_message, DeviceIdentity, CONTENT_SHA256_HEADER, DEVICE_ID_HEADER,
+    MAX_DEVICE_NAME_LENGTH, NONCE_HEADER, SIGNATURE_HEADER, TIMESTAMP_HEADER,
+};
+use crate::{db::now, error::Error};
+
+#[cfg(feature = "gui")]
 use local_ip_address::list_afinet_netifas;
-use tauri::{path::BaseDirectory, AppHandle, Manager};
+#[cfg(feature = "gui")]
+use tauri::{path::BaseDirectory, AppHandle, Manager, State};
+#[cfg(feature = "gui")]
 use tauri_plugin_fs::{FsExt, OpenOptions};
 
+#[cfg(feature = "gui")]
+use crate::AppState;
+
 /*
  * Checks if the database has already been created or not
  * This typically takes some time on first run since a file needs to be created
  * On 2nd run this should not take much time
  */
+#[cfg(feature = "gui")]
 #[tauri::command]
 pub fn database_ready(app_handle: AppHandle) -> bool {
     /*
@@ -46,6 +64,7 @@ pub fn database_ready(app_handle: AppHandle) -> bool {
 /*
  * Get device's operating system
  */
+#[cfg(feature = "gui")]
 #[tauri::command]
 pub fn os_info() -> String {
     tauri_plugin_os::type_().to_string()
@@ -73,6 +92,7 @@ pub fn os_info() -> String {
  * I call it scanning for peers, if there's any response that we can parse and read perfectly
  * that's a filey peer
  */
+#[cfg(feature = "gui")]
 #[tauri::command]
 pub fn local_ips() -> Result<Vec<String>, Error> {
     let local_ips =
@@ -97,6 +117,7 @@ pub fn local_ips() -> Result<Vec<String>, Error> {
  * Though this could be bypassed easily by using a laptop without a battery.
  * Technically speaking a laptop that does not have battery, pretty much is a desktop at this point
  */
+#[cfg(feature = "gui")]
 #[tauri::command]
 pub fn has_battery() -> bool {
     // On devices that are NOT mobile -> checks for battery's existence
@@ -121,3 +142,241 @@ pub fn has_battery() -> bool {
         true
     }
 }
+
+// Returns the identity of this device, the one advertised to the peers through /info
+#[cfg(feature = "gui")]
+#[tauri::command]
+pub async fn get_device_identity(state: State<'_, AppState>) -> Result<DeviceIdentity, Error> {
+    load_identity(&state.db).await
+}
+
+/*
+ * Renames this device, the peers see the new name the next time they call /info
+ * An empty name goes back to the hostname
+ */
+#[cfg(feature = "gui")]
+#[tauri::command]
+pub async fn set_device_name(
+    state: State<'_, AppState>,
+    name: String,
+) -> Result<DeviceIdentity, Error> {
+    save_device_name(&state.db, &name).await
+}
+
+pub async fn save_device_name(db: &SqlitePool, name: &str) -> Result<DeviceIdentity, Error> {
+    let name = name.trim();
+    if name.chars().count() > MAX_DEVICE_NAME_LENGTH {
+        return Err(Error::DeviceNameTooLong(MAX_DEVICE_NAME_LENGTH));
+    }
+    let name = (!name.is_empty()).then_some(name);
+
+    // Makes sure the row exists on the very first run
+    load_identity(db).await?;
+
+    sqlx::query!(
+        "update device_identity set name = $1 where singleton = 1 returning singleton",
+        name
+    )
+    .fetch_one(db)
+    .await?;
+
+    load_identity(db).await
+}
+
+pub async fn load_identity(db: &SqlitePool) -> Result<DeviceIdentity, Error> {
+    let (id, name, signing_key) = load_identity_row(db).await?;
+    Ok(DeviceIdentity {
+        id,
+        default_name: name.is_none(),
+        name: name.unwrap_or_else(|| gethostname::gethostname().to_string_lossy().into_owned()),
+        public_key: STANDARD.encode(signing_key.verifying_key().as_bytes()),
+    })
+}
+
+// The key that proves to the peers that a message comes from this device
+pub async fn load_signing_key(db: &SqlitePool) -> Result<SigningKey, Error> {
+    Ok(load_identity_row(db).await?.2)
+}
+
+/*
+ * Headers proving to a peer that the request comes from this device
+ * The peer checks them against the public key it remembered for the device,
+ * see http_server::requester
+ * The path is the one the request is sent to, query included, the request has no body
+ */
+pub async fn signed_headers(db: &SqlitePool, method: &str, path: &str) -> Result<HeaderMap, Error> {
+    signed_body_headers(db, method, path, &content_sha256(&[])).await
+}
+
+// Same as signed_headers, for a request sending a body with this SHA-256 (hex)
+pub async fn signed_body_headers(
+    db: &SqlitePool,
+    method: &str,
+    path: &str,
+    body_sha256: &str,
+) -> Result<HeaderMap, Error> {
+    let (id, _, signing_key) = load_identity_row(db).await?;
+    let timestamp = now();
+    let nonce = STANDARD.encode(rand::random::<[u8; 16]>());
+    let message = signed_message(method, path, timestamp, &nonce, body_sha256);
+    let signature = signing_key.sign(message.as_bytes());
+
+    let mut headers = HeaderMap::new();
+    for (name, value) in [
+        (DEVICE_ID_HEADER, id.to_string()),
+        (TIMESTAMP_HEADER, timestamp.to_string()),
+        (NONCE_HEADER, nonce),
+        (CONTENT_SHA256_HEADER, body_sha256.to_string()),
+        (SIGNATURE_HEADER, STANDARD.encode(signature.to_bytes())),
+    ] {
+        // Uuids, numbers, hex and base64 are always valid header values
+        headers.insert(name, HeaderValue::from_str(&value).unwrap());
+    }
+    Ok(headers)
+}
+
+/*
+ * Reads the identity row, and generates it first when there is none (first run)
+ * The row is inserted with "on conflict do nothing", so when two callers race on the first run,
+ * both end up reading the same identity
+ */
+async fn load_identity_row(db: &SqlitePool) -> Result<(Uuid, Option<String>, SigningKey), Error> {
+    let row = sqlx::query!(
+        r#"
+            select
+                id as "id!: Hyphenated",
+                name,
+                secret_key
+            from device_identity
+            where singleton = 1
+        "#
+    )
+    .fetch_optional(db)
+    .await?
+    // Each query! has a record type of its own, both are turned into the same tuple
+    .map(|row| (row.id, row.name, row.secret_key));
+
+    let (id, name, secret_key) = match row {
+        Some(row) => row,
+        None => {
+            let id = Uuid::new_v4().to_string();
+            let secret_key = STANDARD.encode(rand::random::<[u8; 32]>());
+            let created_at = now();
+            sqlx::query!(
+                "
+                    insert into device_identity
+                        (singleton, id, secret_key, created_at)
+                    values
+                        (1, $1, $2, $3)
+                    on conflict (singleton)
+                    do nothing
+                ",
+                id,
+                secret_key,
+                created_at
+            )
+            .execute(db)
+            .await?;
+
+            let row = sqlx::query!(
+                r#"
+                    select
+                        id as "id!: Hyphenated",
+                        name,
+                        secret_key
+                    from device_identity
+                    where singleton = 1
+                "#
+            )
+            .fetch_one(db)
+            .await?;
+            (row.id, row.name, row.secret_key)
+        }
+    };
+
+    let secret_key: [u8; 32] = STANDARD
+        .decode(&secret_key)
+        .ok()
+        .and_then(|bytes| bytes.try_into().ok())
+        .ok_or_else(|| Error::InvalidKey("device secret key".into()))?;
+
+    Ok((id.into_uuid(), name, SigningKey::from_bytes(&secret_key)))
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::db::test_db;
+    use ed25519_dalek::{Signature, Verifier};
+
+    #[tokio::test]
+    async fn identity_is_generated_once() {
+        let db = test_db().await;
+        let identity = load_identity(&db).await.unwrap();
+        assert!(identity.default_name);
+
+        let reloaded = load_identity(&db).await.unwrap();
+        assert_eq!(reloaded.id, identity.id);
+        assert_eq!(reloaded.public_key, identity.public_key);
+        assert_eq!(
+            STANDARD.encode(
+                load_signing_key(&db)
+                    .await
+                    .unwrap()
+                    .verifying_key()
+                    .as_bytes()
+            ),
+            identity.public_key
+        );
+    }
+
+    #[tokio::test]
+    async fn device_name_is_trimmed_and_limited() {
+        let db = test_db().await;
+
+        let identity = save_device_name(&db, "  Living room  ").await.unwrap();
+        assert_eq!(identity.name, "Living room");
+        assert!(!identity.default_name);
+
+        let too_long = "x".repeat(MAX_DEVICE_NAME_LENGTH + 1);
+        assert!(matches!(
+            save_device_name(&db, &too_long).await,
+            Err(Error::DeviceNameTooLong(_))
+        ));
+        assert_eq!(load_identity(&db).await.unwrap().name, "Living room");
+
+        // No name goes back to the hostname
+        assert!(save_device_name(&db, " ").await.unwrap().default_name);
+    }
+
+    #[tokio::test]
+    async fn signed_headers_verify_with_the_public_key() {
+        let db = test_db().await;
+        let identity = load_identity(&db).await.unwrap();
+        let headers = signed_headers(&db, "GET", "/files").await.unwrap();
+
+        let header = |name| headers.get(name).unwrap().to_str().unwrap();
+        assert_eq!(header(DEVICE_ID_HEADER), identity.id.to_string());
+        let timestamp: i64 = header(TIMESTAMP_HEADER).parse().unwrap();
+        let nonce = header(NONCE_HEADER);
+        let body = header(CONTENT_SHA256_HEADER);
+        assert_eq!(body, content_sha256(&[]));
+        let signature = STANDARD.decode(header(SIGNATURE_HEADER)).unwrap();
+        let signature = Signature::from_slice(&signature).unwrap();
+
+        let public_key = load_signing_key(&db).await.unwrap().verifying_key();
+        let message = signed_message("GET", "/files", timestamp, nonce, body);
+        assert!(public_key.verify(message.as_bytes(), &signature).is_ok());
+        // The signature is only good for the request it was made for
+        let other = signed_message("GET", "/folders", timestamp, nonce, body);
+        assert!(public_key.verify(other.as_bytes(), &signature).is_err());
+        let other = signed_message("GET", "/files?mode=download", timestamp, nonce, body);
+        assert!(public_key.verify(other.as_bytes(), &signature).is_err());
+        let other = signed_message("GET", "/files", timestamp, nonce, &content_sha256(b"x"));
+        assert!(public_key.verify(other.as_bytes(), &signature).is_err());
+
+        // Every request gets its own nonce
+        let again = signed_headers(&db, "GET", "/files").await.unwrap();
+        assert_ne!(again.get(NONCE_HEADER), headers.get(NONCE_HEADER));
+    }
+}
@@ -0,0 +1,77 @@
+/*
+  Filey - simple peer-to-peer file sending across devices on different platforms
+  Copyright (C) 2024 Wander Watterson
+
+  This program is free software: you can redistribute it and/or modify
+  it under the terms of the GNU General Public License as published by
+  the Free Software Foundation, either version 3 of the License, or
+  (at your option) any later version.
+
+  This program is distributed in the hope that it will be useful,
+  but WITHOUT ANY WARRANTY; without even the implied warranty of
+  MERCHANTABILITY or FITNESS FOR A PARTICULAR PURPOSE. See the
+  GNU General Public License for more details.
+
+  You should have received a copy of the GNU General Public License
+  along with this program. If not, see <https://www.gnu.org/licenses/>.
+*/
+
+use serde::{Deserialize, Serialize};
+use sha2::{Digest, Sha256};
+use uuid::Uuid;
+
+// Longest device name that can be set, it is shown in a single line on the peers
+pub const MAX_DEVICE_NAME_LENGTH: usize = 64;
+
+/*
+ * The identity of this device, generated on first run and kept in the database
+ * Peers recognise the device by the id and the public key, whatever its IP address is
+ * The secret key never leaves the device, which is why it is not part of this model
+ */
+#[derive(Debug, Clone, Serialize, Deserialize)]
+#[serde(rename_all = "camelCase")]
+pub struct DeviceIdentity {
+    pub id: Uuid,
+    // The name picked by the user, or the hostname when there is none
+    pub name: String,
+    // Whether the name is the hostname, because the user did not pick one
+    pub default_name: bool,
+    // Ed25519 public key, base64
+    pub public_key: String,
+}
+
+/*
+ * Headers a device adds to its requests to the peers, so they can tell who is asking
+ * The signature covers the method, the path with its query, the timestamp, the nonce and the hash
+ * of the body, see signed_message
+ */
+pub const DEVICE_ID_HEADER: &str = "x-filey-device-id";
+pub const TIMESTAMP_HEADER: &str = "x-filey-timestamp";
+pub const NONCE_HEADER: &str = "x-filey-nonce";
+pub const CONTENT_SHA256_HEADER: &str = "x-filey-content-sha256";
+pub const SIGNATURE_HEADER: &str = "x-filey-signature";
+
+// How far apart the clocks of two devices can be, in seconds, before a signature is refused
+pub const MAX_SIGNATURE_AGE: i64 = 300;
+
+// Longest nonce a peer accepts, ours are 16 random bytes in base64
+pub const MAX_NONCE_LENGTH: usize = 64;
+
+/*
+ * What gets signed, the path is the one sent, query included
+ * The nonce is only accepted once by the peer, so a signed request cannot be sent again
+ */
+pub fn signed_message(
+    method: &str,
+    path: &str,
+    timestamp: i64,
+    nonce: &str,
+    content_sha256: &str,
+) -> String {
+    format!("{method}\n{path}\n{timestamp}\n{nonce}\n{content_sha256}")
+}
+
+// SHA-256 of a request body as hex, requests without a body sign the hash of an empty one
+pub fn content_sha256(body: &[u8]) -> String {
+    format!("{:x}", Sha256::digest(body))
+}
@@ -0,0 +1,220 @@
+/*
+  Filey - simple peer-to-peer file sending across devices on different platforms
+  Copyright (C) 2024 Wander Watterson
+
+  This program is free software: you can redistribute it and/or modify
+  it under the terms of the GNU General Public License as published by
+  the Free Software Foundation, either version 3 of the License, or
+  (at your option) any later version.
+
+  This program is distributed in the hope that it will be useful,
+  but WITHOUT ANY WARRANTY; without even the implied warranty of
+  MERCHANTABILITY or FITNESS FOR A PARTICULAR PURPOSE. See the
+  GNU General Public License for more details.
+
+  You should have received a copy of the GNU General Public License
+  along with this program. If not, see <https://www.gnu.org/licenses/>.
+*/
+
+use sqlx::SqlitePool;
+use std::{path::Path, time::UNIX_EPOCH};
+use uuid::{fmt::Hyphenated, Uuid};
+
+use super::models::{split_patterns, DropFolderModel};
+use crate::{error::Error, files::models::Visibility};
+
+#[cfg(feature = "gui")]
+use tauri::State;
+
+#[cfg(feature = "gui")]
+use super::models::join_patterns;
+#[cfg(feature = "gui")]
+use crate::{files::models::FileEvent, AppState};
+
+// Returns every drop folder
+#[cfg(feature = "gui")]
+#[tauri::command]
+pub async fn get_drop_folders(state: State<'_, AppState>) -> Result<Vec<DropFolderModel>, Error> {
+    load_drop_folders(&state.db).await
+}
+
+/*
+ * Registers a directory as a drop folder
+ * The files already inside, and every file dropped in later on, get shared with that visibility
+ * The drop folder watcher picks the new folder up and does the actual sharing
+ */
+#[cfg(feature = "gui")]
+#[tauri::command]
+pub async fn add_drop_folder(
+    state: State<'_, AppState>,
+    path: String,
+    visibility: Visibility,
+    include: Vec<String>,
+    exclude: Vec<String>,
+) -> Result<DropFolderModel, Error> {
+    if !tokio::fs::metadata(&path).await?.is_dir() {
+        return Err(Error::NotADirectory(path));
+    }
+
+    let drop_folder = DropFolderModel {
+        id: Uuid::new_v4(),
+        path,
+        visibility,
+        include,
+        exclude,
+    };
+
+    // Make sure the patterns are valid globs before saving them
+    drop_folder.filter()?;
+
+    let id = drop_folder.id.to_string();
+    let visibility = drop_folder.visibility.to_string();
+    let include_patterns = join_patterns(&drop_folder.include);
+    let exclude_patterns = join_patterns(&drop_folder.exclude);
+
+    sqlx::query!(
+        "
+            insert into drop_folders
+                (id, path, visibility, include_patterns, exclude_patterns)
+            values
+                ($1, $2, $3, $4, $5)
+            returning id
+        ",
+        id,
+        drop_folder.path,
+        visibility,
+        include_patterns,
+        exclude_patterns
+    )
+    .fetch_one(&state.db)
+    .await?;
+
+    state.drop_folders_changed.notify_one();
+
+    Ok(drop_folder)
+}
+
+/*
+ * Unregisters a drop folder
+ * The files that were shared from it are removed from the shared list as well,
+ * the files on the disk are left untouched
+ */
+#[cfg(feature = "gui")]
+#[tauri::command]
+pub async fn remove_drop_folder(state: State<'_, AppState>, id: Uuid) -> Result<(), Error> {
+    let drop_folder_id = id.to_string();
+
+    let file_ids = sqlx::query!(
+        r#"select id as "id!: Hyphenated" from files where drop_folder_id = $1"#,
+        drop_folder_id
+    )
+    .fetch_all(&state.db)
+    .await?;
+
+    // The files rows go away along with the folder, thanks to "on delete cascade"
+    sqlx::query!(
+        "delete from drop_folders where id = $1 returning id",
+        drop_folder_id
+    )
+    .fetch_one(&state.db)
+    .await?;
+
+    for row in file_ids {
+        state
+            .file_events
+            .send(FileEvent::Removed {
+                id: row.id.into_uuid(),
+            })
+            .ok();
+    }
+
+    state.drop_folders_changed.notify_one();
+
+    Ok(())
+}
+
+pub async fn load_drop_folders(db: &SqlitePool) -> Result<Vec<DropFolderModel>, Error> {
+    let drop_folders = sqlx::query!(
+        r#"
+            select
+                id as "id!: Hyphenated",
+                path,
+                visibility as "visibility!: Visibility",
+                include_patterns,
+                exclude_patterns
+            from drop_folders
+        "#
+    )
+    .fetch_all(db)
+    .await?
+    .into_iter()
+    .map(|row| DropFolderModel {
+        id: row.id.into_uuid(),
+        path: row.path,
+        visibility: row.visibility,
+        include: split_patterns(&row.include_patterns),
+        exclude: split_patterns(&row.exclude_patterns),
+    })
+    .collect();
+
+    Ok(drop_folders)
+}
+
+/*
+ * Remembers that a file of a drop folder was taken out of the shared list (by hand, or because
+ * its share expired), so that the next look at the folder does not share it all over again
+ * The modified time is remembered along with the path, a file written again later is shared again
+ * Files that were not shared from a drop folder are left alone
+ */
+pub async fn remember_unshared(db: &SqlitePool, file_id: Uuid) -> Result<(), Error> {
+    let id = file_id.to_string();
+    let Some(row) = sqlx::query!(
+        r#"
+            select
+                path,
+                drop_folder_id as "drop_folder_id!"
+            from files
+            where
+                id = $1
+            and drop_folder_id is not null
+        "#,
+        id
+    )
+    .fetch_optional(db)
+    .await?
+    else {
+        return Ok(());
+    };
+
+    let modified_at = modified_at(&row.path).await;
+    sqlx::query!(
+        "
+            insert into drop_folder_unshared
+                (drop_folder_id, path, modified_at)
+            values
+                ($1, $2, $3)
+            on conflict (drop_folder_id, path)
+            do update set
+                modified_at = excluded.modified_at
+        ",
+        row.drop_folder_id,
+        row.path,
+        modified_at
+    )
+    .execute(db)
+    .await?;
+
+    Ok(())
+}
+
+// Unix seconds, nothing when the file is gone
+pub async fn modified_at(path: impl AsRef<Path>) -> Option<i64> {
+    tokio::fs::metadata(path)
+        .await
+        .ok()?
+        .modified()
+        .ok()?
+        .duration_since(UNIX_EPOCH)
+        .ok()
+        .map(|duration| duration.as_secs() as i64)
+}
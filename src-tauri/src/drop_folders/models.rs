@@ -0,0 +1,109 @@
+/*
+  Filey - simple peer-to-peer file sending across devices on different platforms
+  Copyright (C) 2024 Wander Watterson
+
+  This program is free software: you can redistribute it and/or modify
+  it under the terms of the GNU General Public License as published by
+  the Free Software Foundation, either version 3 of the License, or
+  (at your option) any later version.
+
+  This program is distributed in the hope that it will be useful,
+  but WITHOUT ANY WARRANTY; without even the implied warranty of
+  MERCHANTABILITY or FITNESS FOR A PARTICULAR PURPOSE. See the
+  GNU General Public License for more details.
+
+  You should have received a copy of the GNU General Public License
+  along with this program. If not, see <https://www.gnu.org/licenses/>.
+*/
+
+use globset::{Glob, GlobSet, GlobSetBuilder};
+use serde::{Deserialize, Serialize};
+use std::path::Path;
+use uuid::Uuid;
+
+use crate::{error::Error, files::models::Visibility};
+
+/*
+ * Files that are still being written, or that nobody means to share
+ * Browsers and copy tools write into a temporary name first, then rename it when done
+ */
+const IGNORED_PATTERNS: [&str; 9] = [
+    "*.part",
+    "*.partial",
+    "*.crdownload",
+    "*.download",
+    "*.tmp",
+    "*.temp",
+    "~$*",
+    ".~lock*",
+    ".*",
+];
+
+/*
+ * A directory where every file dropped in gets shared automatically
+ * Include and exclude are glob patterns matched against the path relative to the folder,
+ * an empty include list means every file is included
+ */
+#[derive(Debug, Serialize, Deserialize)]
+#[serde(rename_all = "camelCase")]
+pub struct DropFolderModel {
+    pub id: Uuid,
+    pub path: String,
+    pub visibility: Visibility,
+    pub include: Vec<String>,
+    pub exclude: Vec<String>,
+}
+
+impl DropFolderModel {
+    // Compiles the patterns of this folder, fails if any of them is not a valid glob
+    pub fn filter(&self) -> Result<DropFolderFilter, Error> {
+        Ok(DropFolderFilter {
+            include: build_glob_set(&self.include)?,
+            exclude: build_glob_set(&self.exclude)?,
+            ignored: build_glob_set(&IGNORED_PATTERNS)?,
+        })
+    }
+}
+
+pub struct DropFolderFilter {
+    include: GlobSet,
+    exclude: GlobSet,
+    ignored: GlobSet,
+}
+
+impl DropFolderFilter {
+    /*
+     * Whether a file (path relative to the drop folder) should be shared
+     * The file name alone is checked against the ignored patterns,
+     * so that a hidden file inside a sub directory is ignored as well
+     */
+    pub fn matches(&self, relative_path: &Path) -> bool {
+        let ignored = relative_path
+            .file_name()
+            .is_some_and(|name| self.ignored.is_match(name));
+        let included = self.include.is_empty() || self.include.is_match(relative_path);
+        !ignored && included && !self.exclude.is_match(relative_path)
+    }
+}
+
+fn build_glob_set<T: AsRef<str>>(patterns: &[T]) -> Result<GlobSet, Error> {
+    let mut builder = GlobSetBuilder::new();
+    for pattern in patterns {
+        builder.add(Glob::new(pattern.as_ref())?);
+    }
+    Ok(builder.build()?)
+}
+
+// Patterns are stored in the database one per line
+pub fn join_patterns(patterns: &[String]) -> String {
+    patterns.join("\n")
+}
+
+pub fn split_patterns(patterns: &str) -> Vec<String> {
+    patterns
+        .lines()
+        .map(str::trim)
+        .filter(|pattern| !pattern.is_empty())
+        .map(String::from)
+        .collect()
+}
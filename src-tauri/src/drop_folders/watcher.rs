@@ -0,0 +1,400 @@
+/*
+  Filey - simple peer-to-peer file sending across devices on different platforms
+  Copyright (C) 2024 Wander Watterson
+
+  This program is free software: you can redistribute it and/or modify
+  it under the terms of the GNU General Public License as published by
+  the Free Software Foundation, either version 3 of the License, or
+  (at your option) any later version.
+
+  This program is distributed in the hope that it will be useful,
+  but WITHOUT ANY WARRANTY; without even the implied warranty of
+  MERCHANTABILITY or FITNESS FOR A PARTICULAR PURPOSE. See the
+  GNU General Public License for more details.
+
+  You should have received a copy of the GNU General Public License
+  along with this program. If not, see <https://www.gnu.org/licenses/>.
+*/
+
+use log::error;
+use notify::{recommended_watcher, Event, RecommendedWatcher, RecursiveMode, Watcher};
+use std::{
+    collections::{HashMap, HashSet},
+    path::{Path, PathBuf},
+    time::{Duration, SystemTime},
+};
+use tauri::{AppHandle, Manager};
+use tokio::sync::mpsc;
+use uuid::{fmt::Hyphenated, Uuid};
+
+use super::{
+    commands::{load_drop_folders, modified_at},
+    models::{DropFolderFilter, DropFolderModel},
+};
+use crate::{
+    error::Error,
+    files::{
+        commands::insert_file,
+        models::{ExpiryAction, FileEvent, FileModel},
+    },
+    AppState,
+};
+
+// A file has to stay the same for this long before it is considered completely written
+const SETTLE_TIME: Duration = Duration::from_secs(2);
+
+// Size and modified time of a file, once both stop changing the file is done being written
+type Stamp = (u64, Option<SystemTime>);
+
+/*
+ * Starts the background watcher over every drop folder
+ *
+ * Whatever happens inside a drop folder is not acted upon right away, the paths are put
+ * on a pending list first. Every couple of seconds the pending paths are looked at again,
+ * files that did not change since the last look get shared, files that are gone get removed,
+ * and files that are still growing wait for the next look
+ */
+pub fn spawn_drop_folder_watcher(app_handle: AppHandle) {
+    crate::runtime::spawn(async move {
+        if let Err(err) = watch_drop_folders(&app_handle).await {
+            error!("Drop folder watcher stopped: {err}");
+        }
+    });
+}
+
+async fn watch_drop_folders(app_handle: &AppHandle) -> Result<(), Error> {
+    let state = app_handle.state::<AppState>();
+
+    // notify calls us back from its own thread, forward the events into the async world
+    let (event_sender, mut event_receiver) = mpsc::unbounded_channel();
+    let mut watcher = recommended_watcher(move |event: notify::Result<Event>| {
+        event_sender.send(event).ok();
+    })?;
+
+    let mut watched_folders = HashSet::new();
+    // Paths that something happened to, along with the stamp they had on the last look
+    let mut pending: HashMap<PathBuf, Option<Stamp>> = HashMap::new();
+    refresh_drop_folders(app_handle, &mut watcher, &mut watched_folders, &mut pending).await?;
+
+    let mut interval = tokio::time::interval(SETTLE_TIME);
+    loop {
+        tokio::select! {
+            Some(event) = event_receiver.recv() => match event {
+                Ok(event) => {
+                    for path in event.paths {
+                        pending.entry(path).or_insert(None);
+                    }
+                }
+                Err(err) => error!("{err}"),
+            },
+            // A drop folder was added or removed
+            _ = state.drop_folders_changed.notified() => {
+                if let Err(err) = refresh_drop_folders(
+                    app_handle,
+                    &mut watcher,
+                    &mut watched_folders,
+                    &mut pending,
+                )
+                .await
+                {
+                    error!("{err}");
+                }
+            }
+            _ = interval.tick() => {
+                if !pending.is_empty() {
+                    if let Err(err) = process_pending(app_handle, &mut pending).await {
+                        error!("{err}");
+                    }
+                }
+            }
+        }
+    }
+}
+
+/*
+ * Watches every drop folder (sub directories included), and stops watching the removed ones
+ *
+ * Every file inside the folders is put on the pending list, and so is every file that was
+ * shared from a drop folder, this catches up with whatever happened while nobody was watching
+ */
+async fn refresh_drop_folders(
+    app_handle: &AppHandle,
+    watcher: &mut RecommendedWatcher,
+    watched_folders: &mut HashSet<PathBuf>,
+    pending: &mut HashMap<PathBuf, Option<Stamp>>,
+) -> Result<(), Error> {
+    let state = app_handle.state::<AppState>();
+
+    let folders: HashSet<PathBuf> = load_drop_folders(&state.db)
+        .await?
+        .into_iter()
+        .map(|drop_folder| PathBuf::from(drop_folder.path))
+        .collect();
+
+    for folder in watched_folders.difference(&folders) {
+        watcher.unwatch(folder).ok();
+    }
+    for folder in folders.difference(watched_folders) {
+        if let Err(err) = watcher.watch(folder, RecursiveMode::Recursive) {
+            error!("Cannot watch drop folder {}: {err}", folder.display());
+        }
+    }
+
+    for folder in &folders {
+        collect_files(folder, pending).await;
+    }
+    *watched_folders = folders;
+
+    for row in sqlx::query!("select path from files where drop_folder_id is not null")
+        .fetch_all(&state.db)
+        .await?
+    {
+        pending.entry(PathBuf::from(row.path)).or_insert(None);
+    }
+
+    Ok(())
+}
+
+// Puts every file inside a directory (and its sub directories) on the pending list
+async fn collect_files(dir: &Path, pending: &mut HashMap<PathBuf, Option<Stamp>>) {
+    let mut dirs = vec![dir.to_path_buf()];
+    while let Some(dir) = dirs.pop() {
+        let Ok(mut entries) = tokio::fs::read_dir(&dir).await else {
+            continue;
+        };
+        while let Ok(Some(entry)) = entries.next_entry().await {
+            match entry.file_type().await {
+                Ok(file_type) if file_type.is_dir() => dirs.push(entry.path()),
+                Ok(file_type) if file_type.is_file() => {
+                    pending.entry(entry.path()).or_insert(None);
+                }
+                _ => {}
+            }
+        }
+    }
+}
+
+/*
+ * Takes another look at every pending path, see the comment on spawn_drop_folder_watcher
+ * A path that cannot be shared or unshared stays pending and is tried again on the next look,
+ * the other paths are not held up by it
+ */
+async fn process_pending(
+    app_handle: &AppHandle,
+    pending: &mut HashMap<PathBuf, Option<Stamp>>,
+) -> Result<(), Error> {
+    let state = app_handle.state::<AppState>();
+
+    // A folder with a bad filter shares nothing, but its removed files are still unshared
+    let drop_folders: Vec<(DropFolderModel, Option<DropFolderFilter>)> =
+        load_drop_folders(&state.db)
+            .await?
+            .into_iter()
+            .map(|drop_folder| match drop_folder.filter() {
+                Ok(filter) => (drop_folder, Some(filter)),
+                Err(err) => {
+                    error!("Invalid filter on drop folder {}: {err}", drop_folder.path);
+                    (drop_folder, None)
+                }
+            })
+            .collect();
+
+    let paths: Vec<PathBuf> = pending.keys().cloned().collect();
+    for path in paths {
+        // Paths outside of every drop folder are of no interest (the folder was just removed)
+        let Some((drop_folder, filter)) = drop_folders
+            .iter()
+            .find(|(drop_folder, _)| path.starts_with(&drop_folder.path))
+        else {
+            pending.remove(&path);
+            continue;
+        };
+
+        match tokio::fs::metadata(&path).await {
+            // A whole directory was moved in, its files go on the pending list
+            Ok(metadata) if metadata.is_dir() => {
+                pending.remove(&path);
+                collect_files(&path, pending).await;
+            }
+            Ok(metadata) => {
+                let stamp = (metadata.len(), metadata.modified().ok());
+                // Still being written, wait for the next look
+                if pending.get(&path) != Some(&Some(stamp)) {
+                    pending.insert(path, Some(stamp));
+                    continue;
+                }
+                let Some(filter) = filter else {
+                    pending.remove(&path);
+                    continue;
+                };
+                match share_file(app_handle, drop_folder, filter, &path).await {
+                    Ok(()) => {
+                        pending.remove(&path);
+                    }
+                    Err(err) => error!("Cannot share {}, will try again: {err}", path.display()),
+                }
+            }
+            Err(_) => match unshare_removed(app_handle, drop_folder, &path).await {
+                Ok(()) => {
+                    pending.remove(&path);
+                }
+                Err(err) => error!("Cannot unshare {}, will try again: {err}", path.display()),
+            },
+        }
+    }
+
+    Ok(())
+}
+
+/*
+ * Shares a file of a drop folder, unless it is filtered out or already shared,
+ * or it was taken out of the shared list since, see commands::remember_unshared
+ */
+async fn share_file(
+    app_handle: &AppHandle,
+    drop_folder: &DropFolderModel,
+    filter: &DropFolderFilter,
+    path: &Path,
+) -> Result<(), Error> {
+    let state = app_handle.state::<AppState>();
+
+    let Ok(relative_path) = path.strip_prefix(&drop_folder.path) else {
+        return Ok(());
+    };
+    if !filter.matches(relative_path) {
+        return Ok(());
+    }
+
+    let path_text = path.display().to_string();
+    if sqlx::query!("select id from files where path = $1", path_text)
+        .fetch_optional(&state.db)
+        .await?
+        .is_some()
+    {
+        return Ok(());
+    }
+
+    let drop_folder_id = drop_folder.id.to_string();
+    if let Some(unshared) = sqlx::query!(
+        "
+            select modified_at
+            from drop_folder_unshared
+            where
+                drop_folder_id = $1
+            and path = $2
+        ",
+        drop_folder_id,
+        path_text
+    )
+    .fetch_optional(&state.db)
+    .await?
+    {
+        if unshared.modified_at == modified_at(path).await {
+            return Ok(());
+        }
+        // Written again since it was unshared, so it is a new file as far as sharing goes
+        sqlx::query!(
+            "delete from drop_folder_unshared where drop_folder_id = $1 and path = $2",
+            drop_folder_id,
+            path_text
+        )
+        .execute(&state.db)
+        .await?;
+    }
+
+    let name = path
+        .file_name()
+        .map(|name| name.to_string_lossy().to_string())
+        .unwrap_or_else(|| path_text.clone());
+    let mime = mime_guess::from_path(path)
+        .first_or_octet_stream()
+        .to_string();
+
+    insert_file(
+        &state.db,
+        &state.file_events,
+        FileModel {
+            id: Uuid::new_v4(),
+            name,
+            mime,
+            visibility: drop_folder.visibility.clone(),
+            path: path_text,
+            missing: false,
+            size: None,
+            modified_at: None,
+            share_until: None,
+            max_downloads: None,
+            download_count: 0,
+            on_expiry: ExpiryAction::Private,
+            encrypted: false,
+        },
+        Some(drop_folder.id),
+    )
+    .await
+}
+
+/*
+ * Removes the files of a drop folder that are gone from the disk
+ * The path can be a file, or a whole directory that got deleted or moved out
+ * Whatever was remembered as unshared under it is forgotten, the files are gone anyway
+ */
+async fn unshare_removed(
+    app_handle: &AppHandle,
+    drop_folder: &DropFolderModel,
+    path: &Path,
+) -> Result<(), Error> {
+    let state = app_handle.state::<AppState>();
+
+    let drop_folder_id = drop_folder.id.to_string();
+    let rows = sqlx::query!(
+        r#"
+            select
+                id as "id!: Hyphenated",
+                path
+            from files
+            where drop_folder_id = $1
+        "#,
+        drop_folder_id
+    )
+    .fetch_all(&state.db)
+    .await?;
+
+    for row in rows
+        .into_iter()
+        .filter(|row| Path::new(&row.path).starts_with(path))
+    {
+        let id = row.id.to_string();
+        sqlx::query!("delete from files where id = $1 returning id", id)
+            .fetch_optional(&state.db)
+            .await?;
+
+        state
+            .file_events
+            .send(FileEvent::Removed {
+                id: row.id.into_uuid(),
+            })
+            .ok();
+    }
+
+    let unshared = sqlx::query!(
+        "select path from drop_folder_unshared where drop_folder_id = $1",
+        drop_folder_id
+    )
+    .fetch_all(&state.db)
+    .await?;
+
+    for row in unshared
+        .into_iter()
+        .filter(|row| Path::new(&row.path).starts_with(path))
+    {
+        sqlx::query!(
+            "delete from drop_folder_unshared where drop_folder_id = $1 and path = $2",
+            drop_folder_id,
+            row.path
+        )
+        .execute(&state.db)
+        .await?;
+    }
+
+    Ok(())
+}
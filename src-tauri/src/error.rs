@@ -17,12 +17,15 @@
 */
 
 use axum::{http::StatusCode, response::IntoResponse, Json};
+use serde::{ser::SerializeStruct, Deserialize, Serialize};
+use std::io::ErrorKind;
 use thiserror::Error;
 
 use crate::ServerResponse;
 
 #[derive(Debug, Error)]
 pub enum Error {
+    #[cfg(feature = "gui")]
     #[error(transparent)]
     Tauri(#[from] tauri::Error),
 
@@ -32,8 +35,9 @@ pub enum Error {
     #[error("Command ran and returned error: {0}")]
     Command(String),
 
-    #[error("File does not exists on path: {0}")]
-    FileNotFound(#[from] tauri_plugin_fs::Error),
+    #[cfg(feature = "gui")]
+    #[error("Cannot access file: {0}")]
+    FileSystem(#[from] tauri_plugin_fs::Error),
 
     #[error(transparent)]
     Db(#[from] sqlx::Error),
@@ -42,31 +46,305 @@ pub enum Error {
     IpAddr(#[from] local_ip_address::Error),
 
     #[error(transparent)]
-    Reqwest(#[from] tauri_plugin_http::reqwest::Error),
+    Reqwest(#[from] reqwest::Error),
+
+    #[cfg(feature = "gui")]
+    #[error(transparent)]
+    Clipboard(#[from] tauri_plugin_clipboard_manager::Error),
+
+    #[error("Clipboard content is too large: {0} bytes")]
+    ClipboardTooLarge(usize),
+
+    #[error("Clipboard sync is not turned on for peer: {0}")]
+    ClipboardSyncDisabled(String),
+
+    #[error("Message is longer than {0} characters")]
+    MessageTooLong(usize),
+
+    #[cfg(not(any(target_os = "android", target_os = "ios")))]
+    #[error(transparent)]
+    Watcher(#[from] notify::Error),
+
+    #[error(transparent)]
+    Glob(#[from] globset::Error),
+
+    #[error("Path is not a directory: {0}")]
+    NotADirectory(String),
+
+    #[error("Not available on a headless server")]
+    Headless,
+
+    #[error("Device name is longer than {0} characters")]
+    DeviceNameTooLong(usize),
+
+    #[error("Invalid key: {0}")]
+    InvalidKey(String),
+
+    #[error("Peer is too old to be remembered, it has no device identity: {0}")]
+    PeerWithoutIdentity(String),
+
+    #[error("Peer does not support this, it is too old or has no GUI: {0}")]
+    PeerUnsupported(String),
+
+    #[error("Access denied: {0}")]
+    AccessDenied(String),
+
+    #[error("Invalid CIDR range or address: {0}")]
+    InvalidCidr(String),
+
+    #[error("Request signature does not match the device: {0}")]
+    InvalidSignature(String),
+
+    #[error("Only known peers can do this, the request has to be signed: {0}")]
+    UnknownPeer(String),
+
+    #[error("Download limit has to be at least 1: {0}")]
+    InvalidDownloadLimit(i64),
+
+    #[error(transparent)]
+    Noise(#[from] snow::Error),
+
+    #[error("Peer does not hold the device key it was remembered with: {0}")]
+    NoiseKeyMismatch(String),
+
+    #[error(transparent)]
+    Hyper(#[from] hyper::Error),
+
+    #[error(transparent)]
+    Http(#[from] axum::http::Error),
+
+    #[error(transparent)]
+    Encrypt(#[from] age::EncryptError),
+
+    #[error(transparent)]
+    Decrypt(#[from] age::DecryptError),
+
+    #[error("Passphrase cannot be empty")]
+    EmptyPassphrase,
+
+    #[error("Encrypted file cannot be updated block by block: {0}")]
+    EncryptedDelta(String),
+
+    #[error("Too many encrypted downloads at once, try again later: {0}")]
+    EncryptionBusy(String),
+
+    #[cfg(not(any(target_os = "android", target_os = "ios")))]
+    #[error(transparent)]
+    Config(#[from] toml::de::Error),
+
+    #[error(transparent)]
+    Json(#[from] serde_json::Error),
+
+    #[error(transparent)]
+    Axum(#[from] axum::Error),
+
+    #[error("Transfer request not found or already answered: {0}")]
+    TransferNotFound(String),
+
+    #[error("Transfer request was not accepted: {0}")]
+    TransferNotAccepted(String),
+
+    #[error("Received file does not match the announced size: {0}")]
+    TransferSizeMismatch(String),
+
+    #[error("File of the transfer request was received already: {0}")]
+    TransferFileReceived(String),
+
+    #[error("File of the transfer request is being received already: {0}")]
+    TransferInProgress(String),
+
+    #[error("Upload does not continue where it stopped, {0} bytes were received so far")]
+    TransferOffsetMismatch(u64),
+
+    #[error("Invalid file name: {0}")]
+    InvalidFileName(String),
+
+    #[error("Transfer has no file on this device: {0}")]
+    DestinationMissing(String),
+
+    #[error("File reached its download limit: {0}")]
+    DownloadLimitReached(String),
+
+    #[error("Shared folder is read only: {0}")]
+    FolderNotWritable(String),
+
+    #[error("Rebuilt file does not match the peer's copy: {0}")]
+    DeltaMismatch(String),
+}
+
+/*
+ * What went wrong, in a form that programs can act upon without parsing messages
+ * Sent along with the errors of the HTTP server, and of the Tauri commands
+ */
+#[derive(Debug, Clone, Copy, PartialEq, Serialize, Deserialize)]
+#[serde(rename_all = "snake_case")]
+pub enum ErrorCode {
+    NotFound,
+    Forbidden,
+    InvalidInput,
+    TooLarge,
+    Conflict,
+    // The file was shared, but not anymore
+    Gone,
+    RangeNotSatisfiable,
+    Unavailable,
+    PeerUnreachable,
+    // The peer lacks the feature, see http_server::models::Capability
+    PeerUnsupported,
+    Internal,
+}
+
+impl ErrorCode {
+    pub fn status(self) -> StatusCode {
+        match self {
+            ErrorCode::NotFound => StatusCode::NOT_FOUND,
+            ErrorCode::Forbidden => StatusCode::FORBIDDEN,
+            ErrorCode::InvalidInput => StatusCode::BAD_REQUEST,
+            ErrorCode::TooLarge => StatusCode::PAYLOAD_TOO_LARGE,
+            ErrorCode::Conflict => StatusCode::CONFLICT,
+            ErrorCode::Gone => StatusCode::GONE,
+            ErrorCode::RangeNotSatisfiable => StatusCode::RANGE_NOT_SATISFIABLE,
+            ErrorCode::Unavailable => StatusCode::SERVICE_UNAVAILABLE,
+            ErrorCode::PeerUnreachable => StatusCode::BAD_GATEWAY,
+            ErrorCode::PeerUnsupported => StatusCode::NOT_IMPLEMENTED,
+            ErrorCode::Internal => StatusCode::INTERNAL_SERVER_ERROR,
+        }
+    }
+
+    fn from_io(kind: ErrorKind) -> Self {
+        match kind {
+            ErrorKind::NotFound => ErrorCode::NotFound,
+            ErrorKind::PermissionDenied => ErrorCode::Forbidden,
+            ErrorKind::InvalidInput | ErrorKind::InvalidData => ErrorCode::InvalidInput,
+            _ => ErrorCode::Internal,
+        }
+    }
+}
+
+impl Error {
+    pub fn code(&self) -> ErrorCode {
+        match self {
+            // A missing row is a missing (or private) file, snippet, transfer...
+            Error::Db(sqlx::Error::RowNotFound)
+            | Error::TransferNotFound(_)
+            | Error::DestinationMissing(_) => ErrorCode::NotFound,
+            Error::Io(err) => ErrorCode::from_io(err.kind()),
+            #[cfg(feature = "gui")]
+            Error::FileSystem(tauri_plugin_fs::Error::Io(err)) => ErrorCode::from_io(err.kind()),
+            #[cfg(feature = "gui")]
+            Error::FileSystem(tauri_plugin_fs::Error::PathForbidden(_)) => ErrorCode::Forbidden,
+            #[cfg(feature = "gui")]
+            Error::FileSystem(
+                tauri_plugin_fs::Error::InvalidPathUrl | tauri_plugin_fs::Error::UnsafePathBuf(_),
+            ) => ErrorCode::InvalidInput,
+            Error::ClipboardSyncDisabled(_)
+            | Error::TransferNotAccepted(_)
+            | Error::FolderNotWritable(_)
+            | Error::AccessDenied(_)
+            | Error::InvalidSignature(_)
+            | Error::UnknownPeer(_)
+            | Error::NoiseKeyMismatch(_) => ErrorCode::Forbidden,
+            Error::Glob(_)
+            | Error::Json(_)
+            | Error::NotADirectory(_)
+            | Error::InvalidFileName(_)
+            | Error::PeerWithoutIdentity(_)
+            | Error::InvalidCidr(_)
+            | Error::InvalidDownloadLimit(_)
+            | Error::Decrypt(_)
+            | Error::EmptyPassphrase
+            | Error::EncryptedDelta(_) => ErrorCode::InvalidInput,
+            Error::ClipboardTooLarge(_)
+            | Error::MessageTooLong(_)
+            | Error::DeviceNameTooLong(_) => ErrorCode::TooLarge,
+            Error::TransferSizeMismatch(_)
+            | Error::TransferFileReceived(_)
+            | Error::TransferInProgress(_)
+            | Error::DeltaMismatch(_) => ErrorCode::Conflict,
+            Error::DownloadLimitReached(_) => ErrorCode::Gone,
+            Error::TransferOffsetMismatch(_) => ErrorCode::RangeNotSatisfiable,
+            Error::Headless | Error::EncryptionBusy(_) => ErrorCode::Unavailable,
+            Error::Noise(_) | Error::Hyper(_) => ErrorCode::PeerUnreachable,
+            Error::PeerUnsupported(_) => ErrorCode::PeerUnsupported,
+            // The peer answered, its answer says what went wrong over there
+            Error::Reqwest(err) => match err.status() {
+                Some(StatusCode::NOT_FOUND) => ErrorCode::NotFound,
+                Some(StatusCode::FORBIDDEN) => ErrorCode::Forbidden,
+                Some(StatusCode::CONFLICT) => ErrorCode::Conflict,
+                Some(StatusCode::SERVICE_UNAVAILABLE) => ErrorCode::Unavailable,
+                _ => ErrorCode::PeerUnreachable,
+            },
+            _ => ErrorCode::Internal,
+        }
+    }
+
+    // The thing the error is about (a path, a peer, a limit...), when there is one
+    pub fn details(&self) -> Option<String> {
+        match self {
+            Error::Command(details)
+            | Error::ClipboardSyncDisabled(details)
+            | Error::NotADirectory(details)
+            | Error::TransferNotFound(details)
+            | Error::TransferNotAccepted(details)
+            | Error::TransferSizeMismatch(details)
+            | Error::TransferFileReceived(details)
+            | Error::TransferInProgress(details)
+            | Error::InvalidFileName(details)
+            | Error::DestinationMissing(details)
+            | Error::FolderNotWritable(details)
+            | Error::DownloadLimitReached(details)
+            | Error::DeltaMismatch(details)
+            | Error::InvalidKey(details)
+            | Error::PeerWithoutIdentity(details)
+            | Error::PeerUnsupported(details)
+            | Error::AccessDenied(details)
+            | Error::InvalidCidr(details)
+            | Error::InvalidSignature(details)
+            | Error::UnknownPeer(details)
+            | Error::NoiseKeyMismatch(details)
+            | Error::EncryptedDelta(details)
+            | Error::EncryptionBusy(details) => Some(details.clone()),
+            Error::ClipboardTooLarge(size)
+            | Error::MessageTooLong(size)
+            | Error::DeviceNameTooLong(size) => Some(size.to_string()),
+            Error::TransferOffsetMismatch(received) => Some(received.to_string()),
+            Error::InvalidDownloadLimit(limit) => Some(limit.to_string()),
+            Error::Reqwest(err) => err.url().map(|url| url.to_string()),
+            _ => None,
+        }
+    }
 }
 
 /*
- * Serialize custom Rust error types into string
+ * Serialize custom Rust error types into {code, message, details}
+ * This is what the UI receives when a Tauri command fails
  */
-impl serde::Serialize for Error {
+impl Serialize for Error {
     fn serialize<S>(&self, serializer: S) -> Result<S::Ok, S::Error>
     where
         S: serde::ser::Serializer,
     {
-        serializer.serialize_str(self.to_string().as_ref())
+        let mut error = serializer.serialize_struct("Error", 3)?;
+        error.serialize_field("code", &self.code())?;
+        error.serialize_field("message", &self.to_string())?;
+        error.serialize_field("details", &self.details())?;
+        error.end()
     }
 }
 
 /*
  * Custom error types can now be automatically converts to http errors
+ * The status code and the error code in the body both come from Error::code
  */
 impl IntoResponse for Error {
     fn into_response(self) -> axum::response::Response {
+        let code = self.code();
         (
-            StatusCode::INTERNAL_SERVER_ERROR,
+            code.status(),
             Json(ServerResponse {
                 message: self.to_string(),
                 data: (),
+                code: Some(code),
             }),
         )
             .into_response()
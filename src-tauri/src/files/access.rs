@@ -0,0 +1,71 @@
+/*
+  Filey - simple peer-to-peer file sending across devices on different platforms
+  Copyright (C) 2024 Wander Watterson
+
+  This program is free software: you can redistribute it and/or modify
+  it under the terms of the GNU General Public License as published by
+  the Free Software Foundation, either version 3 of the License, or
+  (at your option) any later version.
+
+  This program is distributed in the hope that it will be useful,
+  but WITHOUT ANY WARRANTY; without even the implied warranty of
+  MERCHANTABILITY or FITNESS FOR A PARTICULAR PURPOSE. See the
+  GNU General Public License for more details.
+
+  You should have received a copy of the GNU General Public License
+  along with this program. If not, see <https://www.gnu.org/licenses/>.
+*/
+
+#[cfg(feature = "gui")]
+use std::sync::Arc;
+#[cfg(feature = "gui")]
+use tauri::AppHandle;
+
+#[cfg(feature = "gui")]
+use super::commands::open_file;
+use crate::error::Error;
+
+/*
+ * How the server opens the shared files for reading
+ *
+ * The rows in the files table hold a plain path on desktop platforms, but a content URI on
+ * mobile platforms, and only the latter needs the app around (plugin-fs) to be opened.
+ * Keeping this behind a trait lets the routes run without a Tauri app at all,
+ * in the headless server or in a plain tokio process
+ */
+pub trait FileAccess: Send + Sync {
+    fn open(&self, path: &str) -> Result<tokio::fs::File, Error>;
+}
+
+// Desktop platforms, the paths are real paths on the disk
+pub struct LocalFileAccess;
+
+impl FileAccess for LocalFileAccess {
+    fn open(&self, path: &str) -> Result<tokio::fs::File, Error> {
+        Ok(std::fs::File::open(path)?.into())
+    }
+}
+
+// Mobile platforms, the paths are content URIs that only plugin-fs can read, see open_file
+#[cfg(feature = "gui")]
+pub struct ContentUriFileAccess {
+    pub app_handle: AppHandle,
+}
+
+#[cfg(feature = "gui")]
+impl FileAccess for ContentUriFileAccess {
+    fn open(&self, path: &str) -> Result<tokio::fs::File, Error> {
+        open_file(&self.app_handle, path)
+    }
+}
+
+// The file access that fits the platform the app runs on
+#[cfg(feature = "gui")]
+pub fn platform_file_access(app_handle: &AppHandle) -> Arc<dyn FileAccess> {
+    match cfg!(any(target_os = "android", target_os = "ios")) {
+        true => Arc::new(ContentUriFileAccess {
+            app_handle: app_handle.clone(),
+        }),
+        false => Arc::new(LocalFileAccess),
+    }
+}
@@ -16,16 +16,27 @@
   along with this program. If not, see <https://www.gnu.org/licenses/>.
 */
 
-use super::models::Visibility;
-use crate::{error::Error, files::models::FileModel, AppState};
+use super::models::{FileAcl, FileEvent, FileResponse, ItemKind};
+use crate::{error::Error, files::models::FileModel};
+use sqlx::SqlitePool;
+use tokio::sync::broadcast;
+use uuid::{fmt::Hyphenated, Uuid};
+
+#[cfg(feature = "gui")]
+use super::models::{ExpiryAction, SnippetModel, Visibility};
+#[cfg(feature = "gui")]
+use crate::{drop_folders::commands::remember_unshared, AppState};
+#[cfg(feature = "gui")]
 use std::str::FromStr;
+#[cfg(feature = "gui")]
 use tauri::{AppHandle, Manager, State};
+#[cfg(feature = "gui")]
 use tauri_plugin_fs::{FsExt, OpenOptions, SafeFilePath};
-use uuid::{fmt::Hyphenated, Uuid};
 
 /*
  * This command opens up file explorer and show the file at location
  */
+#[cfg(feature = "gui")]
 #[tauri::command]
 pub async fn reveal(path: &str) -> Result<(), Error> {
     #[cfg(target_os = "macos")]
@@ -58,6 +69,7 @@ pub async fn reveal(path: &str) -> Result<(), Error> {
 /*
  * Checks if a file at path exists or not
  */
+#[cfg(feature = "gui")]
 #[tauri::command]
 pub fn file_exists(app_handle: AppHandle, path: &str) -> bool {
     let Ok(path) = SafeFilePath::from_str(path) else {
@@ -69,7 +81,33 @@ pub fn file_exists(app_handle: AppHandle, path: &str) -> bool {
         .is_ok()
 }
 
+/*
+ *  Opens the file using tauri's plugin-fs file opener
+ *
+ *  Why not just use std::fs::File or tokio::fs::File you ask?
+ *  Well both aforementioned File struct can open desktop file path,
+ *  but NOT mobile platforms
+ *
+ *  Since mobile platforms only return content URI, therefore std::fs::File
+ *  or tokio::fs::File can't read that.
+ *
+ *  However tauri provides plugin-fs that can get the file content from
+ *  both desktop path and content URI, and it returns std::fs::File, which can
+ *  be converted to tokio::fs::File, how convenient is that?
+ */
+#[cfg(feature = "gui")]
+pub fn open_file(app_handle: &AppHandle, path: &str) -> Result<tokio::fs::File, Error> {
+    Ok(app_handle
+        .fs()
+        .open(
+            SafeFilePath::from_str(path)?,
+            OpenOptions::new().read(true).clone(),
+        )?
+        .into())
+}
+
 // Returns a list of local files
+#[cfg(feature = "gui")]
 #[tauri::command]
 pub async fn get_files(state: State<'_, AppState>) -> Result<Vec<FileModel>, Error> {
     let files = sqlx::query_as!(
@@ -80,7 +118,15 @@ pub async fn get_files(state: State<'_, AppState>) -> Result<Vec<FileModel>, Err
                 name,
                 mime,
                 visibility as "visibility!: Visibility",
-                path
+                path,
+                missing,
+                size,
+                modified_at,
+                share_until,
+                max_downloads,
+                download_count,
+                on_expiry as "on_expiry!: ExpiryAction",
+                passphrase is not null as "encrypted!: bool"
             from files
         "#
     )
@@ -96,6 +142,7 @@ pub async fn get_files(state: State<'_, AppState>) -> Result<Vec<FileModel>, Err
     This function here will insert new rows if it has not yet exist,
     or update existing ones
 */
+#[cfg(feature = "gui")]
 #[tauri::command]
 pub async fn upsert_files(
     app_handle: AppHandle,
@@ -104,18 +151,18 @@ pub async fn upsert_files(
     let state = app_handle.state::<AppState>();
     // We receive the list of file info, and iterate through each of them
     for FileModel {
-        id,
+        id: file_id,
         name,
-        visibility,
+        visibility: file_visibility,
         path,
         ..
     } in files
     {
         // Convert id to string
-        let id = id.to_string();
+        let id = file_id.to_string();
 
         // Convert visibility enum to string
-        let visibility = visibility.to_string();
+        let visibility = file_visibility.to_string();
 
         /*
          * Fetch the file by id
@@ -123,9 +170,21 @@ pub async fn upsert_files(
          * I use fetch_optional a lot just to make sure the query will run and forget about
          * the output, causing no errors whatsoever
          */
-        match sqlx::query!("select id from files where id = $1", id)
-            .fetch_optional(&state.db)
-            .await?
+        match sqlx::query!(
+            r#"
+                select
+                    id,
+                    name,
+                    mime,
+                    visibility as "visibility!: Visibility",
+                    passphrase is not null as "encrypted!: bool"
+                from files
+                where id = $1
+            "#,
+            id
+        )
+        .fetch_optional(&state.db)
+        .await?
         {
             // If there is, simply update it
             Some(file) => {
@@ -141,6 +200,23 @@ pub async fn upsert_files(
                 )
                 .fetch_optional(&state.db)
                 .await?;
+
+                // Let the peers know that the file went public or private
+                if file.visibility != file_visibility {
+                    state
+                        .file_events
+                        .send(FileEvent::VisibilityChanged {
+                            file: FileResponse {
+                                id: file_id,
+                                name: file.name,
+                                mime: file.mime,
+                                kind: ItemKind::File,
+                                encrypted: file.encrypted,
+                            },
+                            visibility: file_visibility,
+                        })
+                        .ok();
+                }
             }
             // If there isn't, do some work before insertion
             None => {
@@ -176,23 +252,26 @@ pub async fn upsert_files(
                 };
 
                 // After finish processing, insert a new row
-                sqlx::query!(
-                    "
-                        insert into files
-                            (id, name, mime, visibility, path)
-                        values
-                            ($1, $2, $3, $4, $5)
-                        on conflict (id)
-                        do nothing
-                        returning id
-                    ",
-                    id,
-                    name,
-                    mime,
-                    visibility,
-                    path
+                insert_file(
+                    &state.db,
+                    &state.file_events,
+                    FileModel {
+                        id: file_id,
+                        name,
+                        mime,
+                        visibility: file_visibility,
+                        path,
+                        missing: false,
+                        size: None,
+                        modified_at: None,
+                        share_until: None,
+                        max_downloads: None,
+                        download_count: 0,
+                        on_expiry: ExpiryAction::Private,
+                        encrypted: false,
+                    },
+                    None,
                 )
-                .fetch_optional(&state.db)
                 .await?;
             }
         }
@@ -214,7 +293,15 @@ pub async fn upsert_files(
                 name,
                 mime,
                 visibility as "visibility!: Visibility",
-                path
+                path,
+                missing,
+                size,
+                modified_at,
+                share_until,
+                max_downloads,
+                download_count,
+                on_expiry as "on_expiry!: ExpiryAction",
+                passphrase is not null as "encrypted!: bool"
             from files
         "#
     )
@@ -222,12 +309,432 @@ pub async fn upsert_files(
     .await?)
 }
 
-// Delete the file by id
+/*
+ * Inserts a new row into the files table, and lets the peers know about it
+ * Files added from a drop folder remember which folder they came from,
+ * so that they can be cleaned up along with the folder
+ */
+pub async fn insert_file(
+    db: &SqlitePool,
+    file_events: &broadcast::Sender<FileEvent>,
+    file: FileModel,
+    drop_folder_id: Option<Uuid>,
+) -> Result<(), Error> {
+    let FileModel {
+        id: file_id,
+        name,
+        mime,
+        visibility: file_visibility,
+        path,
+        ..
+    } = file;
+
+    let id = file_id.to_string();
+    let visibility = file_visibility.to_string();
+    let drop_folder_id = drop_folder_id.map(|id| id.to_string());
+
+    sqlx::query!(
+        "
+            insert into files
+                (id, name, mime, visibility, path, drop_folder_id)
+            values
+                ($1, $2, $3, $4, $5, $6)
+            on conflict (id)
+            do nothing
+            returning id
+        ",
+        id,
+        name,
+        mime,
+        visibility,
+        path,
+        drop_folder_id
+    )
+    .fetch_optional(db)
+    .await?;
+
+    file_events
+        .send(FileEvent::Added {
+            file: FileResponse {
+                id: file_id,
+                name,
+                mime,
+                kind: ItemKind::File,
+                encrypted: false,
+            },
+            visibility: file_visibility,
+        })
+        .ok();
+
+    Ok(())
+}
+
+/*
+ * Sets until when, and for how many downloads, a file stays shared
+ * None lifts the limit, and the download count starts over from zero
+ * What happens once a limit is reached is up to on_expiry, see files::expiry
+ */
+#[cfg(feature = "gui")]
+#[tauri::command]
+pub async fn set_share_limits(
+    state: State<'_, AppState>,
+    id: Uuid,
+    share_until: Option<i64>,
+    max_downloads: Option<i64>,
+    on_expiry: ExpiryAction,
+) -> Result<FileModel, Error> {
+    if let Some(max_downloads) = max_downloads.filter(|max_downloads| *max_downloads < 1) {
+        return Err(Error::InvalidDownloadLimit(max_downloads));
+    }
+
+    let file_id = id.to_string();
+    let on_expiry = on_expiry.to_string();
+    sqlx::query!(
+        "
+            update files set
+                share_until = $1,
+                max_downloads = $2,
+                download_count = 0,
+                on_expiry = $3
+            where id = $4
+            returning id
+        ",
+        share_until,
+        max_downloads,
+        on_expiry,
+        file_id
+    )
+    .fetch_one(&state.db)
+    .await?;
+
+    Ok(sqlx::query_as!(
+        FileModel,
+        r#"
+            select
+                id as "id!: Hyphenated",
+                name,
+                mime,
+                visibility as "visibility!: Visibility",
+                path,
+                missing,
+                size,
+                modified_at,
+                share_until,
+                max_downloads,
+                download_count,
+                on_expiry as "on_expiry!: ExpiryAction",
+                passphrase is not null as "encrypted!: bool"
+            from files
+            where id = $1
+        "#,
+        file_id
+    )
+    .fetch_one(&state.db)
+    .await?)
+}
+
+/*
+ * Sets the passphrase a file is encrypted with when it is served, see files::encryption
+ * None serves it as it is again
+ * The passphrase stays on this device, it has to reach the peers some other way
+ */
+#[cfg(feature = "gui")]
+#[tauri::command]
+pub async fn set_file_passphrase(
+    state: State<'_, AppState>,
+    id: Uuid,
+    passphrase: Option<String>,
+) -> Result<FileModel, Error> {
+    if passphrase.as_deref() == Some("") {
+        return Err(Error::EmptyPassphrase);
+    }
+
+    // Stored encrypted, see files::encryption::PassphraseKey
+    let passphrase = passphrase
+        .map(|passphrase| state.passphrase_key.encrypt_passphrase(&passphrase))
+        .transpose()?;
+
+    let file_id = id.to_string();
+    sqlx::query!(
+        "update files set passphrase = $1 where id = $2 returning id",
+        passphrase,
+        file_id
+    )
+    .fetch_one(&state.db)
+    .await?;
+
+    Ok(sqlx::query_as!(
+        FileModel,
+        r#"
+            select
+                id as "id!: Hyphenated",
+                name,
+                mime,
+                visibility as "visibility!: Visibility",
+                path,
+                missing,
+                size,
+                modified_at,
+                share_until,
+                max_downloads,
+                download_count,
+                on_expiry as "on_expiry!: ExpiryAction",
+                passphrase is not null as "encrypted!: bool"
+            from files
+            where id = $1
+        "#,
+        file_id
+    )
+    .fetch_one(&state.db)
+    .await?)
+}
+
+/*
+ * Delete the file by id
+ * A file of a drop folder stays out of the shared list, see drop_folders::commands::remember_unshared
+ */
+#[cfg(feature = "gui")]
 #[tauri::command]
 pub async fn delete_file(state: State<'_, AppState>, id: Uuid) -> Result<(), Error> {
-    let id = id.to_string();
-    sqlx::query!("delete from files where id = $1 returning id", id)
+    remember_unshared(&state.db, id).await?;
+    let file_id = id.to_string();
+    sqlx::query!("delete from files where id = $1 returning id", file_id)
         .fetch_one(&state.db)
         .await?;
+
+    state.file_events.send(FileEvent::Removed { id }).ok();
+    Ok(())
+}
+
+// Returns the known peers and groups a file is shared with
+#[cfg(feature = "gui")]
+#[tauri::command]
+pub async fn get_file_acl(state: State<'_, AppState>, file_id: Uuid) -> Result<FileAcl, Error> {
+    load_file_acl(&state.db, file_id).await
+}
+
+/*
+ * Replaces the known peers and groups a file is shared with
+ * Empty lists turn the file back into a plain private (or public) file
+ */
+#[cfg(feature = "gui")]
+#[tauri::command]
+pub async fn set_file_acl(state: State<'_, AppState>, acl: FileAcl) -> Result<FileAcl, Error> {
+    let file_id = acl.file_id.to_string();
+
+    // Makes sure the file exists, instead of failing on the foreign key
+    let file = sqlx::query!(
+        r#"
+            select
+                name,
+                mime,
+                visibility as "visibility!: Visibility",
+                passphrase is not null as "encrypted!: bool"
+            from files
+            where id = $1
+        "#,
+        file_id
+    )
+    .fetch_one(&state.db)
+    .await?;
+
+    sqlx::query!("delete from file_acl where file_id = $1", file_id)
+        .execute(&state.db)
+        .await?;
+
+    for device_id in acl.device_ids {
+        let device_id = device_id.to_string();
+        sqlx::query!(
+            "insert into file_acl (file_id, device_id) values ($1, $2)",
+            file_id,
+            device_id
+        )
+        .execute(&state.db)
+        .await?;
+    }
+
+    for group_id in acl.group_ids {
+        let group_id = group_id.to_string();
+        sqlx::query!(
+            "insert into file_acl (file_id, group_id) values ($1, $2)",
+            file_id,
+            group_id
+        )
+        .execute(&state.db)
+        .await?;
+    }
+
+    // The peers that just got access see the file show up, the others see it go
+    state
+        .file_events
+        .send(FileEvent::AccessChanged {
+            file: FileResponse {
+                id: acl.file_id,
+                name: file.name,
+                mime: file.mime,
+                kind: ItemKind::File,
+                encrypted: file.encrypted,
+            },
+            visibility: file.visibility,
+        })
+        .ok();
+
+    load_file_acl(&state.db, acl.file_id).await
+}
+
+pub async fn load_file_acl(db: &SqlitePool, file_id: Uuid) -> Result<FileAcl, Error> {
+    let id = file_id.to_string();
+    let rows = sqlx::query!(
+        r#"
+            select
+                device_id as "device_id: Hyphenated",
+                group_id as "group_id: Hyphenated"
+            from file_acl
+            where file_id = $1
+        "#,
+        id
+    )
+    .fetch_all(db)
+    .await?;
+
+    Ok(FileAcl {
+        file_id,
+        device_ids: rows
+            .iter()
+            .filter_map(|row| row.device_id.map(Hyphenated::into_uuid))
+            .collect(),
+        group_ids: rows
+            .iter()
+            .filter_map(|row| row.group_id.map(Hyphenated::into_uuid))
+            .collect(),
+    })
+}
+
+// Returns a list of local text snippets
+#[cfg(feature = "gui")]
+#[tauri::command]
+pub async fn get_snippets(state: State<'_, AppState>) -> Result<Vec<SnippetModel>, Error> {
+    let snippets = sqlx::query_as!(
+        SnippetModel,
+        r#"
+            select
+                id as "id!: Hyphenated",
+                title,
+                body,
+                visibility as "visibility!: Visibility"
+            from snippets
+        "#
+    )
+    .fetch_all(&state.db)
+    .await?;
+
+    Ok(snippets)
+}
+
+/*
+ * Same idea as upsert_files, receives the entire list of snippets from React state
+ * Snippets do not have a path, so there is nothing to detect before insertion,
+ * the title, body and visibility are simply overwritten if the row already exists
+ */
+#[cfg(feature = "gui")]
+#[tauri::command]
+pub async fn upsert_snippets(
+    state: State<'_, AppState>,
+    snippets: Vec<SnippetModel>,
+) -> Result<Vec<SnippetModel>, Error> {
+    for SnippetModel {
+        id: snippet_id,
+        title,
+        body,
+        visibility: snippet_visibility,
+    } in snippets
+    {
+        let id = snippet_id.to_string();
+        let visibility = snippet_visibility.to_string();
+
+        // The old visibility tells whether the snippet is new, or went public or private
+        let old_visibility = sqlx::query!(
+            r#"select visibility as "visibility!: Visibility" from snippets where id = $1"#,
+            id
+        )
+        .fetch_optional(&state.db)
+        .await?
+        .map(|snippet| snippet.visibility);
+
+        sqlx::query!(
+            "
+                insert into snippets
+                    (id, title, body, visibility)
+                values
+                    ($1, $2, $3, $4)
+                on conflict (id)
+                do update set
+                    title = excluded.title,
+                    body = excluded.body,
+                    visibility = excluded.visibility
+                returning id
+            ",
+            id,
+            title,
+            body,
+            visibility
+        )
+        .fetch_optional(&state.db)
+        .await?;
+
+        let file = FileResponse {
+            id: snippet_id,
+            name: title,
+            mime: "text/plain".into(),
+            kind: ItemKind::Snippet,
+            encrypted: false,
+        };
+        let event = match old_visibility {
+            None => Some(FileEvent::Added {
+                file,
+                visibility: snippet_visibility,
+            }),
+            Some(old_visibility) if old_visibility != snippet_visibility => {
+                Some(FileEvent::VisibilityChanged {
+                    file,
+                    visibility: snippet_visibility,
+                })
+            }
+            Some(_) => None,
+        };
+        if let Some(event) = event {
+            state.file_events.send(event).ok();
+        }
+    }
+
+    // Return the entire list of snippets, for the same reason as upsert_files
+    Ok(sqlx::query_as!(
+        SnippetModel,
+        r#"
+            select
+                id as "id!: Hyphenated",
+                title,
+                body,
+                visibility as "visibility!: Visibility"
+            from snippets
+        "#
+    )
+    .fetch_all(&state.db)
+    .await?)
+}
+
+// Delete the snippet by id
+#[cfg(feature = "gui")]
+#[tauri::command]
+pub async fn delete_snippet(state: State<'_, AppState>, id: Uuid) -> Result<(), Error> {
+    let snippet_id = id.to_string();
+    sqlx::query!(
+        "delete from snippets where id = $1 returning id",
+        snippet_id
+    )
+    .fetch_one(&state.db)
+    .await?;
+
+    state.file_events.send(FileEvent::Removed { id }).ok();
     Ok(())
 }
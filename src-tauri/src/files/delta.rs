@@ -0,0 +1,345 @@
+/*
+  Filey - simple peer-to-peer file sending across devices on different platforms
+  Copyright (C) 2024 Wander Watterson
+
+  This program is free software: you can redistribute it and/or modify
+  it under the terms of the GNU General Public License as published by
+  the Free Software Foundation, either version 3 of the License, or
+  (at your option) any later version.
+
+  This program is distributed in the hope that it will be useful,
+  but WITHOUT ANY WARRANTY; without even the implied warranty of
+  MERCHANTABILITY or FITNESS FOR A PARTICULAR PURPOSE. See the
+  GNU General Public License for more details.
+
+  You should have received a copy of the GNU General Public License
+  along with this program. If not, see <https://www.gnu.org/licenses/>.
+*/
+
+/*
+ * rsync style delta transfer, the other way around
+ *
+ * The peer serving the file splits it into blocks, and sends a weak (rolling) and a strong
+ * (SHA-256) checksum of every block. The downloading side slides a window over its own older
+ * copy, one byte at a time, and looks the weak checksum of the window up in those signatures.
+ * The rolling checksum is cheap to move forward by one byte, so every offset gets checked,
+ * which means blocks are found again even if data got inserted or removed before them.
+ * A matching weak checksum is confirmed with the strong one, and that block is copied from
+ * the older copy instead of being downloaded. Only the blocks that are nowhere to be found
+ * get requested, with Range requests on /files/{id}
+ */
+
+use sha2::{Digest, Sha256};
+use std::collections::HashMap;
+use tokio::{
+    fs::File,
+    io::{self, AsyncRead, AsyncReadExt},
+};
+
+use super::models::{BlockSignature, FileSignatures};
+
+pub const MIN_BLOCK_SIZE: u64 = 2 * 1024;
+pub const MAX_BLOCK_SIZE: u64 = 1024 * 1024;
+
+// Roughly the square root of the size, like rsync does, a 4 GB file gets 64 KB blocks
+pub fn block_size_for(size: u64) -> u64 {
+    ((size as f64).sqrt() as u64).clamp(MIN_BLOCK_SIZE, MAX_BLOCK_SIZE)
+}
+
+// The last block is usually shorter than the others
+pub fn block_len(signatures: &FileSignatures, index: usize) -> u64 {
+    let start = index as u64 * signatures.block_size;
+    signatures
+        .block_size
+        .min(signatures.size.saturating_sub(start))
+}
+
+/*
+ * The weak checksum of rsync
+ * a is the sum of the bytes, b is the sum of the bytes weighted by their distance to the end
+ * of the window, both modulo 2^16
+ */
+struct RollingChecksum {
+    a: u32,
+    b: u32,
+    len: u32,
+}
+
+impl RollingChecksum {
+    fn new(block: &[u8]) -> Self {
+        let len = block.len() as u32;
+        let (a, b) = block
+            .iter()
+            .enumerate()
+            .fold((0u32, 0u32), |(a, b), (index, &byte)| {
+                (
+                    a.wrapping_add(byte as u32),
+                    b.wrapping_add((len - index as u32).wrapping_mul(byte as u32)),
+                )
+            });
+        RollingChecksum {
+            a: a & 0xffff,
+            b: b & 0xffff,
+            len,
+        }
+    }
+
+    fn digest(&self) -> u32 {
+        self.a | (self.b << 16)
+    }
+
+    // Moves the window one byte forward, the first byte leaves and a new one comes in
+    fn roll(&mut self, leaving: u8, entering: u8) {
+        self.a = self
+            .a
+            .wrapping_sub(leaving as u32)
+            .wrapping_add(entering as u32)
+            & 0xffff;
+        self.b = self
+            .b
+            .wrapping_sub(self.len.wrapping_mul(leaving as u32))
+            .wrapping_add(self.a)
+            & 0xffff;
+    }
+}
+
+fn strong_checksum(block: &[u8]) -> String {
+    format!("{:x}", Sha256::digest(block))
+}
+
+// Fills the buffer as much as possible, only returns less than its length at the end of the file
+async fn read_block(reader: &mut (impl AsyncRead + Unpin), buffer: &mut [u8]) -> io::Result<usize> {
+    let mut filled = 0;
+    while filled < buffer.len() {
+        let read = reader.read(&mut buffer[filled..]).await?;
+        if read == 0 {
+            break;
+        }
+        filled += read;
+    }
+    Ok(filled)
+}
+
+// Reads the file once, block by block, into its signatures
+pub async fn compute_signatures(mut file: File, block_size: u64) -> io::Result<FileSignatures> {
+    let mut buffer = vec![0; block_size as usize];
+    let mut hasher = Sha256::new();
+    let mut blocks = vec![];
+    let mut size = 0;
+
+    loop {
+        let read = read_block(&mut file, &mut buffer).await?;
+        if read == 0 {
+            break;
+        }
+        let block = &buffer[..read];
+        hasher.update(block);
+        blocks.push(BlockSignature {
+            weak: RollingChecksum::new(block).digest(),
+            strong: strong_checksum(block),
+        });
+        size += read as u64;
+    }
+
+    Ok(FileSignatures {
+        size,
+        block_size,
+        hash: format!("{:x}", hasher.finalize()),
+        blocks,
+    })
+}
+
+/*
+ * A window sliding over a file that is too big to be read at once
+ * The data before the window is thrown away whenever more of the file gets read
+ */
+struct SlidingWindow {
+    file: File,
+    data: Vec<u8>,
+    start: usize,
+    offset: u64,
+    read_size: usize,
+}
+
+impl SlidingWindow {
+    // Reads until there are at least `len` bytes from the start of the window, false at the end
+    async fn ensure(&mut self, len: usize) -> io::Result<bool> {
+        while self.data.len() - self.start < len {
+            if self.start > 0 {
+                self.data.drain(..self.start);
+                self.offset += self.start as u64;
+                self.start = 0;
+            }
+            let filled = self.data.len();
+            self.data.resize(filled + self.read_size, 0);
+            let read = self.file.read(&mut self.data[filled..]).await?;
+            self.data.truncate(filled + read);
+            if read == 0 {
+                return Ok(false);
+            }
+        }
+        Ok(true)
+    }
+
+    fn window(&self, len: usize) -> &[u8] {
+        &self.data[self.start..self.start + len]
+    }
+
+    // Offset of the start of the window in the file
+    fn position(&self) -> u64 {
+        self.offset + self.start as u64
+    }
+}
+
+/*
+ * Finds the blocks of the signatures inside an older copy of the file
+ * Returns, for every block, the offset in the older copy it can be copied from,
+ * or nothing if it has to be downloaded
+ */
+pub async fn find_reusable_blocks(
+    old_copy: File,
+    signatures: &FileSignatures,
+) -> io::Result<Vec<Option<u64>>> {
+    let block_size = signatures.block_size as usize;
+    let mut found: Vec<Option<u64>> = vec![None; signatures.blocks.len()];
+
+    // Only full blocks can be matched by a full window, the short last block is downloaded
+    let mut weak_index: HashMap<u32, Vec<usize>> = HashMap::new();
+    for (index, block) in signatures.blocks.iter().enumerate() {
+        if block_len(signatures, index) == signatures.block_size {
+            weak_index.entry(block.weak).or_default().push(index);
+        }
+    }
+    if weak_index.is_empty() {
+        return Ok(found);
+    }
+
+    let mut window = SlidingWindow {
+        file: old_copy,
+        data: vec![],
+        start: 0,
+        offset: 0,
+        read_size: (block_size * 4).max(64 * 1024),
+    };
+    if !window.ensure(block_size).await? {
+        return Ok(found);
+    }
+    let mut checksum = RollingChecksum::new(window.window(block_size));
+
+    loop {
+        let mut matched = false;
+        if let Some(candidates) = weak_index.get(&checksum.digest()) {
+            let strong = strong_checksum(window.window(block_size));
+            for &index in candidates {
+                if found[index].is_none() && signatures.blocks[index].strong == strong {
+                    found[index] = Some(window.position());
+                    matched = true;
+                }
+            }
+        }
+
+        if matched {
+            // Skip over the matched block, the next one usually follows right after it
+            window.start += block_size;
+            if !window.ensure(block_size).await? {
+                break;
+            }
+            checksum = RollingChecksum::new(window.window(block_size));
+        } else {
+            if !window.ensure(block_size + 1).await? {
+                break;
+            }
+            let data = window.window(block_size + 1);
+            checksum.roll(data[0], data[block_size]);
+            window.start += 1;
+        }
+    }
+
+    Ok(found)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use std::io::Write;
+    use tempfile::NamedTempFile;
+
+    const BLOCK_SIZE: usize = 2048;
+
+    // Same bytes on every run, but without any repeating pattern that would match by chance
+    fn data(len: usize) -> Vec<u8> {
+        let mut seed: u32 = 1;
+        (0..len)
+            .map(|_| {
+                seed = seed.wrapping_mul(1_103_515_245).wrapping_add(12_345);
+                (seed >> 16) as u8
+            })
+            .collect()
+    }
+
+    fn temp_file(content: &[u8]) -> NamedTempFile {
+        let mut file = NamedTempFile::new().unwrap();
+        file.write_all(content).unwrap();
+        file
+    }
+
+    async fn signatures_of(content: &[u8]) -> FileSignatures {
+        let file = temp_file(content);
+        let opened = File::open(file.path()).await.unwrap();
+        compute_signatures(opened, BLOCK_SIZE as u64).await.unwrap()
+    }
+
+    async fn reusable_blocks(old_copy: &[u8], signatures: &FileSignatures) -> Vec<Option<u64>> {
+        let file = temp_file(old_copy);
+        let opened = File::open(file.path()).await.unwrap();
+        find_reusable_blocks(opened, signatures).await.unwrap()
+    }
+
+    #[test]
+    fn roll_matches_a_recomputed_checksum() {
+        let data = data(1000);
+        let len = 64;
+        let mut checksum = RollingChecksum::new(&data[..len]);
+        for start in 1..data.len() - len {
+            checksum.roll(data[start - 1], data[start + len - 1]);
+            let recomputed = RollingChecksum::new(&data[start..start + len]);
+            assert_eq!(checksum.digest(), recomputed.digest(), "offset {start}");
+        }
+    }
+
+    #[tokio::test]
+    async fn unchanged_file_is_reused_except_for_the_short_last_block() {
+        let old_copy = data(BLOCK_SIZE * 4 + 100);
+        let signatures = signatures_of(&old_copy).await;
+
+        let found = reusable_blocks(&old_copy, &signatures).await;
+        assert_eq!(
+            found,
+            vec![Some(0), Some(2048), Some(4096), Some(6144), None]
+        );
+    }
+
+    #[tokio::test]
+    async fn blocks_are_found_after_an_insertion() {
+        let old_copy = data(BLOCK_SIZE * 4);
+        let mut new_copy = old_copy.clone();
+        new_copy.splice(BLOCK_SIZE + 10..BLOCK_SIZE + 10, [7; 100]);
+        let signatures = signatures_of(&new_copy).await;
+
+        // The second block holds the insertion, the ones after it moved by 100 bytes
+        let found = reusable_blocks(&old_copy, &signatures).await;
+        assert_eq!(found, vec![Some(0), None, Some(3996), Some(6044), None]);
+    }
+
+    #[tokio::test]
+    async fn blocks_are_found_after_a_deletion() {
+        let old_copy = data(BLOCK_SIZE * 4);
+        let mut new_copy = old_copy.clone();
+        new_copy.drain(BLOCK_SIZE + 10..BLOCK_SIZE + 110);
+        let signatures = signatures_of(&new_copy).await;
+
+        // The second block lost some bytes, the one after it moved back by 100 bytes
+        let found = reusable_blocks(&old_copy, &signatures).await;
+        assert_eq!(found, vec![Some(0), None, Some(4196), None]);
+    }
+}
@@ -0,0 +1,241 @@
+/*
+  Filey - simple peer-to-peer file sending across devices on different platforms
+  Copyright (C) 2024 Wander Watterson
+
+  This program is free software: you can redistribute it and/or modify
+  it under the terms of the GNU General Public License as published by
+  the Free Software Foundation, either version 3 of the License, or
+  (at your option) any later version.
+
+  This program is distributed in the hope that it will be useful,
+  but WITHOUT ANY WARRANTY; without even the implied warranty of
+  MERCHANTABILITY or FITNESS FOR A PARTICULAR PURPOSE. See the
+  GNU General Public License for more details.
+
+  You should have received a copy of the GNU General Public License
+  along with this program. If not, see <https://www.gnu.org/licenses/>.
+*/
+
+use age::{
+    scrypt,
+    secrecy::{ExposeSecret, SecretString},
+    x25519, Decryptor, Encryptor, Identity,
+};
+use axum::body::Body;
+use base64::{engine::general_purpose::STANDARD, Engine};
+use log::error;
+use std::{io::ErrorKind, iter, path::Path, sync::Arc};
+use tokio::{
+    io::{AsyncRead, AsyncWriteExt, DuplexStream},
+    sync::Semaphore,
+};
+use tokio_util::{
+    compat::{FuturesAsyncReadCompatExt, TokioAsyncReadCompatExt},
+    io::{ReaderStream, SyncIoBridge},
+};
+
+use super::models::ENCRYPTION_BUFFER_SIZE;
+use crate::error::Error;
+
+/*
+ * Files that are shared with a passphrase are encrypted on the way out, in the age format
+ * (https://age-encryption.org), so that they can also be decrypted with the age command line
+ * The file is encrypted while it is being sent, it never exists encrypted on the disk,
+ * and the peer only ever gets the encrypted bytes, whatever devices they go through
+ *
+ * The passphrase goes through scrypt first, about a second of CPU and 256 MiB of memory,
+ * so the encryption runs on a blocking thread, and only as many at once as the encryptions
+ * semaphore allows (MAX_ENCRYPTED_DOWNLOADS), the downloads over that are refused
+ */
+pub fn encrypted_body(
+    file: tokio::fs::File,
+    passphrase: String,
+    encryptions: &Arc<Semaphore>,
+    name: &str,
+) -> Result<Body, Error> {
+    let permit = encryptions
+        .clone()
+        .try_acquire_owned()
+        .map_err(|_| Error::EncryptionBusy(name.to_string()))?;
+
+    let (writer, reader) = tokio::io::duplex(ENCRYPTION_BUFFER_SIZE);
+    crate::runtime::spawn_blocking(move || {
+        // The next download can start once this one is all encrypted, or the peer went away
+        let _permit = permit;
+        // The body just ends early then, age tells the peer that the file is cut short
+        if let Err(err) = encrypt(file, passphrase, writer) {
+            error!("Cannot encrypt the file: {err}");
+        }
+    });
+    Ok(Body::from_stream(ReaderStream::new(reader)))
+}
+
+fn encrypt(file: tokio::fs::File, passphrase: String, output: DuplexStream) -> Result<(), Error> {
+    let encryptor = Encryptor::with_user_passphrase(SecretString::from(passphrase));
+    let mut writer = encryptor.wrap_output(SyncIoBridge::new(output))?;
+    std::io::copy(&mut SyncIoBridge::new(file), &mut writer)?;
+    // Writes the last chunk, without it the file does not decrypt
+    writer.finish()?;
+    Ok(())
+}
+
+/*
+ * Decrypts a file that a peer served encrypted, while it is being received
+ * Only the decrypted bytes are read from it, so they can be written straight to their destination
+ * A wrong passphrase fails here already, a file that is cut short or tampered with fails
+ * while reading
+ */
+pub async fn decrypted_reader<R>(
+    input: R,
+    passphrase: &str,
+) -> Result<impl AsyncRead + Unpin, Error>
+where
+    R: AsyncRead + Unpin,
+{
+    let decryptor = Decryptor::new_async(input.compat()).await?;
+    let identity = scrypt::Identity::new(SecretString::from(passphrase.to_string()));
+    let reader = decryptor.decrypt_async(iter::once(&identity as &dyn Identity))?;
+    Ok(reader.compat())
+}
+
+/*
+ * The key the passphrases are encrypted with in the database (files.passphrase and
+ * transfer_jobs.passphrase), so that a copy of the database does not give them away
+ * It is kept in a file of its own next to the database, that only this user can read
+ */
+pub struct PassphraseKey(x25519::Identity);
+
+impl PassphraseKey {
+    // Reads the key, it is created on first use
+    pub async fn load(path: &Path) -> Result<Self, Error> {
+        let invalid_key = || Error::InvalidKey(path.display().to_string());
+        match tokio::fs::read_to_string(path).await {
+            Ok(text) => Ok(PassphraseKey(
+                text.trim().parse().map_err(|_| invalid_key())?,
+            )),
+            Err(err) if err.kind() == ErrorKind::NotFound => {
+                let identity = x25519::Identity::generate();
+                let mut options = tokio::fs::OpenOptions::new();
+                options.write(true).create_new(true);
+                #[cfg(unix)]
+                options.mode(0o600);
+                options
+                    .open(path)
+                    .await?
+                    .write_all(identity.to_string().expose_secret().as_bytes())
+                    .await?;
+                Ok(PassphraseKey(identity))
+            }
+            Err(err) => Err(err.into()),
+        }
+    }
+
+    // What gets stored instead of the passphrase
+    pub fn encrypt_passphrase(&self, passphrase: &str) -> Result<String, Error> {
+        let encrypted = age::encrypt(&self.0.to_public(), passphrase.as_bytes())?;
+        Ok(STANDARD.encode(encrypted))
+    }
+
+    pub fn decrypt_passphrase(&self, encrypted: &str) -> Result<String, Error> {
+        let invalid_passphrase = || Error::InvalidKey("stored passphrase".into());
+        let encrypted = STANDARD
+            .decode(encrypted)
+            .map_err(|_| invalid_passphrase())?;
+        String::from_utf8(age::decrypt(&self.0, &encrypted)?).map_err(|_| invalid_passphrase())
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use tokio::io::AsyncReadExt;
+    use tokio_stream::StreamExt;
+    use tokio_util::io::StreamReader;
+
+    // Encrypts the content the way get_file serves it, and returns what the peer receives
+    async fn served(content: &[u8], passphrase: &str) -> Vec<u8> {
+        let mut file = tempfile::NamedTempFile::new().unwrap();
+        std::io::Write::write_all(&mut file, content).unwrap();
+        let file = tokio::fs::File::from(file.reopen().unwrap());
+
+        let encryptions = Arc::new(Semaphore::new(1));
+        let body = encrypted_body(file, passphrase.to_string(), &encryptions, "file").unwrap();
+        axum::body::to_bytes(body, usize::MAX)
+            .await
+            .unwrap()
+            .to_vec()
+    }
+
+    async fn decrypt(encrypted: Vec<u8>, passphrase: &str) -> Result<Vec<u8>, Error> {
+        // Small chunks, so the decryption has to carry on across them
+        let chunks: Vec<_> = encrypted
+            .chunks(1000)
+            .map(|chunk| Ok::<_, std::io::Error>(chunk.to_vec()))
+            .collect();
+        let input = StreamReader::new(
+            tokio_stream::iter(chunks).map(|chunk| chunk.map(axum::body::Bytes::from)),
+        );
+        let mut reader = decrypted_reader(input, passphrase).await?;
+        let mut decrypted = vec![];
+        reader.read_to_end(&mut decrypted).await?;
+        Ok(decrypted)
+    }
+
+    #[tokio::test]
+    async fn encrypted_file_decrypts_back() {
+        // Bigger than the buffer and than an age chunk (64 KiB), so it streams through both
+        let content: Vec<u8> = (0..200_000).map(|i| (i % 251) as u8).collect();
+        let encrypted = served(&content, "correct horse").await;
+        assert!(encrypted.starts_with(b"age-encryption.org/v1"));
+        assert_eq!(decrypt(encrypted, "correct horse").await.unwrap(), content);
+    }
+
+    #[tokio::test]
+    async fn wrong_passphrase_does_not_decrypt() {
+        let encrypted = served(b"hello", "correct horse").await;
+        assert!(decrypt(encrypted, "battery staple").await.is_err());
+    }
+
+    #[tokio::test]
+    async fn cut_short_file_does_not_decrypt() {
+        let content = vec![7; 100_000];
+        let mut encrypted = served(&content, "correct horse").await;
+        encrypted.truncate(encrypted.len() - 100);
+        assert!(decrypt(encrypted, "correct horse").await.is_err());
+    }
+
+    #[tokio::test]
+    async fn encryptions_over_the_limit_are_refused() {
+        let file = || tokio::fs::File::from(tempfile::tempfile().unwrap());
+        let encryptions = Arc::new(Semaphore::new(1));
+
+        let first = encrypted_body(file(), "correct horse".into(), &encryptions, "first").unwrap();
+        assert!(matches!(
+            encrypted_body(file(), "correct horse".into(), &encryptions, "second"),
+            Err(Error::EncryptionBusy(_))
+        ));
+
+        // Once the first one is sent, there is room again
+        axum::body::to_bytes(first, usize::MAX).await.unwrap();
+        while encryptions.available_permits() == 0 {
+            tokio::task::yield_now().await;
+        }
+        assert!(encrypted_body(file(), "correct horse".into(), &encryptions, "third").is_ok());
+    }
+
+    #[tokio::test]
+    async fn passphrase_key_is_kept_across_loads() {
+        let key_dir = tempfile::tempdir().unwrap();
+        let path = key_dir.path().join("passphrase.key");
+
+        let encrypted = PassphraseKey::load(&path)
+            .await
+            .unwrap()
+            .encrypt_passphrase("correct horse")
+            .unwrap();
+        assert!(!encrypted.contains("correct horse"));
+
+        let key = PassphraseKey::load(&path).await.unwrap();
+        assert_eq!(key.decrypt_passphrase(&encrypted).unwrap(), "correct horse");
+    }
+}
@@ -0,0 +1,411 @@
+/*
+  Filey - simple peer-to-peer file sending across devices on different platforms
+  Copyright (C) 2024 Wander Watterson
+
+  This program is free software: you can redistribute it and/or modify
+  it under the terms of the GNU General Public License as published by
+  the Free Software Foundation, either version 3 of the License, or
+  (at your option) any later version.
+
+  This program is distributed in the hope that it will be useful,
+  but WITHOUT ANY WARRANTY; without even the implied warranty of
+  MERCHANTABILITY or FITNESS FOR A PARTICULAR PURPOSE. See the
+  GNU General Public License for more details.
+
+  You should have received a copy of the GNU General Public License
+  along with this program. If not, see <https://www.gnu.org/licenses/>.
+*/
+
+use axum::{
+    body::{Body, BodyDataStream, Bytes},
+    http::{header::CONTENT_RANGE, Method, StatusCode},
+    response::Response,
+};
+use log::{error, info};
+use sqlx::SqlitePool;
+use std::{
+    future::Future,
+    pin::Pin,
+    task::{ready, Context, Poll},
+    time::Duration,
+};
+use tokio::sync::broadcast;
+use tokio_stream::Stream;
+use uuid::{fmt::Hyphenated, Uuid};
+
+use super::models::{
+    ExpiryAction, FileEvent, FileResponse, ItemKind, Visibility, EXPIRY_CHECK_INTERVAL,
+};
+use crate::{db::now, drop_folders::commands::remember_unshared, error::Error};
+
+/*
+ * Shared files can be shared until a certain time, or for a number of downloads
+ * The routes stop serving them as soon as a limit is reached, this task then does what
+ * their on_expiry says (turn them private, or remove them), so they do not stay shared forever
+ * in the list of the app either
+ */
+pub fn spawn_share_expiry(db: SqlitePool, file_events: broadcast::Sender<FileEvent>) {
+    crate::runtime::spawn(async move {
+        let mut interval = tokio::time::interval(Duration::from_secs(EXPIRY_CHECK_INTERVAL));
+        loop {
+            interval.tick().await;
+            if let Err(err) = expire_shares(&db, &file_events).await {
+                error!("Cannot expire shares: {err}");
+            }
+        }
+    });
+}
+
+pub async fn expire_shares(
+    db: &SqlitePool,
+    file_events: &broadcast::Sender<FileEvent>,
+) -> Result<(), Error> {
+    let now = now();
+    let expired = sqlx::query!(
+        r#"
+            select
+                id as "id!: Hyphenated",
+                name,
+                mime,
+                on_expiry as "on_expiry!: ExpiryAction",
+                passphrase is not null as "encrypted!: bool"
+            from files
+            where
+                (share_until is not null and share_until <= $1)
+            or (max_downloads is not null and download_count >= max_downloads)
+        "#,
+        now
+    )
+    .fetch_all(db)
+    .await?;
+
+    for file in expired {
+        let file_id = file.id.into_uuid();
+        let id = file_id.to_string();
+
+        match file.on_expiry {
+            ExpiryAction::Remove => {
+                remember_unshared(db, file_id).await?;
+                sqlx::query!("delete from files where id = $1", id)
+                    .execute(db)
+                    .await?;
+                file_events.send(FileEvent::Removed { id: file_id }).ok();
+            }
+            ExpiryAction::Private => {
+                // The limits go away too, or the file would expire again as soon as it is shared
+                sqlx::query!(
+                    "
+                        update files set
+                            visibility = 'private',
+                            share_until = null,
+                            max_downloads = null,
+                            download_count = 0
+                        where id = $1
+                    ",
+                    id
+                )
+                .execute(db)
+                .await?;
+                sqlx::query!("delete from file_acl where file_id = $1", id)
+                    .execute(db)
+                    .await?;
+
+                file_events
+                    .send(FileEvent::VisibilityChanged {
+                        file: FileResponse {
+                            id: file_id,
+                            name: file.name.clone(),
+                            mime: file.mime,
+                            kind: ItemKind::File,
+                            encrypted: file.encrypted,
+                        },
+                        visibility: Visibility::Private,
+                    })
+                    .ok();
+            }
+        }
+        info!(
+            "Share of {} expired, it is now {}",
+            file.name, file.on_expiry
+        );
+    }
+
+    Ok(())
+}
+
+/*
+ * Counts a download of a shared file against its max_downloads, once all of it has been sent
+ * A file that reached its limit is not sent anymore, the peer gets a 410
+ * `limited` is whether the file had a max_downloads when the request looked it up, an expired
+ * file can lose it (see ExpiryAction::Private) and would otherwise be served once more
+ *
+ * A download is a response that goes on to the last byte of the file: the whole file, or a range
+ * that ends there (a resumed download). It counts once that last byte is out, so a transfer
+ * that gets cut short does not use up a download. HEAD requests, and the ranges that stop before
+ * the end (seeking in a video), are not counted
+ * The count only goes up while the limit is not reached, in a single statement, so two downloads
+ * that finish at the same time cannot both count as the last one
+ */
+pub async fn count_download(
+    db: &SqlitePool,
+    file_events: &broadcast::Sender<FileEvent>,
+    id: Uuid,
+    limited: bool,
+    method: &Method,
+    response: Response,
+) -> Result<Response, Error> {
+    if method == Method::HEAD || !reaches_last_byte(&response) {
+        return Ok(response);
+    }
+
+    let file_id = id.to_string();
+    sqlx::query!(
+        "
+            select id
+            from files
+            where
+                id = $1
+            and ($2 = false or download_count < max_downloads)
+        ",
+        file_id,
+        limited
+    )
+    .fetch_optional(db)
+    .await?
+    .ok_or_else(|| Error::DownloadLimitReached(file_id.clone()))?;
+
+    let db = db.clone();
+    let file_events = file_events.clone();
+    let count = async move {
+        if let Err(err) = add_download(&db, &file_events, &file_id, limited).await {
+            error!("Cannot count the download of {file_id}: {err}");
+        }
+    };
+
+    let (parts, body) = response.into_parts();
+    let body = CountedBody {
+        stream: body.into_data_stream(),
+        ended: false,
+        count: Some(Box::pin(count)),
+    };
+    Ok(Response::from_parts(parts, Body::from_stream(body)))
+}
+
+async fn add_download(
+    db: &SqlitePool,
+    file_events: &broadcast::Sender<FileEvent>,
+    file_id: &str,
+    limited: bool,
+) -> Result<(), Error> {
+    sqlx::query!(
+        "
+            update files set
+                download_count = download_count + 1
+            where
+                id = $1
+            and ($2 = false or download_count < max_downloads)
+        ",
+        file_id,
+        limited
+    )
+    .execute(db)
+    .await?;
+
+    // A one-shot file expires right away, instead of on the next check
+    expire_shares(db, file_events).await
+}
+
+// Whether the response holds the whole file, or a range that goes on to its last byte
+fn reaches_last_byte(response: &Response) -> bool {
+    match response.status() {
+        StatusCode::OK => true,
+        // Content-Range: bytes <start>-<end>/<size>
+        StatusCode::PARTIAL_CONTENT => response
+            .headers()
+            .get(CONTENT_RANGE)
+            .and_then(|value| value.to_str().ok())
+            .and_then(|value| value.strip_prefix("bytes "))
+            .and_then(|value| value.split_once('-'))
+            .and_then(|(_, value)| value.split_once('/'))
+            .and_then(|(end, size)| Some((end.parse::<u64>().ok()?, size.parse::<u64>().ok()?)))
+            .is_some_and(|(end, size)| end + 1 == size),
+        _ => false,
+    }
+}
+
+// A response body that runs the count once all of it has been sent, and only then ends
+struct CountedBody {
+    stream: BodyDataStream,
+    ended: bool,
+    count: Option<Pin<Box<dyn Future<Output = ()> + Send>>>,
+}
+
+impl Stream for CountedBody {
+    type Item = Result<Bytes, axum::Error>;
+
+    fn poll_next(self: Pin<&mut Self>, cx: &mut Context<'_>) -> Poll<Option<Self::Item>> {
+        let this = self.get_mut();
+        if !this.ended {
+            match ready!(Pin::new(&mut this.stream).poll_next(cx)) {
+                // An error ends the body too, without counting
+                Some(Err(err)) => {
+                    this.count = None;
+                    this.ended = true;
+                    return Poll::Ready(Some(Err(err)));
+                }
+                Some(chunk) => return Poll::Ready(Some(chunk)),
+                None => this.ended = true,
+            }
+        }
+        if let Some(count) = this.count.as_mut() {
+            ready!(count.as_mut().poll(cx));
+            this.count = None;
+        }
+        Poll::Ready(None)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::{db::test_db, error::ErrorCode};
+
+    // A public file that can be downloaded twice
+    async fn insert_file(db: &SqlitePool, id: Uuid, share_until: Option<i64>, on_expiry: &str) {
+        sqlx::query(
+            "
+                insert into files
+                    (id, name, mime, visibility, path, max_downloads, share_until, on_expiry)
+                values
+                    ($1, 'hello.txt', 'text/plain', 'public', 'hello.txt', 2, $2, $3)
+            ",
+        )
+        .bind(id.to_string())
+        .bind(share_until)
+        .bind(on_expiry)
+        .execute(db)
+        .await
+        .unwrap();
+    }
+
+    async fn download_count(db: &SqlitePool, id: Uuid) -> i64 {
+        sqlx::query_scalar("select download_count from files where id = $1")
+            .bind(id.to_string())
+            .fetch_one(db)
+            .await
+            .unwrap()
+    }
+
+    fn ranged_response(content_range: &str) -> Response {
+        Response::builder()
+            .status(StatusCode::PARTIAL_CONTENT)
+            .header(CONTENT_RANGE, content_range)
+            .body(Body::from("hello"))
+            .unwrap()
+    }
+
+    // Sends the whole response body, as the server does
+    async fn download(
+        db: &SqlitePool,
+        file_events: &broadcast::Sender<FileEvent>,
+        id: Uuid,
+        method: &Method,
+        response: Response,
+    ) -> Result<(), Error> {
+        let response = count_download(db, file_events, id, true, method, response).await?;
+        axum::body::to_bytes(response.into_body(), usize::MAX)
+            .await
+            .unwrap();
+        Ok(())
+    }
+
+    #[tokio::test]
+    async fn count_download_stops_at_the_limit() {
+        let db = test_db().await;
+        let (file_events, _) = broadcast::channel(16);
+        let id = Uuid::new_v4();
+        insert_file(&db, id, None, "remove").await;
+
+        for _ in 0..2 {
+            let response = Response::new(Body::from("hello"));
+            download(&db, &file_events, id, &Method::GET, response)
+                .await
+                .unwrap();
+        }
+        // The last download removed the file (on_expiry = remove), the next one is turned down
+        let response = Response::new(Body::from("hello"));
+        let err = download(&db, &file_events, id, &Method::GET, response)
+            .await
+            .unwrap_err();
+        assert_eq!(err.code(), ErrorCode::Gone);
+    }
+
+    #[tokio::test]
+    async fn count_download_only_counts_ranges_to_the_end() {
+        let db = test_db().await;
+        let (file_events, _) = broadcast::channel(16);
+        let id = Uuid::new_v4();
+        insert_file(&db, id, None, "remove").await;
+
+        let response = ranged_response("bytes 0-99/200");
+        download(&db, &file_events, id, &Method::GET, response)
+            .await
+            .unwrap();
+        assert_eq!(download_count(&db, id).await, 0);
+
+        // A resumed download
+        let response = ranged_response("bytes 100-199/200");
+        download(&db, &file_events, id, &Method::GET, response)
+            .await
+            .unwrap();
+        assert_eq!(download_count(&db, id).await, 1);
+    }
+
+    #[tokio::test]
+    async fn aborted_download_is_not_counted() {
+        let db = test_db().await;
+        let (file_events, _) = broadcast::channel(16);
+        let id = Uuid::new_v4();
+        insert_file(&db, id, None, "remove").await;
+
+        let response = Response::new(Body::from("hello"));
+        let response = count_download(&db, &file_events, id, true, &Method::GET, response)
+            .await
+            .unwrap();
+        // The peer went away before the body was sent
+        drop(response);
+        assert_eq!(download_count(&db, id).await, 0);
+    }
+
+    #[tokio::test]
+    async fn head_request_is_not_counted() {
+        let db = test_db().await;
+        let (file_events, _) = broadcast::channel(16);
+        let id = Uuid::new_v4();
+        insert_file(&db, id, None, "remove").await;
+
+        let response = Response::new(Body::from("hello"));
+        download(&db, &file_events, id, &Method::HEAD, response)
+            .await
+            .unwrap();
+        assert_eq!(download_count(&db, id).await, 0);
+    }
+
+    #[tokio::test]
+    async fn expired_private_file_is_not_served_once_more() {
+        let db = test_db().await;
+        let (file_events, _) = broadcast::channel(16);
+        let id = Uuid::new_v4();
+        insert_file(&db, id, Some(now() - 1), "private").await;
+
+        // Turned private by the expiry, which also lifts its limits
+        expire_shares(&db, &file_events).await.unwrap();
+
+        // A request that looked the file up before that still holds it as limited
+        let response = Response::new(Body::from("hello"));
+        let err = download(&db, &file_events, id, &Method::GET, response)
+            .await
+            .unwrap_err();
+        assert_eq!(err.code(), ErrorCode::Gone);
+    }
+}
@@ -16,5 +16,11 @@
   along with this program. If not, see <https://www.gnu.org/licenses/>.
 */
 
+pub mod access;
 pub mod commands;
+pub mod delta;
+pub mod encryption;
+pub mod expiry;
 pub mod models;
+#[cfg(all(feature = "gui", not(any(target_os = "android", target_os = "ios"))))]
+pub mod watcher;
@@ -22,12 +22,14 @@ use std::str::FromStr;
 use strum::{Display, EnumString};
 use uuid::Uuid;
 
-#[derive(Serialize, Deserialize, Debug, Encode, Display, EnumString)]
+#[derive(Serialize, Deserialize, Debug, Clone, PartialEq, Encode, Display, EnumString)]
 #[serde(rename_all = "lowercase")]
 #[strum(serialize_all = "lowercase")]
 pub enum Visibility {
     Public,
     Private,
+    // Served to anyone who has its id (a link, a QR code), but left out of the shared list
+    Unlisted,
 }
 
 impl Type<Sqlite> for Visibility {
@@ -57,21 +59,232 @@ impl From<String> for Visibility {
     }
 }
 
-// File model in the database
+/*
+ * The kind of item that a peer sees in the shared list
+ * File => backed by a file on disk (or a content URI on mobile)
+ * Snippet => a piece of text stored directly in the database, no path involved
+ */
+#[derive(Serialize, Deserialize, Debug, Clone, Encode, Display, EnumString)]
+#[serde(rename_all = "lowercase")]
+#[strum(serialize_all = "lowercase")]
+pub enum ItemKind {
+    File,
+    Snippet,
+}
+
+impl Type<Sqlite> for ItemKind {
+    fn type_info() -> SqliteTypeInfo {
+        <&str as Type<Sqlite>>::type_info()
+    }
+    fn compatible(ty: &SqliteTypeInfo) -> bool {
+        <&str as Type<Sqlite>>::compatible(ty)
+    }
+}
+
+impl<'r, DB: Database> Decode<'r, DB> for ItemKind
+where
+    &'r str: Decode<'r, DB>,
+{
+    fn decode(
+        value: <DB as sqlx::Database>::ValueRef<'r>,
+    ) -> Result<Self, sqlx::error::BoxDynError> {
+        let value = <&str as Decode<DB>>::decode(value)?;
+        Ok(ItemKind::from_str(value).unwrap())
+    }
+}
+
+/*
+ * What happens to a shared file once its share_until passes, or its max_downloads is reached
+ * Private => the file stays in the list, but stops being shared with anyone
+ * Remove => the file is removed from the list altogether
+ */
+#[derive(Serialize, Deserialize, Debug, Clone, Default, PartialEq, Encode, Display, EnumString)]
+#[serde(rename_all = "lowercase")]
+#[strum(serialize_all = "lowercase")]
+pub enum ExpiryAction {
+    #[default]
+    Private,
+    Remove,
+}
+
+impl Type<Sqlite> for ExpiryAction {
+    fn type_info() -> SqliteTypeInfo {
+        <&str as Type<Sqlite>>::type_info()
+    }
+    fn compatible(ty: &SqliteTypeInfo) -> bool {
+        <&str as Type<Sqlite>>::compatible(ty)
+    }
+}
+
+impl<'r, DB: Database> Decode<'r, DB> for ExpiryAction
+where
+    &'r str: Decode<'r, DB>,
+{
+    fn decode(
+        value: <DB as sqlx::Database>::ValueRef<'r>,
+    ) -> Result<Self, sqlx::error::BoxDynError> {
+        let value = <&str as Decode<DB>>::decode(value)?;
+        Ok(ExpiryAction::from_str(value).unwrap())
+    }
+}
+
+// How often the expired shares are looked for, in seconds, see files::expiry
+pub const EXPIRY_CHECK_INTERVAL: u64 = 30;
+
+// How many encrypted bytes can wait to be sent before the encryption waits for the peer
+pub const ENCRYPTION_BUFFER_SIZE: usize = 64 * 1024;
+
+// How many files can be encrypted for the peers at once, each one starts with a scrypt run
+pub const MAX_ENCRYPTED_DOWNLOADS: usize = 2;
+
+/*
+ * File model in the database
+ * missing, size and modified_at are kept up to date by the file watcher,
+ * the UI does not know them when it adds new files, so they are optional on the way in
+ */
 #[derive(Debug, Type, Serialize, Deserialize)]
+#[serde(rename_all = "camelCase")]
 pub struct FileModel {
     pub id: Uuid,
     pub name: String,
     pub mime: String,
     pub visibility: Visibility,
     pub path: String,
+    #[serde(default)]
+    pub missing: bool,
+    #[serde(default)]
+    pub size: Option<i64>,
+    #[serde(default)]
+    pub modified_at: Option<i64>,
+    /*
+     * The share limits, set with set_share_limits, upsert_files leaves them alone
+     * share_until => unix seconds after which the file stops being served
+     * max_downloads => downloads after which the file stops being served
+     */
+    #[serde(default)]
+    pub share_until: Option<i64>,
+    #[serde(default)]
+    pub max_downloads: Option<i64>,
+    #[serde(default)]
+    pub download_count: i64,
+    #[serde(default)]
+    pub on_expiry: ExpiryAction,
+    // Whether it is served encrypted with a passphrase, see files::encryption
+    #[serde(default)]
+    pub encrypted: bool,
+}
+
+/*
+ * Who a file is shared with, on top of its visibility
+ * A public file is shared with everyone anyway, the list matters for private files:
+ * only the listed known peers, and the members of the listed groups, can see and download them
+ */
+#[derive(Debug, Serialize, Deserialize)]
+#[serde(rename_all = "camelCase")]
+pub struct FileAcl {
+    pub file_id: Uuid,
+    pub device_ids: Vec<Uuid>,
+    pub group_ids: Vec<Uuid>,
+}
+
+// Text snippet model in the database, a title and a body, without any file behind it
+#[derive(Debug, Serialize, Deserialize)]
+pub struct SnippetModel {
+    pub id: Uuid,
+    pub title: String,
+    pub body: String,
+    pub visibility: Visibility,
 }
 
 // Response model for the http server to use
 // The mime and path are stripped off for privacy
-#[derive(Debug, Serialize, Deserialize)]
+// Snippets are listed here as well, with their title as the name and text/plain as the mime
+#[derive(Debug, Clone, Serialize, Deserialize)]
 pub struct FileResponse {
     pub id: Uuid,
     pub name: String,
     pub mime: String,
+    pub kind: ItemKind,
+    // Encrypted files are served in the age format, and need the passphrase to be read
+    #[serde(default)]
+    pub encrypted: bool,
+}
+
+/*
+ * Changes to the shared list, broadcasted whenever files or snippets are added, removed,
+ * or have their visibility changed, and pushed to peers through the /events route
+ * Peers are only told about what they can see, see http_server::routes::events
+ */
+#[derive(Debug, Clone, Serialize, Deserialize)]
+#[serde(tag = "type", rename_all = "camelCase")]
+pub enum FileEvent {
+    Added {
+        file: FileResponse,
+        visibility: Visibility,
+    },
+    Removed {
+        id: Uuid,
+    },
+    VisibilityChanged {
+        file: FileResponse,
+        visibility: Visibility,
+    },
+    // The known peers and groups the file is shared with changed, see FileAcl
+    AccessChanged {
+        file: FileResponse,
+        visibility: Visibility,
+    },
+}
+
+/*
+ * Changes that the file watcher noticed on the shared files, emitted as "file-status" tauri events
+ * Missing => the file is gone from its path
+ * Restored => a missing file showed up again on its path
+ * Modified => the file content changed, the size and modified time are updated
+ * Relocated => the file was renamed inside the same directory, the row now points to the new path
+ */
+#[derive(Debug, Clone, Serialize, Deserialize)]
+#[serde(
+    tag = "type",
+    rename_all = "camelCase",
+    rename_all_fields = "camelCase"
+)]
+pub enum FileStatusEvent {
+    Missing {
+        id: Uuid,
+    },
+    Restored {
+        id: Uuid,
+    },
+    Modified {
+        id: Uuid,
+        size: i64,
+        modified_at: Option<i64>,
+    },
+    Relocated {
+        id: Uuid,
+        name: String,
+        path: String,
+    },
+}
+
+// Signature of one block of a shared file, see files::delta
+#[derive(Debug, Clone, Serialize, Deserialize)]
+pub struct BlockSignature {
+    pub weak: u32,
+    pub strong: String,
+}
+
+/*
+ * Block signatures of a shared file, so a peer with an older copy of it only has to download
+ * the blocks it does not have yet. The hash is the SHA-256 of the whole file (hex),
+ * to check the rebuilt file against
+ */
+#[derive(Debug, Clone, Serialize, Deserialize)]
+#[serde(rename_all = "camelCase")]
+pub struct FileSignatures {
+    pub size: u64,
+    pub block_size: u64,
+    pub hash: String,
+    pub blocks: Vec<BlockSignature>,
 }
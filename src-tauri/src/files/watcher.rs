@@ -0,0 +1,377 @@
+/*
+  Filey - simple peer-to-peer file sending across devices on different platforms
+  Copyright (C) 2024 Wander Watterson
+
+  This program is free software: you can redistribute it and/or modify
+  it under the terms of the GNU General Public License as published by
+  the Free Software Foundation, either version 3 of the License, or
+  (at your option) any later version.
+
+  This program is distributed in the hope that it will be useful,
+  but WITHOUT ANY WARRANTY; without even the implied warranty of
+  MERCHANTABILITY or FITNESS FOR A PARTICULAR PURPOSE. See the
+  GNU General Public License for more details.
+
+  You should have received a copy of the GNU General Public License
+  along with this program. If not, see <https://www.gnu.org/licenses/>.
+*/
+
+use log::error;
+use notify::{
+    event::{ModifyKind, RenameMode},
+    recommended_watcher, Event, EventKind, RecommendedWatcher, RecursiveMode, Watcher,
+};
+use std::{
+    collections::HashSet,
+    fs::Metadata,
+    path::{Path, PathBuf},
+    time::UNIX_EPOCH,
+};
+use tauri::{AppHandle, Emitter, Manager};
+use tokio::sync::{broadcast::error::RecvError, mpsc};
+use uuid::{fmt::Hyphenated, Uuid};
+
+use super::models::{FileEvent, FileStatusEvent};
+use crate::{error::Error, AppState};
+
+/*
+ * Starts the background watcher over every shared file
+ *
+ * Rows in the files table point to paths, and those paths can be moved, modified or deleted
+ * behind our back. The watcher keeps an eye on the parent directory of every shared file,
+ * marks rows missing when their file is gone, updates size and modified time on changes,
+ * and follows files that got renamed inside the same directory.
+ *
+ * Only desktop platforms have real paths to watch, mobile platforms hand out content URIs
+ */
+pub fn spawn_file_watcher(app_handle: AppHandle) {
+    crate::runtime::spawn(async move {
+        if let Err(err) = watch_files(&app_handle).await {
+            error!("File watcher stopped: {err}");
+        }
+    });
+}
+
+async fn watch_files(app_handle: &AppHandle) -> Result<(), Error> {
+    let state = app_handle.state::<AppState>();
+
+    // notify calls us back from its own thread, forward the events into the async world
+    let (event_sender, mut event_receiver) = mpsc::unbounded_channel();
+    let mut watcher = recommended_watcher(move |event: notify::Result<Event>| {
+        event_sender.send(event).ok();
+    })?;
+
+    // Files being added or removed changes the set of directories to watch
+    let mut file_events = state.file_events.subscribe();
+
+    /*
+     * Only setting up the watcher can stop it, anything that goes wrong with a single path
+     * (a deleted directory, a permission error...) is logged, and the watcher keeps going
+     */
+    let mut watched_dirs = HashSet::new();
+    if let Err(err) = refresh_watched_dirs(app_handle, &mut watcher, &mut watched_dirs).await {
+        error!("{err}");
+    }
+
+    loop {
+        tokio::select! {
+            Some(event) = event_receiver.recv() => match event {
+                Ok(event) => {
+                    if let Err(err) = handle_event(app_handle, event).await {
+                        error!("{err}");
+                    }
+                }
+                Err(err) => error!("{err}"),
+            },
+            file_event = file_events.recv() => match file_event {
+                Ok(FileEvent::Added { .. } | FileEvent::Removed { .. })
+                | Err(RecvError::Lagged(_)) => {
+                    if let Err(err) =
+                        refresh_watched_dirs(app_handle, &mut watcher, &mut watched_dirs).await
+                    {
+                        error!("{err}");
+                    }
+                }
+                Ok(FileEvent::VisibilityChanged { .. } | FileEvent::AccessChanged { .. }) => {}
+                Err(RecvError::Closed) => return Ok(()),
+            },
+        }
+    }
+}
+
+/*
+ * Watches the parent directories of all shared files, and stops watching the ones
+ * that no longer have any shared file in them
+ * Watching the directory instead of the file itself is what lets us see renames
+ *
+ * Every file is checked once more afterwards, to catch up with whatever happened
+ * while nobody was watching (the app was closed, or the file was just added)
+ */
+async fn refresh_watched_dirs(
+    app_handle: &AppHandle,
+    watcher: &mut RecommendedWatcher,
+    watched_dirs: &mut HashSet<PathBuf>,
+) -> Result<(), Error> {
+    let state = app_handle.state::<AppState>();
+
+    // Content URIs are not absolute paths, those are skipped
+    let paths: Vec<PathBuf> = sqlx::query!("select path from files")
+        .fetch_all(&state.db)
+        .await?
+        .into_iter()
+        .map(|row| PathBuf::from(row.path))
+        .filter(|path| path.is_absolute())
+        .collect();
+
+    let dirs: HashSet<PathBuf> = paths
+        .iter()
+        .filter_map(|path| path.parent().map(Path::to_path_buf))
+        .collect();
+
+    for dir in watched_dirs.difference(&dirs) {
+        watcher.unwatch(dir).ok();
+    }
+    for dir in dirs.difference(watched_dirs) {
+        if let Err(err) = watcher.watch(dir, RecursiveMode::NonRecursive) {
+            error!("Cannot watch {}: {err}", dir.display());
+        }
+    }
+    *watched_dirs = dirs;
+
+    for path in paths {
+        check_path_logged(app_handle, &path).await;
+    }
+
+    Ok(())
+}
+
+async fn handle_event(app_handle: &AppHandle, event: Event) -> Result<(), Error> {
+    match (event.kind, event.paths.as_slice()) {
+        /*
+         * Some platforms report both sides of a rename at once,
+         * in that case the row can be moved to the new path straight away
+         */
+        (EventKind::Modify(ModifyKind::Name(RenameMode::Both)), [from, to]) => {
+            let state = app_handle.state::<AppState>();
+            let from_path = from.display().to_string();
+            match sqlx::query!(
+                r#"select id as "id!: Hyphenated" from files where path = $1"#,
+                from_path
+            )
+            .fetch_optional(&state.db)
+            .await?
+            {
+                Some(row) if !is_shared(app_handle, to).await? => {
+                    relocate(app_handle, row.id.into_uuid(), to).await
+                }
+                _ => {
+                    check_path_logged(app_handle, from).await;
+                    check_path_logged(app_handle, to).await;
+                    Ok(())
+                }
+            }
+        }
+        // Reading a file changes nothing
+        (EventKind::Access(_), _) => Ok(()),
+        /*
+         * Everything else (created, removed, modified, half of a rename)
+         * is handled by looking at what is on the disk right now
+         */
+        (_, paths) => {
+            for path in paths {
+                check_path_logged(app_handle, path).await;
+            }
+            Ok(())
+        }
+    }
+}
+
+// One path that cannot be checked should not keep the others from being checked
+async fn check_path_logged(app_handle: &AppHandle, path: &Path) {
+    if let Err(err) = check_path(app_handle, path).await {
+        error!("Cannot check {}: {err}", path.display());
+    }
+}
+
+/*
+ * Compares what the database knows about a path with what is actually on the disk
+ *
+ * Shared, but gone from the disk => missing
+ * Shared, and still on the disk => update the size and modified time if they changed
+ * Not shared, but on the disk => might be a shared file that just got renamed, try to relocate it
+ */
+async fn check_path(app_handle: &AppHandle, path: &Path) -> Result<(), Error> {
+    let state = app_handle.state::<AppState>();
+
+    let path_text = path.display().to_string();
+    let metadata = tokio::fs::metadata(path)
+        .await
+        .ok()
+        .filter(|metadata| metadata.is_file());
+
+    let row = sqlx::query!(
+        r#"
+            select
+                id as "id!: Hyphenated",
+                missing,
+                size,
+                modified_at
+            from files
+            where path = $1
+        "#,
+        path_text
+    )
+    .fetch_optional(&state.db)
+    .await?;
+
+    match (row, metadata) {
+        (Some(row), None) => {
+            if !row.missing {
+                let id = row.id.to_string();
+                sqlx::query!(
+                    "update files set missing = true where id = $1 returning id",
+                    id
+                )
+                .fetch_optional(&state.db)
+                .await?;
+
+                app_handle.emit(
+                    "file-status",
+                    FileStatusEvent::Missing {
+                        id: row.id.into_uuid(),
+                    },
+                )?;
+            }
+        }
+        (Some(row), Some(metadata)) => {
+            let (size, modified_at) = file_stats(&metadata);
+            if row.missing || row.size != Some(size) || row.modified_at != modified_at {
+                let id = row.id.to_string();
+                sqlx::query!(
+                    "
+                        update files set
+                            missing = false,
+                            size = $1,
+                            modified_at = $2
+                        where id = $3
+                        returning id
+                    ",
+                    size,
+                    modified_at,
+                    id
+                )
+                .fetch_optional(&state.db)
+                .await?;
+
+                let id = row.id.into_uuid();
+                app_handle.emit(
+                    "file-status",
+                    match row.missing {
+                        true => FileStatusEvent::Restored { id },
+                        false => FileStatusEvent::Modified {
+                            id,
+                            size,
+                            modified_at,
+                        },
+                    },
+                )?;
+            }
+        }
+        (None, Some(metadata)) => {
+            /*
+             * A rename keeps the size and the modified time,
+             * so look for a missing file in the same directory that has both of them
+             */
+            let (size, modified_at) = file_stats(&metadata);
+            let candidates: Vec<Uuid> = sqlx::query!(
+                r#"
+                    select
+                        id as "id!: Hyphenated",
+                        path
+                    from files
+                    where
+                        missing = true
+                    and size = $1
+                    and modified_at = $2
+                "#,
+                size,
+                modified_at
+            )
+            .fetch_all(&state.db)
+            .await?
+            .into_iter()
+            .filter(|row| Path::new(&row.path).parent() == path.parent())
+            .map(|row| row.id.into_uuid())
+            .collect();
+
+            // Only relocate when there is no doubt about which file got renamed
+            if let [id] = candidates.as_slice() {
+                relocate(app_handle, *id, path).await?;
+            }
+        }
+        (None, None) => {}
+    }
+
+    Ok(())
+}
+
+// Points the row to the new path, and takes the new file name along with it
+async fn relocate(app_handle: &AppHandle, id: Uuid, path: &Path) -> Result<(), Error> {
+    let state = app_handle.state::<AppState>();
+
+    let file_id = id.to_string();
+    let path_text = path.display().to_string();
+    let name = path
+        .file_name()
+        .map(|name| name.to_string_lossy().to_string())
+        .unwrap_or_else(|| path_text.clone());
+
+    sqlx::query!(
+        "
+            update files set
+                path = $1,
+                name = $2,
+                missing = false
+            where id = $3
+            returning id
+        ",
+        path_text,
+        name,
+        file_id
+    )
+    .fetch_optional(&state.db)
+    .await?;
+
+    app_handle.emit(
+        "file-status",
+        FileStatusEvent::Relocated {
+            id,
+            name,
+            path: path_text,
+        },
+    )?;
+
+    Ok(())
+}
+
+// Whether a path is already used by a shared file
+async fn is_shared(app_handle: &AppHandle, path: &Path) -> Result<bool, Error> {
+    let state = app_handle.state::<AppState>();
+    let path_text = path.display().to_string();
+    Ok(
+        sqlx::query!("select id from files where path = $1", path_text)
+            .fetch_optional(&state.db)
+            .await?
+            .is_some(),
+    )
+}
+
+// Size in bytes and modified time in unix seconds, as stored in the files table
+fn file_stats(metadata: &Metadata) -> (i64, Option<i64>) {
+    let modified_at = metadata
+        .modified()
+        .ok()
+        .and_then(|modified| modified.duration_since(UNIX_EPOCH).ok())
+        .map(|duration| duration.as_secs() as i64);
+    (metadata.len() as i64, modified_at)
+}
@@ -0,0 +1,330 @@
+/*
+  Filey - simple peer-to-peer file sending across devices on different platforms
+  Copyright (C) 2024 Wander Watterson
+
+  This program is free software: you can redistribute it and/or modify
+  it under the terms of the GNU General Public License as published by
+  the Free Software Foundation, either version 3 of the License, or
+  (at your option) any later version.
+
+  This program is distributed in the hope that it will be useful,
+  but WITHOUT ANY WARRANTY; without even the implied warranty of
+  MERCHANTABILITY or FITNESS FOR A PARTICULAR PURPOSE. See the
+  GNU General Public License for more details.
+
+  You should have received a copy of the GNU General Public License
+  along with this program. If not, see <https://www.gnu.org/licenses/>.
+*/
+
+use log::error;
+use sqlx::SqlitePool;
+use std::path::Path;
+use tauri::{AppHandle, State};
+use uuid::{fmt::Hyphenated, Uuid};
+
+use super::{
+    engine::{load_sync_pairs, sync_pair},
+    models::{
+        SharedFolderModel, SharedFolderPeers, SharedFolderResponse, SyncConflictModel,
+        SyncPairModel, SyncReport,
+    },
+};
+use crate::{
+    device::commands::signed_headers,
+    error::Error,
+    http_server::{commands::require_capability, models::Capability},
+    noise::client::get_from_peer,
+    AppState, ServerResponse,
+};
+
+// Returns the local folders that peers can sync with
+#[tauri::command]
+pub async fn get_shared_folders(
+    state: State<'_, AppState>,
+) -> Result<Vec<SharedFolderModel>, Error> {
+    let folders = sqlx::query_as!(
+        SharedFolderModel,
+        r#"
+            select
+                id as "id!: Hyphenated",
+                path,
+                name,
+                writable
+            from shared_folders
+        "#
+    )
+    .fetch_all(&state.db)
+    .await?;
+
+    Ok(folders)
+}
+
+/*
+ * Lets the given known peers sync with a local folder
+ * Writable folders also take the changes made by the peers, the others can only be read
+ * Sharing a folder again updates it, the peers given replace the ones it had
+ */
+#[tauri::command]
+pub async fn share_folder(
+    state: State<'_, AppState>,
+    path: String,
+    writable: bool,
+    device_ids: Vec<Uuid>,
+) -> Result<SharedFolderModel, Error> {
+    if !tokio::fs::metadata(&path).await?.is_dir() {
+        return Err(Error::NotADirectory(path));
+    }
+
+    let mut folder = SharedFolderModel {
+        id: Uuid::new_v4(),
+        name: Path::new(&path)
+            .file_name()
+            .map(|name| name.to_string_lossy().to_string())
+            .unwrap_or_else(|| path.clone()),
+        path,
+        writable,
+    };
+
+    let id = folder.id.to_string();
+    let row = sqlx::query!(
+        r#"
+            insert into shared_folders
+                (id, path, name, writable)
+            values
+                ($1, $2, $3, $4)
+            on conflict (path)
+            do update set writable = excluded.writable
+            returning id as "id!: Hyphenated"
+        "#,
+        id,
+        folder.path,
+        folder.name,
+        folder.writable
+    )
+    .fetch_one(&state.db)
+    .await?;
+    folder.id = row.id.into_uuid();
+
+    save_folder_peers(&state.db, folder.id, &device_ids).await?;
+    Ok(folder)
+}
+
+// Returns the known peers a shared folder is paired with
+#[tauri::command]
+pub async fn get_shared_folder_peers(
+    state: State<'_, AppState>,
+    folder_id: Uuid,
+) -> Result<SharedFolderPeers, Error> {
+    load_folder_peers(&state.db, folder_id).await
+}
+
+// Replaces the known peers a shared folder is paired with, nobody else sees it from then on
+#[tauri::command]
+pub async fn set_shared_folder_peers(
+    state: State<'_, AppState>,
+    peers: SharedFolderPeers,
+) -> Result<SharedFolderPeers, Error> {
+    // Makes sure the folder exists, instead of failing on the foreign key
+    let id = peers.folder_id.to_string();
+    sqlx::query!("select id from shared_folders where id = $1", id)
+        .fetch_one(&state.db)
+        .await?;
+
+    save_folder_peers(&state.db, peers.folder_id, &peers.device_ids).await?;
+    load_folder_peers(&state.db, peers.folder_id).await
+}
+
+async fn save_folder_peers(
+    db: &SqlitePool,
+    folder_id: Uuid,
+    device_ids: &[Uuid],
+) -> Result<(), Error> {
+    let folder_id = folder_id.to_string();
+    sqlx::query!(
+        "delete from shared_folder_peers where folder_id = $1",
+        folder_id
+    )
+    .execute(db)
+    .await?;
+
+    for device_id in device_ids {
+        let device_id = device_id.to_string();
+        sqlx::query!(
+            "insert into shared_folder_peers (folder_id, device_id) values ($1, $2)",
+            folder_id,
+            device_id
+        )
+        .execute(db)
+        .await?;
+    }
+    Ok(())
+}
+
+async fn load_folder_peers(db: &SqlitePool, folder_id: Uuid) -> Result<SharedFolderPeers, Error> {
+    let id = folder_id.to_string();
+    let rows = sqlx::query!(
+        r#"
+            select device_id as "device_id!: Hyphenated"
+            from shared_folder_peers
+            where folder_id = $1
+        "#,
+        id
+    )
+    .fetch_all(db)
+    .await?;
+
+    Ok(SharedFolderPeers {
+        folder_id,
+        device_ids: rows
+            .into_iter()
+            .map(|row| row.device_id.into_uuid())
+            .collect(),
+    })
+}
+
+// Stops sharing a folder, the files inside stay where they are
+#[tauri::command]
+pub async fn unshare_folder(state: State<'_, AppState>, id: Uuid) -> Result<(), Error> {
+    let id = id.to_string();
+    sqlx::query!("delete from shared_folders where id = $1 returning id", id)
+        .fetch_one(&state.db)
+        .await?;
+    Ok(())
+}
+
+/*
+ * Gets the list of folders another Filey peer shares for syncing
+ * This is on the requesting side, on the serving side, it will be handled
+ * by a handler in http_server::routes::shared_folders
+ */
+#[tauri::command]
+pub async fn get_shared_folders_from_peer(
+    state: State<'_, AppState>,
+    ip: &str,
+) -> Result<Vec<SharedFolderResponse>, Error> {
+    require_capability(ip, Capability::Folders).await?;
+
+    // Signed, the peer only shows its folders to known peers
+    let headers = signed_headers(&state.db, "GET", "/folders").await?;
+    let response: ServerResponse<Vec<SharedFolderResponse>> =
+        get_from_peer(&state.db, ip, "/folders", headers)
+            .await?
+            .error_for_status()?
+            .json()
+            .await?;
+
+    Ok(response.data)
+}
+
+// Returns every local directory that is being synced with a peer
+#[tauri::command]
+pub async fn get_sync_pairs(state: State<'_, AppState>) -> Result<Vec<SyncPairModel>, Error> {
+    load_sync_pairs(&state.db).await
+}
+
+/*
+ * Pairs a local directory with a shared folder of a peer
+ * The first sync runs right away, after that the folders are synced every now and then
+ */
+#[tauri::command]
+pub async fn add_sync_pair(
+    app_handle: AppHandle,
+    state: State<'_, AppState>,
+    local_path: String,
+    ip: String,
+    folder_id: Uuid,
+) -> Result<SyncPairModel, Error> {
+    if !tokio::fs::metadata(&local_path).await?.is_dir() {
+        return Err(Error::NotADirectory(local_path));
+    }
+    require_capability(&ip, Capability::Folders).await?;
+
+    let pair = SyncPairModel {
+        id: Uuid::new_v4(),
+        local_path,
+        peer: ip,
+        folder_id,
+        last_synced_at: None,
+    };
+
+    let id = pair.id.to_string();
+    let folder_id = pair.folder_id.to_string();
+    sqlx::query!(
+        "
+            insert into sync_pairs
+                (id, local_path, peer, folder_id)
+            values
+                ($1, $2, $3, $4)
+            returning id
+        ",
+        id,
+        pair.local_path,
+        pair.peer,
+        folder_id
+    )
+    .fetch_optional(&state.db)
+    .await?;
+
+    let pair_id = pair.id;
+    crate::runtime::spawn(async move {
+        if let Err(err) = sync_pair(&app_handle, pair_id).await {
+            error!("First sync of pair {pair_id} failed: {err}");
+        }
+    });
+
+    Ok(pair)
+}
+
+// Stops syncing, the files on both sides stay where they are
+#[tauri::command]
+pub async fn remove_sync_pair(state: State<'_, AppState>, id: Uuid) -> Result<(), Error> {
+    let id = id.to_string();
+    sqlx::query!("delete from sync_pairs where id = $1 returning id", id)
+        .fetch_one(&state.db)
+        .await?;
+    Ok(())
+}
+
+// Syncs a pair right away, instead of waiting for the next background sync
+#[tauri::command]
+pub async fn sync_now(app_handle: AppHandle, id: Uuid) -> Result<SyncReport, Error> {
+    sync_pair(&app_handle, id).await
+}
+
+// Returns the conflicts of a sync pair, the most recent first
+#[tauri::command]
+pub async fn get_sync_conflicts(
+    state: State<'_, AppState>,
+    pair_id: Uuid,
+) -> Result<Vec<SyncConflictModel>, Error> {
+    let pair_id = pair_id.to_string();
+    let conflicts = sqlx::query_as!(
+        SyncConflictModel,
+        r#"
+            select
+                id as "id!: Hyphenated",
+                pair_id as "pair_id!: Hyphenated",
+                path,
+                conflict_path,
+                created_at
+            from sync_conflicts
+            where pair_id = $1
+            order by created_at desc
+        "#,
+        pair_id
+    )
+    .fetch_all(&state.db)
+    .await?;
+
+    Ok(conflicts)
+}
+
+// Forgets about a conflict once the user sorted it out, the conflict copy is not touched
+#[tauri::command]
+pub async fn dismiss_sync_conflict(state: State<'_, AppState>, id: Uuid) -> Result<(), Error> {
+    let id = id.to_string();
+    sqlx::query!("delete from sync_conflicts where id = $1 returning id", id)
+        .fetch_one(&state.db)
+        .await?;
+    Ok(())
+}
@@ -0,0 +1,693 @@
+/*
+  Filey - simple peer-to-peer file sending across devices on different platforms
+  Copyright (C) 2024 Wander Watterson
+
+  This program is free software: you can redistribute it and/or modify
+  it under the terms of the GNU General Public License as published by
+  the Free Software Foundation, either version 3 of the License, or
+  (at your option) any later version.
+
+  This program is distributed in the hope that it will be useful,
+  but WITHOUT ANY WARRANTY; without even the implied warranty of
+  MERCHANTABILITY or FITNESS FOR A PARTICULAR PURPOSE. See the
+  GNU General Public License for more details.
+
+  You should have received a copy of the GNU General Public License
+  along with this program. If not, see <https://www.gnu.org/licenses/>.
+*/
+
+use log::error;
+use percent_encoding::{utf8_percent_encode, NON_ALPHANUMERIC};
+use reqwest::{self, header::CONTENT_LENGTH, Method, Response};
+use sqlx::SqlitePool;
+use std::{
+    collections::{BTreeMap, BTreeSet, HashMap},
+    path::{Path, PathBuf},
+    time::UNIX_EPOCH,
+};
+use tauri::{AppHandle, Emitter, Manager};
+use tokio::io::AsyncWriteExt;
+use uuid::{fmt::Hyphenated, Uuid};
+
+use super::{
+    files::{resolve_in_folder, temp_path},
+    models::{ManifestEntry, SyncConflictModel, SyncPairModel, SyncReport, SYNC_INTERVAL},
+};
+use crate::{
+    db::now,
+    device::commands::{signed_body_headers, signed_headers},
+    error::Error,
+    files::access::{platform_file_access, FileAccess, LocalFileAccess},
+    history::commands::hash_file,
+    http_server::{commands::require_capability, models::Capability},
+    noise::client::request_peer,
+    AppState, ServerResponse,
+};
+
+// Syncs every sync pair every now and then, so the folders stay identical without any clicking
+pub fn spawn_sync_scheduler(app_handle: AppHandle) {
+    crate::runtime::spawn(async move {
+        let mut ticker = tokio::time::interval(SYNC_INTERVAL);
+        loop {
+            ticker.tick().await;
+
+            let state = app_handle.state::<AppState>();
+            let db = &state.db;
+            let pairs = match load_sync_pairs(db).await {
+                Ok(pairs) => pairs,
+                Err(err) => {
+                    error!("Cannot load the sync pairs: {err}");
+                    continue;
+                }
+            };
+            for pair in pairs {
+                if let Err(err) = sync_pair(&app_handle, pair.id).await {
+                    error!(
+                        "Cannot sync {} with peer {}: {err}",
+                        pair.local_path, pair.peer
+                    );
+                }
+            }
+        }
+    });
+}
+
+// Everything a sync needs to know about the pair it is syncing
+struct SyncContext<'a> {
+    app_handle: &'a AppHandle,
+    db: &'a SqlitePool,
+    pair: &'a SyncPairModel,
+    root: PathBuf,
+    // Path of the peer's shared folder, "/folders/{id}"
+    path: String,
+}
+
+impl SyncContext<'_> {
+    // A request to the peer's shared folder, signed so that the peer knows it is us
+    async fn request(&self, method: Method, route: &str) -> Result<Response, Error> {
+        let path = format!("{}{route}", self.path);
+        let headers = signed_headers(self.db, method.as_str(), &path).await?;
+        let body = reqwest::Body::from(vec![]);
+        request_peer(self.db, &self.pair.peer, method, &path, headers, body).await
+    }
+
+    // Same as request, sending a body with this SHA-256 (hex), the peer checks the body against it
+    async fn body_request(
+        &self,
+        method: Method,
+        route: &str,
+        body: reqwest::Body,
+        size: u64,
+        body_sha256: &str,
+    ) -> Result<Response, Error> {
+        let path = format!("{}{route}", self.path);
+        let mut headers = signed_body_headers(self.db, method.as_str(), &path, body_sha256).await?;
+        headers.insert(CONTENT_LENGTH, size.into());
+        request_peer(self.db, &self.pair.peer, method, &path, headers, body).await
+    }
+}
+
+// "/file?path=...", encoded here so that the query the peer gets is the one that was signed
+fn file_route(path: &str) -> String {
+    format!("/file?path={}", utf8_percent_encode(path, NON_ALPHANUMERIC))
+}
+
+/*
+ * Syncs a local directory with the shared folder of a peer, in both directions
+ *
+ * Both sides are compared with what they looked like after the last sync (the sync state)
+ * Only local changed => the change goes to the peer
+ * Only the peer changed => the change comes here
+ * Both changed => conflict, the newer version wins, the other one is kept as a copy
+ *
+ * A file that fails to sync is skipped, and tried again on the next sync
+ */
+pub async fn sync_pair(app_handle: &AppHandle, pair_id: Uuid) -> Result<SyncReport, Error> {
+    let state = app_handle.state::<AppState>();
+
+    // Two syncs at the same time would step on each other's toes
+    let _guard = state.sync_lock.lock().await;
+
+    let pair = load_sync_pair(&state.db, pair_id).await?;
+    require_capability(&pair.peer, Capability::Folders).await?;
+    let context = SyncContext {
+        app_handle,
+        db: &state.db,
+        pair: &pair,
+        root: PathBuf::from(&pair.local_path),
+        path: format!("/folders/{}", pair.folder_id),
+    };
+
+    let files = platform_file_access(app_handle);
+    let local = scan_folder(files.as_ref(), &state.db, &context.root).await?;
+    let remote: BTreeMap<String, ManifestEntry> = context
+        .request(Method::GET, "/manifest")
+        .await?
+        .error_for_status()?
+        .json::<ServerResponse<Vec<ManifestEntry>>>()
+        .await?
+        .data
+        .into_iter()
+        .map(|entry| (entry.path.clone(), entry))
+        .collect();
+    let base = load_sync_state(&state.db, pair_id).await?;
+
+    let mut report = SyncReport {
+        pair_id,
+        ..Default::default()
+    };
+
+    let paths: BTreeSet<&String> = local
+        .keys()
+        .chain(remote.keys())
+        .chain(base.keys())
+        .collect();
+    for path in paths {
+        if let Err(err) = sync_path(
+            &context,
+            path,
+            local.get(path),
+            remote.get(path),
+            base.get(path),
+            &mut report,
+        )
+        .await
+        {
+            error!("Cannot sync {path} with peer {}: {err}", pair.peer);
+            report.failed += 1;
+        }
+    }
+
+    let id = pair_id.to_string();
+    let last_synced_at = now();
+    sqlx::query!(
+        "update sync_pairs set last_synced_at = $2 where id = $1 returning id",
+        id,
+        last_synced_at
+    )
+    .fetch_one(&state.db)
+    .await?;
+
+    app_handle.emit("sync-finished", &report)?;
+    Ok(report)
+}
+
+/*
+ * What a sync does with one path, from the hashes of both sides and of the last sync
+ * Gone on a side means no hash, see the comment on sync_pair
+ */
+#[derive(Debug, PartialEq)]
+enum SyncStep {
+    // Identical on both sides, or gone on both sides
+    Unchanged,
+    Download,
+    DeleteLocal,
+    Upload,
+    DeleteRemote,
+    // Both sides changed the file, see resolve_conflict
+    Conflict,
+}
+
+fn sync_step(local: Option<&String>, remote: Option<&String>, base: Option<&String>) -> SyncStep {
+    if local == remote {
+        return SyncStep::Unchanged;
+    }
+
+    // Only the peer changed it
+    if local == base {
+        return match remote {
+            Some(_) => SyncStep::Download,
+            None => SyncStep::DeleteLocal,
+        };
+    }
+
+    // Only we changed it
+    if remote == base {
+        return match local {
+            Some(_) => SyncStep::Upload,
+            None => SyncStep::DeleteRemote,
+        };
+    }
+
+    // Both changed it, a change always wins over a deletion
+    match (local, remote) {
+        (Some(_), Some(_)) => SyncStep::Conflict,
+        (Some(_), None) => SyncStep::Upload,
+        (None, _) => SyncStep::Download,
+    }
+}
+
+async fn sync_path(
+    context: &SyncContext<'_>,
+    path: &str,
+    local: Option<&ManifestEntry>,
+    remote: Option<&ManifestEntry>,
+    base: Option<&String>,
+    report: &mut SyncReport,
+) -> Result<(), Error> {
+    let local_hash = local.map(|entry| &entry.hash);
+    let remote_hash = remote.map(|entry| &entry.hash);
+
+    match sync_step(local_hash, remote_hash, base) {
+        SyncStep::Unchanged => save_sync_state(context, path, local_hash).await,
+        SyncStep::Download => {
+            download(context, path, path).await?;
+            report.downloaded += 1;
+            save_sync_state(context, path, remote_hash).await
+        }
+        SyncStep::DeleteLocal => {
+            tokio::fs::remove_file(resolve_in_folder(&context.root, path)?).await?;
+            report.deleted += 1;
+            save_sync_state(context, path, remote_hash).await
+        }
+        SyncStep::Upload => {
+            upload(context, path, path).await?;
+            report.uploaded += 1;
+            save_sync_state(context, path, local_hash).await
+        }
+        SyncStep::DeleteRemote => {
+            context
+                .request(Method::DELETE, &file_route(path))
+                .await?
+                .error_for_status()?;
+            report.deleted += 1;
+            save_sync_state(context, path, local_hash).await
+        }
+        // Only ever a conflict when both sides have the file
+        SyncStep::Conflict => match (local, remote) {
+            (Some(local), Some(remote)) => {
+                resolve_conflict(context, path, local, remote, report).await
+            }
+            _ => Ok(()),
+        },
+    }
+}
+
+/*
+ * Keeps the newer version at the path, and the other one next to it as a conflict copy,
+ * on both sides. Nothing gets overwritten without a copy being kept
+ */
+async fn resolve_conflict(
+    context: &SyncContext<'_>,
+    path: &str,
+    local: &ManifestEntry,
+    remote: &ManifestEntry,
+    report: &mut SyncReport,
+) -> Result<(), Error> {
+    let conflict_path = conflict_path(path);
+
+    if local.modified_at >= remote.modified_at {
+        download(context, path, &conflict_path).await?;
+        upload(context, &conflict_path, &conflict_path).await?;
+        upload(context, path, path).await?;
+        save_sync_state(context, path, Some(&local.hash)).await?;
+        save_sync_state(context, &conflict_path, Some(&remote.hash)).await?;
+    } else {
+        tokio::fs::rename(
+            resolve_in_folder(&context.root, path)?,
+            resolve_in_folder(&context.root, &conflict_path)?,
+        )
+        .await?;
+        download(context, path, path).await?;
+        upload(context, &conflict_path, &conflict_path).await?;
+        save_sync_state(context, path, Some(&remote.hash)).await?;
+        save_sync_state(context, &conflict_path, Some(&local.hash)).await?;
+    }
+
+    let conflict = SyncConflictModel {
+        id: Uuid::new_v4(),
+        pair_id: context.pair.id,
+        path: path.to_string(),
+        conflict_path,
+        created_at: now(),
+    };
+    let id = conflict.id.to_string();
+    let pair_id = conflict.pair_id.to_string();
+    sqlx::query!(
+        "
+            insert into sync_conflicts
+                (id, pair_id, path, conflict_path, created_at)
+            values
+                ($1, $2, $3, $4, $5)
+            returning id
+        ",
+        id,
+        pair_id,
+        conflict.path,
+        conflict.conflict_path,
+        conflict.created_at
+    )
+    .fetch_optional(context.db)
+    .await?;
+
+    context.app_handle.emit("sync-conflict", &conflict)?;
+    report.conflicts += 1;
+    Ok(())
+}
+
+// Downloads a file of the peer's folder into the local directory, under the local path
+async fn download(
+    context: &SyncContext<'_>,
+    remote_path: &str,
+    local_path: &str,
+) -> Result<(), Error> {
+    let target = resolve_in_folder(&context.root, local_path)?;
+    let mut response = context
+        .request(Method::GET, &file_route(remote_path))
+        .await?
+        .error_for_status()?;
+
+    let temp = temp_path(&target).await?;
+    let mut output = tokio::fs::File::create(&temp).await?;
+    while let Some(chunk) = response.chunk().await? {
+        output.write_all(&chunk).await?;
+    }
+    output.flush().await?;
+    drop(output);
+
+    tokio::fs::rename(&temp, &target).await?;
+    Ok(())
+}
+
+// Uploads a file of the local directory into the peer's folder, under the remote path
+async fn upload(
+    context: &SyncContext<'_>,
+    local_path: &str,
+    remote_path: &str,
+) -> Result<(), Error> {
+    let local = resolve_in_folder(&context.root, local_path)?;
+    let hash = hash_file(&LocalFileAccess, &local.to_string_lossy()).await?;
+    let file = tokio::fs::File::open(&local).await?;
+    let size = file.metadata().await?.len();
+
+    context
+        .body_request(
+            Method::PUT,
+            &file_route(remote_path),
+            file.into(),
+            size,
+            &hash,
+        )
+        .await?
+        .error_for_status()?;
+    Ok(())
+}
+
+/*
+ * Lists every file inside a folder with its hash
+ * Hidden files are skipped, this is also where the partial files of unfinished syncs live
+ * Hashes are cached by path, size and modified time, so only changed files are read again
+ */
+pub async fn scan_folder(
+    files: &dyn FileAccess,
+    db: &SqlitePool,
+    root: &Path,
+) -> Result<BTreeMap<String, ManifestEntry>, Error> {
+    if !tokio::fs::metadata(root).await?.is_dir() {
+        return Err(Error::NotADirectory(root.display().to_string()));
+    }
+
+    let mut manifest = BTreeMap::new();
+    let mut dirs = vec![root.to_path_buf()];
+    while let Some(dir) = dirs.pop() {
+        let mut entries = tokio::fs::read_dir(&dir).await?;
+        while let Some(entry) = entries.next_entry().await? {
+            if entry.file_name().to_string_lossy().starts_with('.') {
+                continue;
+            }
+            let metadata = entry.metadata().await?;
+            if metadata.is_dir() {
+                dirs.push(entry.path());
+                continue;
+            }
+            if !metadata.is_file() {
+                continue;
+            }
+
+            let path = entry.path();
+            let relative = path
+                .strip_prefix(root)
+                .unwrap_or(&path)
+                .components()
+                .map(|component| component.as_os_str().to_string_lossy())
+                .collect::<Vec<_>>()
+                .join("/");
+            let size = metadata.len() as i64;
+            let modified_at = metadata
+                .modified()
+                .ok()
+                .and_then(|modified| modified.duration_since(UNIX_EPOCH).ok())
+                .map(|duration| duration.as_secs() as i64)
+                .unwrap_or_default();
+            let hash = cached_hash(files, db, &path, size, modified_at).await?;
+
+            manifest.insert(
+                relative.clone(),
+                ManifestEntry {
+                    path: relative,
+                    size,
+                    modified_at,
+                    hash,
+                },
+            );
+        }
+    }
+    Ok(manifest)
+}
+
+async fn cached_hash(
+    files: &dyn FileAccess,
+    db: &SqlitePool,
+    path: &Path,
+    size: i64,
+    modified_at: i64,
+) -> Result<String, Error> {
+    let path = path.display().to_string();
+
+    if let Some(cached) = sqlx::query!(
+        "
+            select hash
+            from file_hashes
+            where
+                path = $1
+            and size = $2
+            and modified_at = $3
+        ",
+        path,
+        size,
+        modified_at
+    )
+    .fetch_optional(db)
+    .await?
+    {
+        return Ok(cached.hash);
+    }
+
+    let hash = hash_file(files, &path).await?;
+    sqlx::query!(
+        "
+            insert into file_hashes
+                (path, size, modified_at, hash)
+            values
+                ($1, $2, $3, $4)
+            on conflict (path)
+            do update set
+                size = excluded.size,
+                modified_at = excluded.modified_at,
+                hash = excluded.hash
+            returning path
+        ",
+        path,
+        size,
+        modified_at,
+        hash
+    )
+    .fetch_optional(db)
+    .await?;
+
+    Ok(hash)
+}
+
+// "photos/cat.png" => "photos/cat.sync-conflict-1718000000.png"
+fn conflict_path(path: &str) -> String {
+    let (dir, name) = match path.rsplit_once('/') {
+        Some((dir, name)) => (format!("{dir}/"), name),
+        None => (String::new(), path),
+    };
+    let suffix = format!("sync-conflict-{}", now());
+    match name.rsplit_once('.') {
+        Some((stem, extension)) if !stem.is_empty() => {
+            format!("{dir}{stem}.{suffix}.{extension}")
+        }
+        _ => format!("{dir}{name}.{suffix}"),
+    }
+}
+
+async fn save_sync_state(
+    context: &SyncContext<'_>,
+    path: &str,
+    hash: Option<&String>,
+) -> Result<(), Error> {
+    let pair_id = context.pair.id.to_string();
+    match hash {
+        Some(hash) => {
+            sqlx::query!(
+                "
+                    insert into sync_state
+                        (pair_id, path, hash)
+                    values
+                        ($1, $2, $3)
+                    on conflict (pair_id, path)
+                    do update set hash = excluded.hash
+                    returning path
+                ",
+                pair_id,
+                path,
+                hash
+            )
+            .fetch_optional(context.db)
+            .await?;
+        }
+        None => {
+            sqlx::query!(
+                "delete from sync_state where pair_id = $1 and path = $2 returning path",
+                pair_id,
+                path
+            )
+            .fetch_optional(context.db)
+            .await?;
+        }
+    }
+    Ok(())
+}
+
+// The hash of every file as it was after the last sync, by path
+async fn load_sync_state(db: &SqlitePool, pair_id: Uuid) -> Result<HashMap<String, String>, Error> {
+    let pair_id = pair_id.to_string();
+    let rows = sqlx::query!(
+        "select path, hash from sync_state where pair_id = $1",
+        pair_id
+    )
+    .fetch_all(db)
+    .await?;
+
+    Ok(rows.into_iter().map(|row| (row.path, row.hash)).collect())
+}
+
+pub async fn load_sync_pair(db: &SqlitePool, id: Uuid) -> Result<SyncPairModel, Error> {
+    let id = id.to_string();
+    let pair = sqlx::query_as!(
+        SyncPairModel,
+        r#"
+            select
+                id as "id!: Hyphenated",
+                local_path,
+                peer,
+                folder_id as "folder_id!: Hyphenated",
+                last_synced_at
+            from sync_pairs
+            where id = $1
+        "#,
+        id
+    )
+    .fetch_one(db)
+    .await?;
+
+    Ok(pair)
+}
+
+pub async fn load_sync_pairs(db: &SqlitePool) -> Result<Vec<SyncPairModel>, Error> {
+    let pairs = sqlx::query_as!(
+        SyncPairModel,
+        r#"
+            select
+                id as "id!: Hyphenated",
+                local_path,
+                peer,
+                folder_id as "folder_id!: Hyphenated",
+                last_synced_at
+            from sync_pairs
+        "#
+    )
+    .fetch_all(db)
+    .await?;
+
+    Ok(pairs)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn hash(text: &str) -> Option<String> {
+        Some(text.to_string())
+    }
+
+    fn step(local: Option<String>, remote: Option<String>, base: Option<String>) -> SyncStep {
+        sync_step(local.as_ref(), remote.as_ref(), base.as_ref())
+    }
+
+    #[test]
+    fn sync_steps_follow_what_changed_since_the_last_sync() {
+        let (old, local, remote) = (hash("old"), hash("local"), hash("remote"));
+
+        assert_eq!(
+            step(old.clone(), old.clone(), old.clone()),
+            SyncStep::Unchanged
+        );
+        assert_eq!(step(None, None, old.clone()), SyncStep::Unchanged);
+        assert_eq!(
+            step(local.clone(), local.clone(), None),
+            SyncStep::Unchanged
+        );
+
+        // Only the peer changed it, added it, or deleted it
+        assert_eq!(
+            step(old.clone(), remote.clone(), old.clone()),
+            SyncStep::Download
+        );
+        assert_eq!(step(None, remote.clone(), None), SyncStep::Download);
+        assert_eq!(step(old.clone(), None, old.clone()), SyncStep::DeleteLocal);
+
+        // Only we changed it, added it, or deleted it
+        assert_eq!(
+            step(local.clone(), old.clone(), old.clone()),
+            SyncStep::Upload
+        );
+        assert_eq!(step(local.clone(), None, None), SyncStep::Upload);
+        assert_eq!(step(None, old.clone(), old.clone()), SyncStep::DeleteRemote);
+
+        // Both changed it, a change wins over a deletion
+        assert_eq!(
+            step(local.clone(), remote.clone(), old.clone()),
+            SyncStep::Conflict
+        );
+        assert_eq!(
+            step(local.clone(), remote.clone(), None),
+            SyncStep::Conflict
+        );
+        assert_eq!(step(local.clone(), None, old.clone()), SyncStep::Upload);
+        assert_eq!(step(None, remote.clone(), old.clone()), SyncStep::Download);
+    }
+
+    #[test]
+    fn conflict_copies_keep_their_folder_and_extension() {
+        let suffix = |path: &str, prefix: &str| {
+            path.strip_prefix(prefix)
+                .and_then(|rest| rest.strip_prefix(".sync-conflict-"))
+                .map(str::to_string)
+        };
+
+        let copy = conflict_path("photos/cat.png");
+        let time = suffix(&copy, "photos/cat").unwrap();
+        let time = time.strip_suffix(".png").unwrap();
+        assert!(time.parse::<i64>().is_ok(), "{copy}");
+
+        // No extension, a hidden file, or a dot in a folder name
+        for path in ["README", ".bashrc", "v1.2/notes"] {
+            let copy = conflict_path(path);
+            let time = suffix(&copy, path).unwrap();
+            assert!(time.parse::<i64>().is_ok(), "{copy}");
+        }
+    }
+}
@@ -0,0 +1,123 @@
+/*
+  Filey - simple peer-to-peer file sending across devices on different platforms
+  Copyright (C) 2024 Wander Watterson
+
+  This program is free software: you can redistribute it and/or modify
+  it under the terms of the GNU General Public License as published by
+  the Free Software Foundation, either version 3 of the License, or
+  (at your option) any later version.
+
+  This program is distributed in the hope that it will be useful,
+  but WITHOUT ANY WARRANTY; without even the implied warranty of
+  MERCHANTABILITY or FITNESS FOR A PARTICULAR PURPOSE. See the
+  GNU General Public License for more details.
+
+  You should have received a copy of the GNU General Public License
+  along with this program. If not, see <https://www.gnu.org/licenses/>.
+*/
+
+use axum::body::Body;
+use sha2::{Digest, Sha256};
+use std::path::{Component, Path, PathBuf};
+use tokio::io::AsyncWriteExt;
+use tokio_stream::StreamExt;
+use uuid::Uuid;
+
+use crate::error::Error;
+
+/*
+ * Turns a path relative to a synced folder into a real path inside of it
+ * Anything that could climb out of the folder ("..", absolute paths) is refused,
+ * the paths come from the peers after all
+ */
+pub fn resolve_in_folder(root: &Path, path: &str) -> Result<PathBuf, Error> {
+    let mut resolved = root.to_path_buf();
+    for component in Path::new(path).components() {
+        match component {
+            Component::Normal(part) => resolved.push(part),
+            _ => return Err(Error::InvalidFileName(path.to_string())),
+        }
+    }
+    match resolved == root {
+        true => Err(Error::InvalidFileName(path.to_string())),
+        false => Ok(resolved),
+    }
+}
+
+/*
+ * A hidden file next to the target, the content is written there first and then renamed,
+ * so the target is never half written. Missing parent directories are created along the way
+ */
+pub async fn temp_path(target: &Path) -> Result<PathBuf, Error> {
+    let dir = target.parent().unwrap_or(target);
+    tokio::fs::create_dir_all(dir).await?;
+    Ok(dir.join(format!(".{}.part", Uuid::new_v4())))
+}
+
+/*
+ * Saves a request body into a file, the file is replaced only once the whole body is there
+ * With the SHA-256 (hex) the sender signed, a body that does not match it is thrown away
+ */
+pub async fn write_body(
+    target: &Path,
+    body: Body,
+    signed_sha256: Option<&str>,
+) -> Result<(), Error> {
+    let temp = temp_path(target).await?;
+    let mut output = tokio::fs::File::create(&temp).await?;
+    let mut hasher = Sha256::new();
+    let mut stream = body.into_data_stream();
+    while let Some(chunk) = stream.next().await {
+        let chunk = match chunk {
+            Ok(chunk) => chunk,
+            Err(err) => {
+                drop(output);
+                tokio::fs::remove_file(&temp).await.ok();
+                return Err(err.into());
+            }
+        };
+        hasher.update(&chunk);
+        output.write_all(&chunk).await?;
+    }
+    output.flush().await?;
+    drop(output);
+
+    if signed_sha256.is_some_and(|signed| signed != format!("{:x}", hasher.finalize())) {
+        tokio::fs::remove_file(&temp).await.ok();
+        return Err(Error::InvalidSignature(target.display().to_string()));
+    }
+
+    tokio::fs::rename(&temp, target).await?;
+    Ok(())
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn paths_stay_inside_the_folder() {
+        let root = Path::new("/home/me/Sync");
+        assert_eq!(
+            resolve_in_folder(root, "photos/cat.png").unwrap(),
+            root.join("photos").join("cat.png")
+        );
+        for path in [
+            "",
+            ".",
+            "..",
+            "../etc/passwd",
+            "photos/../../x",
+            "/etc/passwd",
+            "./a",
+        ] {
+            assert!(
+                matches!(
+                    resolve_in_folder(root, path),
+                    Err(Error::InvalidFileName(_))
+                ),
+                "{path}"
+            );
+        }
+    }
+}
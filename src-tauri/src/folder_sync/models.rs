@@ -0,0 +1,114 @@
+/*
+  Filey - simple peer-to-peer file sending across devices on different platforms
+  Copyright (C) 2024 Wander Watterson
+
+  This program is free software: you can redistribute it and/or modify
+  it under the terms of the GNU General Public License as published by
+  the Free Software Foundation, either version 3 of the License, or
+  (at your option) any later version.
+
+  This program is distributed in the hope that it will be useful,
+  but WITHOUT ANY WARRANTY; without even the implied warranty of
+  MERCHANTABILITY or FITNESS FOR A PARTICULAR PURPOSE. See the
+  GNU General Public License for more details.
+
+  You should have received a copy of the GNU General Public License
+  along with this program. If not, see <https://www.gnu.org/licenses/>.
+*/
+
+use serde::{Deserialize, Serialize};
+use std::time::Duration;
+use uuid::Uuid;
+
+// How often every sync pair gets synced in the background
+pub const SYNC_INTERVAL: Duration = Duration::from_secs(60);
+
+/*
+ * A local directory that peers can sync with, the ones it is paired with, see SharedFolderPeers
+ * They can always read it, but they can only change it if it is writable
+ */
+#[derive(Debug, Clone, Serialize, Deserialize)]
+#[serde(rename_all = "camelCase")]
+pub struct SharedFolderModel {
+    pub id: Uuid,
+    pub path: String,
+    pub name: String,
+    pub writable: bool,
+}
+
+/*
+ * The known peers a shared folder is paired with, only they get to see it and sync with it
+ * A folder without any is shared with no one
+ */
+#[derive(Debug, Serialize, Deserialize)]
+#[serde(rename_all = "camelCase")]
+pub struct SharedFolderPeers {
+    pub folder_id: Uuid,
+    pub device_ids: Vec<Uuid>,
+}
+
+// What the peers get to see about a shared folder, the path stays private
+#[derive(Debug, Serialize, Deserialize)]
+#[serde(rename_all = "camelCase")]
+pub struct SharedFolderResponse {
+    pub id: Uuid,
+    pub name: String,
+    pub writable: bool,
+}
+
+// A local directory kept identical to a shared folder on a peer
+#[derive(Debug, Serialize, Deserialize)]
+#[serde(rename_all = "camelCase")]
+pub struct SyncPairModel {
+    pub id: Uuid,
+    pub local_path: String,
+    pub peer: String,
+    pub folder_id: Uuid,
+    pub last_synced_at: Option<i64>,
+}
+
+/*
+ * A file inside a synced folder
+ * The path is relative to the folder and always uses "/", whatever the platform is
+ * The hash is the SHA-256 of the content (hex), the modified time is in unix seconds
+ */
+#[derive(Debug, Clone, Serialize, Deserialize)]
+#[serde(rename_all = "camelCase")]
+pub struct ManifestEntry {
+    pub path: String,
+    pub size: i64,
+    pub modified_at: i64,
+    pub hash: String,
+}
+
+// What a sync did, emitted as "sync-finished" and returned by sync_now
+#[derive(Debug, Default, Clone, Serialize, Deserialize)]
+#[serde(rename_all = "camelCase")]
+pub struct SyncReport {
+    pub pair_id: Uuid,
+    pub uploaded: usize,
+    pub downloaded: usize,
+    pub deleted: usize,
+    pub conflicts: usize,
+    pub failed: usize,
+}
+
+/*
+ * Both sides changed the same file since the last sync
+ * The newer version stays at the path, the other one is kept next to it at the conflict path
+ */
+#[derive(Debug, Clone, Serialize, Deserialize)]
+#[serde(rename_all = "camelCase")]
+pub struct SyncConflictModel {
+    pub id: Uuid,
+    pub pair_id: Uuid,
+    pub path: String,
+    pub conflict_path: String,
+    pub created_at: i64,
+}
+
+// ?path=relative/path/inside/the/folder
+#[derive(Debug, Serialize, Deserialize)]
+pub struct FolderFileQuery {
+    pub path: String,
+}
@@ -0,0 +1,276 @@
+/*
+  Filey - simple peer-to-peer file sending across devices on different platforms
+  Copyright (C) 2024 Wander Watterson
+
+  This program is free software: you can redistribute it and/or modify
+  it under the terms of the GNU General Public License as published by
+  the Free Software Foundation, either version 3 of the License, or
+  (at your option) any later version.
+
+  This program is distributed in the hope that it will be useful,
+  but WITHOUT ANY WARRANTY; without even the implied warranty of
+  MERCHANTABILITY or FITNESS FOR A PARTICULAR PURPOSE. See the
+  GNU General Public License for more details.
+
+  You should have received a copy of the GNU General Public License
+  along with this program. If not, see <https://www.gnu.org/licenses/>.
+*/
+
+use axum::middleware::from_fn_with_state;
+use clap::Parser;
+use log::{error, info, LevelFilter};
+use sqlx::SqlitePool;
+use std::{net::SocketAddr, path::PathBuf, sync::Arc};
+use tokio::{
+    net::TcpListener,
+    sync::{broadcast, oneshot, Semaphore},
+};
+use uuid::{fmt::Hyphenated, Uuid};
+
+use super::models::{Cli, Config, HeadlessCommand};
+use crate::{
+    access_control::{commands::spawn_access_log_pruning, middleware::check_access},
+    db::connect_and_migrate_db,
+    device::commands::{load_identity, save_device_name},
+    error::Error,
+    files::{
+        access::LocalFileAccess,
+        commands::insert_file,
+        encryption::PassphraseKey,
+        expiry::spawn_share_expiry,
+        models::{ExpiryAction, FileModel, Visibility, MAX_ENCRYPTED_DOWNLOADS},
+    },
+    http_server::{
+        commands::{backend_shutdown_signal, cors_layer, share_router},
+        models::ServerState,
+    },
+    noise::server::spawn_noise_server,
+};
+
+/*
+ * Entry point of the filey-server binary
+ * Runs a subcommand against the database of the config, "serve" when none is given
+ *
+ * The subcommands that change the shared list only touch the database, a running server
+ * picks the changes up on the next request, but its /events subscribers are not told about them
+ */
+pub fn run() {
+    fern::Dispatch::new()
+        .format(|out, message, record| out.finish(format_args!("[{}] {message}", record.level())))
+        .level(LevelFilter::Info)
+        .chain(std::io::stderr())
+        .apply()
+        .ok();
+
+    let cli = Cli::parse();
+
+    let result = crate::runtime::block_on(async move {
+        let config = Config::load(cli.config).await?;
+        let db = connect_and_migrate_db(config.db_path()).await;
+
+        match cli.command.unwrap_or(HeadlessCommand::Serve) {
+            HeadlessCommand::Serve => serve(&config, db).await,
+            HeadlessCommand::Add { paths, visibility } => add_files(&db, paths, visibility).await,
+            HeadlessCommand::Remove { id } => remove_file(&db, id).await,
+            HeadlessCommand::List => list_files(&db).await,
+            HeadlessCommand::Visibility { id, visibility } => {
+                set_visibility(&db, id, visibility).await
+            }
+            HeadlessCommand::Name { name } => device_name(&db, name).await,
+        }
+    });
+
+    if let Err(err) = result {
+        error!("{err}");
+        std::process::exit(1);
+    }
+}
+
+/*
+ * Serves the shares until SIGTERM or Ctrl+C, the graceful shutdown lets the running
+ * downloads finish first, same as the server in the app
+ */
+async fn serve(config: &Config, db: SqlitePool) -> Result<(), Error> {
+    // Only the latest changes matter, slow subscribers will just miss the old ones
+    let (file_events, _) = broadcast::channel(64);
+
+    // Same share limits as the server in the app
+    spawn_share_expiry(db.clone(), file_events.clone());
+    spawn_access_log_pruning(db.clone());
+
+    let server_state = ServerState {
+        db: db.clone(),
+        files: Arc::new(LocalFileAccess),
+        // filey-server can still be built with the gui feature, it has no app either way
+        #[cfg(feature = "gui")]
+        app_handle: None,
+        file_events,
+        // Same key as the app, they share the data directory
+        passphrase_key: Arc::new(PassphraseKey::load(&config.passphrase_key_path()).await?),
+        nonces: Arc::default(),
+        encryptions: Arc::new(Semaphore::new(MAX_ENCRYPTED_DOWNLOADS)),
+    };
+
+    // Same allow and deny rules as the server in the app
+    let router = share_router()
+        .layer(from_fn_with_state(server_state.clone(), check_access))
+        .with_state(server_state)
+        .layer(cors_layer());
+
+    let tcp_listener = TcpListener::bind(("0.0.0.0", config.port)).await?;
+    info!("Serving on 0.0.0.0:{}", config.port);
+
+    // Peers that are set to be encrypted talk to the same routes on the Noise port
+    let noise_server = spawn_noise_server(db, router.clone());
+
+    // Nothing else stops the headless server, the trigger is kept alive until the end
+    let (_shutdown_trigger, shutdown_listener) = oneshot::channel::<()>();
+
+    axum::serve(
+        tcp_listener,
+        router.into_make_service_with_connect_info::<SocketAddr>(),
+    )
+    .with_graceful_shutdown(backend_shutdown_signal(shutdown_listener))
+    .await?;
+
+    noise_server.abort();
+    info!("Server stopped");
+    Ok(())
+}
+
+/*
+ * Shares files by path, the ones already shared are skipped
+ * So are the paths that do not exist, cannot be read, or are not files, they are all looked at
+ * before anything is shared, so that a bad path does not leave the batch half shared
+ */
+async fn add_files(
+    db: &SqlitePool,
+    paths: Vec<PathBuf>,
+    visibility: Visibility,
+) -> Result<(), Error> {
+    // Nobody listens, the events only matter to a server in the same process
+    let (file_events, _) = broadcast::channel(1);
+
+    let mut files = vec![];
+    for path in paths {
+        let checked = match tokio::fs::canonicalize(&path).await {
+            Ok(path) => tokio::fs::metadata(&path)
+                .await
+                .map(|metadata| (path, metadata)),
+            Err(err) => Err(err),
+        };
+        match checked {
+            Ok((path, metadata)) if metadata.is_file() => files.push(path),
+            Ok((path, _)) => error!("Not a file, skipped: {}", path.display()),
+            Err(err) => error!("Cannot read {}, skipped: {err}", path.display()),
+        }
+    }
+
+    for path in files {
+        let path_text = path.display().to_string();
+        if sqlx::query!("select id from files where path = $1", path_text)
+            .fetch_optional(db)
+            .await?
+            .is_some()
+        {
+            info!("Already shared: {path_text}");
+            continue;
+        }
+
+        let file = FileModel {
+            id: Uuid::new_v4(),
+            name: path
+                .file_name()
+                .map(|name| name.to_string_lossy().to_string())
+                .unwrap_or_else(|| path_text.clone()),
+            mime: mime_guess::from_path(&path)
+                .first_or_octet_stream()
+                .to_string(),
+            visibility: visibility.clone(),
+            path: path_text,
+            missing: false,
+            size: None,
+            modified_at: None,
+            share_until: None,
+            max_downloads: None,
+            download_count: 0,
+            on_expiry: ExpiryAction::Private,
+            encrypted: false,
+        };
+        println!("{}\t{}", file.id, file.path);
+        insert_file(db, &file_events, file, None).await?;
+    }
+
+    Ok(())
+}
+
+async fn remove_file(db: &SqlitePool, id: Uuid) -> Result<(), Error> {
+    let file_id = id.to_string();
+    sqlx::query!("delete from files where id = $1 returning id", file_id)
+        .fetch_one(db)
+        .await?;
+    Ok(())
+}
+
+// One file per line: id, visibility, path, and whether the file is gone from the disk
+async fn list_files(db: &SqlitePool) -> Result<(), Error> {
+    let files = sqlx::query!(
+        r#"
+            select
+                id as "id!: Hyphenated",
+                visibility as "visibility!: Visibility",
+                path,
+                missing
+            from files
+            order by path
+        "#
+    )
+    .fetch_all(db)
+    .await?;
+
+    for file in files {
+        println!(
+            "{}\t{}\t{}{}",
+            file.id,
+            file.visibility,
+            file.path,
+            match file.missing {
+                true => "\t(missing)",
+                false => "",
+            }
+        );
+    }
+
+    Ok(())
+}
+
+async fn set_visibility(db: &SqlitePool, id: Uuid, visibility: Visibility) -> Result<(), Error> {
+    let file_id = id.to_string();
+    let visibility = visibility.to_string();
+    sqlx::query!(
+        "
+            update files set
+                visibility = $1
+            where id = $2
+            returning id
+        ",
+        visibility,
+        file_id
+    )
+    .fetch_one(db)
+    .await?;
+    Ok(())
+}
+
+// Prints the identity that peers see, after renaming the device if a name is given
+async fn device_name(db: &SqlitePool, name: Option<String>) -> Result<(), Error> {
+    let identity = match name {
+        Some(name) => save_device_name(db, &name).await?,
+        None => load_identity(db).await?,
+    };
+    println!(
+        "{}\t{}\t{}",
+        identity.id, identity.name, identity.public_key
+    );
+    Ok(())
+}
@@ -0,0 +1,123 @@
+/*
+  Filey - simple peer-to-peer file sending across devices on different platforms
+  Copyright (C) 2024 Wander Watterson
+
+  This program is free software: you can redistribute it and/or modify
+  it under the terms of the GNU General Public License as published by
+  the Free Software Foundation, either version 3 of the License, or
+  (at your option) any later version.
+
+  This program is distributed in the hope that it will be useful,
+  but WITHOUT ANY WARRANTY; without even the implied warranty of
+  MERCHANTABILITY or FITNESS FOR A PARTICULAR PURPOSE. See the
+  GNU General Public License for more details.
+
+  You should have received a copy of the GNU General Public License
+  along with this program. If not, see <https://www.gnu.org/licenses/>.
+*/
+
+use clap::{Parser, Subcommand};
+use serde::Deserialize;
+use std::path::PathBuf;
+use uuid::Uuid;
+
+use crate::{error::Error, files::models::Visibility};
+
+// Same port as the app, so the peers find the headless server like any other Filey peer
+pub const DEFAULT_PORT: u16 = 38899;
+
+#[derive(Parser)]
+#[command(
+    name = "filey-server",
+    version,
+    about = "Serve Filey shares without the GUI"
+)]
+pub struct Cli {
+    // Defaults to filey/config.toml in the config directory of the user
+    #[arg(short, long, help = "Path to the config file")]
+    pub config: Option<PathBuf>,
+
+    #[command(subcommand)]
+    pub command: Option<HeadlessCommand>,
+}
+
+#[derive(Subcommand)]
+pub enum HeadlessCommand {
+    #[command(about = "Run the server until SIGTERM or Ctrl+C (the default)")]
+    Serve,
+
+    #[command(about = "Share files")]
+    Add {
+        #[arg(required = true)]
+        paths: Vec<PathBuf>,
+        #[arg(long, default_value = "private", help = "public, private or unlisted")]
+        visibility: Visibility,
+    },
+
+    #[command(about = "Stop sharing a file")]
+    Remove { id: Uuid },
+
+    #[command(about = "List the shared files")]
+    List,
+
+    #[command(about = "Make a shared file public, private or unlisted")]
+    Visibility { id: Uuid, visibility: Visibility },
+
+    #[command(about = "Show the identity of this device, or rename it (\"\" for the hostname)")]
+    Name { name: Option<String> },
+}
+
+/*
+ * The config file, every key is optional
+ *
+ * port = 38899
+ * data_dir = "/var/lib/filey"
+ */
+#[derive(Deserialize)]
+#[serde(default)]
+pub struct Config {
+    pub port: u16,
+    // Where data.db lives, defaults to the same directory as the app, so both see the same shares
+    pub data_dir: PathBuf,
+}
+
+impl Default for Config {
+    fn default() -> Self {
+        Self {
+            port: DEFAULT_PORT,
+            data_dir: dirs::data_dir().unwrap_or_default().join("wander.filey"),
+        }
+    }
+}
+
+impl Config {
+    /*
+     * Reads the config file at the given path, which has to exist
+     * Without a path, the default config file is read if there is one
+     */
+    pub async fn load(path: Option<PathBuf>) -> Result<Self, Error> {
+        let path = match path {
+            Some(path) => path,
+            None => {
+                let path = dirs::config_dir()
+                    .unwrap_or_default()
+                    .join("filey")
+                    .join("config.toml");
+                if !tokio::fs::try_exists(&path).await? {
+                    return Ok(Self::default());
+                }
+                path
+            }
+        };
+        Ok(toml::from_str(&tokio::fs::read_to_string(path).await?)?)
+    }
+
+    pub fn db_path(&self) -> String {
+        self.data_dir.join("data.db").display().to_string()
+    }
+
+    // See files::encryption::PassphraseKey
+    pub fn passphrase_key_path(&self) -> PathBuf {
+        self.data_dir.join("passphrase.key")
+    }
+}
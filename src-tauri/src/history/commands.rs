@@ -0,0 +1,251 @@
+/*
+  Filey - simple peer-to-peer file sending across devices on different platforms
+  Copyright (C) 2024 Wander Watterson
+
+  This program is free software: you can redistribute it and/or modify
+  it under the terms of the GNU General Public License as published by
+  the Free Software Foundation, either version 3 of the License, or
+  (at your option) any later version.
+
+  This program is distributed in the hope that it will be useful,
+  but WITHOUT ANY WARRANTY; without even the implied warranty of
+  MERCHANTABILITY or FITNESS FOR A PARTICULAR PURPOSE. See the
+  GNU General Public License for more details.
+
+  You should have received a copy of the GNU General Public License
+  along with this program. If not, see <https://www.gnu.org/licenses/>.
+*/
+
+use sha2::{Digest, Sha256};
+use tauri::{AppHandle, Manager, State};
+use tokio::io::AsyncReadExt;
+use uuid::{fmt::Hyphenated, Uuid};
+
+use super::models::{TransferHistoryModel, TransferOutcome};
+use crate::{
+    error::Error,
+    files::{
+        access::{platform_file_access, FileAccess},
+        commands::reveal,
+    },
+    messages::models::Direction,
+    AppState,
+};
+
+// Returns every finished transfer, the most recent first
+#[tauri::command]
+pub async fn get_transfer_history(
+    state: State<'_, AppState>,
+) -> Result<Vec<TransferHistoryModel>, Error> {
+    let history = sqlx::query_as!(
+        TransferHistoryModel,
+        r#"
+            select
+                id as "id!: Hyphenated",
+                direction as "direction!: Direction",
+                peer,
+                peer_name,
+                name,
+                size,
+                hash,
+                duration_ms,
+                speed,
+                outcome as "outcome!: TransferOutcome",
+                destination,
+                error,
+                created_at
+            from transfer_history
+            order by created_at desc
+        "#
+    )
+    .fetch_all(&state.db)
+    .await?;
+
+    Ok(history)
+}
+
+/*
+ * Searches the history by file name, peer address or peer name
+ * Pasting a SHA-256 hash finds the transfers of that exact file, whatever it was called
+ * The query is matched as typed, % and _ in a file name are not wildcards
+ */
+#[tauri::command]
+pub async fn search_transfer_history(
+    state: State<'_, AppState>,
+    query: &str,
+) -> Result<Vec<TransferHistoryModel>, Error> {
+    let hash = query.trim().to_lowercase();
+    let pattern = format!("%{}%", escape_like(query));
+    let history = sqlx::query_as!(
+        TransferHistoryModel,
+        r#"
+            select
+                id as "id!: Hyphenated",
+                direction as "direction!: Direction",
+                peer,
+                peer_name,
+                name,
+                size,
+                hash,
+                duration_ms,
+                speed,
+                outcome as "outcome!: TransferOutcome",
+                destination,
+                error,
+                created_at
+            from transfer_history
+            where
+                name like $1 escape '\'
+            or  peer like $1 escape '\'
+            or  peer_name like $1 escape '\'
+            or  hash = $2
+            order by created_at desc
+        "#,
+        pattern,
+        hash
+    )
+    .fetch_all(&state.db)
+    .await?;
+
+    Ok(history)
+}
+
+// Escapes the wildcards of a LIKE pattern, for `like ... escape '\'`
+fn escape_like(text: &str) -> String {
+    text.replace('\\', "\\\\")
+        .replace('%', "\\%")
+        .replace('_', "\\_")
+}
+
+// Shows a received file in the file explorer
+#[tauri::command]
+pub async fn reveal_transfer_destination(
+    state: State<'_, AppState>,
+    id: Uuid,
+) -> Result<(), Error> {
+    let history_id = id.to_string();
+    let Some(destination) = sqlx::query!(
+        "select destination from transfer_history where id = $1",
+        history_id
+    )
+    .fetch_one(&state.db)
+    .await?
+    .destination
+    else {
+        return Err(Error::DestinationMissing(history_id));
+    };
+
+    reveal(&destination).await
+}
+
+// Clears the history, or only the transfers that finished before a certain time
+#[tauri::command]
+pub async fn purge_transfer_history(
+    state: State<'_, AppState>,
+    before: Option<i64>,
+) -> Result<(), Error> {
+    sqlx::query!(
+        "
+            delete from transfer_history
+            where created_at < coalesce($1, created_at + 1)
+            returning id
+        ",
+        before
+    )
+    .fetch_all(&state.db)
+    .await?;
+    Ok(())
+}
+
+/*
+ * Adds a finished transfer to the history
+ * Completed transfers get the hash of the file at hash_path, which is the sent file
+ * for outgoing transfers, and the received file for incoming ones
+ */
+pub async fn record_transfer(
+    app_handle: &AppHandle,
+    mut entry: TransferHistoryModel,
+    hash_path: Option<String>,
+) -> Result<(), Error> {
+    if let (TransferOutcome::Completed, Some(path)) = (&entry.outcome, hash_path) {
+        entry.hash = Some(hash_file(platform_file_access(app_handle).as_ref(), &path).await?);
+    }
+
+    let id = entry.id.to_string();
+    let direction = entry.direction.to_string();
+    let outcome = entry.outcome.to_string();
+
+    sqlx::query!(
+        "
+            insert into transfer_history
+                (
+                    id, direction, peer, peer_name, name, size, hash, duration_ms, speed,
+                    outcome, destination, error, created_at
+                )
+            values
+                ($1, $2, $3, $4, $5, $6, $7, $8, $9, $10, $11, $12, $13)
+            returning id
+        ",
+        id,
+        direction,
+        entry.peer,
+        entry.peer_name,
+        entry.name,
+        entry.size,
+        entry.hash,
+        entry.duration_ms,
+        entry.speed,
+        outcome,
+        entry.destination,
+        entry.error,
+        entry.created_at
+    )
+    .fetch_optional(&app_handle.state::<AppState>().db)
+    .await?;
+    Ok(())
+}
+
+// SHA-256 of a file as hex, read in chunks so big files do not end up in memory
+pub async fn hash_file(files: &dyn FileAccess, path: &str) -> Result<String, Error> {
+    let mut file = files.open(path)?;
+    let mut hasher = Sha256::new();
+    let mut buffer = vec![0; 64 * 1024];
+    loop {
+        let read = file.read(&mut buffer).await?;
+        if read == 0 {
+            break;
+        }
+        hasher.update(&buffer[..read]);
+    }
+    Ok(format!("{:x}", hasher.finalize()))
+}
+
+// Average speed in bytes per second, unknown if the transfer took no time at all
+pub fn average_speed(bytes: i64, duration_ms: i64) -> Option<i64> {
+    (duration_ms > 0).then(|| bytes * 1000 / duration_ms)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::db::test_db;
+
+    async fn matches(db: &sqlx::SqlitePool, name: &str, query: &str) -> bool {
+        sqlx::query_scalar::<_, bool>("select $1 like $2 escape '\\'")
+            .bind(name)
+            .bind(format!("%{}%", escape_like(query)))
+            .fetch_one(db)
+            .await
+            .unwrap()
+    }
+
+    #[tokio::test]
+    async fn wildcards_in_the_query_are_matched_as_typed() {
+        let db = test_db().await;
+        assert!(matches(&db, "report_2024.pdf", "t_2").await);
+        assert!(!matches(&db, "report-2024.pdf", "t_2").await);
+        assert!(matches(&db, "100% done.txt", "100%").await);
+        assert!(!matches(&db, "1000 done.txt", "100%").await);
+        assert!(matches(&db, "back\\slash", "k\\s").await);
+    }
+}
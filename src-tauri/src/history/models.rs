@@ -0,0 +1,80 @@
+/*
+  Filey - simple peer-to-peer file sending across devices on different platforms
+  Copyright (C) 2024 Wander Watterson
+
+  This program is free software: you can redistribute it and/or modify
+  it under the terms of the GNU General Public License as published by
+  the Free Software Foundation, either version 3 of the License, or
+  (at your option) any later version.
+
+  This program is distributed in the hope that it will be useful,
+  but WITHOUT ANY WARRANTY; without even the implied warranty of
+  MERCHANTABILITY or FITNESS FOR A PARTICULAR PURPOSE. See the
+  GNU General Public License for more details.
+
+  You should have received a copy of the GNU General Public License
+  along with this program. If not, see <https://www.gnu.org/licenses/>.
+*/
+
+use serde::{Deserialize, Serialize};
+use sqlx::{sqlite::SqliteTypeInfo, Database, Decode, Encode, Sqlite, Type};
+use std::str::FromStr;
+use strum::{Display, EnumString};
+use uuid::Uuid;
+
+use crate::messages::models::Direction;
+
+// How a transfer ended, paused transfers are not finished yet so they are not in the history
+#[derive(Serialize, Deserialize, Debug, Clone, PartialEq, Encode, Display, EnumString)]
+#[serde(rename_all = "lowercase")]
+#[strum(serialize_all = "lowercase")]
+pub enum TransferOutcome {
+    Completed,
+    Failed,
+    Cancelled,
+}
+
+impl Type<Sqlite> for TransferOutcome {
+    fn type_info() -> SqliteTypeInfo {
+        <&str as Type<Sqlite>>::type_info()
+    }
+    fn compatible(ty: &SqliteTypeInfo) -> bool {
+        <&str as Type<Sqlite>>::compatible(ty)
+    }
+}
+
+impl<'r, DB: Database> Decode<'r, DB> for TransferOutcome
+where
+    &'r str: Decode<'r, DB>,
+{
+    fn decode(
+        value: <DB as sqlx::Database>::ValueRef<'r>,
+    ) -> Result<Self, sqlx::error::BoxDynError> {
+        let value = <&str as Decode<DB>>::decode(value)?;
+        Ok(TransferOutcome::from_str(value).unwrap())
+    }
+}
+
+/*
+ * A finished transfer, sent or received
+ * The hash is the SHA-256 of the file (hex), only known when the transfer completed
+ * The speed is the average in bytes per second, over the time the transfer was actually running
+ * The destination is where a received file ended up on this device
+ */
+#[derive(Debug, Clone, Serialize, Deserialize)]
+#[serde(rename_all = "camelCase")]
+pub struct TransferHistoryModel {
+    pub id: Uuid,
+    pub direction: Direction,
+    pub peer: String,
+    pub peer_name: Option<String>,
+    pub name: String,
+    pub size: Option<i64>,
+    pub hash: Option<String>,
+    pub duration_ms: i64,
+    pub speed: Option<i64>,
+    pub outcome: TransferOutcome,
+    pub destination: Option<String>,
+    pub error: Option<String>,
+    pub created_at: i64,
+}
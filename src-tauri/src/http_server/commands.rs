@@ -27,18 +27,54 @@ use axum::{
     },
     Router,
 };
-use tauri::Manager;
-use tauri_plugin_http::reqwest::Client;
-use tokio::{net::TcpListener, signal, sync::oneshot::Receiver};
+use reqwest::Client;
+use tokio::{signal, sync::oneshot::Receiver};
 use tower_http::cors::{AllowOrigin, CorsLayer};
 
 use crate::{
-    error::Error, files::models::FileResponse, http_server::models::Peer, AppState, ServerResponse,
+    error::Error,
+    http_server::models::{Capability, Peer},
+    ServerResponse,
 };
 
 use super::{
-    models::{OsType, ServerState},
-    routes::{get_file, get_files, info, preflight},
+    models::{InfoResponse, ServerState},
+    routes::{events, get_file, get_files, index, info, preflight, signatures},
+    webdav::webdav,
+};
+
+// What the app needs to run the server, and to follow the peers
+#[cfg(feature = "gui")]
+use axum::middleware::from_fn_with_state;
+#[cfg(feature = "gui")]
+use log::{error, info};
+#[cfg(feature = "gui")]
+use std::{net::SocketAddr, sync::Arc};
+#[cfg(feature = "gui")]
+use tauri::{AppHandle, Emitter, Manager};
+#[cfg(feature = "gui")]
+use tokio::{
+    net::TcpListener,
+    sync::{oneshot, Semaphore},
+};
+
+#[cfg(feature = "gui")]
+use crate::{
+    access_control::middleware::check_access,
+    device::commands::signed_headers,
+    files::{
+        access::platform_file_access,
+        models::{FileEvent, FileResponse, MAX_ENCRYPTED_DOWNLOADS},
+    },
+    noise::{client::get_from_peer, server::spawn_noise_server},
+    peers::commands::remember_peer,
+    AppState,
+};
+
+#[cfg(feature = "gui")]
+use super::{
+    models::PeerFileEvent,
+    routes::{clipboard, messages, shared_folders, transfers},
 };
 
 /*
@@ -51,6 +87,7 @@ use super::{
  * Why 38899 you ask? No reason, that's just a random number I thought of, and
  * of course it has to be a fixed port so that other peers can see each other
  */
+#[cfg(feature = "gui")]
 #[tauri::command]
 pub async fn start_server(app_handle: tauri::AppHandle) -> Result<(), Error> {
     // Get the tauri state
@@ -65,53 +102,94 @@ pub async fn start_server(app_handle: tauri::AppHandle) -> Result<(), Error> {
      */
     *state.http_server_shutdown_trigger.lock().await = Some(http_server_shutdown_trigger);
 
-    // Load the handlers into the Router
-    let router = Router::new()
-        .merge(preflight())
-        .merge(info())
-        .merge(get_files())
-        .merge(get_file())
-        .with_state(ServerState {
-            db: state.db.clone(),
-            app_handle: app_handle.clone(),
-        })
-        .layer(
-            CorsLayer::new()
-                .allow_headers([
-                    ORIGIN,
-                    CONTENT_TYPE,
-                    CONTENT_DISPOSITION,
-                    CONTENT_RANGE,
-                    CONTENT_LENGTH,
-                    ACCESS_CONTROL_ALLOW_ORIGIN,
-                    ACCESS_CONTROL_ALLOW_HEADERS,
-                    ACCESS_CONTROL_ALLOW_METHODS,
-                ])
-                .allow_origin(AllowOrigin::any())
-                .allow_methods([
-                    Method::GET,
-                    Method::POST,
-                    Method::PUT,
-                    Method::DELETE,
-                    Method::OPTIONS,
-                ]),
-        );
+    let server_state = ServerState {
+        db: state.db.clone(),
+        files: platform_file_access(&app_handle),
+        app_handle: Some(app_handle.clone()),
+        file_events: state.file_events.clone(),
+        passphrase_key: state.passphrase_key.clone(),
+        nonces: Arc::default(),
+        encryptions: Arc::new(Semaphore::new(MAX_ENCRYPTED_DOWNLOADS)),
+    };
+
+    /*
+     * Load the handlers into the Router
+     * The allow and deny rules are checked before any of them runs
+     */
+    let router = share_router()
+        .merge(clipboard())
+        .merge(messages())
+        .merge(transfers())
+        .merge(shared_folders())
+        .layer(from_fn_with_state(server_state.clone(), check_access))
+        .with_state(server_state)
+        .layer(cors_layer());
 
     // Listens for TCP requests on 0.0.0.0:38899
     let tcp_listener = TcpListener::bind("0.0.0.0:38899")
         .await
         .expect("Cannot listen on 0.0.0.0:38899");
 
-    // Starts the server
-    axum::serve(tcp_listener, router)
-        // ...loaded with a function that listens for shutdown signal
-        .with_graceful_shutdown(backend_shutdown_signal(http_server_shutdown_listener))
-        .await?;
-    Ok(())
+    // The same routes, for the peers that talk to us through the encrypted transport
+    let noise_server = spawn_noise_server(state.db.clone(), router.clone());
+
+    /*
+     * Starts the server
+     * The connect info gives the handlers the address of the peer that sent the request
+     */
+    let served = axum::serve(
+        tcp_listener,
+        router.into_make_service_with_connect_info::<SocketAddr>(),
+    )
+    // ...loaded with a function that listens for shutdown signal
+    .with_graceful_shutdown(backend_shutdown_signal(http_server_shutdown_listener))
+    .await;
+
+    noise_server.abort();
+    Ok(served?)
+}
+
+/*
+ * The routes that serve what is shared on this device, and nothing else
+ * They do not need the GUI, which is why the headless server serves exactly these
+ */
+pub fn share_router() -> Router<ServerState> {
+    Router::new()
+        .merge(preflight())
+        .merge(index())
+        .merge(info())
+        .merge(get_files())
+        .merge(get_file())
+        .merge(signatures())
+        .merge(webdav())
+        .merge(events())
+}
+
+// Peers call the server from their webview, which is a different origin
+pub fn cors_layer() -> CorsLayer {
+    CorsLayer::new()
+        .allow_headers([
+            ORIGIN,
+            CONTENT_TYPE,
+            CONTENT_DISPOSITION,
+            CONTENT_RANGE,
+            CONTENT_LENGTH,
+            ACCESS_CONTROL_ALLOW_ORIGIN,
+            ACCESS_CONTROL_ALLOW_HEADERS,
+            ACCESS_CONTROL_ALLOW_METHODS,
+        ])
+        .allow_origin(AllowOrigin::any())
+        .allow_methods([
+            Method::GET,
+            Method::POST,
+            Method::PUT,
+            Method::DELETE,
+            Method::OPTIONS,
+        ])
 }
 
 // As the name implies, it listens to specific signal to shut the server down
-async fn backend_shutdown_signal(oneshot_recv: Receiver<()>) {
+pub async fn backend_shutdown_signal(oneshot_recv: Receiver<()>) {
     // Ctrl + C signal
     let ctrl_c = async {
         signal::ctrl_c()
@@ -140,6 +218,7 @@ async fn backend_shutdown_signal(oneshot_recv: Receiver<()>) {
 }
 
 // This command stops the server, by getting the backend shutdown trigger, and triggering it
+#[cfg(feature = "gui")]
 #[tauri::command]
 pub async fn stop_server(state: tauri::State<'_, AppState>) -> Result<(), Error> {
     if let Some(shutdown_signal) = state.http_server_shutdown_trigger.lock().await.take() {
@@ -154,15 +233,36 @@ pub async fn stop_server(state: tauri::State<'_, AppState>) -> Result<(), Error>
  * if the address is a Filey peer
  * This is on the requesting side, on the serving side, it will be handled
  * by a handler in backend::handlers::info
+ *
+ * Older peers only answer with their OS, and newer peers might list capabilities
+ * we do not know about, both still make a Peer, see Peer::new
  */
+#[cfg(feature = "gui")]
 #[tauri::command]
-pub async fn check_peer(ip: &str) -> Result<Peer, Error> {
+pub async fn check_peer(state: tauri::State<'_, AppState>, ip: &str) -> Result<Peer, Error> {
+    let peer = fetch_peer(ip).await?;
+    // Every peer found is remembered, see peers::commands::remember_peer
+    remember_peer(&state.db, &peer).await?;
+    Ok(peer)
+}
+
+pub async fn fetch_peer(ip: &str) -> Result<Peer, Error> {
     let address = format!("http://{ip}:38899/info");
-    let response: ServerResponse<OsType> = Client::new().get(&address).send().await?.json().await?;
-    Ok(Peer {
-        address: ip.to_string(),
-        os_type: response.data,
-    })
+    let response: ServerResponse<InfoResponse> =
+        Client::new().get(&address).send().await?.json().await?;
+    Ok(Peer::new(ip.to_string(), response.data))
+}
+
+/*
+ * Checks that the peer at this address serves a feature, before its routes get called
+ * Older peers and headless servers do not have every route, calling them would only end in a 404
+ */
+pub async fn require_capability(ip: &str, capability: Capability) -> Result<Peer, Error> {
+    let peer = fetch_peer(ip).await?;
+    if !peer.supports(&capability) {
+        return Err(Error::PeerUnsupported(format!("{ip}: {capability}")));
+    }
+    Ok(peer)
 }
 
 /*
@@ -170,15 +270,140 @@ pub async fn check_peer(ip: &str) -> Result<Peer, Error> {
  * This is on the requesting side, on the serving side, it will be handled
  * by a handler in backend::handlers::get_files
  */
+#[cfg(feature = "gui")]
 #[tauri::command]
-pub async fn get_files_from_peer(ip: &str) -> Result<Vec<FileResponse>, Error> {
-    let address = format!("http://{ip}:38899/files");
+pub async fn get_files_from_peer(
+    state: tauri::State<'_, AppState>,
+    ip: &str,
+) -> Result<Vec<FileResponse>, Error> {
+    // Signed, so the peer also lists the private files it shares with this device
+    let headers = signed_headers(&state.db, "GET", "/files").await?;
     let response: ServerResponse<Vec<FileResponse>> =
-        Client::new().get(&address).send().await?.json().await?;
+        get_from_peer(&state.db, ip, "/files", headers)
+            .await?
+            .json()
+            .await?;
 
     Ok(response.data)
 }
 
+/*
+ * Subscribes to the /events feed of another Filey peer
+ * Every change to the peer's shared list is re-emitted as a "peer-file-event" tauri event,
+ * so the UI does not need to call get_files_from_peer again to notice new files
+ * Peers without the feed get a PeerUnsupported error, for them the UI has to keep doing so
+ * This is on the requesting side, on the serving side, it will be handled
+ * by a handler in http_server::routes::events
+ */
+#[cfg(feature = "gui")]
+#[tauri::command]
+pub async fn subscribe_peer_events(app_handle: AppHandle, ip: String) -> Result<(), Error> {
+    let state = app_handle.state::<AppState>();
+    require_capability(&ip, Capability::Events).await?;
+
+    // Signed, like get_files_from_peer, so the feed also tells of the private files shared with us
+    let headers = signed_headers(&state.db, "GET", "/events").await?;
+    let response = get_from_peer(&state.db, &ip, "/events", headers)
+        .await?
+        .error_for_status()?;
+
+    let (shutdown_trigger, shutdown_listener) = oneshot::channel::<()>();
+
+    // Subscribing twice to the same peer replaces the old subscription
+    if let Some(old_trigger) = state
+        .peer_event_subscriptions
+        .lock()
+        .await
+        .insert(ip.clone(), shutdown_trigger)
+    {
+        old_trigger.send(()).ok();
+    }
+
+    crate::runtime::spawn(async move {
+        if let Err(err) = forward_peer_events(&app_handle, &ip, response, shutdown_listener).await {
+            error!("Events from peer {ip} stopped: {err}");
+        }
+
+        /*
+         * The listener is gone by now, so our trigger is closed
+         * A trigger that is still open belongs to a newer subscription to the same peer
+         */
+        let state = app_handle.state::<AppState>();
+        let mut subscriptions = state.peer_event_subscriptions.lock().await;
+        if subscriptions
+            .get(&ip)
+            .is_some_and(|trigger| trigger.is_closed())
+        {
+            subscriptions.remove(&ip);
+        }
+        info!("Unsubscribed from the events of peer {ip}");
+    });
+
+    Ok(())
+}
+
+// Stops listening to the /events feed of a peer
+#[cfg(feature = "gui")]
+#[tauri::command]
+pub async fn unsubscribe_peer_events(
+    state: tauri::State<'_, AppState>,
+    ip: &str,
+) -> Result<(), Error> {
+    if let Some(shutdown_trigger) = state.peer_event_subscriptions.lock().await.remove(ip) {
+        shutdown_trigger.send(()).ok();
+    }
+    Ok(())
+}
+
+/*
+ * Reads the Server-Sent Events stream chunk by chunk until it ends, or the shutdown trigger fires
+ * Events are separated by a blank line, and the JSON payload sits on the "data:" lines
+ * Everything else (event names, keep alive comments) is ignored
+ */
+#[cfg(feature = "gui")]
+async fn forward_peer_events(
+    app_handle: &AppHandle,
+    ip: &str,
+    mut response: reqwest::Response,
+    mut shutdown_listener: Receiver<()>,
+) -> Result<(), Error> {
+    let mut buffer: Vec<u8> = vec![];
+    loop {
+        let chunk = tokio::select! {
+            _ = &mut shutdown_listener => return Ok(()),
+            chunk = response.chunk() => chunk?,
+        };
+        // The peer closed the stream
+        let Some(chunk) = chunk else {
+            return Ok(());
+        };
+        buffer.extend_from_slice(&chunk);
+
+        while let Some(end) = buffer.windows(2).position(|window| window == b"\n\n") {
+            let frame: Vec<u8> = buffer.drain(..end + 2).collect();
+            let data = String::from_utf8_lossy(&frame)
+                .lines()
+                .filter_map(|line| line.strip_prefix("data:"))
+                .map(str::trim_start)
+                .collect::<Vec<_>>()
+                .join("\n");
+            if data.is_empty() {
+                continue;
+            }
+            match serde_json::from_str::<FileEvent>(&data) {
+                Ok(event) => app_handle.emit(
+                    "peer-file-event",
+                    PeerFileEvent {
+                        peer: ip.to_string(),
+                        event,
+                    },
+                )?,
+                Err(err) => error!("Cannot parse event from peer {ip}: {err}"),
+            }
+        }
+    }
+}
+
 /*
  * There is no get_file_from_peer command, because getting a single file does NOT get the file info,
  * but the entire file CONTENTS
@@ -17,5 +17,7 @@
 */
 
 pub mod commands;
-mod models;
+pub mod models;
+mod requester;
 mod routes;
+mod webdav;
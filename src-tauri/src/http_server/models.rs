@@ -17,41 +17,272 @@
 */
 
 use serde::{Deserialize, Serialize};
-use sqlx::SqlitePool;
+use sqlx::{sqlite::SqliteTypeInfo, Database, Decode, Encode, Sqlite, SqlitePool, Type};
+use std::{str::FromStr, sync::Arc};
+use strum::{Display, EnumString};
+#[cfg(feature = "gui")]
 use tauri::AppHandle;
+use tokio::sync::{broadcast, Semaphore};
+use uuid::Uuid;
+
+use super::requester::SeenNonces;
+use crate::files::{access::FileAccess, encryption::PassphraseKey, models::FileEvent};
 
 // Axum state
 #[derive(Clone)]
 pub struct ServerState {
     pub db: SqlitePool,
-    pub app_handle: AppHandle,
+    // Opens the shared files, so the routes serving them do not depend on the app
+    pub files: Arc<dyn FileAccess>,
+    /*
+     * None on the headless server (see the headless module), there is no GUI to talk to
+     * The routes that need it (clipboard, messages, transfers...) are not served there
+     */
+    #[cfg(feature = "gui")]
+    pub app_handle: Option<AppHandle>,
+    pub file_events: broadcast::Sender<FileEvent>,
+    pub passphrase_key: Arc<PassphraseKey>,
+    // Nonces of the signed requests already served, see requester
+    pub nonces: Arc<SeenNonces>,
+    // Room for the files being encrypted for the peers, see files::encryption
+    pub encryptions: Arc<Semaphore>,
 }
 
-#[derive(Serialize, Deserialize)]
+impl ServerState {
+    // Whether the GUI is around, for the routes that need it, see app_handle
+    pub fn has_app(&self) -> bool {
+        #[cfg(feature = "gui")]
+        {
+            self.app_handle.is_some()
+        }
+        #[cfg(not(feature = "gui"))]
+        {
+            false
+        }
+    }
+}
+
+#[cfg(test)]
+impl ServerState {
+    // The state of the headless server, on a test database
+    pub async fn for_tests(db: SqlitePool, files: Arc<dyn FileAccess>) -> Self {
+        use crate::files::models::MAX_ENCRYPTED_DOWNLOADS;
+
+        let (file_events, _) = broadcast::channel(16);
+        let key_dir = tempfile::tempdir().unwrap();
+        let passphrase_key = PassphraseKey::load(&key_dir.path().join("passphrase.key"))
+            .await
+            .unwrap();
+        ServerState {
+            db,
+            files,
+            #[cfg(feature = "gui")]
+            app_handle: None,
+            file_events,
+            passphrase_key: Arc::new(passphrase_key),
+            nonces: Arc::default(),
+            encryptions: Arc::new(Semaphore::new(MAX_ENCRYPTED_DOWNLOADS)),
+        }
+    }
+}
+
+#[derive(Serialize, Deserialize, Debug, Clone, PartialEq, Encode, Display, EnumString)]
 #[serde(rename_all = "lowercase")]
+#[strum(serialize_all = "lowercase")]
 pub enum OsType {
     Linux,
     Windows,
     Macos,
     Ios,
     Android,
+    // An OS a newer peer reports, that this version does not know about
+    #[serde(other)]
+    Unknown,
 }
 
-impl From<tauri_plugin_os::OsType> for OsType {
-    fn from(os_type: tauri_plugin_os::OsType) -> Self {
-        match os_type {
-            tauri_plugin_os::OsType::Linux => OsType::Linux,
-            tauri_plugin_os::OsType::Windows => OsType::Windows,
-            tauri_plugin_os::OsType::Macos => OsType::Macos,
-            tauri_plugin_os::OsType::IOS => OsType::Ios,
-            tauri_plugin_os::OsType::Android => OsType::Android,
+impl Type<Sqlite> for OsType {
+    fn type_info() -> SqliteTypeInfo {
+        <&str as Type<Sqlite>>::type_info()
+    }
+    fn compatible(ty: &SqliteTypeInfo) -> bool {
+        <&str as Type<Sqlite>>::compatible(ty)
+    }
+}
+
+impl<'r, DB: Database> Decode<'r, DB> for OsType
+where
+    &'r str: Decode<'r, DB>,
+{
+    fn decode(
+        value: <DB as sqlx::Database>::ValueRef<'r>,
+    ) -> Result<Self, sqlx::error::BoxDynError> {
+        let value = <&str as Decode<DB>>::decode(value)?;
+        Ok(OsType::from_str(value).unwrap())
+    }
+}
+
+impl OsType {
+    // The OS this build runs on
+    pub fn current() -> Self {
+        match std::env::consts::OS {
+            "linux" => OsType::Linux,
+            "windows" => OsType::Windows,
+            "macos" => OsType::Macos,
+            "ios" => OsType::Ios,
+            "android" => OsType::Android,
+            _ => OsType::Unknown,
         }
     }
 }
 
+/*
+ * Version of what the routes send and expect, bumped whenever that changes
+ * 1 => /info only answered with the OsType
+ * 2 => /info answers with ServerInfo
+ */
+pub const PROTOCOL_VERSION: u32 = 2;
+
+/*
+ * Features a peer can serve, peers check for them before using a route
+ * (see http_server::commands::require_capability)
+ * Names that are unknown (from a newer peer) become Unknown instead of failing the whole list
+ */
+#[derive(Debug, Clone, PartialEq, Serialize, Deserialize, Display)]
+#[serde(rename_all = "lowercase")]
+#[strum(serialize_all = "lowercase")]
+pub enum Capability {
+    Files,
+    Snippets,
+    Events,
+    Ranges,
+    // The block signatures of the files, for delta downloads, see files::delta
+    Signatures,
+    Webdav,
+    Clipboard,
+    Messages,
+    Transfers,
+    Folders,
+    // The encrypted transport, see noise
+    Noise,
+    // Files shared with a passphrase, in the age format, see files::encryption
+    Encryption,
+    #[serde(other)]
+    Unknown,
+}
+
+// Answer of the /info route
+#[derive(Serialize, Deserialize)]
+#[serde(rename_all = "camelCase")]
+pub struct ServerInfo {
+    pub protocol_version: u32,
+    pub app_version: String,
+    pub os_type: OsType,
+    #[serde(default)]
+    pub device_id: Option<Uuid>,
+    #[serde(default)]
+    pub device_name: Option<String>,
+    // Ed25519 public key of the device, base64
+    #[serde(default)]
+    pub public_key: Option<String>,
+    #[serde(default)]
+    pub capabilities: Vec<Capability>,
+}
+
+// Peers on protocol version 1 only send their OsType
+#[derive(Serialize, Deserialize)]
+#[serde(untagged)]
+pub enum InfoResponse {
+    Current(ServerInfo),
+    Legacy(OsType),
+}
+
 #[derive(Serialize, Deserialize)]
 #[serde(rename_all = "camelCase")]
 pub struct Peer {
     pub address: String,
     pub os_type: OsType,
+    pub protocol_version: u32,
+    // Everything below is unknown for peers on protocol version 1
+    pub app_version: Option<String>,
+    pub device_id: Option<Uuid>,
+    pub device_name: Option<String>,
+    pub public_key: Option<String>,
+    pub capabilities: Vec<Capability>,
+}
+
+impl Peer {
+    pub fn supports(&self, capability: &Capability) -> bool {
+        self.capabilities.contains(capability)
+    }
+
+    pub fn new(address: String, info: InfoResponse) -> Self {
+        match info {
+            InfoResponse::Current(info) => Self {
+                address,
+                os_type: info.os_type,
+                protocol_version: info.protocol_version,
+                app_version: Some(info.app_version),
+                device_id: info.device_id,
+                device_name: info.device_name,
+                public_key: info.public_key,
+                capabilities: info
+                    .capabilities
+                    .into_iter()
+                    .filter(|capability| *capability != Capability::Unknown)
+                    .collect(),
+            },
+            // Only what every version 1 peer is sure to serve, newer features might be missing
+            InfoResponse::Legacy(os_type) => Self {
+                address,
+                os_type,
+                protocol_version: 1,
+                app_version: None,
+                device_id: None,
+                device_name: None,
+                public_key: None,
+                capabilities: vec![Capability::Files, Capability::Ranges],
+            },
+        }
+    }
+}
+
+// Payload of the tauri event that re-emits a change in a peer's shared list
+#[derive(Clone, Serialize, Deserialize)]
+pub struct PeerFileEvent {
+    pub peer: String,
+    pub event: FileEvent,
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn newer_peer_is_understood() {
+        let info = serde_json::from_str(
+            r#"{
+                "protocolVersion": 3,
+                "appVersion": "9.0.0",
+                "osType": "haiku",
+                "capabilities": ["files", "teleport", "transfers"]
+            }"#,
+        )
+        .unwrap();
+        let peer = Peer::new("10.0.0.2".into(), info);
+        assert_eq!(peer.os_type, OsType::Unknown);
+        assert_eq!(
+            peer.capabilities,
+            [Capability::Files, Capability::Transfers]
+        );
+    }
+
+    #[test]
+    fn version_1_peer_only_serves_files() {
+        let info = serde_json::from_str(r#""linux""#).unwrap();
+        let peer = Peer::new("10.0.0.2".into(), info);
+        assert_eq!(peer.protocol_version, 1);
+        assert!(peer.supports(&Capability::Files));
+        assert!(!peer.supports(&Capability::Transfers));
+        assert!(!peer.supports(&Capability::Signatures));
+    }
 }
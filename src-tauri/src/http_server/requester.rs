@@ -0,0 +1,312 @@
+/*
+  Filey - simple peer-to-peer file sending across devices on different platforms
+  Copyright (C) 2024 Wander Watterson
+
+  This program is free software: you can redistribute it and/or modify
+  it under the terms of the GNU General Public License as published by
+  the Free Software Foundation, either version 3 of the License, or
+  (at your option) any later version.
+
+  This program is distributed in the hope that it will be useful,
+  but WITHOUT ANY WARRANTY; without even the implied warranty of
+  MERCHANTABILITY or FITNESS FOR A PARTICULAR PURPOSE. See the
+  GNU General Public License for more details.
+
+  You should have received a copy of the GNU General Public License
+  along with this program. If not, see <https://www.gnu.org/licenses/>.
+*/
+
+use axum::{extract::FromRequestParts, http::request::Parts};
+use base64::{engine::general_purpose::STANDARD, Engine};
+use ed25519_dalek::{Signature, VerifyingKey};
+use sqlx::SqlitePool;
+use std::{collections::HashMap, sync::Mutex};
+use uuid::Uuid;
+
+use super::models::ServerState;
+use crate::{
+    db::now,
+    device::models::{
+        content_sha256, signed_message, CONTENT_SHA256_HEADER, DEVICE_ID_HEADER, MAX_NONCE_LENGTH,
+        MAX_SIGNATURE_AGE, NONCE_HEADER, SIGNATURE_HEADER, TIMESTAMP_HEADER,
+    },
+    error::Error,
+    noise::models::NoisePeer,
+};
+
+/*
+ * The known peer that sent a request, from the signed headers, see device::commands::signed_headers
+ * or from the device key it used on the encrypted transport, see noise::server
+ * None => the request is not signed (a browser, an older peer), or comes from a device that is
+ * not known here, it only gets to see what is public
+ *
+ * A request that is signed, but whose signature does not match the known public key
+ * (or is too old, or was already used), is refused instead of being treated as anonymous
+ */
+pub struct Requester(pub Option<Uuid>);
+
+impl FromRequestParts<ServerState> for Requester {
+    type Rejection = Error;
+
+    async fn from_request_parts(parts: &mut Parts, state: &ServerState) -> Result<Self, Error> {
+        // On the encrypted transport, the handshake already told who is on the other end
+        if let Some(NoisePeer(Some(device_id))) = parts.extensions.get::<NoisePeer>() {
+            return Ok(Requester(Some(*device_id)));
+        }
+
+        let header = |name| {
+            parts
+                .headers
+                .get(name)
+                .and_then(|value| value.to_str().ok())
+        };
+        let (Some(device_id), Some(timestamp), Some(nonce), Some(body), Some(signature)) = (
+            header(DEVICE_ID_HEADER),
+            header(TIMESTAMP_HEADER),
+            header(NONCE_HEADER),
+            header(CONTENT_SHA256_HEADER),
+            header(SIGNATURE_HEADER),
+        ) else {
+            return Ok(Requester(None));
+        };
+
+        let device_id = Uuid::parse_str(device_id)
+            .map_err(|_| Error::InvalidSignature(device_id.to_string()))?;
+        let Some(public_key) = load_public_key(&state.db, device_id).await? else {
+            return Ok(Requester(None));
+        };
+
+        let timestamp: i64 = timestamp
+            .parse()
+            .map_err(|_| Error::InvalidSignature(device_id.to_string()))?;
+        if (now() - timestamp).abs() > MAX_SIGNATURE_AGE || nonce.len() > MAX_NONCE_LENGTH {
+            return Err(Error::InvalidSignature(device_id.to_string()));
+        }
+
+        let path = parts
+            .uri
+            .path_and_query()
+            .map_or(parts.uri.path(), |path| path.as_str());
+        let message = signed_message(parts.method.as_str(), path, timestamp, nonce, body);
+        let verified = STANDARD
+            .decode(signature)
+            .ok()
+            .and_then(|bytes| Signature::from_slice(&bytes).ok())
+            .is_some_and(|signature| {
+                public_key
+                    .verify_strict(message.as_bytes(), &signature)
+                    .is_ok()
+            });
+
+        // A signature seen before is a request sent again by someone who caught it
+        if !verified || !state.nonces.first_use(device_id, nonce, timestamp) {
+            return Err(Error::InvalidSignature(device_id.to_string()));
+        }
+
+        // The routes reading a body check it against the signed hash, see SignedContent
+        let body = SignedContent(body.to_string());
+        parts.extensions.insert(body);
+        Ok(Requester(Some(device_id)))
+    }
+}
+
+// SHA-256 (hex) of the body a verified request signed, for the routes reading the body
+#[derive(Clone)]
+pub struct SignedContent(pub String);
+
+// Refuses a body read whole that is not the one signed, unsigned requests have nothing to check
+pub fn check_signed_body(signed: Option<&SignedContent>, body: &[u8]) -> Result<(), Error> {
+    match signed {
+        Some(SignedContent(hash)) if *hash != content_sha256(body) => {
+            Err(Error::InvalidSignature(hash.clone()))
+        }
+        _ => Ok(()),
+    }
+}
+
+/*
+ * The nonces of the signed requests, remembered as long as their timestamp would be accepted
+ * Older ones are forgotten, their requests are refused for being too old anyway
+ */
+#[derive(Default)]
+pub struct SeenNonces(Mutex<HashMap<(Uuid, String), i64>>);
+
+impl SeenNonces {
+    // Whether the nonce is new, it is remembered from now on
+    fn first_use(&self, device_id: Uuid, nonce: &str, timestamp: i64) -> bool {
+        let mut seen = self.0.lock().unwrap();
+        let oldest = now() - MAX_SIGNATURE_AGE;
+        seen.retain(|_, seen_timestamp| *seen_timestamp >= oldest);
+        seen.insert((device_id, nonce.to_string()), timestamp)
+            .is_none()
+    }
+}
+
+// The public key remembered for a known peer, peers::commands::remember_peer keeps it
+async fn load_public_key(db: &SqlitePool, device_id: Uuid) -> Result<Option<VerifyingKey>, Error> {
+    let id = device_id.to_string();
+    let Some(public_key) = sqlx::query!(
+        "select public_key from known_peers where device_id = $1",
+        id
+    )
+    .fetch_optional(db)
+    .await?
+    .and_then(|row| row.public_key) else {
+        return Ok(None);
+    };
+
+    let key: [u8; 32] = STANDARD
+        .decode(&public_key)
+        .ok()
+        .and_then(|bytes| bytes.try_into().ok())
+        .ok_or_else(|| Error::InvalidKey(format!("public key of peer {device_id}")))?;
+    VerifyingKey::from_bytes(&key)
+        .map(Some)
+        .map_err(|_| Error::InvalidKey(format!("public key of peer {device_id}")))
+}
+
+/*
+ * A known peer that sent a request, the request is refused when there is none
+ * For what is never served to anonymous peers, like the shared folders, see folder_sync
+ */
+pub struct KnownRequester(pub Uuid);
+
+impl FromRequestParts<ServerState> for KnownRequester {
+    type Rejection = Error;
+
+    async fn from_request_parts(parts: &mut Parts, state: &ServerState) -> Result<Self, Error> {
+        match Requester::from_request_parts(parts, state).await? {
+            Requester(Some(device_id)) => Ok(KnownRequester(device_id)),
+            Requester(None) => Err(Error::UnknownPeer(parts.uri.path().to_string())),
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::{
+        db::{insert_known_peer, test_db},
+        device::commands::{load_identity, load_signing_key, signed_headers},
+        files::access::LocalFileAccess,
+    };
+    use axum::http::{HeaderMap, HeaderValue, Request};
+    use ed25519_dalek::Signer;
+    use std::sync::Arc;
+
+    // This device, remembered as a known peer of itself, so it can sign the requests
+    async fn known_state() -> (ServerState, Uuid) {
+        let db = test_db().await;
+        let identity = load_identity(&db).await.unwrap();
+        insert_known_peer(&db, identity.id, Some(&identity.public_key)).await;
+        let state = ServerState::for_tests(db, Arc::new(LocalFileAccess)).await;
+        (state, identity.id)
+    }
+
+    async fn requester(
+        state: &ServerState,
+        path: &str,
+        headers: HeaderMap,
+    ) -> Result<Requester, Error> {
+        let mut request = Request::get(path).body(()).unwrap();
+        *request.headers_mut() = headers;
+        let (mut parts, _) = request.into_parts();
+        Requester::from_request_parts(&mut parts, state).await
+    }
+
+    #[tokio::test]
+    async fn signed_request_names_the_peer() {
+        let (state, device_id) = known_state().await;
+        let headers = signed_headers(&state.db, "GET", "/files?mode=download")
+            .await
+            .unwrap();
+        let Requester(requester) = requester(&state, "/files?mode=download", headers)
+            .await
+            .unwrap();
+        assert_eq!(requester, Some(device_id));
+    }
+
+    #[tokio::test]
+    async fn signed_request_cannot_be_sent_again() {
+        let (state, _) = known_state().await;
+        let headers = signed_headers(&state.db, "GET", "/files").await.unwrap();
+        assert!(requester(&state, "/files", headers.clone()).await.is_ok());
+        assert!(matches!(
+            requester(&state, "/files", headers).await,
+            Err(Error::InvalidSignature(_))
+        ));
+    }
+
+    #[tokio::test]
+    async fn signature_for_another_query_is_refused() {
+        let (state, _) = known_state().await;
+        let headers = signed_headers(&state.db, "GET", "/folders/1/file?path=a.txt")
+            .await
+            .unwrap();
+        assert!(matches!(
+            requester(&state, "/folders/1/file?path=b.txt", headers).await,
+            Err(Error::InvalidSignature(_))
+        ));
+    }
+
+    #[tokio::test]
+    async fn unsigned_request_is_anonymous() {
+        let (state, _) = known_state().await;
+        let Requester(requester) = requester(&state, "/files", HeaderMap::new()).await.unwrap();
+        assert_eq!(requester, None);
+    }
+
+    #[tokio::test]
+    async fn unknown_device_is_anonymous() {
+        let db = test_db().await;
+        let headers = signed_headers(&db, "GET", "/files").await.unwrap();
+        let state = ServerState::for_tests(db, Arc::new(LocalFileAccess)).await;
+        let Requester(requester) = requester(&state, "/files", headers).await.unwrap();
+        assert_eq!(requester, None);
+    }
+
+    #[tokio::test]
+    async fn signature_for_another_path_is_refused() {
+        let (state, _) = known_state().await;
+        let headers = signed_headers(&state.db, "GET", "/files").await.unwrap();
+        assert!(matches!(
+            requester(&state, "/folders", headers).await,
+            Err(Error::InvalidSignature(_))
+        ));
+    }
+
+    #[tokio::test]
+    async fn old_signature_is_refused() {
+        let (state, device_id) = known_state().await;
+        let timestamp = now() - MAX_SIGNATURE_AGE - 1;
+        let signing_key = load_signing_key(&state.db).await.unwrap();
+        let body = content_sha256(&[]);
+        let message = signed_message("GET", "/files", timestamp, "nonce", &body);
+        let signature = signing_key.sign(message.as_bytes());
+
+        let mut headers = HeaderMap::new();
+        for (name, value) in [
+            (DEVICE_ID_HEADER, device_id.to_string()),
+            (TIMESTAMP_HEADER, timestamp.to_string()),
+            (NONCE_HEADER, "nonce".to_string()),
+            (CONTENT_SHA256_HEADER, body),
+            (SIGNATURE_HEADER, STANDARD.encode(signature.to_bytes())),
+        ] {
+            headers.insert(name, HeaderValue::from_str(&value).unwrap());
+        }
+        assert!(matches!(
+            requester(&state, "/files", headers).await,
+            Err(Error::InvalidSignature(_))
+        ));
+    }
+
+    #[tokio::test]
+    async fn known_requester_refuses_anonymous_requests() {
+        let (state, _) = known_state().await;
+        let (mut parts, _) = Request::get("/folders").body(()).unwrap().into_parts();
+        assert!(matches!(
+            KnownRequester::from_request_parts(&mut parts, &state).await,
+            Err(Error::UnknownPeer(_))
+        ));
+    }
+}
@@ -17,27 +17,93 @@
 */
 
 use crate::{
-    error::Error, files::models::FileResponse, http_server::models::OsType, ServerResponse,
+    db::now,
+    device::commands::load_identity,
+    error::Error,
+    files::{
+        delta::{block_size_for, compute_signatures, MAX_BLOCK_SIZE, MIN_BLOCK_SIZE},
+        encryption::encrypted_body,
+        expiry::count_download,
+        models::{FileEvent, FileResponse, ItemKind},
+    },
+    http_server::models::{Capability, OsType, ServerInfo, PROTOCOL_VERSION},
+    ServerResponse,
+};
+// The routes that only the app serves, see http_server::commands::start_server
+#[cfg(feature = "gui")]
+use crate::{
+    clipboard::{
+        commands::{load_clipboard_history, save_clipboard_entry, write_clipboard},
+        models::{
+            ClipboardContent, ClipboardEntry, MAX_CLIPBOARD_IMAGE_SIZE, MAX_CLIPBOARD_PULL_SIZE,
+        },
+    },
+    folder_sync::{
+        engine::scan_folder,
+        files::{resolve_in_folder, write_body},
+        models::{FolderFileQuery, SharedFolderModel, SharedFolderResponse},
+    },
+    history::{
+        commands::{average_speed, record_transfer},
+        models::{TransferHistoryModel, TransferOutcome},
+    },
+    messages::{
+        commands::{check_message_length, save_message},
+        models::{Direction, MessageModel, MessageRequest},
+    },
+    transfers::{
+        commands::{
+            is_always_allowed, is_received, load_transfer_request, partial_path, receive_file,
+            received_size, save_transfer_request, set_transfer_status, wait_for_approval,
+        },
+        models::{
+            ReceivedFile, TransferFile, TransferOffer, TransferReply, TransferRequestModel,
+            TransferStatus,
+        },
+    },
+    AppState,
 };
 use axum::{
     extract::{Path, Query, State},
-    response::{AppendHeaders, IntoResponse, Response},
+    response::{
+        sse::{Event, KeepAlive, Sse},
+        AppendHeaders, Html, IntoResponse, Response,
+    },
     routing::{get, options},
     Json, Router,
 };
 use axum_extra::{headers::Range, TypedHeader};
 use axum_range::{KnownSize, Ranged};
+use log::error;
 use reqwest::{
     header::{CONTENT_DISPOSITION, CONTENT_TYPE},
-    StatusCode,
+    Method, StatusCode,
 };
 use serde::{Deserialize, Serialize};
-use std::str::FromStr;
-use tauri_plugin_fs::{FsExt, OpenOptions, SafeFilePath};
-use tauri_plugin_os::type_;
+use sqlx::SqlitePool;
+use std::{
+    collections::HashSet,
+    sync::{Arc, Mutex},
+};
+use tokio_stream::{wrappers::BroadcastStream, StreamExt};
 use uuid::{fmt::Hyphenated, Uuid};
 
-use super::models::ServerState;
+use super::{models::ServerState, requester::Requester};
+
+#[cfg(feature = "gui")]
+use axum::{
+    body::{Body, Bytes},
+    extract::{ConnectInfo, DefaultBodyLimit},
+    routing::post,
+    Extension,
+};
+#[cfg(feature = "gui")]
+use std::{net::SocketAddr, time::Instant};
+#[cfg(feature = "gui")]
+use tauri::{AppHandle, Emitter, Manager};
+
+#[cfg(feature = "gui")]
+use super::requester::{check_signed_body, KnownRequester, SignedContent};
 
 pub fn preflight() -> Router<ServerState> {
     async fn handler(Path(rest): Path<String>) -> Result<Response, Error> {
@@ -47,6 +113,7 @@ pub fn preflight() -> Router<ServerState> {
             Json(ServerResponse {
                 message: "Preflight request passed".into(),
                 data: (),
+                code: None,
             }),
         )
             .into_response())
@@ -54,14 +121,47 @@ pub fn preflight() -> Router<ServerState> {
     Router::new().route("/{*rest}", options(handler))
 }
 
-// Returns a message and OS type
+/*
+ * Returns what this server is and what it can do, see ServerInfo
+ * The headless server has no GUI, so it only serves the shares
+ */
 pub fn info() -> Router<ServerState> {
-    async fn handler() -> Result<Response, Error> {
+    async fn handler(State(server_state): State<ServerState>) -> Result<Response, Error> {
+        let identity = load_identity(&server_state.db).await?;
+
+        let mut capabilities = vec![
+            Capability::Files,
+            Capability::Snippets,
+            Capability::Events,
+            Capability::Ranges,
+            Capability::Signatures,
+            Capability::Webdav,
+            Capability::Noise,
+            Capability::Encryption,
+        ];
+        if server_state.has_app() {
+            capabilities.extend([
+                Capability::Clipboard,
+                Capability::Messages,
+                Capability::Transfers,
+                Capability::Folders,
+            ]);
+        }
+
         Ok((
             StatusCode::OK,
-            Json(ServerResponse::<OsType> {
+            Json(ServerResponse {
                 message: "This Filey server is healthy".into(),
-                data: type_().into(),
+                data: ServerInfo {
+                    protocol_version: PROTOCOL_VERSION,
+                    app_version: env!("CARGO_PKG_VERSION").into(),
+                    os_type: OsType::current(),
+                    device_id: Some(identity.id),
+                    device_name: Some(identity.name),
+                    public_key: Some(identity.public_key),
+                    capabilities,
+                },
+                code: None,
             }),
         )
             .into_response())
@@ -69,28 +169,231 @@ pub fn info() -> Router<ServerState> {
     Router::new().route("/info", get(handler))
 }
 
-// Show the list of PUBLIC files
-pub fn get_files() -> Router<ServerState> {
+/*
+ * A plain HTML page listing the PUBLIC files and text snippets, served at /
+ * This is for devices without Filey, anyone with a browser can preview or download the files
+ * The page is self contained (no scripts, no external styles), so it works on locked down machines
+ */
+pub fn index() -> Router<ServerState> {
     async fn handler(State(ServerState { db, .. }): State<ServerState>) -> Result<Response, Error> {
-        let db_files = sqlx::query_as!(
-            FileResponse,
+        let now = now();
+        let items = sqlx::query!(
             r#"
                 select
                     id as "id!: Hyphenated",
-                    name,
-                    mime
+                    name as "name!",
+                    mime as "mime!",
+                    size,
+                    'file' as "kind!: ItemKind"
                 from files
+                where
+                    visibility = 'public'
+                and missing = false
+                and (share_until is null or share_until > $1)
+                and (max_downloads is null or download_count < max_downloads)
+                union all
+                select
+                    id,
+                    title,
+                    'text/plain',
+                    length(cast(body as blob)),
+                    'snippet'
+                from snippets
                 where visibility = 'public'
-            "#
+                order by 2 collate nocase
+            "#,
+            now
         )
         .fetch_all(&db)
         .await?;
 
+        let rows = match items.is_empty() {
+            true => r#"<tr><td class="empty">Nothing is shared right now</td></tr>"#.to_string(),
+            false => items
+                .iter()
+                .map(|item| {
+                    // concat! hides the names from format!, so they are all passed by name
+                    format!(
+                        concat!(
+                            r#"<tr><td class="icon">{icon}</td>"#,
+                            r#"<td class="name">{name}</td>"#,
+                            r#"<td class="size">{size}</td>"#,
+                            r#"<td class="actions">"#,
+                            r#"<a href="{href}?mode=view" target="_blank">View</a>"#,
+                            r#"<a href="{href}?mode=download" download>Download</a>"#,
+                            r#"</td></tr>"#
+                        ),
+                        icon = mime_icon(&item.kind, &item.mime),
+                        name = escape_html(&item.name),
+                        size = item.size.map(format_size).unwrap_or_default(),
+                        href = format!("/files/{}", item.id),
+                    )
+                })
+                .collect::<Vec<_>>()
+                .join("\n"),
+        };
+
+        let count = match items.len() {
+            1 => "1 item".to_string(),
+            count => format!("{count} items"),
+        };
+
+        Ok(Html(
+            include_str!("index.html")
+                .replace("{{count}}", &count)
+                .replace("{{rows}}", &rows),
+        )
+        .into_response())
+    }
+    Router::new().route("/", get(handler))
+}
+
+// An emoji that hints at what kind of file it is, good enough without shipping icon files
+fn mime_icon(kind: &ItemKind, mime: &str) -> &'static str {
+    match (kind, mime.split('/').next().unwrap_or_default()) {
+        (ItemKind::Snippet, _) => "\u{1F4DD}",
+        (_, "image") => "\u{1F5BC}\u{FE0F}",
+        (_, "video") => "\u{1F3AC}",
+        (_, "audio") => "\u{1F3B5}",
+        (_, "text") => "\u{1F4C4}",
+        _ if mime == "application/pdf" => "\u{1F4D5}",
+        _ if mime.contains("zip") || mime.contains("compressed") || mime.contains("tar") => {
+            "\u{1F4E6}"
+        }
+        _ => "\u{1F4CE}",
+    }
+}
+
+// 1536 => "1.5 KB"
+fn format_size(size: i64) -> String {
+    const UNITS: [&str; 5] = ["B", "KB", "MB", "GB", "TB"];
+    let mut size = size as f64;
+    let mut unit = 0;
+    while size >= 1024.0 && unit < UNITS.len() - 1 {
+        size /= 1024.0;
+        unit += 1;
+    }
+    match unit {
+        0 => format!("{size} {}", UNITS[unit]),
+        _ => format!("{size:.1} {}", UNITS[unit]),
+    }
+}
+
+fn escape_html(text: &str) -> String {
+    text.replace('&', "&amp;")
+        .replace('<', "&lt;")
+        .replace('>', "&gt;")
+        .replace('"', "&quot;")
+        .replace('\'', "&#39;")
+}
+
+/*
+ * Server-Sent Events feed of the changes to the shared list
+ * Every time a file or snippet gets added, removed, or goes public or private,
+ * an event named "file" is pushed to the peers listening on this route
+ *
+ * A peer only hears of what /files lists for it, see peer_event
+ */
+pub fn events() -> Router<ServerState> {
+    async fn handler(
+        State(ServerState {
+            db, file_events, ..
+        }): State<ServerState>,
+        Requester(requester): Requester,
+    ) -> Result<Response, Error> {
+        /*
+         * Each peer gets its own receiver
+         * If a peer falls too far behind, the events it missed are skipped
+         */
+        let receiver = file_events.subscribe();
+        let listed: HashSet<Uuid> = list_shared(&db, requester)
+            .await?
+            .into_iter()
+            .map(|file| file.id)
+            .collect();
+        let listed = Arc::new(Mutex::new(listed));
+
+        let stream = BroadcastStream::new(receiver)
+            .filter_map(Result::ok)
+            .then(move |event| {
+                let db = db.clone();
+                let listed = listed.clone();
+                async move {
+                    peer_event(&db, requester, &listed, event)
+                        .await
+                        .map_err(|err| error!("Cannot filter the event: {err}"))
+                        .ok()
+                        .flatten()
+                }
+            })
+            .filter_map(|event| event.map(|event| Event::default().event("file").json_data(event)));
+
+        Ok(Sse::new(stream)
+            .keep_alive(KeepAlive::default())
+            .into_response())
+    }
+    Router::new().route("/events", get(handler))
+}
+
+/*
+ * What a peer gets to see of a change to the shared list
+ * `listed` holds the ids the peer has seen listed so far. Once one of them is no longer listed
+ * (removed, turned private or unlisted, or no longer shared with the peer), the peer gets
+ * a Removed event for it.
+ * Changes to the files it never saw listed are not sent at all, so an unlisted file
+ * does not give its id away
+ */
+async fn peer_event(
+    db: &SqlitePool,
+    requester: Option<Uuid>,
+    listed: &Mutex<HashSet<Uuid>>,
+    event: FileEvent,
+) -> Result<Option<FileEvent>, Error> {
+    let (id, is_listed) = match &event {
+        FileEvent::Removed { id } => (*id, false),
+        FileEvent::Added { file, .. }
+        | FileEvent::VisibilityChanged { file, .. }
+        | FileEvent::AccessChanged { file, .. } => (
+            file.id,
+            list_shared(db, requester)
+                .await?
+                .iter()
+                .any(|listed_file| listed_file.id == file.id),
+        ),
+    };
+
+    let mut listed = listed.lock().unwrap_or_else(|err| err.into_inner());
+    Ok(match (is_listed, event) {
+        // Just shared with the peer, to the peer it is as good as added
+        (true, FileEvent::AccessChanged { file, visibility }) => listed
+            .insert(id)
+            .then_some(FileEvent::Added { file, visibility }),
+        (true, event) => {
+            listed.insert(id);
+            Some(event)
+        }
+        (false, _) => listed.remove(&id).then_some(FileEvent::Removed { id }),
+    })
+}
+
+/*
+ * Show the list of PUBLIC files, text snippets are listed along with them
+ * Unlisted files and snippets are left out, they are only served by their id on /files/{id}
+ * Private files are listed as well to the peers they are shared with, see files::models::FileAcl
+ */
+pub fn get_files() -> Router<ServerState> {
+    async fn handler(
+        State(ServerState { db, .. }): State<ServerState>,
+        Requester(requester): Requester,
+    ) -> Result<Response, Error> {
+        let db_files = list_shared(&db, requester).await?;
+
         Ok((
             StatusCode::OK,
             Json(ServerResponse {
                 message: "Get all files success".into(),
                 data: db_files,
+                code: None,
             }),
         )
             .into_response())
@@ -98,6 +401,58 @@ pub fn get_files() -> Router<ServerState> {
     Router::new().route("/files", get(handler))
 }
 
+// What /files lists to the requester, see get_files
+async fn list_shared(db: &SqlitePool, requester: Option<Uuid>) -> Result<Vec<FileResponse>, Error> {
+    let requester = requester.map(|device_id| device_id.to_string());
+    let now = now();
+    Ok(sqlx::query_as!(
+        FileResponse,
+        r#"
+            select
+                id as "id!: Hyphenated",
+                name,
+                mime,
+                'file' as "kind!: ItemKind",
+                passphrase is not null as "encrypted!: bool"
+            from files
+            where
+                missing = false
+            and (share_until is null or share_until > $2)
+            and (max_downloads is null or download_count < max_downloads)
+            and (
+                visibility = 'public'
+                or exists (
+                    select 1
+                    from file_acl
+                    where
+                        file_acl.file_id = files.id
+                    and (
+                        file_acl.device_id = $1
+                        or file_acl.group_id in (
+                            select group_id
+                            from peer_group_members
+                            where device_id = $1
+                        )
+                    )
+                )
+            )
+            union all
+            select
+                id,
+                title,
+                'text/plain',
+                'snippet',
+                false
+            from snippets
+            where visibility = 'public'
+        "#,
+        requester,
+        now
+    )
+    .fetch_all(db)
+    .await?)
+}
+
 /*
  * This is for additional query
  * ?mode=download or ?mode=view
@@ -116,12 +471,25 @@ struct ModeQuery {
     mode: Option<Mode>,
 }
 
+// Builds the Content-Disposition header value based on the mode
+fn content_disposition(mode: Mode, name: &str) -> String {
+    format!(
+        "{}; filename={name}",
+        match mode {
+            Mode::View => "inline",
+            Mode::Download => "attachment",
+        }
+    )
+}
+
 // Returns the file content
 pub fn get_file() -> Router<ServerState> {
     async fn handler(
-        State(ServerState { db, app_handle }): State<ServerState>,
-        Path(id): Path<Uuid>,
+        State(state): State<ServerState>,
+        Path(file_id): Path<Uuid>,
         Query(ModeQuery { mode }): Query<ModeQuery>,
+        Requester(requester): Requester,
+        method: Method,
         range: Option<TypedHeader<Range>>,
     ) -> Result<Response, Error> {
         /*
@@ -129,49 +497,124 @@ pub fn get_file() -> Router<ServerState> {
          * Default is view
          */
         let mode = mode.unwrap_or(Mode::View);
-        let id = id.to_string();
+        let id = file_id.to_string();
+        let requester = requester.map(|device_id| device_id.to_string());
+        let now = now();
 
-        // Get the exact file by id from the path, and it is also has to be set public
-        let row = sqlx::query!(
-            "
-                select name, path, mime
+        /*
+         * Get the exact file by id from the path, and it is also has to be set public or unlisted,
+         * or shared with the requesting peer
+         */
+        let Some(row) = sqlx::query!(
+            r#"
+                select
+                    name,
+                    path,
+                    mime,
+                    passphrase,
+                    max_downloads is not null as "limited!: bool"
                 from files
                 where
                     id = $1
-                and visibility = 'public'
+                and missing = false
+                and (share_until is null or share_until > $3)
+                and (max_downloads is null or download_count < max_downloads)
+                and (
+                    visibility in ('public', 'unlisted')
+                    or exists (
+                        select 1
+                        from file_acl
+                        where
+                            file_acl.file_id = files.id
+                        and (
+                            file_acl.device_id = $2
+                            or file_acl.group_id in (
+                                select group_id
+                                from peer_group_members
+                                where device_id = $2
+                            )
+                        )
+                    )
+                )
                 limit 1
-            ",
-            id
+            "#,
+            id,
+            requester,
+            now
         )
-        .fetch_one(&db)
-        .await?;
+        .fetch_optional(&state.db)
+        .await?
+        else {
+            /*
+             * Not a file, but it might be a text snippet
+             * Snippets live in the database, so the body is sent straight away as plain text
+             */
+            let snippet = sqlx::query!(
+                "
+                    select title, body
+                    from snippets
+                    where
+                        id = $1
+                    and visibility in ('public', 'unlisted')
+                    limit 1
+                ",
+                id
+            )
+            .fetch_one(&state.db)
+            .await?;
+
+            return Ok((
+                AppendHeaders([
+                    (CONTENT_TYPE, "text/plain; charset=utf-8".to_string()),
+                    (
+                        CONTENT_DISPOSITION,
+                        content_disposition(mode, &format!("{}.txt", snippet.title)),
+                    ),
+                ]),
+                snippet.body,
+            )
+                .into_response());
+        };
 
         // Extract the variables from select statement
         let name = row.name;
         let path = row.path;
         let mime = row.mime;
 
+        let file = state.files.open(&path)?;
+
         /*
-         *  Opens the file using tauri's plugin-fs file opener
-         *
-         *  Why not just use std::fs::File or tokio::fs::File you ask?
-         *  Well both aforementioned File struct can open desktop file path,
-         *  but NOT mobile platforms
-         *
-         *  Since mobile platforms only return content URI, therefore std::fs::File
-         *  or tokio::fs::File can't read that.
-         *
-         *  However tauri provides plugin-fs that can get the file content from
-         *  both desktop path and content URI, and it returns std::fs::File, which can
-         *  be converted to tokio::fs::File, how convenient is that?
+         * Files shared with a passphrase are sent encrypted, see files::encryption
+         * The encrypted size is not known in advance, so they are always sent whole
          */
-        let file: tokio::fs::File = app_handle
-            .fs()
-            .open(
-                SafeFilePath::from_str(&path)?,
-                OpenOptions::new().read(true).clone(),
-            )?
-            .into();
+        if let Some(passphrase) = row.passphrase {
+            let response = (
+                AppendHeaders([
+                    (CONTENT_TYPE, "application/octet-stream".to_string()),
+                    (
+                        CONTENT_DISPOSITION,
+                        content_disposition(Mode::Download, &format!("{name}.age")),
+                    ),
+                ]),
+                encrypted_body(
+                    file,
+                    state.passphrase_key.decrypt_passphrase(&passphrase)?,
+                    &state.encryptions,
+                    &name,
+                )?,
+            )
+                .into_response();
+            return count_download(
+                &state.db,
+                &state.file_events,
+                file_id,
+                row.limited,
+                &method,
+                response,
+            )
+            .await;
+        }
+
         /*
          *  axum_range's KnownSize will setup apropriate headers to tell the browser
          *  on the requesting side to STREAM the file.
@@ -187,23 +630,1011 @@ pub fn get_file() -> Router<ServerState> {
          * Append extra headers, indicating the mime for the browser to know how to render
          * along with the mode to view the content in browser, or just download it
          */
-        Ok((
+        let response = (
             AppendHeaders([
                 (CONTENT_TYPE, mime),
-                (
-                    CONTENT_DISPOSITION,
-                    format!(
-                        "{}; filename={name}",
-                        match mode {
-                            Mode::View => "inline",
-                            Mode::Download => "attachment",
-                        }
-                    ),
-                ),
+                (CONTENT_DISPOSITION, content_disposition(mode, &name)),
             ]),
             Ranged::new(range, body),
         )
-            .into_response())
+            .into_response();
+
+        // Counted against max_downloads once it is sent, see files::expiry
+        count_download(
+            &state.db,
+            &state.file_events,
+            file_id,
+            row.limited,
+            &method,
+            response,
+        )
+        .await
     }
     Router::new().route("/files/{id}", get(handler))
 }
+
+#[derive(Serialize, Deserialize)]
+struct BlockSizeQuery {
+    block_size: Option<u64>,
+}
+
+/*
+ * Block signatures of a PUBLIC or unlisted file (or one shared with the peer), for peers that
+ * have an older copy of it
+ * Encrypted files have none, the signatures would tell about their content
+ * They only download the blocks they are missing afterwards, with Range requests on /files/{id}
+ * ?block_size=N picks the block size, otherwise it depends on the size of the file
+ */
+pub fn signatures() -> Router<ServerState> {
+    async fn handler(
+        State(state): State<ServerState>,
+        Path(id): Path<Uuid>,
+        Query(BlockSizeQuery { block_size }): Query<BlockSizeQuery>,
+        Requester(requester): Requester,
+    ) -> Result<Response, Error> {
+        let id = id.to_string();
+        let requester = requester.map(|device_id| device_id.to_string());
+        let now = now();
+        let row = sqlx::query!(
+            "
+                select path
+                from files
+                where
+                    id = $1
+                and missing = false
+                and passphrase is null
+                and (share_until is null or share_until > $3)
+                and (max_downloads is null or download_count < max_downloads)
+                and (
+                    visibility in ('public', 'unlisted')
+                    or exists (
+                        select 1
+                        from file_acl
+                        where
+                            file_acl.file_id = files.id
+                        and (
+                            file_acl.device_id = $2
+                            or file_acl.group_id in (
+                                select group_id
+                                from peer_group_members
+                                where device_id = $2
+                            )
+                        )
+                    )
+                )
+                limit 1
+            ",
+            id,
+            requester,
+            now
+        )
+        .fetch_one(&state.db)
+        .await?;
+
+        let file = state.files.open(&row.path)?;
+        let size = file.metadata().await?.len();
+        let block_size = block_size
+            .unwrap_or_else(|| block_size_for(size))
+            .clamp(MIN_BLOCK_SIZE, MAX_BLOCK_SIZE);
+
+        Ok((
+            StatusCode::OK,
+            Json(ServerResponse {
+                message: "Get file signatures success".into(),
+                data: compute_signatures(file, block_size).await?,
+                code: None,
+            }),
+        )
+            .into_response())
+    }
+    Router::new().route("/files/{id}/signatures", get(handler))
+}
+
+#[cfg(feature = "gui")]
+#[derive(Serialize, Deserialize)]
+struct OffsetQuery {
+    offset: Option<u64>,
+}
+
+#[cfg(feature = "gui")]
+#[derive(Serialize, Deserialize)]
+struct LimitQuery {
+    limit: Option<i64>,
+}
+
+/*
+ * Clipboard sync between peers
+ * POST => a peer pushes its new clipboard content to us
+ * GET => a peer pulls the last N entries of our clipboard history (?limit=N), as much as fits
+ *        in MAX_CLIPBOARD_PULL_SIZE, images only when the sync includes them
+ * Both only work while clipboard sync is turned on, and the peer is one of the selected known
+ * peers, the requests have to be signed, see requester
+ */
+#[cfg(feature = "gui")]
+pub fn clipboard() -> Router<ServerState> {
+    /*
+     * Checks that clipboard sync is on and the peer is selected
+     * Returns the peer's device id, and whether the sync includes images
+     */
+    async fn allowed_peer(
+        app_handle: &AppHandle,
+        address: SocketAddr,
+        requester: Option<Uuid>,
+    ) -> Result<(Uuid, bool), Error> {
+        let state = app_handle.state::<AppState>();
+        let sync = state.clipboard_sync.lock().await;
+        match (sync.as_ref(), requester) {
+            (Some(sync), Some(device_id)) if sync.peers.contains(&device_id) => {
+                Ok((device_id, sync.include_images))
+            }
+            _ => Err(Error::ClipboardSyncDisabled(address.ip().to_string())),
+        }
+    }
+
+    async fn push_handler(
+        State(ServerState { db, app_handle, .. }): State<ServerState>,
+        ConnectInfo(address): ConnectInfo<SocketAddr>,
+        Requester(requester): Requester,
+        signed: Option<Extension<SignedContent>>,
+        body: Bytes,
+    ) -> Result<Response, Error> {
+        let app_handle = app_handle.ok_or(Error::Headless)?;
+        let (device_id, _) = allowed_peer(&app_handle, address, requester).await?;
+        check_signed_body(signed.as_ref().map(|Extension(signed)| signed), &body)?;
+        let content: ClipboardContent = serde_json::from_slice(&body)?;
+        content.check_size()?;
+
+        /*
+         * Remember the content before writing it into the clipboard,
+         * so the clipboard watcher does not push it right back to the peer
+         */
+        if let Some(sync) = app_handle
+            .state::<AppState>()
+            .clipboard_sync
+            .lock()
+            .await
+            .as_mut()
+        {
+            sync.last_hash = Some(content.hash_value());
+        }
+        write_clipboard(&app_handle, &content)?;
+
+        let entry = ClipboardEntry {
+            id: Uuid::new_v4(),
+            origin: device_id.to_string(),
+            created_at: now(),
+            content,
+        };
+        save_clipboard_entry(&db, &entry).await?;
+
+        // Let the UI know that the clipboard has been replaced by a peer
+        app_handle.emit("clipboard-received", &entry)?;
+
+        Ok((
+            StatusCode::OK,
+            Json(ServerResponse {
+                message: "Clipboard received".into(),
+                data: (),
+                code: None,
+            }),
+        )
+            .into_response())
+    }
+
+    async fn pull_handler(
+        State(ServerState { db, app_handle, .. }): State<ServerState>,
+        ConnectInfo(address): ConnectInfo<SocketAddr>,
+        Query(LimitQuery { limit }): Query<LimitQuery>,
+        Requester(requester): Requester,
+    ) -> Result<Response, Error> {
+        let app_handle = app_handle.ok_or(Error::Headless)?;
+        let (_, include_images) = allowed_peer(&app_handle, address, requester).await?;
+
+        // Default to the last 10 entries, and never more than 100 at once
+        let limit = limit.unwrap_or(10).clamp(1, 100);
+        let entries =
+            load_clipboard_history(&db, limit, include_images, MAX_CLIPBOARD_PULL_SIZE).await?;
+
+        Ok((
+            StatusCode::OK,
+            Json(ServerResponse {
+                message: "Get clipboard history success".into(),
+                data: entries,
+                code: None,
+            }),
+        )
+            .into_response())
+    }
+
+    Router::new()
+        .route("/clipboard", get(pull_handler).post(push_handler))
+        // Images are sent as base64, which is a third bigger than the raw bytes
+        .layer(DefaultBodyLimit::max(MAX_CLIPBOARD_IMAGE_SIZE * 2))
+}
+
+/*
+ * Receives a short text message from a peer
+ * The conversation is keyed by the peer's address, and the UI gets notified with an event
+ */
+#[cfg(feature = "gui")]
+pub fn messages() -> Router<ServerState> {
+    async fn handler(
+        State(ServerState { db, app_handle, .. }): State<ServerState>,
+        ConnectInfo(address): ConnectInfo<SocketAddr>,
+        Json(MessageRequest { sender, body }): Json<MessageRequest>,
+    ) -> Result<Response, Error> {
+        let app_handle = app_handle.ok_or(Error::Headless)?;
+        check_message_length(&body)?;
+
+        let message = MessageModel {
+            id: Uuid::new_v4(),
+            peer: address.ip().to_string(),
+            direction: Direction::Incoming,
+            sender: sender.clone(),
+            body,
+            created_at: now(),
+        };
+        save_message(&db, &message, Some(&sender)).await?;
+
+        app_handle.emit("message-received", &message)?;
+
+        Ok((
+            StatusCode::OK,
+            Json(ServerResponse {
+                message: "Message received".into(),
+                data: (),
+                code: None,
+            }),
+        )
+            .into_response())
+    }
+    Router::new().route("/messages", post(handler))
+}
+
+/*
+ * Files sent to us by a peer
+ * POST => the peer offers the files (sender, names, sizes), and waits until the user accepts
+ *         or declines, unless the peer signed it and is always allowed
+ *         The request expires after a while
+ * PUT => the peer uploads the files of an accepted request one by one, by their index,
+ *        continuing from ?offset=N if the upload got interrupted before
+ * GET => how many bytes of a file were uploaded so far
+ */
+#[cfg(feature = "gui")]
+pub fn transfers() -> Router<ServerState> {
+    async fn offer_handler(
+        State(ServerState { db, app_handle, .. }): State<ServerState>,
+        ConnectInfo(address): ConnectInfo<SocketAddr>,
+        Requester(requester): Requester,
+        signed: Option<Extension<SignedContent>>,
+        body: Bytes,
+    ) -> Result<Response, Error> {
+        let app_handle = app_handle.ok_or(Error::Headless)?;
+        check_signed_body(signed.as_ref().map(|Extension(signed)| signed), &body)?;
+        let TransferOffer { sender, files } = serde_json::from_slice(&body)?;
+
+        // The sizes come from the peer, a made up one cannot overflow the total
+        let total_size = files.iter().fold(0i64, |total, file| {
+            total.saturating_add(i64::try_from(file.size).unwrap_or(i64::MAX))
+        });
+        let request = TransferRequestModel {
+            id: Uuid::new_v4(),
+            peer: address.ip().to_string(),
+            device_id: requester,
+            sender,
+            total_size,
+            files,
+            status: TransferStatus::Pending,
+            created_at: now(),
+        };
+        save_transfer_request(&db, &request).await?;
+
+        let status = match is_always_allowed(&db, request.device_id).await? {
+            true => TransferStatus::Accepted,
+            false => wait_for_approval(&app_handle, &request).await?,
+        };
+        set_transfer_status(&db, request.id, &status).await?;
+
+        Ok((
+            StatusCode::OK,
+            Json(ServerResponse {
+                message: format!("Transfer request {status}"),
+                data: TransferReply {
+                    id: request.id,
+                    status,
+                },
+                code: None,
+            }),
+        )
+            .into_response())
+    }
+
+    // Checks that the request got accepted, and was made by this peer
+    async fn accepted_file(
+        db: &SqlitePool,
+        address: SocketAddr,
+        id: Uuid,
+        index: usize,
+    ) -> Result<(TransferRequestModel, TransferFile), Error> {
+        let request = load_transfer_request(db, id).await?;
+        if request.peer != address.ip().to_string() || request.status != TransferStatus::Accepted {
+            return Err(Error::TransferNotAccepted(id.to_string()));
+        }
+        let file = request
+            .files
+            .get(index)
+            .cloned()
+            .ok_or_else(|| Error::TransferNotFound(format!("{id}/files/{index}")))?;
+        Ok((request, file))
+    }
+
+    // How much of the file we already got, so an interrupted upload can continue from there
+    async fn received_handler(
+        State(ServerState { db, app_handle, .. }): State<ServerState>,
+        ConnectInfo(address): ConnectInfo<SocketAddr>,
+        Path((id, index)): Path<(Uuid, usize)>,
+    ) -> Result<Response, Error> {
+        let app_handle = app_handle.ok_or(Error::Headless)?;
+        let (_, file) = accepted_file(&db, address, id, index).await?;
+        let part = partial_path(&app_handle, &format!("{id}-{index}"))?;
+
+        // The partial file is gone once the file is complete, see receive_file
+        let received = match is_received(&db, id, index).await? {
+            true => file.size,
+            false => received_size(&part).await,
+        };
+
+        Ok((
+            StatusCode::OK,
+            Json(ServerResponse {
+                message: "Get received size success".into(),
+                data: received,
+                code: None,
+            }),
+        )
+            .into_response())
+    }
+
+    async fn upload_handler(
+        State(ServerState { db, app_handle, .. }): State<ServerState>,
+        ConnectInfo(address): ConnectInfo<SocketAddr>,
+        Path((id, index)): Path<(Uuid, usize)>,
+        Query(OffsetQuery { offset }): Query<OffsetQuery>,
+        body: Body,
+    ) -> Result<Response, Error> {
+        let app_handle = app_handle.ok_or(Error::Headless)?;
+        let (request, file) = accepted_file(&db, address, id, index).await?;
+
+        let started = Instant::now();
+        let Some(path) =
+            receive_file(&app_handle, id, index, &file, offset.unwrap_or(0), body).await?
+        else {
+            return Ok((
+                StatusCode::OK,
+                Json(ServerResponse {
+                    message: "File partially received".into(),
+                    data: (),
+                    code: None,
+                }),
+            )
+                .into_response());
+        };
+
+        // An upload that got interrupted before only counts the time of its last part
+        let duration_ms = started.elapsed().as_millis() as i64;
+        let size = file.size as i64;
+        let destination = path.display().to_string();
+
+        app_handle.emit(
+            "transfer-file-received",
+            ReceivedFile {
+                request_id: id,
+                name: file.name.clone(),
+                path: destination.clone(),
+            },
+        )?;
+
+        // The file gets hashed for the history in the background, the peer does not need to wait
+        let entry = TransferHistoryModel {
+            id: Uuid::new_v4(),
+            direction: Direction::Incoming,
+            peer: request.peer,
+            peer_name: Some(request.sender),
+            name: file.name,
+            size: Some(size),
+            hash: None,
+            duration_ms,
+            speed: average_speed(size, duration_ms),
+            outcome: TransferOutcome::Completed,
+            destination: Some(destination.clone()),
+            error: None,
+            created_at: now(),
+        };
+        crate::runtime::spawn(async move {
+            if let Err(err) = record_transfer(&app_handle, entry, Some(destination)).await {
+                error!("Cannot add received file to the history: {err}");
+            }
+        });
+
+        Ok((
+            StatusCode::OK,
+            Json(ServerResponse {
+                message: "File received".into(),
+                data: (),
+                code: None,
+            }),
+        )
+            .into_response())
+    }
+
+    Router::new()
+        .route("/transfers", post(offer_handler))
+        .route(
+            "/transfers/{id}/files/{index}",
+            get(received_handler).put(upload_handler),
+        )
+        // The uploads are streamed to disk, and checked against the announced size instead
+        .layer(DefaultBodyLimit::disable())
+}
+
+/*
+ * Folders shared for syncing, see folder_sync::engine::sync_pair for the other side
+ * GET /folders => the list of shared folders
+ * GET /folders/{id}/manifest => every file inside the folder, with its size, modified time and hash
+ * GET /folders/{id}/file?path= => the content of a file inside the folder
+ * PUT, DELETE /folders/{id}/file?path= => changes a file inside the folder, if it is writable
+ * Only known peers get to see or change them, the requests have to be signed, see requester
+ */
+#[cfg(feature = "gui")]
+pub fn shared_folders() -> Router<ServerState> {
+    // A folder the peer is paired with, the others are not found, see SharedFolderPeers
+    async fn load_folder(
+        db: &SqlitePool,
+        id: Uuid,
+        requester: Uuid,
+    ) -> Result<SharedFolderModel, Error> {
+        let id = id.to_string();
+        let requester = requester.to_string();
+        let folder = sqlx::query_as!(
+            SharedFolderModel,
+            r#"
+                select
+                    id as "id!: Hyphenated",
+                    path,
+                    name,
+                    writable
+                from shared_folders
+                where
+                    id = $1
+                    and id in (
+                        select folder_id
+                        from shared_folder_peers
+                        where device_id = $2
+                    )
+            "#,
+            id,
+            requester
+        )
+        .fetch_one(db)
+        .await?;
+        Ok(folder)
+    }
+
+    async fn writable_folder(
+        db: &SqlitePool,
+        id: Uuid,
+        requester: Uuid,
+    ) -> Result<SharedFolderModel, Error> {
+        let folder = load_folder(db, id, requester).await?;
+        match folder.writable {
+            true => Ok(folder),
+            false => Err(Error::FolderNotWritable(folder.name)),
+        }
+    }
+
+    async fn list_handler(
+        State(ServerState { db, .. }): State<ServerState>,
+        KnownRequester(requester): KnownRequester,
+    ) -> Result<Response, Error> {
+        let requester = requester.to_string();
+        let folders = sqlx::query_as!(
+            SharedFolderResponse,
+            r#"
+                select
+                    id as "id!: Hyphenated",
+                    name,
+                    writable
+                from shared_folders
+                where id in (
+                    select folder_id
+                    from shared_folder_peers
+                    where device_id = $1
+                )
+            "#,
+            requester
+        )
+        .fetch_all(&db)
+        .await?;
+
+        Ok((
+            StatusCode::OK,
+            Json(ServerResponse {
+                message: "Get all shared folders success".into(),
+                data: folders,
+                code: None,
+            }),
+        )
+            .into_response())
+    }
+
+    async fn manifest_handler(
+        State(ServerState { db, files, .. }): State<ServerState>,
+        Path(id): Path<Uuid>,
+        KnownRequester(requester): KnownRequester,
+    ) -> Result<Response, Error> {
+        let folder = load_folder(&db, id, requester).await?;
+        let manifest = scan_folder(files.as_ref(), &db, std::path::Path::new(&folder.path)).await?;
+
+        Ok((
+            StatusCode::OK,
+            Json(ServerResponse {
+                message: "Get folder manifest success".into(),
+                data: manifest.into_values().collect::<Vec<_>>(),
+                code: None,
+            }),
+        )
+            .into_response())
+    }
+
+    async fn download_handler(
+        State(ServerState { db, files, .. }): State<ServerState>,
+        Path(id): Path<Uuid>,
+        Query(FolderFileQuery { path }): Query<FolderFileQuery>,
+        KnownRequester(requester): KnownRequester,
+        range: Option<TypedHeader<Range>>,
+    ) -> Result<Response, Error> {
+        let folder = load_folder(&db, id, requester).await?;
+        let path = resolve_in_folder(std::path::Path::new(&folder.path), &path)?;
+        let file = files.open(&path.to_string_lossy())?;
+
+        let body = KnownSize::file(file).await?;
+        let range = range.map(|TypedHeader(range)| range);
+        Ok((
+            AppendHeaders([(CONTENT_TYPE, "application/octet-stream")]),
+            Ranged::new(range, body),
+        )
+            .into_response())
+    }
+
+    async fn upload_handler(
+        State(ServerState { db, .. }): State<ServerState>,
+        Path(id): Path<Uuid>,
+        Query(FolderFileQuery { path }): Query<FolderFileQuery>,
+        KnownRequester(requester): KnownRequester,
+        // None on the encrypted transport, which already keeps the body from being changed
+        signed: Option<Extension<SignedContent>>,
+        body: Body,
+    ) -> Result<Response, Error> {
+        let folder = writable_folder(&db, id, requester).await?;
+        let target = resolve_in_folder(std::path::Path::new(&folder.path), &path)?;
+
+        let signed = signed.map(|Extension(SignedContent(hash))| hash);
+        write_body(&target, body, signed.as_deref()).await?;
+
+        Ok((
+            StatusCode::OK,
+            Json(ServerResponse {
+                message: "File synced".into(),
+                data: (),
+                code: None,
+            }),
+        )
+            .into_response())
+    }
+
+    async fn delete_handler(
+        State(ServerState { db, .. }): State<ServerState>,
+        Path(id): Path<Uuid>,
+        Query(FolderFileQuery { path }): Query<FolderFileQuery>,
+        KnownRequester(requester): KnownRequester,
+    ) -> Result<Response, Error> {
+        let folder = writable_folder(&db, id, requester).await?;
+        tokio::fs::remove_file(resolve_in_folder(
+            std::path::Path::new(&folder.path),
+            &path,
+        )?)
+        .await?;
+
+        Ok((
+            StatusCode::OK,
+            Json(ServerResponse {
+                message: "File deleted".into(),
+                data: (),
+                code: None,
+            }),
+        )
+            .into_response())
+    }
+
+    Router::new()
+        .route("/folders", get(list_handler))
+        .route("/folders/{id}/manifest", get(manifest_handler))
+        .route(
+            "/folders/{id}/file",
+            get(download_handler)
+                .put(upload_handler)
+                .delete(delete_handler),
+        )
+        .layer(DefaultBodyLimit::disable())
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::{
+        db::{insert_known_peer, test_db},
+        files::{
+            access::{FileAccess, LocalFileAccess},
+            models::Visibility,
+        },
+        noise::models::NoisePeer,
+    };
+    use axum::{
+        body::{to_bytes, Body, BodyDataStream},
+        http::Request,
+    };
+    use std::{io::Write, time::Duration};
+    use tempfile::NamedTempFile;
+    use tower::ServiceExt;
+
+    #[cfg(feature = "gui")]
+    use crate::device::models::content_sha256;
+
+    // Hands out the same file whatever path is asked for, and remembers the paths
+    struct FakeFileAccess {
+        file: NamedTempFile,
+        opened: Mutex<Vec<String>>,
+    }
+
+    impl FileAccess for FakeFileAccess {
+        fn open(&self, path: &str) -> Result<tokio::fs::File, Error> {
+            self.opened.lock().unwrap().push(path.to_string());
+            Ok(self.file.reopen()?.into())
+        }
+    }
+
+    async fn insert_file(db: &SqlitePool, id: Uuid, visibility: &str) {
+        sqlx::query(
+            "
+                insert into files
+                    (id, name, mime, visibility, path)
+                values
+                    ($1, 'hello.txt', 'text/plain', $2, $3)
+            ",
+        )
+        .bind(id.to_string())
+        .bind(visibility)
+        // The paths are unique
+        .bind(format!("content://{id}"))
+        .execute(db)
+        .await
+        .unwrap();
+    }
+
+    fn file_response(id: Uuid) -> FileResponse {
+        FileResponse {
+            id,
+            name: "hello.txt".into(),
+            mime: "text/plain".into(),
+            kind: ItemKind::File,
+            encrypted: false,
+        }
+    }
+
+    // Reads the /events body until `until` shows up in it
+    async fn read_events(stream: &mut BodyDataStream, until: &str) -> String {
+        let mut text = String::new();
+        while !text.contains(until) {
+            let chunk = tokio::time::timeout(Duration::from_secs(5), stream.next())
+                .await
+                .expect("The event never came")
+                .unwrap()
+                .unwrap();
+            text.push_str(&String::from_utf8_lossy(&chunk));
+        }
+        text
+    }
+
+    // A shared folder paired with one known peer
+    async fn insert_shared_folder(
+        db: &SqlitePool,
+        path: &std::path::Path,
+        writable: bool,
+        device_id: Uuid,
+    ) -> Uuid {
+        let folder_id = Uuid::new_v4();
+        sqlx::query(
+            "insert into shared_folders (id, path, name, writable) values ($1, $2, 'Folder', $3)",
+        )
+        .bind(folder_id.to_string())
+        .bind(path.display().to_string())
+        .bind(writable)
+        .execute(db)
+        .await
+        .unwrap();
+        sqlx::query("insert into shared_folder_peers (folder_id, device_id) values ($1, $2)")
+            .bind(folder_id.to_string())
+            .bind(device_id.to_string())
+            .execute(db)
+            .await
+            .unwrap();
+        folder_id
+    }
+
+    // The body of /files, as the requester sees it
+    async fn list_files(state: &ServerState, requester: Option<Uuid>) -> String {
+        let mut request = Request::get("/files").body(Body::empty()).unwrap();
+        request.extensions_mut().insert(NoisePeer(requester));
+        let response = get_files()
+            .with_state(state.clone())
+            .oneshot(request)
+            .await
+            .unwrap();
+        let body = to_bytes(response.into_body(), usize::MAX).await.unwrap();
+        String::from_utf8(body.to_vec()).unwrap()
+    }
+
+    #[tokio::test]
+    async fn get_file_reads_through_file_access() {
+        let db = test_db().await;
+        let id = Uuid::new_v4();
+        insert_file(&db, id, "public").await;
+
+        let mut file = NamedTempFile::new().unwrap();
+        file.write_all(b"hello").unwrap();
+        let files = Arc::new(FakeFileAccess {
+            file,
+            opened: Mutex::default(),
+        });
+        let state = ServerState::for_tests(db, files.clone()).await;
+
+        let request = Request::get(format!("/files/{id}"))
+            .body(Body::empty())
+            .unwrap();
+        let response = get_file().with_state(state).oneshot(request).await.unwrap();
+
+        assert_eq!(response.status(), StatusCode::OK);
+        let body = to_bytes(response.into_body(), usize::MAX).await.unwrap();
+        assert_eq!(&body[..], b"hello");
+        // The path in the row is not a real path, only the file access knows how to open it
+        assert_eq!(*files.opened.lock().unwrap(), [format!("content://{id}")]);
+    }
+
+    // The route needs no app, but only the app serves it, see start_server
+    #[cfg(feature = "gui")]
+    #[tokio::test]
+    async fn manifest_is_served_without_an_app() {
+        let db = test_db().await;
+        let device_id = Uuid::new_v4();
+        insert_known_peer(&db, device_id, None).await;
+
+        let dir = tempfile::tempdir().unwrap();
+        std::fs::write(dir.path().join("hello.txt"), b"hello").unwrap();
+        std::fs::create_dir(dir.path().join("notes")).unwrap();
+        std::fs::write(dir.path().join("notes").join("todo.txt"), b"").unwrap();
+        std::fs::write(dir.path().join(".hidden"), b"").unwrap();
+        let folder_id = insert_shared_folder(&db, dir.path(), false, device_id).await;
+        let state = ServerState::for_tests(db, Arc::new(LocalFileAccess)).await;
+
+        let mut request = Request::get(format!("/folders/{folder_id}/manifest"))
+            .body(Body::empty())
+            .unwrap();
+        request.extensions_mut().insert(NoisePeer(Some(device_id)));
+        let response = shared_folders()
+            .with_state(state)
+            .oneshot(request)
+            .await
+            .unwrap();
+
+        assert_eq!(response.status(), StatusCode::OK);
+        let body = to_bytes(response.into_body(), usize::MAX).await.unwrap();
+        let body = String::from_utf8(body.to_vec()).unwrap();
+        // SHA-256 of "hello"
+        assert!(body.contains("2cf24dba5fb0a30e26e83b2ac5b9e29e1b161e5c1fa7425e73043362938b9824"));
+        assert!(body.contains(r#""path":"notes/todo.txt""#));
+        assert!(!body.contains(".hidden"));
+    }
+
+    #[cfg(feature = "gui")]
+    #[tokio::test]
+    async fn folder_is_only_served_to_the_peers_it_is_paired_with() {
+        let db = test_db().await;
+        let paired = Uuid::new_v4();
+        let other = Uuid::new_v4();
+        insert_known_peer(&db, paired, None).await;
+        insert_known_peer(&db, other, None).await;
+
+        let dir = tempfile::tempdir().unwrap();
+        let folder_id = insert_shared_folder(&db, dir.path(), true, paired).await;
+        let state = ServerState::for_tests(db, Arc::new(LocalFileAccess)).await;
+
+        let get = |path: String, device_id: Uuid| {
+            let mut request = Request::get(path).body(Body::empty()).unwrap();
+            request.extensions_mut().insert(NoisePeer(Some(device_id)));
+            shared_folders().with_state(state.clone()).oneshot(request)
+        };
+
+        let response = get("/folders".into(), paired).await.unwrap();
+        let body = to_bytes(response.into_body(), usize::MAX).await.unwrap();
+        assert!(String::from_utf8(body.to_vec())
+            .unwrap()
+            .contains(&folder_id.to_string()));
+        let manifest = format!("/folders/{folder_id}/manifest");
+        let response = get(manifest.clone(), paired).await.unwrap();
+        assert_eq!(response.status(), StatusCode::OK);
+
+        // Another known peer does not even get to know the folder is there
+        let response = get("/folders".into(), other).await.unwrap();
+        let body = to_bytes(response.into_body(), usize::MAX).await.unwrap();
+        assert!(!String::from_utf8(body.to_vec())
+            .unwrap()
+            .contains(&folder_id.to_string()));
+        let response = get(manifest, other).await.unwrap();
+        assert_eq!(response.status(), StatusCode::NOT_FOUND);
+    }
+
+    #[cfg(feature = "gui")]
+    #[tokio::test]
+    async fn upload_not_matching_its_signed_hash_is_thrown_away() {
+        let db = test_db().await;
+        let device_id = Uuid::new_v4();
+        insert_known_peer(&db, device_id, None).await;
+
+        let dir = tempfile::tempdir().unwrap();
+        let folder_id = insert_shared_folder(&db, dir.path(), true, device_id).await;
+        let state = ServerState::for_tests(db, Arc::new(LocalFileAccess)).await;
+
+        let upload = |signed: &str| {
+            let mut request = Request::put(format!("/folders/{folder_id}/file?path=a.txt"))
+                .body(Body::from("changed on the way"))
+                .unwrap();
+            request.extensions_mut().insert(NoisePeer(Some(device_id)));
+            request
+                .extensions_mut()
+                .insert(SignedContent(signed.to_string()));
+            shared_folders().with_state(state.clone()).oneshot(request)
+        };
+
+        let response = upload(&content_sha256(b"hello")).await.unwrap();
+        assert_eq!(response.status(), StatusCode::FORBIDDEN);
+        assert!(!dir.path().join("a.txt").exists());
+
+        let response = upload(&content_sha256(b"changed on the way"))
+            .await
+            .unwrap();
+        assert_eq!(response.status(), StatusCode::OK);
+        assert_eq!(
+            std::fs::read_to_string(dir.path().join("a.txt")).unwrap(),
+            "changed on the way"
+        );
+    }
+
+    #[tokio::test]
+    async fn events_do_not_give_unlisted_files_away() {
+        let db = test_db().await;
+        let (listed, unlisted, added) = (Uuid::new_v4(), Uuid::new_v4(), Uuid::new_v4());
+        insert_file(&db, listed, "public").await;
+        insert_file(&db, unlisted, "private").await;
+        let state = ServerState::for_tests(db.clone(), Arc::new(LocalFileAccess)).await;
+        let file_events = state.file_events.clone();
+
+        let request = Request::get("/events").body(Body::empty()).unwrap();
+        let response = events().with_state(state).oneshot(request).await.unwrap();
+
+        // A private file goes unlisted, and a file the peer saw listed goes unlisted too
+        for id in [unlisted, listed] {
+            sqlx::query("update files set visibility = 'unlisted' where id = $1")
+                .bind(id.to_string())
+                .execute(&db)
+                .await
+                .unwrap();
+            file_events
+                .send(FileEvent::VisibilityChanged {
+                    file: file_response(id),
+                    visibility: Visibility::Unlisted,
+                })
+                .unwrap();
+        }
+        insert_file(&db, added, "public").await;
+        file_events
+            .send(FileEvent::Added {
+                file: file_response(added),
+                visibility: Visibility::Public,
+            })
+            .unwrap();
+
+        let mut stream = response.into_body().into_data_stream();
+        let text = read_events(&mut stream, &added.to_string()).await;
+        assert!(!text.contains(&unlisted.to_string()));
+        assert!(text.contains(&format!(r#"{{"type":"removed","id":"{listed}"}}"#)));
+        assert!(!text.contains("visibilityChanged"));
+    }
+
+    #[tokio::test]
+    async fn events_follow_the_acl() {
+        let db = test_db().await;
+        let (file_id, device_id) = (Uuid::new_v4(), Uuid::new_v4());
+        insert_file(&db, file_id, "private").await;
+        insert_known_peer(&db, device_id, None).await;
+        let state = ServerState::for_tests(db.clone(), Arc::new(LocalFileAccess)).await;
+        let file_events = state.file_events.clone();
+
+        let mut request = Request::get("/events").body(Body::empty()).unwrap();
+        request.extensions_mut().insert(NoisePeer(Some(device_id)));
+        let response = events().with_state(state).oneshot(request).await.unwrap();
+        let mut stream = response.into_body().into_data_stream();
+        let access_changed = || FileEvent::AccessChanged {
+            file: file_response(file_id),
+            visibility: Visibility::Private,
+        };
+
+        sqlx::query("insert into file_acl (file_id, device_id) values ($1, $2)")
+            .bind(file_id.to_string())
+            .bind(device_id.to_string())
+            .execute(&db)
+            .await
+            .unwrap();
+        file_events.send(access_changed()).unwrap();
+        let text = read_events(&mut stream, &file_id.to_string()).await;
+        assert!(text.contains(r#""type":"added""#));
+
+        sqlx::query("delete from file_acl where file_id = $1")
+            .bind(file_id.to_string())
+            .execute(&db)
+            .await
+            .unwrap();
+        file_events.send(access_changed()).unwrap();
+        let text = read_events(&mut stream, &file_id.to_string()).await;
+        assert!(text.contains(&format!(r#"{{"type":"removed","id":"{file_id}"}}"#)));
+    }
+
+    #[tokio::test]
+    async fn private_files_are_listed_to_the_peers_they_are_shared_with() {
+        let db = test_db().await;
+        let (in_group, outsider) = (Uuid::new_v4(), Uuid::new_v4());
+        for device_id in [in_group, outsider] {
+            insert_known_peer(&db, device_id, None).await;
+        }
+        let (shared, private) = (Uuid::new_v4(), Uuid::new_v4());
+        insert_file(&db, shared, "private").await;
+        insert_file(&db, private, "private").await;
+        let group_id = Uuid::new_v4().to_string();
+        sqlx::query("insert into peer_groups (id, name, created_at) values ($1, 'Family', 0)")
+            .bind(&group_id)
+            .execute(&db)
+            .await
+            .unwrap();
+        sqlx::query("insert into peer_group_members (group_id, device_id) values ($1, $2)")
+            .bind(&group_id)
+            .bind(in_group.to_string())
+            .execute(&db)
+            .await
+            .unwrap();
+        sqlx::query("insert into file_acl (file_id, group_id) values ($1, $2)")
+            .bind(shared.to_string())
+            .bind(&group_id)
+            .execute(&db)
+            .await
+            .unwrap();
+        let state = ServerState::for_tests(db, Arc::new(LocalFileAccess)).await;
+
+        let listed = list_files(&state, Some(in_group)).await;
+        assert!(listed.contains(&shared.to_string()));
+        assert!(!listed.contains(&private.to_string()));
+
+        for requester in [Some(outsider), None] {
+            let listed = list_files(&state, requester).await;
+            assert!(!listed.contains(&shared.to_string()));
+        }
+    }
+}
@@ -0,0 +1,915 @@
+/*
+  Filey - simple peer-to-peer file sending across devices on different platforms
+  Copyright (C) 2024 Wander Watterson
+
+  This program is free software: you can redistribute it and/or modify
+  it under the terms of the GNU General Public License as published by
+  the Free Software Foundation, either version 3 of the License, or
+  (at your option) any later version.
+
+  This program is distributed in the hope that it will be useful,
+  but WITHOUT ANY WARRANTY; without even the implied warranty of
+  MERCHANTABILITY or FITNESS FOR A PARTICULAR PURPOSE. See the
+  GNU General Public License for more details.
+
+  You should have received a copy of the GNU General Public License
+  along with this program. If not, see <https://www.gnu.org/licenses/>.
+*/
+
+/*
+ * A small WebDAV server, so the file managers of the peers (Nautilus, Finder, Explorer, ...)
+ * can mount this device like a network drive, at http://host:38899/dav
+ *
+ * /dav/files => the files and text snippets that /files lists, the PUBLIC ones, and the ones
+ *              shared with the peer that logged in
+ * /dav/folders/{folder name} => the shared folders paired with the peer that logged in,
+ *                               see folder_sync
+ *
+ * File managers cannot sign their requests, they log in with HTTP Basic instead: any user name,
+ * and the password this device made for the peer, see peers::commands::create_webdav_token
+ * /dav/folders asks for it, the rest can be browsed without logging in
+ * Like everything on 38899 it goes over plain HTTP, the password can be seen on the network
+ *
+ * Everything can be read (PROPFIND, GET with ranges), but only writable shared folders can
+ * be changed (PUT, DELETE, MKCOL, MOVE). There is no locking, some clients mount it read only
+ * because of that
+ */
+
+use axum::{
+    body::Body,
+    extract::FromRequestParts,
+    extract::{Path, State},
+    http::{
+        header::{ALLOW, AUTHORIZATION, CONTENT_TYPE, WWW_AUTHENTICATE},
+        request::Parts,
+        HeaderMap, Method,
+    },
+    response::{AppendHeaders, IntoResponse, Response},
+    routing::any,
+    Router,
+};
+use axum_extra::{headers::Range, TypedHeader};
+use axum_range::{KnownSize, Ranged};
+use base64::{engine::general_purpose::STANDARD, Engine};
+use percent_encoding::{percent_decode_str, utf8_percent_encode, AsciiSet, NON_ALPHANUMERIC};
+use reqwest::StatusCode;
+use sqlx::SqlitePool;
+use std::{collections::HashSet, path::PathBuf, time::SystemTime};
+use uuid::{fmt::Hyphenated, Uuid};
+
+use super::{models::ServerState, requester::Requester};
+use crate::{
+    db::now,
+    device::models::content_sha256,
+    error::Error,
+    files::expiry::count_download,
+    folder_sync::{
+        files::{resolve_in_folder, write_body},
+        models::SharedFolderModel,
+    },
+};
+
+const ALLOWED_METHODS: &str = "OPTIONS, PROPFIND, GET, HEAD, PUT, DELETE, MKCOL, MOVE";
+
+// Everything in a path segment except the unreserved characters gets percent encoded
+const PATH_SEGMENT: &AsciiSet = &NON_ALPHANUMERIC
+    .remove(b'-')
+    .remove(b'.')
+    .remove(b'_')
+    .remove(b'~');
+
+// What a path under /dav points to
+enum Resource {
+    Root,
+    Files,
+    Folders,
+    SharedFile {
+        id: Uuid,
+        path: String,
+        mime: String,
+        // Whether it has a max_downloads, see files::expiry::count_download
+        limited: bool,
+    },
+    Snippet {
+        body: String,
+    },
+    // A file or directory inside a shared folder, or the shared folder itself
+    FolderEntry {
+        folder: SharedFolderModel,
+        path: PathBuf,
+    },
+}
+
+// The properties of a resource that are sent back on PROPFIND
+struct Properties {
+    href: String,
+    name: String,
+    collection: bool,
+    size: Option<u64>,
+    modified: Option<SystemTime>,
+    mime: Option<String>,
+}
+
+/*
+ * The peer that logged in with the WebDAV password this device made for it,
+ * or the known peer that sent the request, see Requester
+ * A wrong password asks the file manager to log in again
+ */
+struct DavRequester(Option<Uuid>);
+
+impl FromRequestParts<ServerState> for DavRequester {
+    type Rejection = Response;
+
+    async fn from_request_parts(parts: &mut Parts, state: &ServerState) -> Result<Self, Response> {
+        let Some(password) = basic_password(&parts.headers) else {
+            return match Requester::from_request_parts(parts, state).await {
+                Ok(Requester(requester)) => Ok(DavRequester(requester)),
+                Err(err) => Err(err.into_response()),
+            };
+        };
+
+        let token = content_sha256(password.as_bytes());
+        match sqlx::query_scalar!(
+            r#"
+                select device_id as "device_id!: Hyphenated"
+                from known_peers
+                where webdav_token = $1
+            "#,
+            token
+        )
+        .fetch_optional(&state.db)
+        .await
+        {
+            Ok(Some(device_id)) => Ok(DavRequester(Some(device_id.into_uuid()))),
+            Ok(None) => Err(challenge()),
+            Err(err) => Err(Error::from(err).into_response()),
+        }
+    }
+}
+
+// Authorization: Basic base64(user:password), the user name does not matter
+fn basic_password(headers: &HeaderMap) -> Option<String> {
+    let credentials = headers
+        .get(AUTHORIZATION)?
+        .to_str()
+        .ok()?
+        .strip_prefix("Basic ")?;
+    let credentials = String::from_utf8(STANDARD.decode(credentials.trim()).ok()?).ok()?;
+    let (_, password) = credentials.split_once(':')?;
+    Some(password.to_string())
+}
+
+// Asks the file manager to log in, see DavRequester
+fn challenge() -> Response {
+    (
+        StatusCode::UNAUTHORIZED,
+        AppendHeaders([(WWW_AUTHENTICATE, r#"Basic realm="Filey", charset="UTF-8""#)]),
+    )
+        .into_response()
+}
+
+pub fn webdav() -> Router<ServerState> {
+    async fn root_handler(
+        state: State<ServerState>,
+        method: Method,
+        requester: DavRequester,
+        headers: HeaderMap,
+        range: Option<TypedHeader<Range>>,
+        body: Body,
+    ) -> Result<Response, Error> {
+        dispatch(
+            state,
+            method,
+            String::new(),
+            requester,
+            headers,
+            range,
+            body,
+        )
+        .await
+    }
+
+    async fn handler(
+        state: State<ServerState>,
+        method: Method,
+        Path(path): Path<String>,
+        requester: DavRequester,
+        headers: HeaderMap,
+        range: Option<TypedHeader<Range>>,
+        body: Body,
+    ) -> Result<Response, Error> {
+        dispatch(state, method, path, requester, headers, range, body).await
+    }
+
+    Router::new()
+        .route("/dav", any(root_handler))
+        .route("/dav/", any(root_handler))
+        .route("/dav/{*path}", any(handler))
+}
+
+async fn dispatch(
+    State(state): State<ServerState>,
+    method: Method,
+    path: String,
+    DavRequester(requester): DavRequester,
+    headers: HeaderMap,
+    range: Option<TypedHeader<Range>>,
+    body: Body,
+) -> Result<Response, Error> {
+    let db = &state.db;
+    let segments = split_path(&path);
+
+    if method == Method::OPTIONS {
+        return Ok((
+            StatusCode::OK,
+            AppendHeaders([
+                ("DAV", "1"),
+                ("MS-Author-Via", "DAV"),
+                ("Allow", ALLOWED_METHODS),
+            ]),
+        )
+            .into_response());
+    }
+
+    // The shared folders are never shown to anonymous peers, file managers log in once asked to
+    if requester.is_none() && segments.first().is_some_and(|segment| segment == "folders") {
+        return Ok(challenge());
+    }
+
+    let Some(resource) = resolve(db, &segments, requester).await? else {
+        return Ok(StatusCode::NOT_FOUND.into_response());
+    };
+
+    match method.as_str() {
+        "PROPFIND" => propfind(&state, &headers, segments, resource, requester).await,
+        "GET" | "HEAD" => get(&state, resource, &method, range).await,
+        "PUT" | "DELETE" | "MKCOL" | "MOVE" => {
+            let Resource::FolderEntry { folder, path } = resource else {
+                return Ok(StatusCode::FORBIDDEN.into_response());
+            };
+            // The shared folder itself cannot be replaced, removed or moved
+            if !folder.writable || path == std::path::Path::new(&folder.path) {
+                return Ok(StatusCode::FORBIDDEN.into_response());
+            }
+            write(db, method, &headers, requester, folder, path, body).await
+        }
+        _ => Ok((
+            StatusCode::METHOD_NOT_ALLOWED,
+            AppendHeaders([(ALLOW, ALLOWED_METHODS)]),
+        )
+            .into_response()),
+    }
+}
+
+// "folders/My Stuff/a.txt" => ["folders", "My Stuff", "a.txt"], axum already decoded the path
+fn split_path(path: &str) -> Vec<String> {
+    path.split('/')
+        .filter(|segment| !segment.is_empty())
+        .map(str::to_string)
+        .collect()
+}
+
+fn href(segments: &[String], collection: bool) -> String {
+    let mut href = String::from("/dav/");
+    href.push_str(
+        &segments
+            .iter()
+            .map(|segment| utf8_percent_encode(segment, PATH_SEGMENT).to_string())
+            .collect::<Vec<_>>()
+            .join("/"),
+    );
+    if collection && !segments.is_empty() {
+        href.push('/');
+    }
+    href
+}
+
+async fn resolve(
+    db: &SqlitePool,
+    segments: &[String],
+    requester: Option<Uuid>,
+) -> Result<Option<Resource>, Error> {
+    let resource = match segments {
+        [] => Some(Resource::Root),
+        [files] if files == "files" => Some(Resource::Files),
+        [files, name] if files == "files" => shared_items(db, requester)
+            .await?
+            .into_iter()
+            .find(|(item_name, _)| item_name == name)
+            .map(|(_, resource)| resource),
+        [folders] if folders == "folders" => Some(Resource::Folders),
+        [folders, name, rest @ ..] if folders == "folders" => {
+            // Hidden files are the partial files of unfinished uploads and syncs
+            if rest.iter().any(|segment| segment.starts_with('.')) {
+                return Ok(None);
+            }
+            let Some(folder) = shared_folders(db, requester)
+                .await?
+                .into_iter()
+                .find(|folder| &folder.name == name)
+            else {
+                return Ok(None);
+            };
+            let path = match rest.is_empty() {
+                true => PathBuf::from(&folder.path),
+                false => resolve_in_folder(std::path::Path::new(&folder.path), &rest.join("/"))?,
+            };
+            Some(Resource::FolderEntry { folder, path })
+        }
+        _ => None,
+    };
+    Ok(resource)
+}
+
+/*
+ * The files and text snippets by name, like routes::get_files lists them to the requester
+ * Names that are taken already get " (2)", " (3)", ... so every item can be told apart
+ * Encrypted files are left out, they would be served here as they are on the disk
+ */
+async fn shared_items(
+    db: &SqlitePool,
+    requester: Option<Uuid>,
+) -> Result<Vec<(String, Resource)>, Error> {
+    let requester = requester.map(|device_id| device_id.to_string());
+    let now = now();
+    let files = sqlx::query!(
+        r#"
+            select
+                id as "id!: Hyphenated",
+                name,
+                path,
+                mime,
+                max_downloads is not null as "limited!: bool"
+            from files
+            where
+                missing = false
+            and passphrase is null
+            and (share_until is null or share_until > $2)
+            and (max_downloads is null or download_count < max_downloads)
+            and (
+                visibility = 'public'
+                or exists (
+                    select 1
+                    from file_acl
+                    where
+                        file_acl.file_id = files.id
+                    and (
+                        file_acl.device_id = $1
+                        or file_acl.group_id in (
+                            select group_id
+                            from peer_group_members
+                            where device_id = $1
+                        )
+                    )
+                )
+            )
+            order by name, id
+        "#,
+        requester,
+        now
+    )
+    .fetch_all(db)
+    .await?;
+
+    let snippets = sqlx::query!(
+        "
+            select title, body
+            from snippets
+            where visibility = 'public'
+            order by title, id
+        "
+    )
+    .fetch_all(db)
+    .await?;
+
+    let mut taken = HashSet::new();
+    let mut items = vec![];
+    for file in files {
+        items.push((
+            unique_name(&mut taken, &file.name),
+            Resource::SharedFile {
+                id: file.id.into_uuid(),
+                path: file.path,
+                mime: file.mime,
+                limited: file.limited,
+            },
+        ));
+    }
+    for snippet in snippets {
+        items.push((
+            unique_name(&mut taken, &format!("{}.txt", snippet.title)),
+            Resource::Snippet { body: snippet.body },
+        ));
+    }
+    Ok(items)
+}
+
+fn unique_name(taken: &mut HashSet<String>, name: &str) -> String {
+    let mut unique = name.to_string();
+    let mut count = 2;
+    while !taken.insert(unique.clone()) {
+        unique = match name.rsplit_once('.') {
+            Some((stem, extension)) if !stem.is_empty() => format!("{stem} ({count}).{extension}"),
+            _ => format!("{name} ({count})"),
+        };
+        count += 1;
+    }
+    unique
+}
+
+// The shared folders a peer is paired with, none when it is not a known peer
+async fn shared_folders(
+    db: &SqlitePool,
+    requester: Option<Uuid>,
+) -> Result<Vec<SharedFolderModel>, Error> {
+    let Some(requester) = requester else {
+        return Ok(vec![]);
+    };
+    let requester = requester.to_string();
+    let folders = sqlx::query_as!(
+        SharedFolderModel,
+        r#"
+            select
+                id as "id!: Hyphenated",
+                path,
+                name,
+                writable
+            from shared_folders
+            where id in (
+                select folder_id
+                from shared_folder_peers
+                where device_id = $1
+            )
+            order by name
+        "#,
+        requester
+    )
+    .fetch_all(db)
+    .await?;
+    Ok(folders)
+}
+
+// Nothing if the resource does not exist (anymore)
+async fn properties(
+    state: &ServerState,
+    segments: &[String],
+    resource: &Resource,
+) -> Option<Properties> {
+    let name = segments.last().cloned().unwrap_or_default();
+    let collection = |name: String| Properties {
+        href: href(segments, true),
+        name,
+        collection: true,
+        size: None,
+        modified: None,
+        mime: None,
+    };
+
+    match resource {
+        Resource::Root | Resource::Files | Resource::Folders => Some(collection(name)),
+        Resource::SharedFile { path, mime, .. } => {
+            let metadata = state.files.open(path).ok()?.metadata().await.ok()?;
+            Some(Properties {
+                href: href(segments, false),
+                name,
+                collection: false,
+                size: Some(metadata.len()),
+                modified: metadata.modified().ok(),
+                mime: Some(mime.clone()),
+            })
+        }
+        Resource::Snippet { body } => Some(Properties {
+            href: href(segments, false),
+            name,
+            collection: false,
+            size: Some(body.len() as u64),
+            modified: None,
+            mime: Some("text/plain; charset=utf-8".into()),
+        }),
+        Resource::FolderEntry { path, .. } => {
+            let metadata = tokio::fs::metadata(path).await.ok()?;
+            if metadata.is_dir() {
+                return Some(Properties {
+                    modified: metadata.modified().ok(),
+                    ..collection(name)
+                });
+            }
+            Some(Properties {
+                href: href(segments, false),
+                name,
+                collection: false,
+                size: Some(metadata.len()),
+                modified: metadata.modified().ok(),
+                mime: Some(
+                    mime_guess::from_path(path)
+                        .first_or_octet_stream()
+                        .to_string(),
+                ),
+            })
+        }
+    }
+}
+
+// The resources inside a collection, along with their path segments
+async fn children(
+    db: &SqlitePool,
+    segments: &[String],
+    resource: &Resource,
+    requester: Option<Uuid>,
+) -> Result<Vec<(Vec<String>, Resource)>, Error> {
+    let child = |name: &str| {
+        let mut child = segments.to_vec();
+        child.push(name.to_string());
+        child
+    };
+
+    let children = match resource {
+        Resource::Root => vec![
+            (child("files"), Resource::Files),
+            (child("folders"), Resource::Folders),
+        ],
+        Resource::Files => shared_items(db, requester)
+            .await?
+            .into_iter()
+            .map(|(name, resource)| (child(&name), resource))
+            .collect(),
+        Resource::Folders => shared_folders(db, requester)
+            .await?
+            .into_iter()
+            .map(|folder| {
+                (
+                    child(&folder.name),
+                    Resource::FolderEntry {
+                        path: PathBuf::from(&folder.path),
+                        folder,
+                    },
+                )
+            })
+            .collect(),
+        Resource::FolderEntry { folder, path } => {
+            let mut children = vec![];
+            let Ok(mut entries) = tokio::fs::read_dir(path).await else {
+                return Ok(children);
+            };
+            while let Some(entry) = entries.next_entry().await? {
+                let name = entry.file_name().to_string_lossy().to_string();
+                if name.starts_with('.') {
+                    continue;
+                }
+                children.push((
+                    child(&name),
+                    Resource::FolderEntry {
+                        folder: folder.clone(),
+                        path: entry.path(),
+                    },
+                ));
+            }
+            children
+        }
+        Resource::SharedFile { .. } | Resource::Snippet { .. } => vec![],
+    };
+    Ok(children)
+}
+
+/*
+ * Lists the properties of a resource, and of the resources inside of it unless "Depth: 0"
+ * "Depth: infinity" is treated like "Depth: 1", the whole tree is never walked at once
+ */
+async fn propfind(
+    state: &ServerState,
+    headers: &HeaderMap,
+    segments: Vec<String>,
+    resource: Resource,
+    requester: Option<Uuid>,
+) -> Result<Response, Error> {
+    let Some(own) = properties(state, &segments, &resource).await else {
+        return Ok(StatusCode::NOT_FOUND.into_response());
+    };
+
+    let depth = headers
+        .get("Depth")
+        .and_then(|depth| depth.to_str().ok())
+        .unwrap_or("infinity");
+
+    let mut entries = vec![];
+    if own.collection && depth != "0" {
+        for (child_segments, child) in children(&state.db, &segments, &resource, requester).await? {
+            if let Some(properties) = properties(state, &child_segments, &child).await {
+                entries.push(properties);
+            }
+        }
+    }
+    entries.insert(0, own);
+
+    let mut xml = String::from(
+        "<?xml version=\"1.0\" encoding=\"utf-8\"?>\n<D:multistatus xmlns:D=\"DAV:\">\n",
+    );
+    for entry in entries {
+        xml.push_str(&response_xml(&entry));
+    }
+    xml.push_str("</D:multistatus>\n");
+
+    Ok((
+        StatusCode::MULTI_STATUS,
+        AppendHeaders([(CONTENT_TYPE, "application/xml; charset=utf-8")]),
+        xml,
+    )
+        .into_response())
+}
+
+fn response_xml(properties: &Properties) -> String {
+    let mut props = format!(
+        "<D:displayname>{}</D:displayname>",
+        escape_xml(&properties.name)
+    );
+    props.push_str(match properties.collection {
+        true => "<D:resourcetype><D:collection/></D:resourcetype>",
+        false => "<D:resourcetype/>",
+    });
+    if let Some(size) = properties.size {
+        props.push_str(&format!("<D:getcontentlength>{size}</D:getcontentlength>"));
+    }
+    if let Some(modified) = properties.modified {
+        props.push_str(&format!(
+            "<D:getlastmodified>{}</D:getlastmodified>",
+            httpdate::fmt_http_date(modified)
+        ));
+    }
+    if let Some(mime) = &properties.mime {
+        props.push_str(&format!(
+            "<D:getcontenttype>{}</D:getcontenttype>",
+            escape_xml(mime)
+        ));
+    }
+
+    format!(
+        "<D:response><D:href>{}</D:href><D:propstat><D:prop>{props}</D:prop>\
+        <D:status>HTTP/1.1 200 OK</D:status></D:propstat></D:response>\n",
+        escape_xml(&properties.href)
+    )
+}
+
+fn escape_xml(text: &str) -> String {
+    text.replace('&', "&amp;")
+        .replace('<', "&lt;")
+        .replace('>', "&gt;")
+        .replace('"', "&quot;")
+        .replace('\'', "&apos;")
+}
+
+// The content of a file, with range support so videos can be played straight from the mount
+async fn get(
+    state: &ServerState,
+    resource: Resource,
+    method: &Method,
+    range: Option<TypedHeader<Range>>,
+) -> Result<Response, Error> {
+    let range = range.map(|TypedHeader(range)| range);
+
+    // Only the shared files count as downloads, see files::expiry
+    let (file, mime, counted) = match resource {
+        Resource::SharedFile {
+            id,
+            path,
+            mime,
+            limited,
+        } => (state.files.open(&path)?, mime, Some((id, limited))),
+        Resource::Snippet { body } => {
+            return Ok((
+                AppendHeaders([(CONTENT_TYPE, "text/plain; charset=utf-8")]),
+                body,
+            )
+                .into_response());
+        }
+        Resource::FolderEntry { path, .. } if path.is_file() => (
+            state.files.open(&path.to_string_lossy())?,
+            mime_guess::from_path(&path)
+                .first_or_octet_stream()
+                .to_string(),
+            None,
+        ),
+        Resource::FolderEntry { path, .. } if !path.exists() => {
+            return Ok(StatusCode::NOT_FOUND.into_response());
+        }
+        // Collections have no content, file managers use PROPFIND on them
+        _ => {
+            return Ok((
+                StatusCode::METHOD_NOT_ALLOWED,
+                AppendHeaders([(ALLOW, "OPTIONS, PROPFIND")]),
+            )
+                .into_response())
+        }
+    };
+
+    let body = KnownSize::file(file).await?;
+    let response = (
+        AppendHeaders([(CONTENT_TYPE, mime)]),
+        Ranged::new(range, body),
+    )
+        .into_response();
+
+    match counted {
+        Some((id, limited)) => {
+            count_download(&state.db, &state.file_events, id, limited, method, response).await
+        }
+        None => Ok(response),
+    }
+}
+
+// Changes inside a writable shared folder
+async fn write(
+    db: &SqlitePool,
+    method: Method,
+    headers: &HeaderMap,
+    requester: Option<Uuid>,
+    folder: SharedFolderModel,
+    path: PathBuf,
+    body: Body,
+) -> Result<Response, Error> {
+    match method.as_str() {
+        "PUT" => {
+            let existed = path.exists();
+            // File managers do not sign their requests, there is no signed hash to check
+            write_body(&path, body, None).await?;
+            Ok(match existed {
+                true => StatusCode::NO_CONTENT,
+                false => StatusCode::CREATED,
+            }
+            .into_response())
+        }
+        "DELETE" => {
+            match tokio::fs::metadata(&path).await {
+                Ok(metadata) if metadata.is_dir() => tokio::fs::remove_dir_all(&path).await?,
+                Ok(_) => tokio::fs::remove_file(&path).await?,
+                Err(_) => return Ok(StatusCode::NOT_FOUND.into_response()),
+            }
+            Ok(StatusCode::NO_CONTENT.into_response())
+        }
+        "MKCOL" => {
+            if path.exists() {
+                return Ok(StatusCode::METHOD_NOT_ALLOWED.into_response());
+            }
+            tokio::fs::create_dir(&path).await?;
+            Ok(StatusCode::CREATED.into_response())
+        }
+        // Renames, only inside the same shared folder
+        _ => {
+            let destination = headers
+                .get("Destination")
+                .and_then(|destination| destination.to_str().ok())
+                .and_then(|destination| destination.split_once("/dav/"))
+                .map(|(_, destination)| destination.split('?').next().unwrap_or_default())
+                .map(|destination| {
+                    split_path(&percent_decode_str(destination).decode_utf8_lossy())
+                });
+            let resource = match destination {
+                Some(destination) => resolve(db, &destination, requester).await?,
+                None => None,
+            };
+            let Some(Resource::FolderEntry {
+                folder: target_folder,
+                path: target,
+            }) = resource
+            else {
+                return Ok(StatusCode::FORBIDDEN.into_response());
+            };
+            if target_folder.id != folder.id || target == std::path::Path::new(&folder.path) {
+                return Ok(StatusCode::FORBIDDEN.into_response());
+            }
+
+            let overwrite = headers
+                .get("Overwrite")
+                .map_or(true, |overwrite| overwrite.as_bytes() != b"F");
+            let existed = target.exists();
+            if existed && !overwrite {
+                return Ok(StatusCode::PRECONDITION_FAILED.into_response());
+            }
+
+            tokio::fs::rename(&path, &target).await?;
+            Ok(match existed {
+                true => StatusCode::NO_CONTENT,
+                false => StatusCode::CREATED,
+            }
+            .into_response())
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::{
+        db::{insert_known_peer, test_db},
+        files::access::LocalFileAccess,
+    };
+    use axum::{body::to_bytes, http::Request};
+    use std::sync::Arc;
+    use tower::ServiceExt;
+
+    // A known peer with a WebDAV password, and a shared folder paired with it
+    async fn logged_in_state(password: &str) -> (ServerState, Uuid, tempfile::TempDir) {
+        let db = test_db().await;
+        let device_id = Uuid::new_v4();
+        insert_known_peer(&db, device_id, None).await;
+        sqlx::query("update known_peers set webdav_token = $1 where device_id = $2")
+            .bind(content_sha256(password.as_bytes()))
+            .bind(device_id.to_string())
+            .execute(&db)
+            .await
+            .unwrap();
+
+        let dir = tempfile::tempdir().unwrap();
+        let folder_id = Uuid::new_v4().to_string();
+        sqlx::query(
+            "insert into shared_folders (id, path, name, writable) values ($1, $2, 'Stuff', false)",
+        )
+        .bind(&folder_id)
+        .bind(dir.path().display().to_string())
+        .execute(&db)
+        .await
+        .unwrap();
+        sqlx::query("insert into shared_folder_peers (folder_id, device_id) values ($1, $2)")
+            .bind(&folder_id)
+            .bind(device_id.to_string())
+            .execute(&db)
+            .await
+            .unwrap();
+
+        let state = ServerState::for_tests(db, Arc::new(LocalFileAccess)).await;
+        (state, device_id, dir)
+    }
+
+    async fn propfind(state: &ServerState, path: &str, password: Option<&str>) -> Response {
+        let mut request = Request::builder()
+            .method("PROPFIND")
+            .uri(path)
+            .header("Depth", "1");
+        if let Some(password) = password {
+            let credentials = STANDARD.encode(format!("anyone:{password}"));
+            request = request.header(AUTHORIZATION, format!("Basic {credentials}"));
+        }
+        webdav()
+            .with_state(state.clone())
+            .oneshot(request.body(Body::empty()).unwrap())
+            .await
+            .unwrap()
+    }
+
+    #[tokio::test]
+    async fn folders_are_mounted_with_the_webdav_password() {
+        let (state, _, _dir) = logged_in_state("secret").await;
+
+        // The file manager is asked to log in
+        let response = propfind(&state, "/dav/folders", None).await;
+        assert_eq!(response.status(), StatusCode::UNAUTHORIZED);
+        assert!(response.headers().contains_key(WWW_AUTHENTICATE));
+
+        let response = propfind(&state, "/dav/folders", Some("wrong")).await;
+        assert_eq!(response.status(), StatusCode::UNAUTHORIZED);
+
+        let response = propfind(&state, "/dav/folders", Some("secret")).await;
+        assert_eq!(response.status(), StatusCode::MULTI_STATUS);
+        let body = to_bytes(response.into_body(), usize::MAX).await.unwrap();
+        assert!(String::from_utf8(body.to_vec()).unwrap().contains("Stuff"));
+    }
+
+    #[tokio::test]
+    async fn files_follow_their_access_list() {
+        let (state, device_id, dir) = logged_in_state("secret").await;
+        let path = dir.path().join("only-for-you.txt");
+        std::fs::write(&path, "hello").unwrap();
+        let file_id = Uuid::new_v4().to_string();
+        sqlx::query(
+            "
+                insert into files
+                    (id, name, mime, visibility, path)
+                values
+                    ($1, 'only-for-you.txt', 'text/plain', 'private', $2)
+            ",
+        )
+        .bind(&file_id)
+        .bind(path.display().to_string())
+        .execute(&state.db)
+        .await
+        .unwrap();
+        sqlx::query("insert into file_acl (file_id, device_id) values ($1, $2)")
+            .bind(&file_id)
+            .bind(device_id.to_string())
+            .execute(&state.db)
+            .await
+            .unwrap();
+
+        // Private, only the peer it is shared with sees it
+        let response = propfind(&state, "/dav/files", None).await;
+        let body = to_bytes(response.into_body(), usize::MAX).await.unwrap();
+        assert!(!String::from_utf8(body.to_vec())
+            .unwrap()
+            .contains("only-for-you.txt"));
+
+        let response = propfind(&state, "/dav/files", Some("secret")).await;
+        let body = to_bytes(response.into_body(), usize::MAX).await.unwrap();
+        assert!(String::from_utf8(body.to_vec())
+            .unwrap()
+            .contains("only-for-you.txt"));
+    }
+}
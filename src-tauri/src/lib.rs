@@ -16,33 +16,104 @@
   along with this program. If not, see <https://www.gnu.org/licenses/>.
 */
 
+// Without the gui feature, what only the app calls in the shared modules goes unused
+#![cfg_attr(not(feature = "gui"), allow(dead_code))]
+
+#[cfg(feature = "gui")]
+use access_control::commands::*;
+#[cfg(feature = "gui")]
+use clipboard::{commands::*, models::ClipboardSync};
+#[cfg(feature = "gui")]
 use device::commands::*;
-use files::commands::*;
+#[cfg(feature = "gui")]
+use drop_folders::commands::*;
+use error::ErrorCode;
+#[cfg(feature = "gui")]
+use files::{commands::*, encryption::PassphraseKey, models::FileEvent};
+#[cfg(feature = "gui")]
+use folder_sync::commands::*;
+#[cfg(feature = "gui")]
+use history::commands::*;
+#[cfg(feature = "gui")]
 use http_server::commands::*;
+#[cfg(feature = "gui")]
+use messages::commands::*;
+#[cfg(feature = "gui")]
+use peers::commands::*;
+#[cfg(feature = "gui")]
+use transfers::{commands::*, manager::TransferManager};
 
 use serde::{Deserialize, Serialize};
+#[cfg(feature = "gui")]
 use sqlx::SqlitePool;
+#[cfg(feature = "gui")]
+use std::{collections::HashMap, sync::Arc};
+#[cfg(feature = "gui")]
 use tauri::{path::BaseDirectory, Manager};
+#[cfg(feature = "gui")]
 use tauri_plugin_log::{Target, TargetKind};
-use tokio::sync::{oneshot::Sender, Mutex};
+#[cfg(feature = "gui")]
+use tokio::sync::{broadcast, oneshot::Sender, Mutex, Notify};
+#[cfg(feature = "gui")]
+use uuid::Uuid;
 
+/*
+ * Without the gui feature, only what the headless server needs is built, so that filey-server
+ * builds and runs without tauri and the GUI libraries, the modules that only the app uses
+ * are left out, and so are the tauri commands of the others
+ */
+mod access_control;
+#[cfg(feature = "gui")]
+mod clipboard;
 mod db;
 mod device;
+mod drop_folders;
 mod error;
 mod files;
+mod folder_sync;
+#[cfg(not(any(target_os = "android", target_os = "ios")))]
+pub mod headless;
+#[cfg(feature = "gui")]
+mod history;
 mod http_server;
+#[cfg(feature = "gui")]
+mod messages;
+mod noise;
+#[cfg(feature = "gui")]
+mod peers;
+mod runtime;
+#[cfg(feature = "gui")]
+mod transfers;
 
+#[cfg(feature = "gui")]
 pub struct AppState {
     pub db: SqlitePool,
     pub http_server_shutdown_trigger: Mutex<Option<Sender<()>>>,
+    pub clipboard_sync: Mutex<Option<ClipboardSync>>,
+    pub file_events: broadcast::Sender<FileEvent>,
+    // Shutdown triggers of the /events subscriptions to other peers, keyed by peer address
+    pub peer_event_subscriptions: Mutex<HashMap<String, Sender<()>>>,
+    // Wakes up the drop folder watcher when a drop folder is added or removed
+    pub drop_folders_changed: Notify,
+    // Decision triggers of the incoming transfer requests waiting for the user to answer
+    pub pending_transfers: Mutex<HashMap<Uuid, Sender<bool>>>,
+    pub transfers: TransferManager,
+    // Only one folder sync runs at a time
+    pub sync_lock: Mutex<()>,
+    // Keeps the passphrases of encrypted files out of the database in plain text
+    pub passphrase_key: Arc<PassphraseKey>,
 }
 
 #[derive(Debug, Serialize, Deserialize)]
 pub struct ServerResponse<T: Serialize> {
     pub message: String,
     pub data: T,
+    // Only set on errors, see error::ErrorCode
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub code: Option<ErrorCode>,
 }
 
+#[cfg(feature = "gui")]
 #[cfg_attr(mobile, tauri::mobile_entry_point)]
 pub fn run() {
     tauri::Builder::default()
@@ -51,6 +122,7 @@ pub fn run() {
         .plugin(tauri_plugin_os::init())
         .plugin(tauri_plugin_http::init())
         .plugin(tauri_plugin_fs::init())
+        .plugin(tauri_plugin_clipboard_manager::init())
         .setup(|app| {
             if cfg!(debug_assertions) {
                 app.handle().plugin(
@@ -67,13 +139,49 @@ pub fn run() {
                 .display()
                 .to_string();
 
-            let db = tauri::async_runtime::block_on(db::connect_and_migrate_db(path));
+            let db = runtime::block_on(db::connect_and_migrate_db(path));
+
+            let key_path = app
+                .path()
+                .resolve("passphrase.key", BaseDirectory::AppData)?;
+            let passphrase_key = runtime::block_on(PassphraseKey::load(&key_path))?;
+
+            // Only the latest changes matter, slow subscribers will just miss the old ones
+            let (file_events, _) = broadcast::channel(64);
+
+            // Stops sharing the files whose time or download limit is reached
+            files::expiry::spawn_share_expiry(db.clone(), file_events.clone());
+
+            // Keeps the access log from growing forever
+            access_control::commands::spawn_access_log_pruning(db.clone());
 
             app.manage(AppState {
                 db,
                 http_server_shutdown_trigger: Mutex::new(None),
+                clipboard_sync: Mutex::new(None),
+                file_events,
+                peer_event_subscriptions: Mutex::new(HashMap::new()),
+                drop_folders_changed: Notify::new(),
+                pending_transfers: Mutex::new(HashMap::new()),
+                transfers: TransferManager::default(),
+                sync_lock: Mutex::new(()),
+                passphrase_key: Arc::new(passphrase_key),
             });
 
+            // Picks up the transfer queue where it was left off
+            transfers::manager::spawn_transfer_manager(app.handle().clone());
+
+            // Keeps the synced folders identical to the ones on the peers
+            folder_sync::engine::spawn_sync_scheduler(app.handle().clone());
+
+            // Keep track of shared files being moved, modified or deleted
+            #[cfg(not(any(target_os = "android", target_os = "ios")))]
+            files::watcher::spawn_file_watcher(app.handle().clone());
+
+            // Share whatever gets dropped into the drop folders
+            #[cfg(not(any(target_os = "android", target_os = "ios")))]
+            drop_folders::watcher::spawn_drop_folder_watcher(app.handle().clone());
+
             Ok(())
         })
         .invoke_handler(tauri::generate_handler![
@@ -81,15 +189,86 @@ pub fn run() {
             os_info,
             local_ips,
             has_battery,
+            get_device_identity,
+            set_device_name,
             reveal,
             file_exists,
             get_files,
             upsert_files,
             delete_file,
+            get_file_acl,
+            set_file_acl,
+            set_share_limits,
+            set_file_passphrase,
+            get_snippets,
+            upsert_snippets,
+            delete_snippet,
+            get_drop_folders,
+            add_drop_folder,
+            remove_drop_folder,
             start_server,
             stop_server,
             check_peer,
             get_files_from_peer,
+            subscribe_peer_events,
+            unsubscribe_peer_events,
+            get_known_peers,
+            add_known_peer,
+            rename_known_peer,
+            set_known_peer_favourite,
+            set_known_peer_encrypted,
+            create_webdav_token,
+            revoke_webdav_token,
+            forget_known_peer,
+            get_peer_groups,
+            create_peer_group,
+            rename_peer_group,
+            set_peer_group_members,
+            delete_peer_group,
+            get_access_rules,
+            add_access_rule,
+            delete_access_rule,
+            get_access_log,
+            block_from_access_log,
+            clear_access_log,
+            start_clipboard_sync,
+            stop_clipboard_sync,
+            get_clipboard_history,
+            clear_clipboard_history,
+            get_clipboard_from_peer,
+            send_message,
+            get_conversations,
+            get_messages,
+            delete_conversation,
+            send_files_to_peer,
+            respond_transfer_request,
+            get_pending_transfer_requests,
+            get_transfer_rules,
+            add_transfer_rule,
+            delete_transfer_rule,
+            download_from_peer,
+            get_transfer_jobs,
+            pause_transfer,
+            resume_transfer,
+            cancel_transfer,
+            set_transfer_priority,
+            clear_finished_transfers,
+            get_transfer_history,
+            search_transfer_history,
+            reveal_transfer_destination,
+            purge_transfer_history,
+            get_shared_folders,
+            share_folder,
+            unshare_folder,
+            get_shared_folder_peers,
+            set_shared_folder_peers,
+            get_shared_folders_from_peer,
+            get_sync_pairs,
+            add_sync_pair,
+            remove_sync_pair,
+            sync_now,
+            get_sync_conflicts,
+            dismiss_sync_conflict,
         ])
         .run(tauri::generate_context!())
         .expect("Application failed to start");
@@ -0,0 +1,206 @@
+/*
+  Filey - simple peer-to-peer file sending across devices on different platforms
+  Copyright (C) 2024 Wander Watterson
+
+  This program is free software: you can redistribute it and/or modify
+  it under the terms of the GNU General Public License as published by
+  the Free Software Foundation, either version 3 of the License, or
+  (at your option) any later version.
+
+  This program is distributed in the hope that it will be useful,
+  but WITHOUT ANY WARRANTY; without even the implied warranty of
+  MERCHANTABILITY or FITNESS FOR A PARTICULAR PURPOSE. See the
+  GNU General Public License for more details.
+
+  You should have received a copy of the GNU General Public License
+  along with this program. If not, see <https://www.gnu.org/licenses/>.
+*/
+
+use reqwest::{
+    header::{HeaderMap, HeaderValue, CONTENT_TYPE},
+    Method,
+};
+use sqlx::SqlitePool;
+use tauri::State;
+use uuid::{fmt::Hyphenated, Uuid};
+
+use super::models::{
+    ConversationModel, Direction, MessageModel, MessageRequest, MAX_MESSAGE_LENGTH,
+};
+use crate::{
+    db::now,
+    error::Error,
+    http_server::{commands::require_capability, models::Capability},
+    noise::client::request_peer,
+    AppState,
+};
+
+/*
+ * Sends a short text message to another Filey peer
+ * The message is only saved after the peer accepted it,
+ * on the serving side, it will be handled by a handler in http_server::routes::messages
+ */
+#[tauri::command]
+pub async fn send_message(
+    state: State<'_, AppState>,
+    ip: &str,
+    body: String,
+) -> Result<MessageModel, Error> {
+    check_message_length(&body)?;
+    require_capability(ip, Capability::Messages).await?;
+
+    // The peer sees our host name as the sender
+    let sender = tauri_plugin_os::hostname();
+
+    let request = serde_json::to_vec(&MessageRequest {
+        sender: sender.clone(),
+        body: body.clone(),
+    })?;
+    let mut headers = HeaderMap::new();
+    headers.insert(CONTENT_TYPE, HeaderValue::from_static("application/json"));
+    request_peer(
+        &state.db,
+        ip,
+        Method::POST,
+        "/messages",
+        headers,
+        request.into(),
+    )
+    .await?
+    .error_for_status()?;
+
+    let message = MessageModel {
+        id: Uuid::new_v4(),
+        peer: ip.to_string(),
+        direction: Direction::Outgoing,
+        sender,
+        body,
+        created_at: now(),
+    };
+    save_message(&state.db, &message, None).await?;
+
+    Ok(message)
+}
+
+// Returns every conversation, the most recently active first
+#[tauri::command]
+pub async fn get_conversations(
+    state: State<'_, AppState>,
+) -> Result<Vec<ConversationModel>, Error> {
+    let conversations = sqlx::query_as!(
+        ConversationModel,
+        r#"
+            select
+                peer as "peer!",
+                name,
+                updated_at
+            from conversations
+            order by updated_at desc
+        "#
+    )
+    .fetch_all(&state.db)
+    .await?;
+
+    Ok(conversations)
+}
+
+// Returns the messages in the conversation with a peer, oldest first
+#[tauri::command]
+pub async fn get_messages(
+    state: State<'_, AppState>,
+    peer: &str,
+) -> Result<Vec<MessageModel>, Error> {
+    let messages = sqlx::query_as!(
+        MessageModel,
+        r#"
+            select
+                id as "id!: Hyphenated",
+                peer,
+                direction as "direction!: Direction",
+                sender,
+                body,
+                created_at
+            from messages
+            where peer = $1
+            order by created_at asc
+        "#,
+        peer
+    )
+    .fetch_all(&state.db)
+    .await?;
+
+    Ok(messages)
+}
+
+// Deletes the conversation with a peer, the messages go along with it
+#[tauri::command]
+pub async fn delete_conversation(state: State<'_, AppState>, peer: &str) -> Result<(), Error> {
+    sqlx::query!(
+        "delete from conversations where peer = $1 returning peer",
+        peer
+    )
+    .fetch_one(&state.db)
+    .await?;
+    Ok(())
+}
+
+pub fn check_message_length(body: &str) -> Result<(), Error> {
+    match body.chars().count() > MAX_MESSAGE_LENGTH {
+        true => Err(Error::MessageTooLong(MAX_MESSAGE_LENGTH)),
+        false => Ok(()),
+    }
+}
+
+/*
+ * Saves a message, creating the conversation with the peer if it does not exist yet
+ * The name is the sender's name on incoming messages, it replaces the conversation name
+ * so that it follows whatever the peer is calling itself.
+ * On outgoing messages there is no name, the peer's address is used for new conversations
+ */
+pub async fn save_message(
+    db: &SqlitePool,
+    message: &MessageModel,
+    name: Option<&str>,
+) -> Result<(), Error> {
+    let id = message.id.to_string();
+    let direction = message.direction.to_string();
+
+    sqlx::query!(
+        "
+            insert into conversations
+                (peer, name, updated_at)
+            values
+                ($1, coalesce($2, $1), $3)
+            on conflict (peer)
+            do update set
+                name = coalesce($2, conversations.name),
+                updated_at = excluded.updated_at
+            returning peer
+        ",
+        message.peer,
+        name,
+        message.created_at
+    )
+    .fetch_optional(db)
+    .await?;
+
+    sqlx::query!(
+        "
+            insert into messages
+                (id, peer, direction, sender, body, created_at)
+            values
+                ($1, $2, $3, $4, $5, $6)
+            returning id
+        ",
+        id,
+        message.peer,
+        direction,
+        message.sender,
+        message.body,
+        message.created_at
+    )
+    .fetch_optional(db)
+    .await?;
+
+    Ok(())
+}
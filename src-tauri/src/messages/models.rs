@@ -0,0 +1,86 @@
+/*
+  Filey - simple peer-to-peer file sending across devices on different platforms
+  Copyright (C) 2024 Wander Watterson
+
+  This program is free software: you can redistribute it and/or modify
+  it under the terms of the GNU General Public License as published by
+  the Free Software Foundation, either version 3 of the License, or
+  (at your option) any later version.
+
+  This program is distributed in the hope that it will be useful,
+  but WITHOUT ANY WARRANTY; without even the implied warranty of
+  MERCHANTABILITY or FITNESS FOR A PARTICULAR PURPOSE. See the
+  GNU General Public License for more details.
+
+  You should have received a copy of the GNU General Public License
+  along with this program. If not, see <https://www.gnu.org/licenses/>.
+*/
+
+use serde::{Deserialize, Serialize};
+use sqlx::{sqlite::SqliteTypeInfo, Database, Decode, Encode, Sqlite, Type};
+use std::str::FromStr;
+use strum::{Display, EnumString};
+use uuid::Uuid;
+
+// Messages longer than this are not "short text messages" anymore, send a snippet instead
+pub const MAX_MESSAGE_LENGTH: usize = 4096;
+
+/*
+ * Whether the message was received from the peer, or sent by us to the peer
+ */
+#[derive(Serialize, Deserialize, Debug, Clone, PartialEq, Encode, Display, EnumString)]
+#[serde(rename_all = "lowercase")]
+#[strum(serialize_all = "lowercase")]
+pub enum Direction {
+    Incoming,
+    Outgoing,
+}
+
+impl Type<Sqlite> for Direction {
+    fn type_info() -> SqliteTypeInfo {
+        <&str as Type<Sqlite>>::type_info()
+    }
+    fn compatible(ty: &SqliteTypeInfo) -> bool {
+        <&str as Type<Sqlite>>::compatible(ty)
+    }
+}
+
+impl<'r, DB: Database> Decode<'r, DB> for Direction
+where
+    &'r str: Decode<'r, DB>,
+{
+    fn decode(
+        value: <DB as sqlx::Database>::ValueRef<'r>,
+    ) -> Result<Self, sqlx::error::BoxDynError> {
+        let value = <&str as Decode<DB>>::decode(value)?;
+        Ok(Direction::from_str(value).unwrap())
+    }
+}
+
+// A conversation with a peer, keyed by the peer's address
+#[derive(Debug, Serialize, Deserialize)]
+#[serde(rename_all = "camelCase")]
+pub struct ConversationModel {
+    pub peer: String,
+    pub name: String,
+    pub updated_at: i64,
+}
+
+// Message model in the database
+#[derive(Debug, Clone, Serialize, Deserialize)]
+#[serde(rename_all = "camelCase")]
+pub struct MessageModel {
+    pub id: Uuid,
+    pub peer: String,
+    pub direction: Direction,
+    pub sender: String,
+    pub body: String,
+    pub created_at: i64,
+}
+
+// What a peer sends to the /messages route, the sender is the name of the sending device
+#[derive(Debug, Serialize, Deserialize)]
+pub struct MessageRequest {
+    pub sender: String,
+    pub body: String,
+}
@@ -0,0 +1,262 @@
+/*
+  Filey - simple peer-to-peer file sending across devices on different platforms
+  Copyright (C) 2024 Wander Watterson
+
+  This program is free software: you can redistribute it and/or modify
+  it under the terms of the GNU General Public License as published by
+  the Free Software Foundation, either version 3 of the License, or
+  (at your option) any later version.
+
+  This program is distributed in the hope that it will be useful,
+  but WITHOUT ANY WARRANTY; without even the implied warranty of
+  MERCHANTABILITY or FITNESS FOR A PARTICULAR PURPOSE. See the
+  GNU General Public License for more details.
+
+  You should have received a copy of the GNU General Public License
+  along with this program. If not, see <https://www.gnu.org/licenses/>.
+*/
+
+use axum::{
+    body::Body,
+    http::{header::HOST, Request},
+};
+use hyper_util::rt::TokioIo;
+use log::error;
+use reqwest::{self, header::HeaderMap, Client, Method, Response};
+use sqlx::SqlitePool;
+use tokio::net::TcpStream;
+use uuid::{fmt::Hyphenated, Uuid};
+
+use super::{
+    models::{noise_public_key, NOISE_PORT},
+    transport::handshake,
+};
+use crate::{device::commands::load_signing_key, error::Error};
+
+/*
+ * Every request to a peer goes through here (except /info, which is public and is how peers
+ * are found): through the encrypted transport when the known peer is set to use it,
+ * through plain HTTP on 38899 otherwise
+ * The peer at the address is the known peer that was seen there, see known_peer_at
+ * An encrypted peer is only ever reached encrypted, when that fails the request fails too
+ * path is the path with the query, e.g. /files/{id}?mode=download
+ */
+pub async fn request_peer(
+    db: &SqlitePool,
+    ip: &str,
+    method: Method,
+    path: &str,
+    headers: HeaderMap,
+    body: reqwest::Body,
+) -> Result<Response, Error> {
+    let public_key = match known_peer_at(db, ip).await? {
+        Some(device_id) => encrypted_peer_key(db, device_id).await?,
+        None => None,
+    };
+    send(db, ip, public_key, method, path, headers, body).await
+}
+
+// Same as request_peer, for a known peer, at the address it was last seen at
+pub async fn request_known_peer(
+    db: &SqlitePool,
+    device_id: Uuid,
+    method: Method,
+    path: &str,
+    headers: HeaderMap,
+    body: reqwest::Body,
+) -> Result<Response, Error> {
+    let id = device_id.to_string();
+    let ip = sqlx::query_scalar!(
+        "select last_address from known_peers where device_id = $1",
+        id
+    )
+    .fetch_one(db)
+    .await?;
+    let public_key = encrypted_peer_key(db, device_id).await?;
+    send(db, &ip, public_key, method, path, headers, body).await
+}
+
+// A GET through request_peer
+pub async fn get_from_peer(
+    db: &SqlitePool,
+    ip: &str,
+    path: &str,
+    headers: HeaderMap,
+) -> Result<Response, Error> {
+    request_peer(
+        db,
+        ip,
+        Method::GET,
+        path,
+        headers,
+        reqwest::Body::from(vec![]),
+    )
+    .await
+}
+
+async fn send(
+    db: &SqlitePool,
+    ip: &str,
+    public_key: Option<String>,
+    method: Method,
+    path: &str,
+    headers: HeaderMap,
+    body: reqwest::Body,
+) -> Result<Response, Error> {
+    match public_key {
+        Some(public_key) => send_encrypted(db, ip, &public_key, method, path, headers, body).await,
+        None => Ok(Client::new()
+            .request(method, format!("http://{ip}:38899{path}"))
+            .headers(headers)
+            .body(body)
+            .send()
+            .await?),
+    }
+}
+
+/*
+ * The known peer at this address, by every address it was seen at, not only the last one
+ * When peers took turns at the address, one set to be encrypted wins over the others,
+ * so a plain HTTP peer that shows up there cannot take its traffic
+ */
+async fn known_peer_at(db: &SqlitePool, ip: &str) -> Result<Option<Uuid>, Error> {
+    Ok(sqlx::query_scalar!(
+        r#"
+            select known_peers.device_id as "device_id!: Hyphenated"
+            from known_peer_addresses
+            join known_peers on known_peers.device_id = known_peer_addresses.device_id
+            where known_peer_addresses.address = $1
+            order by
+                known_peers.encrypted desc,
+                known_peer_addresses.last_seen_at desc
+            limit 1
+        "#,
+        ip
+    )
+    .fetch_optional(db)
+    .await?
+    .map(Hyphenated::into_uuid))
+}
+
+// The device public key of the known peer, when it is set to be encrypted
+async fn encrypted_peer_key(db: &SqlitePool, device_id: Uuid) -> Result<Option<String>, Error> {
+    let id = device_id.to_string();
+    Ok(sqlx::query_scalar!(
+        r#"
+            select public_key as "public_key!"
+            from known_peers
+            where
+                device_id = $1
+            and encrypted = true
+            and public_key is not null
+        "#,
+        id
+    )
+    .fetch_optional(db)
+    .await?)
+}
+
+async fn send_encrypted(
+    db: &SqlitePool,
+    ip: &str,
+    public_key: &str,
+    method: Method,
+    path: &str,
+    headers: HeaderMap,
+    body: reqwest::Body,
+) -> Result<Response, Error> {
+    let tcp = TcpStream::connect((ip, NOISE_PORT)).await?;
+    let signing_key = load_signing_key(db).await?;
+    let (stream, remote_key) = handshake(tcp, &signing_key, true).await?;
+
+    // Whoever answers has to hold the key the known peer was remembered with
+    if noise_public_key(public_key) != Some(remote_key) {
+        return Err(Error::NoiseKeyMismatch(ip.to_string()));
+    }
+
+    let (mut sender, connection) =
+        hyper::client::conn::http1::handshake(TokioIo::new(stream)).await?;
+    let address = ip.to_string();
+    crate::runtime::spawn(async move {
+        if let Err(err) = connection.await {
+            error!("Encrypted connection to {address} failed: {err}");
+        }
+    });
+
+    let mut request = Request::builder()
+        .method(method)
+        .uri(path)
+        .header(HOST, ip)
+        .body(Body::new(body))?;
+    request.headers_mut().extend(headers);
+    let response = sender.send_request(request).await?;
+
+    // Same response as the plain HTTP one, the body is streamed as it gets decrypted
+    Ok(Response::from(response.map(|body| {
+        reqwest::Body::wrap_stream(Body::new(body).into_data_stream())
+    })))
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::db::{insert_known_peer, test_db};
+
+    async fn insert_encrypted_peer(db: &SqlitePool, device_id: Uuid) {
+        insert_known_peer(db, device_id, Some("key")).await;
+        sqlx::query("update known_peers set encrypted = true where device_id = $1")
+            .bind(device_id.to_string())
+            .execute(db)
+            .await
+            .unwrap();
+    }
+
+    async fn insert_address(db: &SqlitePool, device_id: Uuid, address: &str, seen_at: i64) {
+        sqlx::query(
+            "
+                insert into known_peer_addresses
+                    (device_id, address, last_seen_at)
+                values
+                    ($1, $2, $3)
+            ",
+        )
+        .bind(device_id.to_string())
+        .bind(address)
+        .bind(seen_at)
+        .execute(db)
+        .await
+        .unwrap();
+    }
+
+    #[tokio::test]
+    async fn encrypted_peer_keeps_its_addresses() {
+        let db = test_db().await;
+        let (encrypted, plain) = (Uuid::new_v4(), Uuid::new_v4());
+        insert_encrypted_peer(&db, encrypted).await;
+        insert_known_peer(&db, plain, None).await;
+
+        // Not the last address of the encrypted peer, and another peer was seen there since
+        insert_address(&db, encrypted, "10.0.0.2", 1).await;
+        insert_address(&db, plain, "10.0.0.2", 2).await;
+
+        let device_id = known_peer_at(&db, "10.0.0.2").await.unwrap();
+        assert_eq!(device_id, Some(encrypted));
+        let public_key = encrypted_peer_key(&db, encrypted).await.unwrap();
+        assert_eq!(public_key.as_deref(), Some("key"));
+        assert_eq!(encrypted_peer_key(&db, plain).await.unwrap(), None);
+    }
+
+    #[tokio::test]
+    async fn encrypted_peer_is_never_reached_in_plain_http() {
+        let db = test_db().await;
+        let device_id = Uuid::new_v4();
+        insert_encrypted_peer(&db, device_id).await;
+        insert_address(&db, device_id, "127.0.0.1", 1).await;
+
+        // Whatever goes wrong with the encrypted transport, the request is not sent on 38899
+        let err = get_from_peer(&db, "127.0.0.1", "/files", HeaderMap::new())
+            .await
+            .unwrap_err();
+        assert!(!matches!(err, Error::Reqwest(_)), "{err}");
+    }
+}
@@ -0,0 +1,56 @@
+/*
+  Filey - simple peer-to-peer file sending across devices on different platforms
+  Copyright (C) 2024 Wander Watterson
+
+  This program is free software: you can redistribute it and/or modify
+  it under the terms of the GNU General Public License as published by
+  the Free Software Foundation, either version 3 of the License, or
+  (at your option) any later version.
+
+  This program is distributed in the hope that it will be useful,
+  but WITHOUT ANY WARRANTY; without even the implied warranty of
+  MERCHANTABILITY or FITNESS FOR A PARTICULAR PURPOSE. See the
+  GNU General Public License for more details.
+
+  You should have received a copy of the GNU General Public License
+  along with this program. If not, see <https://www.gnu.org/licenses/>.
+*/
+
+use base64::{engine::general_purpose::STANDARD, Engine};
+use ed25519_dalek::VerifyingKey;
+use uuid::Uuid;
+
+/*
+ * The encrypted transport listens next to the HTTP server, on 38900
+ * Both peers authenticate with their device keys through a Noise XX handshake,
+ * and the same routes as on 38899 are then served over the encrypted channel
+ */
+pub const NOISE_PORT: u16 = 38900;
+pub const NOISE_PATTERN: &str = "Noise_XX_25519_ChaChaPoly_BLAKE2s";
+
+// Noise messages are sent with a 2 bytes length in front, which caps their size
+pub const MAX_NOISE_MESSAGE: usize = 65535;
+// Every encrypted message carries a 16 bytes authentication tag
+pub const MAX_NOISE_PAYLOAD: usize = MAX_NOISE_MESSAGE - 16;
+
+// How long the handshake can take, in seconds, before the connection is dropped
+pub const NOISE_HANDSHAKE_TIMEOUT: u64 = 10;
+
+/*
+ * The known peer on the other end of an encrypted connection, found by its static key
+ * Added to every request that comes through the connection, see http_server::requester
+ * None => the key does not belong to any known peer
+ */
+#[derive(Debug, Clone, Copy)]
+pub struct NoisePeer(pub Option<Uuid>);
+
+/*
+ * The Noise static key (X25519) that goes with an Ed25519 device public key, base64
+ * Device keys are Ed25519 for signing, the handshake uses the same key on the Montgomery curve,
+ * so peers do not need a second key to be exchanged and remembered
+ */
+pub fn noise_public_key(public_key: &str) -> Option<[u8; 32]> {
+    let key: [u8; 32] = STANDARD.decode(public_key).ok()?.try_into().ok()?;
+    let key = VerifyingKey::from_bytes(&key).ok()?;
+    Some(key.to_montgomery().to_bytes())
+}
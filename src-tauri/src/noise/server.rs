@@ -0,0 +1,124 @@
+/*
+  Filey - simple peer-to-peer file sending across devices on different platforms
+  Copyright (C) 2024 Wander Watterson
+
+  This program is free software: you can redistribute it and/or modify
+  it under the terms of the GNU General Public License as published by
+  the Free Software Foundation, either version 3 of the License, or
+  (at your option) any later version.
+
+  This program is distributed in the hope that it will be useful,
+  but WITHOUT ANY WARRANTY; without even the implied warranty of
+  MERCHANTABILITY or FITNESS FOR A PARTICULAR PURPOSE. See the
+  GNU General Public License for more details.
+
+  You should have received a copy of the GNU General Public License
+  along with this program. If not, see <https://www.gnu.org/licenses/>.
+*/
+
+use axum::{extract::ConnectInfo, Extension, Router};
+use hyper_util::{rt::TokioIo, service::TowerToHyperService};
+use log::{error, info};
+use sqlx::SqlitePool;
+use std::net::SocketAddr;
+use tokio::net::{TcpListener, TcpStream};
+use uuid::{fmt::Hyphenated, Uuid};
+
+use super::{
+    models::{noise_public_key, NoisePeer, NOISE_PORT},
+    transport::handshake,
+};
+use crate::{device::commands::load_signing_key, error::Error, runtime::JoinHandle};
+
+/*
+ * Serves the router over the encrypted transport, next to the HTTP server
+ * The router is the one the HTTP server serves, with its allow and deny rules,
+ * the task is aborted along with the HTTP server
+ */
+pub fn spawn_noise_server(db: SqlitePool, router: Router) -> JoinHandle<()> {
+    crate::runtime::spawn(async move {
+        if let Err(err) = serve_noise(db, router).await {
+            error!("Encrypted transport stopped: {err}");
+        }
+    })
+}
+
+async fn serve_noise(db: SqlitePool, router: Router) -> Result<(), Error> {
+    let tcp_listener = TcpListener::bind(("0.0.0.0", NOISE_PORT)).await?;
+    info!("Encrypted transport on 0.0.0.0:{NOISE_PORT}");
+
+    loop {
+        let (tcp, address) = tcp_listener.accept().await?;
+        let db = db.clone();
+        let router = router.clone();
+        crate::runtime::spawn(async move {
+            if let Err(err) = serve_connection(&db, router, tcp, address).await {
+                error!("Encrypted connection from {address} failed: {err}");
+            }
+        });
+    }
+}
+
+async fn serve_connection(
+    db: &SqlitePool,
+    router: Router,
+    tcp: TcpStream,
+    address: SocketAddr,
+) -> Result<(), Error> {
+    let signing_key = load_signing_key(db).await?;
+    let (stream, remote_key) = handshake(tcp, &signing_key, false).await?;
+    let peer = NoisePeer(find_known_peer(db, &remote_key).await?);
+
+    // The routes see the same connect info as on the HTTP server, and who is on the other end
+    let router = router
+        .layer(Extension(ConnectInfo(address)))
+        .layer(Extension(peer));
+
+    hyper::server::conn::http1::Builder::new()
+        .serve_connection(TokioIo::new(stream), TowerToHyperService::new(router))
+        .await?;
+    Ok(())
+}
+
+// The known peer whose device key goes with the Noise static key
+async fn find_known_peer(db: &SqlitePool, remote_key: &[u8; 32]) -> Result<Option<Uuid>, Error> {
+    let known_peers = sqlx::query!(
+        r#"
+            select
+                device_id as "device_id!: Hyphenated",
+                public_key as "public_key!"
+            from known_peers
+            where public_key is not null
+        "#
+    )
+    .fetch_all(db)
+    .await?;
+
+    Ok(known_peers
+        .into_iter()
+        .find(|peer| noise_public_key(&peer.public_key).as_ref() == Some(remote_key))
+        .map(|peer| peer.device_id.into_uuid()))
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::{
+        db::{insert_known_peer, test_db},
+        device::commands::load_identity,
+    };
+
+    #[tokio::test]
+    async fn known_peer_is_found_by_its_noise_key() {
+        let db = test_db().await;
+        let identity = load_identity(&db).await.unwrap();
+        insert_known_peer(&db, identity.id, Some(&identity.public_key)).await;
+
+        let noise_key = noise_public_key(&identity.public_key).unwrap();
+        let found = find_known_peer(&db, &noise_key).await.unwrap();
+        assert_eq!(found, Some(identity.id));
+
+        let unknown = find_known_peer(&db, &[9; 32]).await.unwrap();
+        assert_eq!(unknown, None);
+    }
+}
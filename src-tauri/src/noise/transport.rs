@@ -0,0 +1,276 @@
+/*
+  Filey - simple peer-to-peer file sending across devices on different platforms
+  Copyright (C) 2024 Wander Watterson
+
+  This program is free software: you can redistribute it and/or modify
+  it under the terms of the GNU General Public License as published by
+  the Free Software Foundation, either version 3 of the License, or
+  (at your option) any later version.
+
+  This program is distributed in the hope that it will be useful,
+  but WITHOUT ANY WARRANTY; without even the implied warranty of
+  MERCHANTABILITY or FITNESS FOR A PARTICULAR PURPOSE. See the
+  GNU General Public License for more details.
+
+  You should have received a copy of the GNU General Public License
+  along with this program. If not, see <https://www.gnu.org/licenses/>.
+*/
+
+use ed25519_dalek::SigningKey;
+use snow::Builder;
+use std::{
+    sync::{Arc, Mutex},
+    time::Duration,
+};
+use tokio::{
+    io::{duplex, split, AsyncRead, AsyncReadExt, AsyncWrite, AsyncWriteExt, DuplexStream},
+    net::TcpStream,
+    time::timeout,
+};
+
+use super::models::{MAX_NOISE_MESSAGE, MAX_NOISE_PAYLOAD, NOISE_HANDSHAKE_TIMEOUT, NOISE_PATTERN};
+use crate::error::Error;
+
+/*
+ * Runs the Noise XX handshake over the TCP connection, with the device key as the static key
+ * Returns the decrypted side of the connection, along with the static key of the other peer
+ *
+ * Whatever is written to the returned stream is encrypted and sent to the peer,
+ * whatever the peer sends can be read from it once decrypted, so HTTP runs on top as is
+ */
+pub async fn handshake(
+    tcp: TcpStream,
+    signing_key: &SigningKey,
+    initiator: bool,
+) -> Result<(DuplexStream, [u8; 32]), Error> {
+    timeout(
+        Duration::from_secs(NOISE_HANDSHAKE_TIMEOUT),
+        run_handshake(tcp, signing_key, initiator),
+    )
+    .await
+    .map_err(std::io::Error::from)?
+}
+
+async fn run_handshake(
+    mut tcp: TcpStream,
+    signing_key: &SigningKey,
+    initiator: bool,
+) -> Result<(DuplexStream, [u8; 32]), Error> {
+    // The X25519 private key is the scalar of the Ed25519 key, see noise::models::noise_public_key
+    let private_key = signing_key.to_scalar_bytes();
+    let builder = Builder::new(NOISE_PATTERN.parse()?).local_private_key(&private_key);
+    let mut handshake = match initiator {
+        true => builder.build_initiator()?,
+        false => builder.build_responder()?,
+    };
+
+    let mut message = vec![0; MAX_NOISE_MESSAGE];
+    let mut payload = vec![0; MAX_NOISE_MESSAGE];
+    while !handshake.is_handshake_finished() {
+        if handshake.is_my_turn() {
+            let length = handshake.write_message(&[], &mut message)?;
+            write_frame(&mut tcp, &message[..length]).await?;
+        } else {
+            let length = read_frame(&mut tcp, &mut message).await?;
+            handshake.read_message(&message[..length], &mut payload)?;
+        }
+    }
+
+    let remote_key: [u8; 32] = handshake
+        .get_remote_static()
+        .and_then(|key| key.try_into().ok())
+        .ok_or_else(|| Error::InvalidKey("Noise static key of the peer".into()))?;
+    let transport = Arc::new(Mutex::new(handshake.into_transport_mode()?));
+
+    let (stream, pump) = duplex(MAX_NOISE_PAYLOAD);
+    let (mut pump_reader, mut pump_writer) = split(pump);
+    let (mut tcp_reader, mut tcp_writer) = tcp.into_split();
+
+    // Peer -> stream, until the peer closes the connection or sends something that does not decrypt
+    let decrypting = transport.clone();
+    crate::runtime::spawn(async move {
+        let mut message = vec![0; MAX_NOISE_MESSAGE];
+        let mut payload = vec![0; MAX_NOISE_MESSAGE];
+        while let Ok(length) = read_frame(&mut tcp_reader, &mut message).await {
+            let Ok(length) = decrypting
+                .lock()
+                .unwrap()
+                .read_message(&message[..length], &mut payload)
+            else {
+                break;
+            };
+            if pump_writer.write_all(&payload[..length]).await.is_err() {
+                break;
+            }
+        }
+        pump_writer.shutdown().await.ok();
+    });
+
+    // Stream -> peer, until the stream is closed
+    crate::runtime::spawn(async move {
+        let mut payload = vec![0; MAX_NOISE_PAYLOAD];
+        let mut message = vec![0; MAX_NOISE_MESSAGE];
+        while let Ok(length @ 1..) = pump_reader.read(&mut payload).await {
+            let Ok(length) = transport
+                .lock()
+                .unwrap()
+                .write_message(&payload[..length], &mut message)
+            else {
+                break;
+            };
+            if write_frame(&mut tcp_writer, &message[..length])
+                .await
+                .is_err()
+            {
+                break;
+            }
+        }
+        tcp_writer.shutdown().await.ok();
+    });
+
+    Ok((stream, remote_key))
+}
+
+// A frame is a 2 bytes big endian length, followed by the Noise message
+async fn write_frame(writer: &mut (impl AsyncWrite + Unpin), message: &[u8]) -> Result<(), Error> {
+    writer.write_u16(message.len() as u16).await?;
+    writer.write_all(message).await?;
+    writer.flush().await?;
+    Ok(())
+}
+
+async fn read_frame(
+    reader: &mut (impl AsyncRead + Unpin),
+    buffer: &mut [u8],
+) -> Result<usize, Error> {
+    let length = reader.read_u16().await? as usize;
+    reader.read_exact(&mut buffer[..length]).await?;
+    Ok(length)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::noise::models::noise_public_key;
+    use base64::{engine::general_purpose::STANDARD, Engine};
+    use tokio::net::TcpListener;
+
+    // Both ends of a handshake over a loopback connection
+    async fn connect(
+        initiator_key: &SigningKey,
+        responder_key: &SigningKey,
+    ) -> (
+        Result<(DuplexStream, [u8; 32]), Error>,
+        Result<(DuplexStream, [u8; 32]), Error>,
+    ) {
+        let listener = TcpListener::bind("127.0.0.1:0").await.unwrap();
+        let address = listener.local_addr().unwrap();
+        let (outgoing, incoming) = tokio::join!(TcpStream::connect(address), listener.accept());
+        let (incoming, _) = incoming.unwrap();
+        tokio::join!(
+            handshake(outgoing.unwrap(), initiator_key, true),
+            handshake(incoming, responder_key, false)
+        )
+    }
+
+    fn noise_key(signing_key: &SigningKey) -> [u8; 32] {
+        noise_public_key(&STANDARD.encode(signing_key.verifying_key().to_bytes())).unwrap()
+    }
+
+    #[tokio::test]
+    async fn handshake_gives_the_device_key_of_the_other_peer() {
+        let initiator_key = SigningKey::from_bytes(&[1; 32]);
+        let responder_key = SigningKey::from_bytes(&[2; 32]);
+        let (initiator, responder) = connect(&initiator_key, &responder_key).await;
+        let (_, initiator_remote) = initiator.unwrap();
+        let (_, responder_remote) = responder.unwrap();
+
+        assert_eq!(initiator_remote, noise_key(&responder_key));
+        assert_eq!(responder_remote, noise_key(&initiator_key));
+    }
+
+    #[tokio::test]
+    async fn streams_carry_the_data_both_ways() {
+        let initiator_key = SigningKey::from_bytes(&[1; 32]);
+        let responder_key = SigningKey::from_bytes(&[2; 32]);
+        let (initiator, responder) = connect(&initiator_key, &responder_key).await;
+        let (mut initiator, _) = initiator.unwrap();
+        let (mut responder, _) = responder.unwrap();
+
+        // Larger than one Noise message, so it is split across frames
+        let request = vec![7; MAX_NOISE_PAYLOAD * 2 + 10];
+        initiator.write_all(&request).await.unwrap();
+        let mut received = vec![0; request.len()];
+        responder.read_exact(&mut received).await.unwrap();
+        assert_eq!(received, request);
+
+        responder.write_all(b"answer").await.unwrap();
+        responder.shutdown().await.unwrap();
+        let mut answer = vec![];
+        initiator.read_to_end(&mut answer).await.unwrap();
+        assert_eq!(answer, b"answer");
+    }
+
+    #[tokio::test]
+    async fn frames_that_do_not_decrypt_close_the_stream() {
+        let signing_key = SigningKey::from_bytes(&[1; 32]);
+        let listener = TcpListener::bind("127.0.0.1:0").await.unwrap();
+        let address = listener.local_addr().unwrap();
+
+        // A peer that runs the handshake, then sends garbage instead of a Noise message
+        let peer = tokio::spawn(async move {
+            let (mut tcp, _) = listener.accept().await.unwrap();
+            let private_key = SigningKey::from_bytes(&[2; 32]).to_scalar_bytes();
+            let mut handshake = Builder::new(NOISE_PATTERN.parse().unwrap())
+                .local_private_key(&private_key)
+                .build_responder()
+                .unwrap();
+            let mut message = vec![0; MAX_NOISE_MESSAGE];
+            let mut payload = vec![0; MAX_NOISE_MESSAGE];
+            while !handshake.is_handshake_finished() {
+                if handshake.is_my_turn() {
+                    let length = handshake.write_message(&[], &mut message).unwrap();
+                    write_frame(&mut tcp, &message[..length]).await.unwrap();
+                } else {
+                    let length = read_frame(&mut tcp, &mut message).await.unwrap();
+                    handshake
+                        .read_message(&message[..length], &mut payload)
+                        .unwrap();
+                }
+            }
+            write_frame(&mut tcp, &[0; 32]).await.unwrap();
+            tcp
+        });
+
+        let tcp = TcpStream::connect(address).await.unwrap();
+        let (mut stream, _) = handshake(tcp, &signing_key, true).await.unwrap();
+        let _tcp = peer.await.unwrap();
+
+        let mut received = vec![];
+        stream.read_to_end(&mut received).await.unwrap();
+        assert!(received.is_empty());
+    }
+
+    #[tokio::test]
+    async fn handshake_fails_when_the_peer_is_not_noise() {
+        let signing_key = SigningKey::from_bytes(&[1; 32]);
+        let listener = TcpListener::bind("127.0.0.1:0").await.unwrap();
+        let address = listener.local_addr().unwrap();
+
+        // Answers the first handshake message with a frame that is not one
+        let peer = tokio::spawn(async move {
+            let (mut tcp, _) = listener.accept().await.unwrap();
+            let mut message = vec![0; MAX_NOISE_MESSAGE];
+            read_frame(&mut tcp, &mut message).await.unwrap();
+            write_frame(&mut tcp, b"HTTP/1.1 400 Bad Request")
+                .await
+                .unwrap();
+            tcp
+        });
+
+        let tcp = TcpStream::connect(address).await.unwrap();
+        let result = handshake(tcp, &signing_key, true).await;
+        assert!(matches!(result, Err(Error::Noise(_))));
+        peer.await.unwrap();
+    }
+}
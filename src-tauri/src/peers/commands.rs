@@ -0,0 +1,519 @@
+/*
+  Filey - simple peer-to-peer file sending across devices on different platforms
+  Copyright (C) 2024 Wander Watterson
+
+  This program is free software: you can redistribute it and/or modify
+  it under the terms of the GNU General Public License as published by
+  the Free Software Foundation, either version 3 of the License, or
+  (at your option) any later version.
+
+  This program is distributed in the hope that it will be useful,
+  but WITHOUT ANY WARRANTY; without even the implied warranty of
+  MERCHANTABILITY or FITNESS FOR A PARTICULAR PURPOSE. See the
+  GNU General Public License for more details.
+
+  You should have received a copy of the GNU General Public License
+  along with this program. If not, see <https://www.gnu.org/licenses/>.
+*/
+
+use base64::{engine::general_purpose::URL_SAFE_NO_PAD, Engine};
+use log::warn;
+use sqlx::SqlitePool;
+use tauri::State;
+use uuid::{fmt::Hyphenated, Uuid};
+
+use super::models::{KnownPeerModel, PeerGroupModel};
+use crate::{
+    db::now,
+    device::models::content_sha256,
+    error::Error,
+    http_server::{
+        commands::fetch_peer,
+        models::{OsType, Peer},
+    },
+    AppState,
+};
+
+// Returns every known peer, the favourites first, then the most recently seen
+#[tauri::command]
+pub async fn get_known_peers(state: State<'_, AppState>) -> Result<Vec<KnownPeerModel>, Error> {
+    let rows = sqlx::query!(
+        r#"
+            select device_id as "device_id!: Hyphenated"
+            from known_peers
+            order by favourite desc, last_seen_at desc
+        "#
+    )
+    .fetch_all(&state.db)
+    .await?;
+
+    let mut known_peers = vec![];
+    for row in rows {
+        known_peers.push(load_known_peer(&state.db, row.device_id.into_uuid()).await?);
+    }
+    Ok(known_peers)
+}
+
+/*
+ * Adds a peer by host, for peers the subnet scan cannot find (a VPN address, another subnet...)
+ * The host has to answer as a Filey peer, and be recent enough to have a device identity
+ */
+#[tauri::command]
+pub async fn add_known_peer(
+    state: State<'_, AppState>,
+    host: String,
+) -> Result<KnownPeerModel, Error> {
+    let host = host.trim();
+    let peer = fetch_peer(host).await?;
+    let Some(device_id) = remember_peer(&state.db, &peer).await? else {
+        return Err(match peer.device_id {
+            Some(_) => Error::NoiseKeyMismatch(host.to_string()),
+            None => Error::PeerWithoutIdentity(host.to_string()),
+        });
+    };
+    load_known_peer(&state.db, device_id).await
+}
+
+// Gives a known peer a nickname, an empty one goes back to the name the peer gave itself
+#[tauri::command]
+pub async fn rename_known_peer(
+    state: State<'_, AppState>,
+    device_id: Uuid,
+    nickname: String,
+) -> Result<KnownPeerModel, Error> {
+    let id = device_id.to_string();
+    let nickname = nickname.trim();
+    let nickname = (!nickname.is_empty()).then_some(nickname);
+
+    sqlx::query!(
+        "update known_peers set nickname = $1 where device_id = $2 returning device_id",
+        nickname,
+        id
+    )
+    .fetch_one(&state.db)
+    .await?;
+
+    load_known_peer(&state.db, device_id).await
+}
+
+#[tauri::command]
+pub async fn set_known_peer_favourite(
+    state: State<'_, AppState>,
+    device_id: Uuid,
+    favourite: bool,
+) -> Result<KnownPeerModel, Error> {
+    let id = device_id.to_string();
+    sqlx::query!(
+        "update known_peers set favourite = $1 where device_id = $2 returning device_id",
+        favourite,
+        id
+    )
+    .fetch_one(&state.db)
+    .await?;
+
+    load_known_peer(&state.db, device_id).await
+}
+
+/*
+ * Talks to the peer only through the encrypted transport (see noise::client::request_peer),
+ * authenticated with the device key the peer was remembered with, instead of plain HTTP
+ */
+#[tauri::command]
+pub async fn set_known_peer_encrypted(
+    state: State<'_, AppState>,
+    device_id: Uuid,
+    encrypted: bool,
+) -> Result<KnownPeerModel, Error> {
+    let id = device_id.to_string();
+    sqlx::query!(
+        "
+            update known_peers set encrypted = $1
+            where
+                device_id = $2
+            and public_key is not null
+            returning device_id
+        ",
+        encrypted,
+        id
+    )
+    .fetch_optional(&state.db)
+    .await?
+    .ok_or_else(|| Error::PeerWithoutIdentity(id))?;
+
+    load_known_peer(&state.db, device_id).await
+}
+
+/*
+ * Makes a WebDAV password for a known peer, for its file manager to mount the shared folders
+ * paired with it, file managers cannot sign their requests (see http_server::webdav)
+ * Only a hash of it is kept, so it is shown this once, a new one replaces the old one
+ */
+#[tauri::command]
+pub async fn create_webdav_token(
+    state: State<'_, AppState>,
+    device_id: Uuid,
+) -> Result<String, Error> {
+    let token = URL_SAFE_NO_PAD.encode(rand::random::<[u8; 18]>());
+    let hash = content_sha256(token.as_bytes());
+    let id = device_id.to_string();
+    sqlx::query!(
+        "update known_peers set webdav_token = $1 where device_id = $2 returning device_id",
+        hash,
+        id
+    )
+    .fetch_one(&state.db)
+    .await?;
+    Ok(token)
+}
+
+// The file manager of the peer cannot log in to WebDAV anymore
+#[tauri::command]
+pub async fn revoke_webdav_token(
+    state: State<'_, AppState>,
+    device_id: Uuid,
+) -> Result<KnownPeerModel, Error> {
+    let id = device_id.to_string();
+    sqlx::query!(
+        "update known_peers set webdav_token = null where device_id = $1 returning device_id",
+        id
+    )
+    .fetch_one(&state.db)
+    .await?;
+
+    load_known_peer(&state.db, device_id).await
+}
+
+// Forgets a peer and the addresses it was seen at, it comes back the next time it is seen
+#[tauri::command]
+pub async fn forget_known_peer(state: State<'_, AppState>, device_id: Uuid) -> Result<(), Error> {
+    let id = device_id.to_string();
+    sqlx::query!(
+        "delete from known_peers where device_id = $1 returning device_id",
+        id
+    )
+    .fetch_one(&state.db)
+    .await?;
+    Ok(())
+}
+
+/*
+ * Records that a peer was seen at its address just now
+ * The device name and OS are refreshed, the nickname and favourite flag are kept
+ * The public key is kept as well once there is one, whoever shows up later with the same device id
+ * but another key is not that peer, see noise::client, and is left out
+ * Returns the device id, nothing for peers without an identity (protocol version 1) or left out
+ */
+pub async fn remember_peer(db: &SqlitePool, peer: &Peer) -> Result<Option<Uuid>, Error> {
+    let Some(device_id) = peer.device_id else {
+        return Ok(None);
+    };
+
+    let id = device_id.to_string();
+    let os_type = peer.os_type.to_string();
+    let seen_at = now();
+
+    let result = sqlx::query!(
+        "
+            insert into known_peers
+                (
+                    device_id, device_name, os_type, public_key,
+                    last_address, last_seen_at, created_at
+                )
+            values
+                ($1, $2, $3, $4, $5, $6, $6)
+            on conflict (device_id)
+            do update set
+                device_name = excluded.device_name,
+                os_type = excluded.os_type,
+                public_key = coalesce(known_peers.public_key, excluded.public_key),
+                last_address = excluded.last_address,
+                last_seen_at = excluded.last_seen_at
+            where known_peers.public_key is null
+                or known_peers.public_key = excluded.public_key
+        ",
+        id,
+        peer.device_name,
+        os_type,
+        peer.public_key,
+        peer.address,
+        seen_at
+    )
+    .execute(db)
+    .await?;
+    if result.rows_affected() == 0 {
+        warn!(
+            "Peer at {} claims device {device_id} with another key, it is not remembered",
+            peer.address
+        );
+        return Ok(None);
+    }
+
+    sqlx::query!(
+        "
+            insert into known_peer_addresses
+                (device_id, address, last_seen_at)
+            values
+                ($1, $2, $3)
+            on conflict (device_id, address)
+            do update set last_seen_at = excluded.last_seen_at
+        ",
+        id,
+        peer.address,
+        seen_at
+    )
+    .execute(db)
+    .await?;
+
+    Ok(Some(device_id))
+}
+
+pub async fn load_known_peer(db: &SqlitePool, device_id: Uuid) -> Result<KnownPeerModel, Error> {
+    let id = device_id.to_string();
+
+    let row = sqlx::query!(
+        r#"
+            select
+                device_name,
+                nickname,
+                os_type as "os_type!: OsType",
+                public_key,
+                favourite,
+                encrypted,
+                webdav_token is not null as "webdav_access!: bool",
+                last_address,
+                last_seen_at,
+                created_at
+            from known_peers
+            where device_id = $1
+        "#,
+        id
+    )
+    .fetch_one(db)
+    .await?;
+
+    let addresses = sqlx::query!(
+        "
+            select address
+            from known_peer_addresses
+            where device_id = $1
+            order by last_seen_at desc
+        ",
+        id
+    )
+    .fetch_all(db)
+    .await?
+    .into_iter()
+    .map(|row| row.address)
+    .collect();
+
+    Ok(KnownPeerModel {
+        device_id,
+        device_name: row.device_name,
+        nickname: row.nickname,
+        os_type: row.os_type,
+        public_key: row.public_key,
+        favourite: row.favourite,
+        encrypted: row.encrypted,
+        webdav_access: row.webdav_access,
+        last_address: row.last_address,
+        addresses,
+        last_seen_at: row.last_seen_at,
+        created_at: row.created_at,
+    })
+}
+
+// Returns every peer group, by name
+#[tauri::command]
+pub async fn get_peer_groups(state: State<'_, AppState>) -> Result<Vec<PeerGroupModel>, Error> {
+    let rows = sqlx::query!(
+        r#"
+            select id as "id!: Hyphenated"
+            from peer_groups
+            order by name collate nocase
+        "#
+    )
+    .fetch_all(&state.db)
+    .await?;
+
+    let mut groups = vec![];
+    for row in rows {
+        groups.push(load_peer_group(&state.db, row.id.into_uuid()).await?);
+    }
+    Ok(groups)
+}
+
+#[tauri::command]
+pub async fn create_peer_group(
+    state: State<'_, AppState>,
+    name: String,
+) -> Result<PeerGroupModel, Error> {
+    let group_id = Uuid::new_v4();
+    let id = group_id.to_string();
+    let name = name.trim();
+    let created_at = now();
+
+    sqlx::query!(
+        "insert into peer_groups (id, name, created_at) values ($1, $2, $3)",
+        id,
+        name,
+        created_at
+    )
+    .execute(&state.db)
+    .await?;
+
+    load_peer_group(&state.db, group_id).await
+}
+
+#[tauri::command]
+pub async fn rename_peer_group(
+    state: State<'_, AppState>,
+    group_id: Uuid,
+    name: String,
+) -> Result<PeerGroupModel, Error> {
+    let id = group_id.to_string();
+    let name = name.trim();
+
+    sqlx::query!(
+        "update peer_groups set name = $1 where id = $2 returning id",
+        name,
+        id
+    )
+    .fetch_one(&state.db)
+    .await?;
+
+    load_peer_group(&state.db, group_id).await
+}
+
+// Replaces the members of a group, the files shared with the group follow along
+#[tauri::command]
+pub async fn set_peer_group_members(
+    state: State<'_, AppState>,
+    group_id: Uuid,
+    device_ids: Vec<Uuid>,
+) -> Result<PeerGroupModel, Error> {
+    let id = group_id.to_string();
+
+    sqlx::query!("select id from peer_groups where id = $1", id)
+        .fetch_one(&state.db)
+        .await?;
+
+    sqlx::query!("delete from peer_group_members where group_id = $1", id)
+        .execute(&state.db)
+        .await?;
+
+    for device_id in device_ids {
+        let device_id = device_id.to_string();
+        sqlx::query!(
+            "insert into peer_group_members (group_id, device_id) values ($1, $2)",
+            id,
+            device_id
+        )
+        .execute(&state.db)
+        .await?;
+    }
+
+    load_peer_group(&state.db, group_id).await
+}
+
+// Deletes a group, the files shared with it stop being shared with its members
+#[tauri::command]
+pub async fn delete_peer_group(state: State<'_, AppState>, group_id: Uuid) -> Result<(), Error> {
+    let id = group_id.to_string();
+    sqlx::query!("delete from peer_groups where id = $1 returning id", id)
+        .fetch_one(&state.db)
+        .await?;
+    Ok(())
+}
+
+pub async fn load_peer_group(db: &SqlitePool, group_id: Uuid) -> Result<PeerGroupModel, Error> {
+    let id = group_id.to_string();
+
+    let row = sqlx::query!("select name, created_at from peer_groups where id = $1", id)
+        .fetch_one(db)
+        .await?;
+
+    let device_ids = sqlx::query!(
+        r#"
+            select device_id as "device_id!: Hyphenated"
+            from peer_group_members
+            where group_id = $1
+        "#,
+        id
+    )
+    .fetch_all(db)
+    .await?
+    .into_iter()
+    .map(|row| row.device_id.into_uuid())
+    .collect();
+
+    Ok(PeerGroupModel {
+        id: group_id,
+        name: row.name,
+        device_ids,
+        created_at: row.created_at,
+    })
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::db::test_db;
+
+    fn peer(device_id: Option<Uuid>, address: &str, public_key: &str) -> Peer {
+        Peer {
+            address: address.to_string(),
+            os_type: OsType::Linux,
+            protocol_version: 2,
+            app_version: Some("0.2.0".into()),
+            device_id,
+            device_name: Some("Desk".into()),
+            public_key: Some(public_key.to_string()),
+            capabilities: vec![],
+        }
+    }
+
+    #[tokio::test]
+    async fn peer_without_identity_is_not_remembered() {
+        let db = test_db().await;
+        let remembered = remember_peer(&db, &peer(None, "192.168.1.2", "key"))
+            .await
+            .unwrap();
+        assert_eq!(remembered, None);
+    }
+
+    #[tokio::test]
+    async fn peer_keeps_its_first_public_key_and_every_address() {
+        let db = test_db().await;
+        let device_id = Uuid::new_v4();
+        remember_peer(&db, &peer(Some(device_id), "192.168.1.2", "first"))
+            .await
+            .unwrap();
+        remember_peer(&db, &peer(Some(device_id), "192.168.1.3", "first"))
+            .await
+            .unwrap();
+
+        let known = load_known_peer(&db, device_id).await.unwrap();
+        assert_eq!(known.public_key.as_deref(), Some("first"));
+        assert_eq!(known.last_address, "192.168.1.3");
+        assert_eq!(known.addresses.len(), 2);
+    }
+
+    #[tokio::test]
+    async fn peer_with_another_key_does_not_move_the_known_peer() {
+        let db = test_db().await;
+        let device_id = Uuid::new_v4();
+        remember_peer(&db, &peer(Some(device_id), "192.168.1.2", "first"))
+            .await
+            .unwrap();
+        // Anyone can claim the same device id, that does not make its address the peer's
+        let remembered = remember_peer(&db, &peer(Some(device_id), "192.168.1.3", "second"))
+            .await
+            .unwrap();
+        assert_eq!(remembered, None);
+
+        let known = load_known_peer(&db, device_id).await.unwrap();
+        assert_eq!(known.public_key.as_deref(), Some("first"));
+        assert_eq!(known.last_address, "192.168.1.2");
+        assert_eq!(known.addresses.len(), 1);
+    }
+}
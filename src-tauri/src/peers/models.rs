@@ -0,0 +1,59 @@
+/*
+  Filey - simple peer-to-peer file sending across devices on different platforms
+  Copyright (C) 2024 Wander Watterson
+
+  This program is free software: you can redistribute it and/or modify
+  it under the terms of the GNU General Public License as published by
+  the Free Software Foundation, either version 3 of the License, or
+  (at your option) any later version.
+
+  This program is distributed in the hope that it will be useful,
+  but WITHOUT ANY WARRANTY; without even the implied warranty of
+  MERCHANTABILITY or FITNESS FOR A PARTICULAR PURPOSE. See the
+  GNU General Public License for more details.
+
+  You should have received a copy of the GNU General Public License
+  along with this program. If not, see <https://www.gnu.org/licenses/>.
+*/
+
+use serde::{Deserialize, Serialize};
+use uuid::Uuid;
+
+use crate::http_server::models::OsType;
+
+/*
+ * A peer that was seen before, remembered by its device identity
+ * so it is still recognised after its IP address changed
+ * Peers on protocol version 1 have no identity, they are never remembered
+ */
+#[derive(Debug, Serialize, Deserialize)]
+#[serde(rename_all = "camelCase")]
+pub struct KnownPeerModel {
+    pub device_id: Uuid,
+    // The name the peer gave itself
+    pub device_name: Option<String>,
+    // The name the user gave the peer, shown instead of the device name
+    pub nickname: Option<String>,
+    pub os_type: OsType,
+    pub public_key: Option<String>,
+    pub favourite: bool,
+    // Whether everything sent to the peer goes through the encrypted transport
+    pub encrypted: bool,
+    // Whether its file manager has a password to mount the shared folders, see http_server::webdav
+    pub webdav_access: bool,
+    pub last_address: String,
+    // Every address the peer was seen at, the most recent first
+    pub addresses: Vec<String>,
+    pub last_seen_at: i64,
+    pub created_at: i64,
+}
+
+// A named set of known peers, so a file can be shared with all of them at once
+#[derive(Debug, Serialize, Deserialize)]
+#[serde(rename_all = "camelCase")]
+pub struct PeerGroupModel {
+    pub id: Uuid,
+    pub name: String,
+    pub device_ids: Vec<Uuid>,
+    pub created_at: i64,
+}
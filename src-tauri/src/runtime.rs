@@ -0,0 +1,34 @@
+/*
+  Filey - simple peer-to-peer file sending across devices on different platforms
+  Copyright (C) 2024 Wander Watterson
+
+  This program is free software: you can redistribute it and/or modify
+  it under the terms of the GNU General Public License as published by
+  the Free Software Foundation, either version 3 of the License, or
+  (at your option) any later version.
+
+  This program is distributed in the hope that it will be useful,
+  but WITHOUT ANY WARRANTY; without even the implied warranty of
+  MERCHANTABILITY or FITNESS FOR A PARTICULAR PURPOSE. See the
+  GNU General Public License for more details.
+
+  You should have received a copy of the GNU General Public License
+  along with this program. If not, see <https://www.gnu.org/licenses/>.
+*/
+
+/*
+ * Where the background tasks run
+ * In the app, on the Tauri runtime, so they can be spawned from its setup hook already
+ * Without the gui feature (filey-server), on a tokio runtime of our own
+ */
+#[cfg(feature = "gui")]
+pub use tauri::async_runtime::{block_on, spawn, spawn_blocking, JoinHandle};
+#[cfg(not(feature = "gui"))]
+pub use tokio::task::{spawn, spawn_blocking, JoinHandle};
+
+#[cfg(not(feature = "gui"))]
+pub fn block_on<F: std::future::Future>(task: F) -> F::Output {
+    tokio::runtime::Runtime::new()
+        .expect("Cannot start the async runtime")
+        .block_on(task)
+}
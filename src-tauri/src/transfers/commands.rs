@@ -0,0 +1,801 @@
+/*
+  Filey - simple peer-to-peer file sending across devices on different platforms
+  Copyright (C) 2024 Wander Watterson
+
+  This program is free software: you can redistribute it and/or modify
+  it under the terms of the GNU General Public License as published by
+  the Free Software Foundation, either version 3 of the License, or
+  (at your option) any later version.
+
+  This program is distributed in the hope that it will be useful,
+  but WITHOUT ANY WARRANTY; without even the implied warranty of
+  MERCHANTABILITY or FITNESS FOR A PARTICULAR PURPOSE. See the
+  GNU General Public License for more details.
+
+  You should have received a copy of the GNU General Public License
+  along with this program. If not, see <https://www.gnu.org/licenses/>.
+*/
+
+use axum::body::Body;
+use log::info;
+use reqwest::{
+    header::{HeaderValue, CONTENT_TYPE},
+    Method,
+};
+use sqlx::SqlitePool;
+use std::{
+    collections::HashSet,
+    path::{Path, PathBuf},
+};
+use tauri::{AppHandle, Emitter, Manager, State};
+use tokio::{io::AsyncWriteExt, sync::oneshot};
+use tokio_stream::StreamExt;
+use uuid::{fmt::Hyphenated, Uuid};
+
+use super::{
+    manager::{emit_job, enqueue_job, load_jobs, record_job},
+    models::{
+        TransferFile, TransferJobModel, TransferJobState, TransferOffer, TransferReply,
+        TransferRequestModel, TransferRuleModel, TransferStatus, APPROVAL_TIMEOUT,
+    },
+};
+use crate::{
+    db::now,
+    device::{commands::signed_body_headers, models::content_sha256},
+    error::Error,
+    files::commands::open_file,
+    http_server::{commands::require_capability, models::Capability},
+    messages::models::Direction,
+    noise::client::request_peer,
+    AppState, ServerResponse,
+};
+
+/*
+ * Answers a pending transfer request from a peer
+ * With always_allow, the peer will not have to ask again the next time it sends us files,
+ * as long as it is a known peer that signed its request
+ * On the serving side, the peer is waiting in the handler in http_server::routes::transfers
+ */
+#[tauri::command]
+pub async fn respond_transfer_request(
+    state: State<'_, AppState>,
+    id: Uuid,
+    accept: bool,
+    always_allow: bool,
+) -> Result<(), Error> {
+    // The request is gone if it has been answered already, or the peer stopped waiting
+    let Some(decision) = state.pending_transfers.lock().await.remove(&id) else {
+        return Err(Error::TransferNotFound(id.to_string()));
+    };
+
+    if accept && always_allow {
+        if let Some(device_id) = load_transfer_request(&state.db, id).await?.device_id {
+            save_transfer_rule(&state.db, device_id).await?;
+        }
+    }
+
+    decision.send(accept).ok();
+    Ok(())
+}
+
+// Returns the requests that are still waiting for an answer, in case the UI missed the events
+#[tauri::command]
+pub async fn get_pending_transfer_requests(
+    state: State<'_, AppState>,
+) -> Result<Vec<TransferRequestModel>, Error> {
+    let ids: Vec<Uuid> = state
+        .pending_transfers
+        .lock()
+        .await
+        .keys()
+        .copied()
+        .collect();
+
+    let mut requests = vec![];
+    for id in ids {
+        requests.push(load_transfer_request(&state.db, id).await?);
+    }
+    requests.sort_by_key(|request| request.created_at);
+
+    Ok(requests)
+}
+
+// Returns the known peers that are always allowed to send us files
+#[tauri::command]
+pub async fn get_transfer_rules(
+    state: State<'_, AppState>,
+) -> Result<Vec<TransferRuleModel>, Error> {
+    let rules = sqlx::query_as!(
+        TransferRuleModel,
+        r#"
+            select
+                device_id as "device_id!: Hyphenated",
+                created_at
+            from transfer_rules
+            order by created_at desc
+        "#
+    )
+    .fetch_all(&state.db)
+    .await?;
+
+    Ok(rules)
+}
+
+#[tauri::command]
+pub async fn add_transfer_rule(state: State<'_, AppState>, device_id: Uuid) -> Result<(), Error> {
+    save_transfer_rule(&state.db, device_id).await
+}
+
+// The peer will have to ask again the next time it sends us files
+#[tauri::command]
+pub async fn delete_transfer_rule(
+    state: State<'_, AppState>,
+    device_id: Uuid,
+) -> Result<(), Error> {
+    let device_id = device_id.to_string();
+    sqlx::query!(
+        "delete from transfer_rules where device_id = $1 returning device_id",
+        device_id
+    )
+    .fetch_one(&state.db)
+    .await?;
+    Ok(())
+}
+
+/*
+ * Sends files to another Filey peer
+ * The peer is asked first with the file names and sizes, and once the user on the other side
+ * accepted them, every file is put into the transfer queue to be uploaded
+ * On the serving side, it will be handled by the handlers in http_server::routes::transfers
+ */
+#[tauri::command]
+pub async fn send_files_to_peer(
+    app_handle: AppHandle,
+    ip: &str,
+    paths: Vec<String>,
+) -> Result<TransferReply, Error> {
+    require_capability(ip, Capability::Transfers).await?;
+
+    let mut files = vec![];
+    for path in &paths {
+        let size = open_file(&app_handle, path)?.metadata().await?.len();
+        files.push(TransferFile {
+            name: file_name(path)?,
+            size,
+        });
+    }
+
+    /*
+     * This waits until the user on the other side answers, or the request expires
+     * Signed, so the peer can let a known peer it always allows send right away
+     */
+    let offer = serde_json::to_vec(&TransferOffer {
+        sender: tauri_plugin_os::hostname(),
+        files: files.clone(),
+    })?;
+    let state = app_handle.state::<AppState>();
+    let mut headers =
+        signed_body_headers(&state.db, "POST", "/transfers", &content_sha256(&offer)).await?;
+    headers.insert(CONTENT_TYPE, HeaderValue::from_static("application/json"));
+    let reply = request_peer(
+        &state.db,
+        ip,
+        Method::POST,
+        "/transfers",
+        headers,
+        offer.into(),
+    )
+    .await?
+    .error_for_status()?
+    .json::<ServerResponse<TransferReply>>()
+    .await?
+    .data;
+
+    if reply.status != TransferStatus::Accepted {
+        return Ok(reply);
+    }
+
+    for (index, (path, file)) in paths.into_iter().zip(files).enumerate() {
+        enqueue_job(
+            &app_handle,
+            &TransferJobModel {
+                id: Uuid::new_v4(),
+                direction: Direction::Outgoing,
+                peer: ip.to_string(),
+                name: file.name,
+                source: path,
+                request_id: Some(reply.id),
+                file_index: Some(index as i64),
+                base_path: None,
+                passphrase: None,
+                destination: None,
+                size: Some(file.size as i64),
+                transferred: 0,
+                priority: 0,
+                state: TransferJobState::Queued,
+                error: None,
+                active_ms: 0,
+                created_at: now(),
+                updated_at: now(),
+            },
+        )
+        .await?;
+    }
+
+    Ok(reply)
+}
+
+/*
+ * Puts a file of another Filey peer into the transfer queue to be downloaded
+ * The size is filled in once the download starts
+ * With a base path pointing to an older copy of the file, only the blocks that changed
+ * are downloaded, and the older copy gets updated instead of a new file being created
+ * With a passphrase, the file is one the peer serves encrypted, it is decrypted while it is
+ * downloaded so it is only ever stored decrypted on this device
+ * A peer too old for delta downloads sends the whole file instead, as a new file
+ */
+#[tauri::command]
+pub async fn download_from_peer(
+    app_handle: AppHandle,
+    ip: &str,
+    id: Uuid,
+    name: String,
+    base_path: Option<String>,
+    passphrase: Option<String>,
+) -> Result<TransferJobModel, Error> {
+    if passphrase.as_deref() == Some("") {
+        return Err(Error::EmptyPassphrase);
+    }
+    if let (Some(base_path), Some(_)) = (&base_path, &passphrase) {
+        return Err(Error::EncryptedDelta(base_path.clone()));
+    }
+    if let Some(base_path) = &base_path {
+        if !tokio::fs::metadata(base_path).await?.is_file() {
+            return Err(Error::InvalidFileName(base_path.clone()));
+        }
+    }
+
+    let peer = match &passphrase {
+        Some(_) => require_capability(ip, Capability::Encryption).await?,
+        None => require_capability(ip, Capability::Files).await?,
+    };
+    let base_path = base_path.filter(|_| {
+        let delta = peer.supports(&Capability::Signatures) && peer.supports(&Capability::Ranges);
+        if !delta {
+            info!("Peer {ip} has no delta downloads, {name} is downloaded whole");
+        }
+        delta
+    });
+
+    let state = app_handle.state::<AppState>();
+    let job = TransferJobModel {
+        id: Uuid::new_v4(),
+        direction: Direction::Incoming,
+        peer: ip.to_string(),
+        name,
+        source: id.to_string(),
+        request_id: None,
+        file_index: None,
+        base_path,
+        // Stored encrypted until the download is over, see files::encryption::PassphraseKey
+        passphrase: passphrase
+            .map(|passphrase| state.passphrase_key.encrypt_passphrase(&passphrase))
+            .transpose()?,
+        destination: None,
+        size: None,
+        transferred: 0,
+        priority: 0,
+        state: TransferJobState::Queued,
+        error: None,
+        active_ms: 0,
+        created_at: now(),
+        updated_at: now(),
+    };
+    enqueue_job(&app_handle, &job).await?;
+    Ok(job)
+}
+
+// Returns every job in the transfer queue, including the finished ones
+#[tauri::command]
+pub async fn get_transfer_jobs(state: State<'_, AppState>) -> Result<Vec<TransferJobModel>, Error> {
+    load_jobs(&state.db).await
+}
+
+// Stops a running job, or holds a queued one back, it keeps what it transferred so far
+#[tauri::command]
+pub async fn pause_transfer(app_handle: AppHandle, id: Uuid) -> Result<(), Error> {
+    let state = app_handle.state::<AppState>();
+
+    // A running job saves its own state once it has stopped, see manager::run_job
+    if state.transfers.stop(id, TransferJobState::Paused).await {
+        return Ok(());
+    }
+
+    let job_id = id.to_string();
+    let updated_at = now();
+    sqlx::query!(
+        "
+            update transfer_jobs
+            set
+                state = 'paused',
+                updated_at = $2
+            where
+                id = $1
+            and state = 'queued'
+            returning id
+        ",
+        job_id,
+        updated_at
+    )
+    .fetch_one(&state.db)
+    .await?;
+
+    emit_job(&app_handle, id).await
+}
+
+// Puts a paused or failed job back into the queue, it continues where it stopped
+#[tauri::command]
+pub async fn resume_transfer(app_handle: AppHandle, id: Uuid) -> Result<(), Error> {
+    let state = app_handle.state::<AppState>();
+
+    let job_id = id.to_string();
+    let updated_at = now();
+    sqlx::query!(
+        "
+            update transfer_jobs
+            set
+                state = 'queued',
+                error = null,
+                updated_at = $2
+            where
+                id = $1
+            and state in ('paused', 'failed')
+            returning id
+        ",
+        job_id,
+        updated_at
+    )
+    .fetch_one(&state.db)
+    .await?;
+
+    emit_job(&app_handle, id).await?;
+    state.transfers.queue_changed.notify_one();
+    Ok(())
+}
+
+// Stops a job for good, a partially downloaded file is thrown away
+#[tauri::command]
+pub async fn cancel_transfer(app_handle: AppHandle, id: Uuid) -> Result<(), Error> {
+    let state = app_handle.state::<AppState>();
+
+    // A running job saves its own state once it has stopped, see manager::run_job
+    if state.transfers.stop(id, TransferJobState::Cancelled).await {
+        return Ok(());
+    }
+
+    let job_id = id.to_string();
+    let updated_at = now();
+    sqlx::query!(
+        "
+            update transfer_jobs
+            set
+                state = 'cancelled',
+                updated_at = $2,
+                passphrase = null
+            where
+                id = $1
+            and state in ('queued', 'paused', 'failed')
+            returning id
+        ",
+        job_id,
+        updated_at
+    )
+    .fetch_one(&state.db)
+    .await?;
+
+    tokio::fs::remove_file(partial_path(&app_handle, &job_id)?)
+        .await
+        .ok();
+
+    emit_job(&app_handle, id).await?;
+    record_job(&app_handle, id).await
+}
+
+// Jobs with a higher priority leave the queue first
+#[tauri::command]
+pub async fn set_transfer_priority(
+    app_handle: AppHandle,
+    id: Uuid,
+    priority: i64,
+) -> Result<(), Error> {
+    let job_id = id.to_string();
+    sqlx::query!(
+        "update transfer_jobs set priority = $2 where id = $1 returning id",
+        job_id,
+        priority
+    )
+    .fetch_one(&app_handle.state::<AppState>().db)
+    .await?;
+
+    emit_job(&app_handle, id).await
+}
+
+// Removes the completed, failed and cancelled jobs from the list
+#[tauri::command]
+pub async fn clear_finished_transfers(state: State<'_, AppState>) -> Result<(), Error> {
+    sqlx::query!(
+        "
+            delete from transfer_jobs
+            where state in ('completed', 'failed', 'cancelled')
+            returning id
+        "
+    )
+    .fetch_all(&state.db)
+    .await?;
+    Ok(())
+}
+
+/*
+ * Creates a pending request, lets the UI know about it with a "transfer-request" event,
+ * and waits for the user to answer it with the respond_transfer_request command
+ * If nobody answers in time, the request expires
+ */
+pub async fn wait_for_approval(
+    app_handle: &AppHandle,
+    request: &TransferRequestModel,
+) -> Result<TransferStatus, Error> {
+    let state = app_handle.state::<AppState>();
+
+    let (decision_sender, decision_receiver) = oneshot::channel::<bool>();
+    state
+        .pending_transfers
+        .lock()
+        .await
+        .insert(request.id, decision_sender);
+
+    app_handle.emit("transfer-request", request)?;
+
+    let status = match tokio::time::timeout(APPROVAL_TIMEOUT, decision_receiver).await {
+        Ok(Ok(true)) => TransferStatus::Accepted,
+        Ok(Ok(false)) => TransferStatus::Declined,
+        _ => TransferStatus::Expired,
+    };
+    state.pending_transfers.lock().await.remove(&request.id);
+
+    // Close the prompt if it is still open
+    if status == TransferStatus::Expired {
+        app_handle.emit("transfer-request-expired", request.id)?;
+    }
+
+    Ok(status)
+}
+
+// Whether the known peer that signed a request never has to ask, anonymous peers always have to
+pub async fn is_always_allowed(db: &SqlitePool, device_id: Option<Uuid>) -> Result<bool, Error> {
+    let Some(device_id) = device_id else {
+        return Ok(false);
+    };
+    let device_id = device_id.to_string();
+    let rule = sqlx::query!(
+        "select device_id from transfer_rules where device_id = $1 limit 1",
+        device_id
+    )
+    .fetch_optional(db)
+    .await?;
+    Ok(rule.is_some())
+}
+
+async fn save_transfer_rule(db: &SqlitePool, device_id: Uuid) -> Result<(), Error> {
+    let device_id = device_id.to_string();
+    let created_at = now();
+    sqlx::query!(
+        "
+            insert into transfer_rules
+                (device_id, created_at)
+            values
+                ($1, $2)
+            on conflict (device_id) do nothing
+            returning device_id
+        ",
+        device_id,
+        created_at
+    )
+    .fetch_optional(db)
+    .await?;
+    Ok(())
+}
+
+pub async fn save_transfer_request(
+    db: &SqlitePool,
+    request: &TransferRequestModel,
+) -> Result<(), Error> {
+    let id = request.id.to_string();
+    let device_id = request.device_id.map(|device_id| device_id.to_string());
+    let files = serde_json::to_string(&request.files)?;
+    let status = request.status.to_string();
+
+    sqlx::query!(
+        "
+            insert into transfer_requests
+                (id, peer, device_id, sender, files, total_size, status, created_at)
+            values
+                ($1, $2, $3, $4, $5, $6, $7, $8)
+            returning id
+        ",
+        id,
+        request.peer,
+        device_id,
+        request.sender,
+        files,
+        request.total_size,
+        status,
+        request.created_at
+    )
+    .fetch_optional(db)
+    .await?;
+    Ok(())
+}
+
+pub async fn set_transfer_status(
+    db: &SqlitePool,
+    id: Uuid,
+    status: &TransferStatus,
+) -> Result<(), Error> {
+    let id = id.to_string();
+    let status = status.to_string();
+    sqlx::query!(
+        "update transfer_requests set status = $2 where id = $1 returning id",
+        id,
+        status
+    )
+    .fetch_one(db)
+    .await?;
+    Ok(())
+}
+
+pub async fn load_transfer_request(
+    db: &SqlitePool,
+    id: Uuid,
+) -> Result<TransferRequestModel, Error> {
+    let id = id.to_string();
+    let row = sqlx::query!(
+        r#"
+            select
+                id as "id!: Hyphenated",
+                peer,
+                device_id as "device_id: Hyphenated",
+                sender,
+                files,
+                total_size,
+                status as "status!: TransferStatus",
+                created_at
+            from transfer_requests
+            where id = $1
+        "#,
+        id
+    )
+    .fetch_one(db)
+    .await?;
+
+    Ok(TransferRequestModel {
+        id: row.id.into_uuid(),
+        peer: row.peer,
+        device_id: row.device_id.map(Hyphenated::into_uuid),
+        sender: row.sender,
+        files: serde_json::from_str(&row.files)?,
+        total_size: row.total_size,
+        status: row.status,
+        created_at: row.created_at,
+    })
+}
+
+/*
+ * Streams an uploaded file into a hidden partial file in the downloads folder
+ * An interrupted upload keeps what it got so far, and the peer continues from the offset
+ * it gets from received_size. Once the whole file is there, it gets its real name
+ * The upload cannot be bigger than the peer said it would be, otherwise it is thrown away
+ * Every file of a request is received once, and by one upload at a time, so an accepted request
+ * cannot be used to fill the disk with copies of it
+ */
+pub async fn receive_file(
+    app_handle: &AppHandle,
+    request_id: Uuid,
+    index: usize,
+    file: &TransferFile,
+    offset: u64,
+    body: Body,
+) -> Result<Option<PathBuf>, Error> {
+    let state = app_handle.state::<AppState>();
+    let key = format!("{request_id}/files/{index}");
+
+    // Claimed before checking, so an upload finishing right now is seen as received
+    let Some(_receiving) = Receiving::claim(&state.transfers.receiving, (request_id, index)) else {
+        return Err(Error::TransferInProgress(key));
+    };
+    if is_received(&state.db, request_id, index).await? {
+        return Err(Error::TransferFileReceived(key));
+    }
+
+    let part = partial_path(app_handle, &format!("{request_id}-{index}"))?;
+
+    let received = received_size(&part).await;
+    if received != offset {
+        return Err(Error::TransferOffsetMismatch(received));
+    }
+
+    let mut output = match offset {
+        0 => tokio::fs::File::create(&part).await?,
+        _ => {
+            tokio::fs::OpenOptions::new()
+                .append(true)
+                .open(&part)
+                .await?
+        }
+    };
+    let mut stream = body.into_data_stream();
+    let mut received = offset;
+
+    while let Some(chunk) = stream.next().await {
+        let chunk = match chunk {
+            Ok(chunk) => chunk,
+            Err(err) => {
+                output.flush().await?;
+                return Err(err.into());
+            }
+        };
+        received += chunk.len() as u64;
+        if received > file.size {
+            drop(output);
+            tokio::fs::remove_file(&part).await.ok();
+            return Err(Error::TransferSizeMismatch(file.name.clone()));
+        }
+        output.write_all(&chunk).await?;
+    }
+    output.flush().await?;
+    drop(output);
+
+    if received < file.size {
+        return Ok(None);
+    }
+
+    let path = unique_path(&app_handle.path().download_dir()?, &file_name(&file.name)?).await;
+    tokio::fs::rename(&part, &path).await?;
+
+    let request_id = request_id.to_string();
+    let file_index = index as i64;
+    let destination = path.display().to_string();
+    let received_at = now();
+    sqlx::query!(
+        "
+            insert into transfer_received_files
+                (request_id, file_index, destination, received_at)
+            values
+                ($1, $2, $3, $4)
+        ",
+        request_id,
+        file_index,
+        destination,
+        received_at
+    )
+    .execute(&state.db)
+    .await?;
+
+    Ok(Some(path))
+}
+
+// Whether a file of a transfer request was completely received already
+pub async fn is_received(db: &SqlitePool, request_id: Uuid, index: usize) -> Result<bool, Error> {
+    let request_id = request_id.to_string();
+    let file_index = index as i64;
+    Ok(sqlx::query!(
+        "
+            select request_id
+            from transfer_received_files
+            where
+                request_id = $1
+            and file_index = $2
+        ",
+        request_id,
+        file_index
+    )
+    .fetch_optional(db)
+    .await?
+    .is_some())
+}
+
+// A file of a transfer request being received, it is released when the upload ends or gets dropped
+struct Receiving<'a> {
+    receiving: &'a std::sync::Mutex<HashSet<(Uuid, usize)>>,
+    key: (Uuid, usize),
+}
+
+impl<'a> Receiving<'a> {
+    // Nothing if another upload is receiving the same file
+    fn claim(
+        receiving: &'a std::sync::Mutex<HashSet<(Uuid, usize)>>,
+        key: (Uuid, usize),
+    ) -> Option<Self> {
+        let claimed = receiving
+            .lock()
+            .unwrap_or_else(|poisoned| poisoned.into_inner())
+            .insert(key);
+        claimed.then_some(Receiving { receiving, key })
+    }
+}
+
+impl Drop for Receiving<'_> {
+    fn drop(&mut self) {
+        self.receiving
+            .lock()
+            .unwrap_or_else(|poisoned| poisoned.into_inner())
+            .remove(&self.key);
+    }
+}
+
+// Where a file is kept in the downloads folder until it is completely transferred
+pub fn partial_path(app_handle: &AppHandle, key: &str) -> Result<PathBuf, Error> {
+    Ok(app_handle
+        .path()
+        .download_dir()?
+        .join(format!(".{key}.part")))
+}
+
+// How much of a partial file is there, nothing if it does not exist yet
+pub async fn received_size(part: &Path) -> u64 {
+    tokio::fs::metadata(part)
+        .await
+        .map(|metadata| metadata.len())
+        .unwrap_or(0)
+}
+
+/*
+ * Only keeps the last component of a path, so a peer cannot write outside of the downloads
+ * folder with names like "../../.bashrc"
+ */
+pub fn file_name(path: &str) -> Result<String, Error> {
+    Path::new(path)
+        .file_name()
+        .map(|name| name.to_string_lossy().to_string())
+        .ok_or_else(|| Error::InvalidFileName(path.to_string()))
+}
+
+// Appends " (1)", " (2)", ... to the name until it does not collide with an existing file
+pub async fn unique_path(dir: &Path, name: &str) -> PathBuf {
+    let mut path = dir.join(name);
+    let stem = Path::new(name)
+        .file_stem()
+        .map(|stem| stem.to_string_lossy().to_string())
+        .unwrap_or_default();
+    let extension = Path::new(name).extension().map(|ext| ext.to_string_lossy());
+
+    let mut count = 1;
+    while tokio::fs::try_exists(&path).await.unwrap_or(false) {
+        path = dir.join(match &extension {
+            Some(extension) => format!("{stem} ({count}).{extension}"),
+            None => format!("{stem} ({count})"),
+        });
+        count += 1;
+    }
+    path
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::db::{insert_known_peer, test_db};
+
+    #[tokio::test]
+    async fn only_the_allowed_known_peer_skips_asking() {
+        let db = test_db().await;
+        let allowed = Uuid::new_v4();
+        let other = Uuid::new_v4();
+        insert_known_peer(&db, allowed, None).await;
+        insert_known_peer(&db, other, None).await;
+        save_transfer_rule(&db, allowed).await.unwrap();
+
+        assert!(is_always_allowed(&db, Some(allowed)).await.unwrap());
+        assert!(!is_always_allowed(&db, Some(other)).await.unwrap());
+        // Whatever address it comes from, a peer that did not sign has to ask
+        assert!(!is_always_allowed(&db, None).await.unwrap());
+    }
+}
@@ -0,0 +1,859 @@
+/*
+  Filey - simple peer-to-peer file sending across devices on different platforms
+  Copyright (C) 2024 Wander Watterson
+
+  This program is free software: you can redistribute it and/or modify
+  it under the terms of the GNU General Public License as published by
+  the Free Software Foundation, either version 3 of the License, or
+  (at your option) any later version.
+
+  This program is distributed in the hope that it will be useful,
+  but WITHOUT ANY WARRANTY; without even the implied warranty of
+  MERCHANTABILITY or FITNESS FOR A PARTICULAR PURPOSE. See the
+  GNU General Public License for more details.
+
+  You should have received a copy of the GNU General Public License
+  along with this program. If not, see <https://www.gnu.org/licenses/>.
+*/
+
+use log::error;
+use reqwest::{
+    header::{HeaderMap, HeaderValue, CONTENT_LENGTH, RANGE},
+    Body, Method, StatusCode,
+};
+use sha2::{Digest, Sha256};
+use sqlx::SqlitePool;
+use std::{
+    collections::{HashMap, HashSet},
+    io::SeekFrom,
+    path::{Path, PathBuf},
+    sync::{
+        atomic::{AtomicU64, Ordering},
+        Arc,
+    },
+    time::Instant,
+};
+use tauri::{AppHandle, Emitter, Manager};
+use tokio::{
+    io::{AsyncReadExt, AsyncSeekExt, AsyncWriteExt},
+    sync::{
+        oneshot::{self, Receiver, Sender},
+        Mutex, Notify,
+    },
+};
+use tokio_stream::StreamExt;
+use tokio_util::io::{ReaderStream, StreamReader};
+use uuid::{fmt::Hyphenated, Uuid};
+
+use super::{
+    commands::{file_name, partial_path, received_size, unique_path},
+    models::{
+        TransferJobModel, TransferJobState, TransferProgress, MAX_RUNNING_TRANSFERS,
+        PROGRESS_INTERVAL,
+    },
+};
+use crate::{
+    db::now,
+    device::commands::signed_headers,
+    error::Error,
+    files::{
+        commands::open_file,
+        delta::{block_len, find_reusable_blocks},
+        encryption::decrypted_reader,
+        models::FileSignatures,
+    },
+    history::{
+        commands::{average_speed, record_transfer},
+        models::{TransferHistoryModel, TransferOutcome},
+    },
+    messages::models::Direction,
+    noise::client::{get_from_peer, request_peer},
+    AppState, ServerResponse,
+};
+
+/*
+ * Runs the uploads and downloads in the background, a few at a time
+ * Everything about a job lives in the transfer_jobs table, so the queue survives restarts,
+ * the manager itself only keeps track of the jobs that are running right now
+ */
+#[derive(Default)]
+pub struct TransferManager {
+    /*
+     * Stop triggers of the running jobs, the job stops with the state it receives
+     * A job keeps its slot until it has actually stopped, it is only removed by run_job,
+     * the trigger is taken once it has been sent, see TransferManager::stop
+     */
+    pub running: Mutex<HashMap<Uuid, Option<Sender<TransferJobState>>>>,
+    // Wakes up the manager when a job gets queued, or a running job stops
+    pub queue_changed: Notify,
+    /*
+     * Files of accepted transfer requests that a peer is uploading to us right now,
+     * by request id and file index, see commands::receive_file
+     * Only touched in between awaits, so that it can be released when an upload gets dropped
+     */
+    pub receiving: std::sync::Mutex<HashSet<(Uuid, usize)>>,
+}
+
+impl TransferManager {
+    /*
+     * Asks a running job to stop with the given state
+     * False when the job is not running, true when it is, even if it was asked to stop already
+     */
+    pub async fn stop(&self, id: Uuid, job_state: TransferJobState) -> bool {
+        let mut running = self.running.lock().await;
+        let Some(stop_trigger) = running.get_mut(&id) else {
+            return false;
+        };
+        if let Some(stop_trigger) = stop_trigger.take() {
+            stop_trigger.send(job_state).ok();
+        }
+        true
+    }
+}
+
+pub fn spawn_transfer_manager(app_handle: AppHandle) {
+    crate::runtime::spawn(async move {
+        if let Err(err) = run_transfer_manager(&app_handle).await {
+            error!("Transfer manager stopped: {err}");
+        }
+    });
+}
+
+async fn run_transfer_manager(app_handle: &AppHandle) -> Result<(), Error> {
+    let state = app_handle.state::<AppState>();
+
+    // The jobs that were running when the app was closed go back to the queue
+    sqlx::query!("update transfer_jobs set state = 'queued' where state = 'running' returning id")
+        .fetch_all(&state.db)
+        .await?;
+
+    loop {
+        start_queued_jobs(app_handle).await?;
+        state.transfers.queue_changed.notified().await;
+    }
+}
+
+// Starts the queued jobs with the highest priority, until every slot is taken
+async fn start_queued_jobs(app_handle: &AppHandle) -> Result<(), Error> {
+    let state = app_handle.state::<AppState>();
+    let mut running = state.transfers.running.lock().await;
+
+    while running.len() < MAX_RUNNING_TRANSFERS {
+        let Some(next) = sqlx::query!(
+            r#"
+                select id as "id!: Hyphenated"
+                from transfer_jobs
+                where state = 'queued'
+                order by
+                    priority desc,
+                    created_at asc
+                limit 1
+            "#
+        )
+        .fetch_optional(&state.db)
+        .await?
+        else {
+            break;
+        };
+        let id = next.id.into_uuid();
+
+        set_job_state(app_handle, id, TransferJobState::Running, None).await?;
+
+        let (stop_trigger, stop_listener) = oneshot::channel::<TransferJobState>();
+        running.insert(id, Some(stop_trigger));
+
+        let app_handle = app_handle.clone();
+        crate::runtime::spawn(async move { run_job(&app_handle, id, stop_listener).await });
+    }
+    Ok(())
+}
+
+async fn run_job(app_handle: &AppHandle, id: Uuid, stop_listener: Receiver<TransferJobState>) {
+    let state = app_handle.state::<AppState>();
+
+    let started = Instant::now();
+    let (job_state, job_error) = match transfer(app_handle, id, stop_listener).await {
+        Ok(job_state) => (job_state, None),
+        Err(err) => (TransferJobState::Failed, Some(err.to_string())),
+    };
+    let active_ms = started.elapsed().as_millis() as i64;
+
+    state.transfers.running.lock().await.remove(&id);
+    if let Err(err) = stop_job(app_handle, id, job_state, job_error, active_ms).await {
+        error!("Cannot update transfer {id}: {err}");
+    }
+    state.transfers.queue_changed.notify_one();
+
+    // Hashing a big file takes a while, so it happens after the next job got its slot
+    if let Err(err) = record_job(app_handle, id).await {
+        error!("Cannot add transfer {id} to the history: {err}");
+    }
+}
+
+// Saves the state the job stopped with, along with the time it spent running
+async fn stop_job(
+    app_handle: &AppHandle,
+    id: Uuid,
+    job_state: TransferJobState,
+    job_error: Option<String>,
+    active_ms: i64,
+) -> Result<(), Error> {
+    let job_id = id.to_string();
+    sqlx::query!(
+        "update transfer_jobs set active_ms = active_ms + $2 where id = $1 returning id",
+        job_id,
+        active_ms
+    )
+    .fetch_one(&app_handle.state::<AppState>().db)
+    .await?;
+
+    set_job_state(app_handle, id, job_state, job_error).await
+}
+
+// Adds the job to the transfer history, if it is finished
+pub async fn record_job(app_handle: &AppHandle, id: Uuid) -> Result<(), Error> {
+    let job = load_job(&app_handle.state::<AppState>().db, id).await?;
+
+    let outcome = match job.state {
+        TransferJobState::Completed => TransferOutcome::Completed,
+        TransferJobState::Failed => TransferOutcome::Failed,
+        TransferJobState::Cancelled => TransferOutcome::Cancelled,
+        _ => return Ok(()),
+    };
+    let hash_path = match job.direction {
+        Direction::Outgoing => Some(job.source),
+        Direction::Incoming => job.destination.clone(),
+    };
+
+    record_transfer(
+        app_handle,
+        TransferHistoryModel {
+            id: Uuid::new_v4(),
+            direction: job.direction,
+            peer: job.peer,
+            peer_name: None,
+            name: job.name,
+            size: job.size,
+            hash: None,
+            duration_ms: job.active_ms,
+            speed: average_speed(job.transferred, job.active_ms),
+            outcome,
+            destination: job.destination,
+            error: job.error,
+            created_at: now(),
+        },
+        hash_path,
+    )
+    .await
+}
+
+/*
+ * Transfers the file until it is done, or the job gets paused or cancelled
+ * The progress is reported every now and then, and saved so that the UI can show it after a restart
+ */
+async fn transfer(
+    app_handle: &AppHandle,
+    id: Uuid,
+    mut stop_listener: Receiver<TransferJobState>,
+) -> Result<TransferJobState, Error> {
+    let state = app_handle.state::<AppState>();
+    let job = load_job(&state.db, id).await?;
+
+    let transferred = Arc::new(AtomicU64::new(job.transferred as u64));
+    let mut work = Box::pin(async {
+        match (&job.direction, &job.base_path) {
+            (Direction::Outgoing, _) => upload(app_handle, &job, &transferred).await,
+            (Direction::Incoming, Some(base_path)) => {
+                download_delta(app_handle, &job, base_path, &transferred).await
+            }
+            (Direction::Incoming, None) => download(app_handle, &job, &transferred).await,
+        }
+    });
+
+    let mut ticker = tokio::time::interval(PROGRESS_INTERVAL);
+    let job_state = loop {
+        tokio::select! {
+            result = &mut work => {
+                result?;
+                break TransferJobState::Completed;
+            }
+            stop = &mut stop_listener => break stop.unwrap_or(TransferJobState::Paused),
+            _ = ticker.tick() => {
+                report_progress(app_handle, id, transferred.load(Ordering::Relaxed)).await?;
+            }
+        }
+    };
+    // Closes the file before it gets removed
+    drop(work);
+
+    report_progress(app_handle, id, transferred.load(Ordering::Relaxed)).await?;
+
+    // A cancelled download does not need the partially downloaded file anymore
+    if job_state == TransferJobState::Cancelled && job.direction == Direction::Incoming {
+        let part = match &job.base_path {
+            Some(base_path) => delta_path(Path::new(base_path), id),
+            None => partial_path(app_handle, &id.to_string())?,
+        };
+        tokio::fs::remove_file(part).await.ok();
+    }
+
+    Ok(job_state)
+}
+
+/*
+ * Uploads a file of an accepted transfer request
+ * The peer is asked how much of the file it already got first, so a paused or interrupted
+ * upload continues from there, instead of starting over
+ * On the serving side, it will be handled by the handlers in http_server::routes::transfers
+ */
+async fn upload(
+    app_handle: &AppHandle,
+    job: &TransferJobModel,
+    transferred: &Arc<AtomicU64>,
+) -> Result<(), Error> {
+    let (Some(request_id), Some(index)) = (job.request_id, job.file_index) else {
+        return Err(Error::TransferNotAccepted(job.id.to_string()));
+    };
+    let state = app_handle.state::<AppState>();
+    let path = format!("/transfers/{request_id}/files/{index}");
+
+    let offset = get_from_peer(&state.db, &job.peer, &path, HeaderMap::new())
+        .await?
+        .error_for_status()?
+        .json::<ServerResponse<u64>>()
+        .await?
+        .data;
+    transferred.store(offset, Ordering::Relaxed);
+
+    let mut file = open_file(app_handle, &job.source)?;
+    let size = file.metadata().await?.len();
+    // The peer got the whole file already, the answer to the last upload got lost
+    if offset >= size {
+        return Ok(());
+    }
+    file.seek(SeekFrom::Start(offset)).await?;
+
+    let counter = transferred.clone();
+    let stream = ReaderStream::new(file).map(move |chunk| {
+        if let Ok(chunk) = &chunk {
+            counter.fetch_add(chunk.len() as u64, Ordering::Relaxed);
+        }
+        chunk
+    });
+
+    let mut headers = HeaderMap::new();
+    headers.insert(CONTENT_LENGTH, size.saturating_sub(offset).into());
+    request_peer(
+        &state.db,
+        &job.peer,
+        Method::PUT,
+        &format!("{path}?offset={offset}"),
+        headers,
+        Body::wrap_stream(stream),
+    )
+    .await?
+    .error_for_status()?;
+
+    Ok(())
+}
+
+/*
+ * Downloads a file from a peer into the downloads folder
+ * The file is written next to it as a hidden partial file first, and continued with a Range
+ * request when the job is resumed. It only gets its real name once it is complete
+ * An encrypted file is decrypted while it is being written, see files::encryption
+ * The peer always sends it whole, so its download starts over when it is resumed
+ * On the serving side, it will be handled by a handler in http_server::routes::get_file
+ */
+async fn download(
+    app_handle: &AppHandle,
+    job: &TransferJobModel,
+    transferred: &Arc<AtomicU64>,
+) -> Result<(), Error> {
+    let state = app_handle.state::<AppState>();
+    let db = &state.db;
+    let part = partial_path(app_handle, &job.id.to_string())?;
+    let offset = match job.passphrase {
+        Some(_) => 0,
+        None => received_size(&part).await,
+    };
+
+    let path = format!("/files/{}?mode=download", job.source);
+    let mut headers = signed_headers(db, "GET", &path).await?;
+    if offset > 0 {
+        headers.insert(RANGE, range_header(offset, None));
+    }
+    let mut response = get_from_peer(db, &job.peer, &path, headers).await?;
+
+    // The whole file was already there, it just did not get renamed before the app was closed
+    if offset > 0 && response.status() == StatusCode::RANGE_NOT_SATISFIABLE {
+        return finish_download(app_handle, db, job).await;
+    }
+    response = response.error_for_status()?;
+
+    // The peer may not support ranges for everything (text snippets), then it starts over
+    let resumed = response.status() == StatusCode::PARTIAL_CONTENT;
+    let offset = if resumed { offset } else { 0 };
+    let mut output = match resumed {
+        true => {
+            tokio::fs::OpenOptions::new()
+                .append(true)
+                .open(&part)
+                .await?
+        }
+        false => tokio::fs::File::create(&part).await?,
+    };
+    transferred.store(offset, Ordering::Relaxed);
+
+    if let Some(length) = response.content_length() {
+        set_job_size(db, job.id, (offset + length) as i64).await?;
+    }
+
+    match &job.passphrase {
+        Some(passphrase) => {
+            let passphrase = state.passphrase_key.decrypt_passphrase(passphrase)?;
+            let body = response
+                .bytes_stream()
+                .map(|chunk| chunk.map_err(std::io::Error::other));
+            let input = decrypted_reader(StreamReader::new(body), &passphrase).await?;
+            let mut chunks = ReaderStream::new(input);
+            while let Some(chunk) = chunks.next().await {
+                let chunk = chunk?;
+                output.write_all(&chunk).await?;
+                transferred.fetch_add(chunk.len() as u64, Ordering::Relaxed);
+            }
+        }
+        None => {
+            while let Some(chunk) = response.chunk().await? {
+                output.write_all(&chunk).await?;
+                transferred.fetch_add(chunk.len() as u64, Ordering::Relaxed);
+            }
+        }
+    }
+    output.flush().await?;
+    drop(output);
+
+    finish_download(app_handle, db, job).await
+}
+
+/*
+ * Updates an older local copy of a file to the peer's version, see files::delta
+ * The blocks that are already in the older copy are copied from it, the rest is downloaded
+ * with Range requests, one per run of missing blocks. The new version is written next to the
+ * older copy, checked against the peer's hash, and then replaces it
+ * A paused delta download starts over, the signatures may have changed in the meantime
+ * On the serving side, it will be handled by the handlers in http_server::routes::signatures
+ * and http_server::routes::get_file
+ */
+async fn download_delta(
+    app_handle: &AppHandle,
+    job: &TransferJobModel,
+    base_path: &str,
+    transferred: &Arc<AtomicU64>,
+) -> Result<(), Error> {
+    let state = app_handle.state::<AppState>();
+    let db = &state.db;
+    let path = format!("/files/{}", job.source);
+    let signatures_path = format!("{path}/signatures");
+
+    let headers = signed_headers(db, "GET", &signatures_path).await?;
+    let signatures = get_from_peer(db, &job.peer, &signatures_path, headers)
+        .await?
+        .error_for_status()?
+        .json::<ServerResponse<FileSignatures>>()
+        .await?
+        .data;
+    set_job_size(db, job.id, signatures.size as i64).await?;
+    transferred.store(0, Ordering::Relaxed);
+
+    let base_path = Path::new(base_path);
+    let reusable =
+        find_reusable_blocks(tokio::fs::File::open(base_path).await?, &signatures).await?;
+
+    let part = delta_path(base_path, job.id);
+    let mut output = tokio::fs::File::create(&part).await?;
+    let mut old_copy = tokio::fs::File::open(base_path).await?;
+    let mut hasher = Sha256::new();
+
+    let mut index = 0;
+    while index < reusable.len() {
+        if let Some(offset) = reusable[index] {
+            let mut block = vec![0; block_len(&signatures, index) as usize];
+            old_copy.seek(SeekFrom::Start(offset)).await?;
+            old_copy.read_exact(&mut block).await?;
+            output.write_all(&block).await?;
+            hasher.update(&block);
+            transferred.fetch_add(block.len() as u64, Ordering::Relaxed);
+            index += 1;
+            continue;
+        }
+
+        // Every missing block in a row is downloaded at once
+        let first = index;
+        while index < reusable.len() && reusable[index].is_none() {
+            index += 1;
+        }
+        let start = first as u64 * signatures.block_size;
+        let end = (index as u64 * signatures.block_size).min(signatures.size) - 1;
+
+        let mut headers = signed_headers(db, "GET", &path).await?;
+        headers.insert(RANGE, range_header(start, Some(end)));
+        let mut response = get_from_peer(db, &job.peer, &path, headers)
+            .await?
+            .error_for_status()?;
+        if response.status() != StatusCode::PARTIAL_CONTENT {
+            return Err(Error::DeltaMismatch(job.name.clone()));
+        }
+        while let Some(chunk) = response.chunk().await? {
+            output.write_all(&chunk).await?;
+            hasher.update(&chunk);
+            transferred.fetch_add(chunk.len() as u64, Ordering::Relaxed);
+        }
+    }
+    output.flush().await?;
+    drop(output);
+
+    if format!("{:x}", hasher.finalize()) != signatures.hash {
+        tokio::fs::remove_file(&part).await.ok();
+        return Err(Error::DeltaMismatch(job.name.clone()));
+    }
+    tokio::fs::rename(&part, base_path).await?;
+
+    let id = job.id.to_string();
+    let destination = base_path.display().to_string();
+    sqlx::query!(
+        "update transfer_jobs set destination = $2 where id = $1 returning id",
+        id,
+        destination
+    )
+    .fetch_one(db)
+    .await?;
+    Ok(())
+}
+
+// bytes=<start>-<end>, up to the end of the file when there is no end
+fn range_header(start: u64, end: Option<u64>) -> HeaderValue {
+    let end = end.map(|end| end.to_string()).unwrap_or_default();
+    // Numbers are always a valid header value
+    HeaderValue::from_str(&format!("bytes={start}-{end}")).unwrap()
+}
+
+// Where the new version of a file is written during a delta download, next to the older copy
+fn delta_path(base_path: &Path, id: Uuid) -> PathBuf {
+    base_path
+        .parent()
+        .unwrap_or(base_path)
+        .join(format!(".{id}.part"))
+}
+
+// Gives the downloaded file its real name, without overwriting anything
+async fn finish_download(
+    app_handle: &AppHandle,
+    db: &SqlitePool,
+    job: &TransferJobModel,
+) -> Result<(), Error> {
+    let part = partial_path(app_handle, &job.id.to_string())?;
+    let destination = unique_path(&app_handle.path().download_dir()?, &file_name(&job.name)?).await;
+    tokio::fs::rename(&part, &destination).await?;
+
+    let id = job.id.to_string();
+    let destination = destination.display().to_string();
+    sqlx::query!(
+        "
+            update transfer_jobs
+            set
+                destination = $2,
+                passphrase = null
+            where id = $1
+            returning id
+        ",
+        id,
+        destination
+    )
+    .fetch_one(db)
+    .await?;
+    Ok(())
+}
+
+// Adds a job to the queue, it starts as soon as there is a free slot
+pub async fn enqueue_job(app_handle: &AppHandle, job: &TransferJobModel) -> Result<(), Error> {
+    let state = app_handle.state::<AppState>();
+
+    let id = job.id.to_string();
+    let direction = job.direction.to_string();
+    let request_id = job.request_id.map(|id| id.to_string());
+    let job_state = job.state.to_string();
+
+    sqlx::query!(
+        "
+            insert into transfer_jobs
+                (
+                    id, direction, peer, name, source, request_id, file_index,
+                    base_path, passphrase, size, priority, state, created_at, updated_at
+                )
+            values
+                ($1, $2, $3, $4, $5, $6, $7, $8, $9, $10, $11, $12, $13, $14)
+            returning id
+        ",
+        id,
+        direction,
+        job.peer,
+        job.name,
+        job.source,
+        request_id,
+        job.file_index,
+        job.base_path,
+        job.passphrase,
+        job.size,
+        job.priority,
+        job_state,
+        job.created_at,
+        job.updated_at
+    )
+    .fetch_optional(&state.db)
+    .await?;
+
+    app_handle.emit("transfer-job", job)?;
+    state.transfers.queue_changed.notify_one();
+    Ok(())
+}
+
+pub async fn set_job_state(
+    app_handle: &AppHandle,
+    id: Uuid,
+    job_state: TransferJobState,
+    job_error: Option<String>,
+) -> Result<(), Error> {
+    let state = app_handle.state::<AppState>();
+    let db = &state.db;
+    let job_id = id.to_string();
+    let job_state = job_state.to_string();
+    let updated_at = now();
+
+    // A finished job has no use for its passphrase anymore, a failed one can still be resumed
+    sqlx::query!(
+        "
+            update transfer_jobs
+            set
+                state = $2,
+                error = $3,
+                updated_at = $4,
+                passphrase = case
+                    when $2 in ('completed', 'cancelled') then null
+                    else passphrase
+                end
+            where id = $1
+            returning id
+        ",
+        job_id,
+        job_state,
+        job_error,
+        updated_at
+    )
+    .fetch_one(db)
+    .await?;
+
+    emit_job(app_handle, id).await
+}
+
+// Lets the UI know that something changed about a job
+pub async fn emit_job(app_handle: &AppHandle, id: Uuid) -> Result<(), Error> {
+    let job = load_job(&app_handle.state::<AppState>().db, id).await?;
+    app_handle.emit("transfer-job", job)?;
+    Ok(())
+}
+
+async fn set_job_size(db: &SqlitePool, id: Uuid, size: i64) -> Result<(), Error> {
+    let id = id.to_string();
+    sqlx::query!(
+        "update transfer_jobs set size = $2 where id = $1 returning id",
+        id,
+        size
+    )
+    .fetch_one(db)
+    .await?;
+    Ok(())
+}
+
+async fn report_progress(app_handle: &AppHandle, id: Uuid, transferred: u64) -> Result<(), Error> {
+    let state = app_handle.state::<AppState>();
+    let db = &state.db;
+    let job_id = id.to_string();
+    let transferred = transferred as i64;
+    let updated_at = now();
+
+    let job = sqlx::query!(
+        "
+            update transfer_jobs
+            set
+                transferred = $2,
+                updated_at = $3
+            where id = $1
+            returning size
+        ",
+        job_id,
+        transferred,
+        updated_at
+    )
+    .fetch_one(db)
+    .await?;
+
+    app_handle.emit(
+        "transfer-progress",
+        TransferProgress {
+            id,
+            transferred,
+            size: job.size,
+        },
+    )?;
+    Ok(())
+}
+
+// The request id is optional, and sqlx cannot turn an optional text into an optional uuid
+struct TransferJobRow {
+    id: Uuid,
+    direction: Direction,
+    peer: String,
+    name: String,
+    source: String,
+    request_id: Option<String>,
+    file_index: Option<i64>,
+    base_path: Option<String>,
+    passphrase: Option<String>,
+    destination: Option<String>,
+    size: Option<i64>,
+    transferred: i64,
+    priority: i64,
+    state: TransferJobState,
+    error: Option<String>,
+    active_ms: i64,
+    created_at: i64,
+    updated_at: i64,
+}
+
+impl From<TransferJobRow> for TransferJobModel {
+    fn from(row: TransferJobRow) -> Self {
+        TransferJobModel {
+            id: row.id,
+            direction: row.direction,
+            peer: row.peer,
+            name: row.name,
+            source: row.source,
+            request_id: row.request_id.and_then(|id| id.parse().ok()),
+            file_index: row.file_index,
+            base_path: row.base_path,
+            passphrase: row.passphrase,
+            destination: row.destination,
+            size: row.size,
+            transferred: row.transferred,
+            priority: row.priority,
+            state: row.state,
+            error: row.error,
+            active_ms: row.active_ms,
+            created_at: row.created_at,
+            updated_at: row.updated_at,
+        }
+    }
+}
+
+pub async fn load_job(db: &SqlitePool, id: Uuid) -> Result<TransferJobModel, Error> {
+    let id = id.to_string();
+    let row = sqlx::query_as!(
+        TransferJobRow,
+        r#"
+            select
+                id as "id!: Hyphenated",
+                direction as "direction!: Direction",
+                peer,
+                name,
+                source,
+                request_id,
+                file_index,
+                base_path,
+                passphrase,
+                destination,
+                size,
+                transferred,
+                priority,
+                state as "state!: TransferJobState",
+                error,
+                active_ms,
+                created_at,
+                updated_at
+            from transfer_jobs
+            where id = $1
+        "#,
+        id
+    )
+    .fetch_one(db)
+    .await?;
+
+    Ok(row.into())
+}
+
+// Every job, the running ones first, then the queue in the order it will run
+pub async fn load_jobs(db: &SqlitePool) -> Result<Vec<TransferJobModel>, Error> {
+    let rows = sqlx::query_as!(
+        TransferJobRow,
+        r#"
+            select
+                id as "id!: Hyphenated",
+                direction as "direction!: Direction",
+                peer,
+                name,
+                source,
+                request_id,
+                file_index,
+                base_path,
+                passphrase,
+                destination,
+                size,
+                transferred,
+                priority,
+                state as "state!: TransferJobState",
+                error,
+                active_ms,
+                created_at,
+                updated_at
+            from transfer_jobs
+            order by
+                state = 'running' desc,
+                state = 'queued' desc,
+                priority desc,
+                created_at asc
+        "#
+    )
+    .fetch_all(db)
+    .await?;
+
+    Ok(rows.into_iter().map(TransferJobModel::from).collect())
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[tokio::test]
+    async fn stopped_job_keeps_its_slot_until_it_is_done() {
+        let transfers = TransferManager::default();
+        let id = Uuid::new_v4();
+        let (stop_trigger, stop_listener) = oneshot::channel();
+        transfers
+            .running
+            .lock()
+            .await
+            .insert(id, Some(stop_trigger));
+
+        assert!(transfers.stop(id, TransferJobState::Paused).await);
+        assert_eq!(stop_listener.await.unwrap(), TransferJobState::Paused);
+        assert!(transfers.running.lock().await.contains_key(&id));
+
+        // Asked again while it is stopping, there is nothing more to send
+        assert!(transfers.stop(id, TransferJobState::Cancelled).await);
+        assert!(
+            !transfers
+                .stop(Uuid::new_v4(), TransferJobState::Paused)
+                .await
+        );
+    }
+}
@@ -0,0 +1,207 @@
+/*
+  Filey - simple peer-to-peer file sending across devices on different platforms
+  Copyright (C) 2024 Wander Watterson
+
+  This program is free software: you can redistribute it and/or modify
+  it under the terms of the GNU General Public License as published by
+  the Free Software Foundation, either version 3 of the License, or
+  (at your option) any later version.
+
+  This program is distributed in the hope that it will be useful,
+  but WITHOUT ANY WARRANTY; without even the implied warranty of
+  MERCHANTABILITY or FITNESS FOR A PARTICULAR PURPOSE. See the
+  GNU General Public License for more details.
+
+  You should have received a copy of the GNU General Public License
+  along with this program. If not, see <https://www.gnu.org/licenses/>.
+*/
+
+use serde::{Deserialize, Serialize};
+use sqlx::{sqlite::SqliteTypeInfo, Database, Decode, Encode, Sqlite, Type};
+use std::{str::FromStr, time::Duration};
+use strum::{Display, EnumString};
+use uuid::Uuid;
+
+use crate::messages::models::Direction;
+
+// How many transfers can run at the same time, the rest wait in the queue
+pub const MAX_RUNNING_TRANSFERS: usize = 3;
+
+// How often the progress of a running transfer is reported to the UI and saved
+pub const PROGRESS_INTERVAL: Duration = Duration::from_millis(500);
+
+// How long a peer waits for the user to accept or decline before the request expires
+pub const APPROVAL_TIMEOUT: Duration = Duration::from_secs(60);
+
+/*
+ * Pending => waiting for the user to answer
+ * Accepted => the peer can upload the files now
+ * Declined => the user said no
+ * Expired => nobody answered in time
+ */
+#[derive(Serialize, Deserialize, Debug, Clone, PartialEq, Encode, Display, EnumString)]
+#[serde(rename_all = "lowercase")]
+#[strum(serialize_all = "lowercase")]
+pub enum TransferStatus {
+    Pending,
+    Accepted,
+    Declined,
+    Expired,
+}
+
+impl Type<Sqlite> for TransferStatus {
+    fn type_info() -> SqliteTypeInfo {
+        <&str as Type<Sqlite>>::type_info()
+    }
+    fn compatible(ty: &SqliteTypeInfo) -> bool {
+        <&str as Type<Sqlite>>::compatible(ty)
+    }
+}
+
+impl<'r, DB: Database> Decode<'r, DB> for TransferStatus
+where
+    &'r str: Decode<'r, DB>,
+{
+    fn decode(
+        value: <DB as sqlx::Database>::ValueRef<'r>,
+    ) -> Result<Self, sqlx::error::BoxDynError> {
+        let value = <&str as Decode<DB>>::decode(value)?;
+        Ok(TransferStatus::from_str(value).unwrap())
+    }
+}
+
+// A file the peer wants to send us, the size is in bytes
+#[derive(Debug, Clone, Serialize, Deserialize)]
+pub struct TransferFile {
+    pub name: String,
+    pub size: u64,
+}
+
+// What a peer sends to the /transfers route before uploading anything
+#[derive(Debug, Serialize, Deserialize)]
+pub struct TransferOffer {
+    pub sender: String,
+    pub files: Vec<TransferFile>,
+}
+
+// Transfer request model in the database, the files are stored as JSON
+#[derive(Debug, Clone, Serialize, Deserialize)]
+#[serde(rename_all = "camelCase")]
+pub struct TransferRequestModel {
+    pub id: Uuid,
+    pub peer: String,
+    // The known peer that signed the offer, None for a peer that did not sign it
+    pub device_id: Option<Uuid>,
+    pub sender: String,
+    pub files: Vec<TransferFile>,
+    pub total_size: i64,
+    pub status: TransferStatus,
+    pub created_at: i64,
+}
+
+// The answer to an offer, the peer uploads the files with the id if it got accepted
+#[derive(Debug, Serialize, Deserialize)]
+pub struct TransferReply {
+    pub id: Uuid,
+    pub status: TransferStatus,
+}
+
+// A known peer that is always allowed to send us files without asking
+#[derive(Debug, Serialize, Deserialize)]
+#[serde(rename_all = "camelCase")]
+pub struct TransferRuleModel {
+    pub device_id: Uuid,
+    pub created_at: i64,
+}
+
+// Emitted to the UI every time a file of an accepted request finished uploading
+#[derive(Debug, Clone, Serialize)]
+#[serde(rename_all = "camelCase")]
+pub struct ReceivedFile {
+    pub request_id: Uuid,
+    pub name: String,
+    pub path: String,
+}
+
+/*
+ * Queued => waiting for a free slot, the highest priority goes first
+ * Running => being transferred right now
+ * Paused => stopped by the user, resuming continues where it stopped
+ * Completed, Failed, Cancelled => done, failed transfers can be resumed
+ */
+#[derive(Serialize, Deserialize, Debug, Clone, PartialEq, Encode, Display, EnumString)]
+#[serde(rename_all = "lowercase")]
+#[strum(serialize_all = "lowercase")]
+pub enum TransferJobState {
+    Queued,
+    Running,
+    Paused,
+    Completed,
+    Failed,
+    Cancelled,
+}
+
+impl Type<Sqlite> for TransferJobState {
+    fn type_info() -> SqliteTypeInfo {
+        <&str as Type<Sqlite>>::type_info()
+    }
+    fn compatible(ty: &SqliteTypeInfo) -> bool {
+        <&str as Type<Sqlite>>::compatible(ty)
+    }
+}
+
+impl<'r, DB: Database> Decode<'r, DB> for TransferJobState
+where
+    &'r str: Decode<'r, DB>,
+{
+    fn decode(
+        value: <DB as sqlx::Database>::ValueRef<'r>,
+    ) -> Result<Self, sqlx::error::BoxDynError> {
+        let value = <&str as Decode<DB>>::decode(value)?;
+        Ok(TransferJobState::from_str(value).unwrap())
+    }
+}
+
+/*
+ * A single file in the transfer queue
+ * Outgoing => an upload, the source is the local path, and the request id and file index
+ *             are the ones given by the peer when it accepted the files
+ * Incoming => a download, the source is the id of the file on the peer
+ *             With a base path, only the changes are downloaded into that older copy of the file
+ *             With a passphrase, the peer serves it encrypted, and it is decrypted on the way in
+ * The destination is where a download ended up, once it completed
+ * The active time is how long the job has been running, the time spent paused does not count
+ */
+#[derive(Debug, Clone, Serialize, Deserialize)]
+#[serde(rename_all = "camelCase")]
+pub struct TransferJobModel {
+    pub id: Uuid,
+    pub direction: Direction,
+    pub peer: String,
+    pub name: String,
+    pub source: String,
+    pub request_id: Option<Uuid>,
+    pub file_index: Option<i64>,
+    pub base_path: Option<String>,
+    // Kept out of what the UI gets, and forgotten once the download completed
+    #[serde(skip_serializing, default)]
+    pub passphrase: Option<String>,
+    pub destination: Option<String>,
+    pub size: Option<i64>,
+    pub transferred: i64,
+    pub priority: i64,
+    pub state: TransferJobState,
+    pub error: Option<String>,
+    pub active_ms: i64,
+    pub created_at: i64,
+    pub updated_at: i64,
+}
+
+// Emitted to the UI while a transfer is running
+#[derive(Debug, Clone, Serialize)]
+#[serde(rename_all = "camelCase")]
+pub struct TransferProgress {
+    pub id: Uuid,
+    pub transferred: i64,
+    pub size: Option<i64>,
+}
@@ -42,6 +42,7 @@ import {
   isServerOnlineAtom,
   localIpsAtom,
 } from "@/features/server/store";
+import { CommandError } from "@/features/server/types";
 import { filesAtom } from "../store";
 import { FileModel } from "../types";
 import {
@@ -49,6 +50,7 @@ import {
   IconEye,
   IconEyeOff,
   IconFolderSearch,
+  IconLink,
   IconPhoto,
   IconQrcode,
   IconTrash,
@@ -180,8 +182,8 @@ export const FileItem = ({
 
             {/* Qr code */}
             <Tooltip
-              disabled={isServerOnline && visibility === "public"}
-              label="Requires server status online and visibility public"
+              disabled={isServerOnline && visibility !== "private"}
+              label="Requires server status online and visibility public or unlisted"
             >
               <Button
                 px="3px"
@@ -206,9 +208,11 @@ export const FileItem = ({
             {isFileyLocal && (
               <Tooltip
                 label={`Currently ${visibility} - ${
-                  visibility === "public"
-                    ? "Everyone can see"
-                    : "No one can see"
+                  {
+                    public: "Everyone can see",
+                    unlisted: "Only those with the link can see",
+                    private: "No one can see",
+                  }[visibility]
                 }`}
               >
                 <Button
@@ -217,20 +221,40 @@ export const FileItem = ({
                   onClick={() => {
                     const modifiedFiles: FileModel[] = files.map((file) => {
                       if (file.id === id) {
-                        file.visibility =
-                          visibility === "public" ? "private" : "public";
+                        // public -> unlisted -> private -> public
+                        file.visibility = (
+                          {
+                            public: "unlisted",
+                            unlisted: "private",
+                            private: "public",
+                          } as const
+                        )[visibility];
                       }
                       return file;
                     });
 
                     setFiles({ type: "upsert", files: modifiedFiles });
                   }}
-                  color={visibility === "public" ? "lime" : "gray"}
+                  color={
+                    { public: "lime", unlisted: "yellow", private: "gray" }[
+                      visibility
+                    ]
+                  }
                   leftSection={
-                    visibility === "public" ? <IconEye /> : <IconEyeOff />
+                    {
+                      public: <IconEye />,
+                      unlisted: <IconLink />,
+                      private: <IconEyeOff />,
+                    }[visibility]
                   }
                 >
-                  {visibility === "public" ? "Public" : "Private"}
+                  {
+                    {
+                      public: "Public",
+                      unlisted: "Unlisted",
+                      private: "Private",
+                    }[visibility]
+                  }
                 </Button>
               </Tooltip>
             )}
@@ -243,7 +267,11 @@ export const FileItem = ({
               isFileyLocal && isDesktop && (
                 <Tooltip label={path}>
                   <Button
-                    onClick={() => invoke("reveal", { path }).catch(error)}
+                    onClick={() =>
+                      invoke("reveal", { path }).catch((err: CommandError) =>
+                        error(err.message)
+                      )
+                    }
                     variant="subtle"
                     color={theme.colors.indigo[3]}
                     px="10px"
@@ -17,19 +17,50 @@
 */
 
 /**
- * These 2 models map directly to the models in /src-tauri/src/files/models.rs
+ * These models map directly to the models in /src-tauri/src/files/models.rs
  */
 
+// unlisted => reachable by its link, but left out of the shared list
+export type Visibility = "public" | "private" | "unlisted";
+
+// What happens to a file once its share limit is reached
+export type ExpiryAction = "private" | "remove";
+
 export type FileModel = {
   id: string;
   name: string;
   mime: string;
-  visibility: "public" | "private";
+  visibility: Visibility;
   path: string;
+  missing?: boolean; // Set by the file watcher when the file is gone from its path
+  size?: number | null;
+  modifiedAt?: number | null;
+  // Share limits, set with the set_share_limits command
+  shareUntil?: number | null; // Unix seconds
+  maxDownloads?: number | null;
+  downloadCount?: number;
+  onExpiry?: ExpiryAction;
+  encrypted?: boolean; // Served encrypted with a passphrase, see set_file_passphrase
+};
+
+// Who a private file is shared with, public files are shared with everyone anyway
+export type FileAcl = {
+  fileId: string;
+  deviceIds: string[]; // Known peers, see KnownPeer
+  groupIds: string[]; // See PeerGroup
+};
+
+export type SnippetModel = {
+  id: string;
+  title: string;
+  body: string;
+  visibility: Visibility;
 };
 
 export type FileResponse = {
   id: string;
   name: string;
   mime: string;
+  kind: "file" | "snippet";
+  encrypted?: boolean; // In the age format, download_from_peer needs the passphrase
 };
@@ -22,4 +22,99 @@ import { OsType } from "@tauri-apps/plugin-os";
 export type Peer = {
   address: "This machine" | string; // Local IP address of the peer
   osType: OsType; // OS of the peer
+  // Below is only known for peers found by check_peer
+  protocolVersion?: number; // 1 for older peers, that only report their OS
+  appVersion?: string | null;
+  deviceId?: string | null;
+  deviceName?: string | null;
+  publicKey?: string | null; // Ed25519, base64
+  capabilities?: Capability[]; // What the peer can serve, check before using a feature
+};
+
+// Mirrors http_server::models::Capability on the Rust side
+export type Capability =
+  | "files"
+  | "snippets"
+  | "events"
+  | "ranges"
+  | "signatures"
+  | "webdav"
+  | "clipboard"
+  | "messages"
+  | "transfers"
+  | "folders"
+  | "noise" // The encrypted transport on port 38900
+  | "encryption"; // Files shared with a passphrase, in the age format
+
+// What went wrong, mirrors error::ErrorCode on the Rust side
+export type ErrorCode =
+  | "not_found"
+  | "forbidden"
+  | "invalid_input"
+  | "too_large"
+  | "conflict"
+  | "gone"
+  | "range_not_satisfiable"
+  | "unavailable"
+  | "peer_unreachable"
+  | "peer_unsupported" // The peer is too old for the feature, see Capability
+  | "internal";
+
+// What a failed Tauri command rejects with
+export type CommandError = {
+  code: ErrorCode;
+  message: string;
+  details: string | null; // The path, peer or limit the error is about, if any
+};
+
+// The identity of this device, as returned by get_device_identity and set_device_name
+export type DeviceIdentity = {
+  id: string; // Stays the same across IP address changes
+  name: string; // Picked by the user, or the hostname
+  defaultName: boolean; // Whether the name is the hostname
+  publicKey: string; // Ed25519, base64
+};
+
+// A peer that was seen before, mirrors peers::models::KnownPeerModel on the Rust side
+export type KnownPeer = {
+  deviceId: string;
+  deviceName: string | null; // The name the peer gave itself
+  nickname: string | null; // The name the user gave the peer
+  osType: OsType;
+  publicKey: string | null;
+  favourite: boolean;
+  encrypted: boolean; // Everything sent to the peer goes through the encrypted transport
+  webdavAccess: boolean; // Its file manager can log in to mount the shared folders
+  lastAddress: string;
+  addresses: string[]; // Every address the peer was seen at, the most recent first
+  lastSeenAt: number; // Unix seconds
+  createdAt: number;
+};
+
+// A named set of known peers, mirrors peers::models::PeerGroupModel on the Rust side
+export type PeerGroup = {
+  id: string;
+  name: string;
+  deviceIds: string[];
+  createdAt: number; // Unix seconds
+};
+
+// Mirrors access_control::models on the Rust side
+export type AccessAction = "allow" | "deny";
+
+export type AccessRule = {
+  id: string;
+  cidr: string; // 192.168.1.0/24, or a single address like 10.0.0.7/32
+  action: AccessAction;
+  createdAt: number; // Unix seconds
+};
+
+export type AccessLogEntry = {
+  id: string;
+  address: string;
+  method: string;
+  path: string;
+  status: number;
+  allowed: boolean;
+  createdAt: number; // Unix seconds
 };